@@ -28,11 +28,19 @@ use armour_lang::labels::{Label, Labels};
 use armour_lang::literals::{CPID, DPID};
 use armour_lang::policies::{self, OnboardingPolicy, GlobalPolicies, DPPolicies};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 pub const CONTROL_PLANE: &str = "https://localhost:8088";
 pub const TCP_PORT: u16 = 8088;
 
+/// Seconds between host heartbeats
+pub const HEARTBEAT_INTERVAL: u64 = 10;
+/// Seconds without a heartbeat before a host is considered stale
+pub const HOST_STALE_AFTER: u64 = 30;
+/// Seconds without a heartbeat before a host is considered dead
+pub const HOST_DEAD_AFTER: u64 = 120;
+
 
 pub const ONBOARDING_POLICY_KEY : &str = "onboarding_policy";
 pub const GLOBAL_POLICY_KEY : &str = "global_policy";
@@ -53,7 +61,56 @@ pub type LabelMap = std::collections::BTreeMap<String, Labels>;
 pub struct OnboardHostRequest {
     pub host: url::Url,
     pub label: Label,
-    pub credentials: HostCredentials, // FIXME change types as needed
+    pub credentials: HostCredentials,
+}
+
+/// Periodic report from a host, with its proxies and the (blake3) hashes of their policies
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostHeartbeat {
+    pub label: Label,
+    pub proxies: Vec<ProxyHeartbeat>,
+    /// version of the host's label cache
+    #[serde(default)]
+    pub label_cache: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyHeartbeat {
+    pub label: Label,
+    pub pid: u32,
+    /// map from global service IDs to hashes of the installed policies
+    pub services: BTreeMap<String, String>,
+}
+
+/// Liveness of a host, based on the time since its last heartbeat
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HostLiveness {
+    Healthy,
+    Stale,
+    Dead,
+}
+
+impl HostLiveness {
+    pub fn new(since_heartbeat: std::time::Duration) -> Self {
+        let secs = since_heartbeat.as_secs();
+        if secs >= HOST_DEAD_AFTER {
+            HostLiveness::Dead
+        } else if secs >= HOST_STALE_AFTER {
+            HostLiveness::Stale
+        } else {
+            HostLiveness::Healthy
+        }
+    }
+}
+
+impl std::fmt::Display for HostLiveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HostLiveness::Healthy => write!(f, "healthy"),
+            HostLiveness::Stale => write!(f, "stale"),
+            HostLiveness::Dead => write!(f, "dead"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -96,20 +153,26 @@ pub struct POnboardedServiceRequest {
     pub host: Label,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PolicyUpdateRequest {
     pub label: Label,
     pub policy: DPPolicies,
     pub labels: LabelMap,
+    // recorded in the policy history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CPPolicyUpdateRequest {
     pub label: Label,
     pub policy: GlobalPolicies,
     pub labels: LabelMap,
     //used to select onboarded services that need to be updated when the global policy is updated
     pub selector: Option<Label>,
+    // recorded in the policy history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -117,6 +180,9 @@ pub struct OnboardingUpdateRequest {
     pub label: Label,
     pub policy: OnboardingPolicy,
     pub labels: LabelMap,
+    // recorded in the policy history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
 }
 
 impl OnboardingUpdateRequest {
@@ -128,13 +194,15 @@ impl OnboardingUpdateRequest {
             policy: g,
             labels: self.labels,
             selector: None,
+            comment: self.comment,
         }
     }
     pub fn unpack(pol: CPPolicyUpdateRequest) -> Self {
         OnboardingUpdateRequest{
             label: pol.label.clone(),
             policy: pol.policy.policy(policies::Protocol::HTTP).unwrap().clone(),
-            labels: pol.labels
+            labels: pol.labels,
+            comment: pol.comment,
         }
     }
 }
@@ -144,8 +212,391 @@ pub struct PolicyQueryRequest {
     pub label: Label,
 }
 
+/// Query the policies of each service whose ID has the `selector` label
+/// (e.g. `ServiceID::<host>::**` for the services on-boarded by a host)
+#[derive(Serialize, Deserialize)]
+pub struct ServicesQueryRequest {
+    pub selector: Label,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PolicyQueryResponse {
     pub policy: DPPolicies,
     pub labels: LabelMap,
 }
+
+/// Policy recorded in a revision
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RevisionPolicy {
+    Local(PolicyUpdateRequest),
+    // global and (packed) onboarding policies
+    Global(CPPolicyUpdateRequest),
+}
+
+impl RevisionPolicy {
+    pub fn label(&self) -> &Label {
+        match self {
+            RevisionPolicy::Local(request) => &request.label,
+            RevisionPolicy::Global(request) => &request.label,
+        }
+    }
+    pub fn comment(&self) -> Option<&String> {
+        match self {
+            RevisionPolicy::Local(request) => request.comment.as_ref(),
+            RevisionPolicy::Global(request) => request.comment.as_ref(),
+        }
+    }
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        match self {
+            RevisionPolicy::Local(request) => request.comment = comment,
+            RevisionPolicy::Global(request) => request.comment = comment,
+        }
+    }
+    pub fn blake3(&self) -> String {
+        match self {
+            RevisionPolicy::Local(request) => request.policy.blake3(),
+            RevisionPolicy::Global(request) => request.policy.blake3(),
+        }
+    }
+}
+
+/// Immutable record of a policy update
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PolicyRevision {
+    pub label: Label,
+    pub revision: i64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    // identity (from client certificate) of the caller that made the update
+    pub author: String,
+    pub hash: String, // blake3
+    pub comment: Option<String>,
+    pub policy: RevisionPolicy,
+}
+
+impl std::fmt::Display for PolicyRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>4}  {}  {}  {}",
+            self.revision,
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.author,
+            &self.hash[..std::cmp::min(12, self.hash.len())]
+        )?;
+        if let Some(comment) = &self.comment {
+            write!(f, "  {}", comment)?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PolicyRollbackRequest {
+    pub label: Label,
+    pub revision: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+/// Policy (blake3) hashes of a label affected by a REST call
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditTarget {
+    pub label: Label,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Key for the hashes of the audit log (see `audit_key`)
+pub type AuditKey = [u8; 32];
+
+/// Maximum number of audit log records in a response
+pub const AUDIT_PAGE_LIMIT: usize = 1000;
+
+/// Derive an audit log key from (secret) key material
+pub fn audit_key(key_material: &[u8]) -> AuditKey {
+    let mut key = AuditKey::default();
+    blake3::derive_key("armour control plane audit log", key_material, &mut key);
+    key
+}
+
+/// Entry of the control plane audit log.
+///
+/// Entries are chained: `hash` is the blake3 hash of the entry (computed with an empty `hash`),
+/// which includes the hash of the `previous` entry. When the log is keyed, the hash is a
+/// keyed blake3 hash (a MAC), so that the chain cannot be rebuilt without the key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub sequence: i64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub endpoint: String,
+    // identity (from client certificate) of the caller
+    pub identity: String,
+    pub targets: Vec<AuditTarget>,
+    pub result: String,
+    pub previous: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    pub fn blake3(&self, key: Option<&AuditKey>) -> String {
+        let mut record = self.clone();
+        record.hash = String::new();
+        let bytes = serde_json::to_vec(&record).unwrap_or_default();
+        match key {
+            Some(key) => blake3::keyed_hash(key, &bytes),
+            None => blake3::hash(&bytes),
+        }
+        .to_hex()
+        .to_string()
+    }
+    pub fn has_label(&self, label: &Label) -> bool {
+        self.targets
+            .iter()
+            .any(|target| label.matches_with(&target.label))
+    }
+    /// Check that the record follows the `last` record of a chain (if any), and is unmodified
+    pub fn verify_next(&self, last: Option<&AuditRecord>, key: Option<&AuditKey>) -> Result<(), String> {
+        if let Some(last) = last {
+            if self.sequence != last.sequence + 1 {
+                return Err(format!(
+                    "record {} follows record {}",
+                    self.sequence, last.sequence
+                ));
+            }
+            if self.previous != last.hash {
+                return Err(format!(
+                    "record {} is not chained to the previous record",
+                    self.sequence
+                ));
+            }
+        } else if self.sequence == 1 && !self.previous.is_empty() {
+            return Err("first record has a previous hash".to_string());
+        }
+        if self.hash != self.blake3(key) {
+            return Err(format!("record {} has been modified", self.sequence));
+        }
+        Ok(())
+    }
+    /// Check that the records form an unbroken chain (from the first record)
+    pub fn verify_chain<'a, I: IntoIterator<Item = &'a AuditRecord>>(
+        records: I,
+        key: Option<&AuditKey>,
+    ) -> Result<usize, String> {
+        let mut count = 0;
+        let mut last: Option<&AuditRecord> = None;
+        for record in records {
+            record.verify_next(last, key)?;
+            last = Some(record);
+            count += 1
+        }
+        Ok(count)
+    }
+}
+
+impl std::fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>5}  {}  {}  {}  {}",
+            self.sequence,
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.identity,
+            self.endpoint,
+            self.result
+        )?;
+        for target in self.targets.iter() {
+            let short = |hash: &Option<String>| match hash {
+                Some(hash) => hash[..std::cmp::min(12, hash.len())].to_string(),
+                None => "-".to_string(),
+            };
+            write!(
+                f,
+                "  {} ({} -> {})",
+                target.label,
+                short(&target.before),
+                short(&target.after)
+            )?
+        }
+        Ok(())
+    }
+}
+
+/// Select audit log records by time range and (target) label.
+///
+/// At most `limit` (and `AUDIT_PAGE_LIMIT`) records are returned, that follow the
+/// record with sequence number `after`. Further records are fetched by setting `after`
+/// to the sequence number of the last record returned.
+#[derive(Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub label: Option<Label>,
+    #[serde(default)]
+    pub after: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Range of the audit log, for exporting the log a page at a time
+#[derive(Default, Serialize, Deserialize)]
+pub struct AuditPage {
+    #[serde(default)]
+    pub after: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl AuditPage {
+    /// Number of records to return, at most `AUDIT_PAGE_LIMIT`
+    pub fn limit(limit: Option<usize>) -> usize {
+        limit.unwrap_or(AUDIT_PAGE_LIMIT).clamp(1, AUDIT_PAGE_LIMIT)
+    }
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.from.map(|from| from <= record.time).unwrap_or(true)
+            && self.to.map(|to| record.time <= to).unwrap_or(true)
+            && self
+                .label
+                .as_ref()
+                .map(|label| record.has_label(label))
+                .unwrap_or(true)
+    }
+}
+
+/// Change to the state of the control plane
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ControlEvent {
+    HostOnboarded {
+        label: Label,
+        host: url::Url,
+    },
+    HostDropped {
+        label: Label,
+        host: url::Url,
+    },
+    ServiceOnboarded {
+        service: Label,
+        host: Label,
+    },
+    ServiceDropped {
+        service: Label,
+    },
+    PolicyUpdated {
+        label: Label,
+        revision: i64,
+        hash: String,
+    },
+    PolicyDropped {
+        label: Label,
+    },
+    SpecializationFailed {
+        label: Label,
+        error: String,
+    },
+    // `service` is `None` for label cache updates
+    PushFailed {
+        host: url::Url,
+        service: Option<Label>,
+        error: String,
+    },
+    // sent to a resuming client when events that follow `after` are no longer available
+    // (or the control plane has restarted), with the sequence number `EVENTS_MISSED_SEQUENCE`
+    EventsMissed {
+        after: i64,
+    },
+}
+
+impl std::fmt::Display for ControlEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ControlEvent::HostOnboarded { label, host } => {
+                write!(f, "host on-boarded: {} ({})", label, host)
+            }
+            ControlEvent::HostDropped { label, host } => {
+                write!(f, "host dropped: {} ({})", label, host)
+            }
+            ControlEvent::ServiceOnboarded { service, host } => {
+                write!(f, "service on-boarded: {} on {}", service, host)
+            }
+            ControlEvent::ServiceDropped { service } => write!(f, "service dropped: {}", service),
+            ControlEvent::PolicyUpdated {
+                label,
+                revision,
+                hash,
+            } => write!(
+                f,
+                "policy updated: {} revision {} ({})",
+                label,
+                revision,
+                &hash[..std::cmp::min(12, hash.len())]
+            ),
+            ControlEvent::PolicyDropped { label } => write!(f, "policy dropped: {}", label),
+            ControlEvent::SpecializationFailed { label, error } => {
+                write!(f, "specialization failed: {}: {}", label, error)
+            }
+            ControlEvent::PushFailed {
+                host,
+                service: Some(service),
+                error,
+            } => write!(f, "push failed: policy of {} to {}: {}", service, host, error),
+            ControlEvent::PushFailed {
+                host,
+                service: None,
+                error,
+            } => write!(f, "push failed: label cache to {}: {}", host, error),
+            ControlEvent::EventsMissed { after } => {
+                write!(f, "events missed: events after {} are not available", after)
+            }
+        }
+    }
+}
+
+/// Sequence number of `EventsMissed` events. Other events are numbered from 1, so clients do not
+/// resume from it.
+pub const EVENTS_MISSED_SEQUENCE: i64 = 0;
+
+/// Event streamed from `/events`, numbered in order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub sequence: i64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    pub event: ControlEvent,
+}
+
+impl std::fmt::Display for EventRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>5}  {}  {}",
+            self.sequence,
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.event
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    // server-sent events
+    Sse,
+    // newline-delimited JSON
+    Ndjson,
+}
+
+/// Query string of `/events`
+#[derive(Default, Serialize, Deserialize)]
+pub struct EventsQuery {
+    // resume with the events that follow this sequence number
+    #[serde(default)]
+    pub after: Option<i64>,
+    #[serde(default)]
+    pub format: Option<EventFormat>,
+}
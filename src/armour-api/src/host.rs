@@ -24,10 +24,14 @@
  * SOFTWARE.
  */
 
-use crate::proxy::HttpConfig;
+use crate::metrics;
+use crate::proxy::{
+    HttpConfig, LabelOp, LoadBalancing, ProxyProtocol, ServiceId, TcpUpdate, Upstreams,
+};
 use crate::{DeserializeDecoder, SerializeEncoder};
 use actix::prelude::*;
 use armour_lang::{
+    expressions,
     labels::{Label, Labels},
     literals::{DPID},
     policies,
@@ -40,12 +44,118 @@ use tokio_util::codec::{Decoder, Encoder};
 pub const DATA_PLANE_HOST: &str = "https://localhost:8090";
 pub const TCP_PORT: u16 = 8090;
 pub const UDS_SOCKET: &str = "armour";
+/// Seconds that a proxy keeps trying to re-connect to its host, after losing the connection
+pub const RESUME_TIMEOUT: u64 = 60;
+
+/// Request audit (dry-run) mode change
+#[derive(Serialize, Deserialize)]
+pub struct AuditUpdate {
+    pub label: Label,
+    pub enabled: bool,
+}
 
 /// Request policy update
+///
+/// The policy applies to a single `service` behind the proxies, if given, and otherwise to the whole proxy
 #[derive(Serialize, Deserialize)]
 pub struct PolicyUpdate {
     pub label: Label,
     pub policy: policies::DPPolicies,
+    #[serde(default)]
+    pub service: Option<ServiceId>,
+}
+
+/// Request shadow policy update (no policy removes the shadow policy)
+#[derive(Serialize, Deserialize)]
+pub struct ShadowUpdate {
+    pub label: Label,
+    pub policy: Option<policies::DPPolicies>,
+}
+
+/// Request proxy launch (`force` launches another proxy, even if one with the label exists)
+#[derive(Serialize, Deserialize)]
+pub struct LaunchRequest {
+    pub label: Label,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub debug: bool,
+    pub timeout: Option<u8>,
+}
+
+/// Request start of an HTTP, TCP or UDP proxy
+///
+/// An HTTP proxy with `upstreams` is an ingress proxy, balancing over the upstream servers
+#[derive(Serialize, Deserialize)]
+pub struct StartRequest {
+    pub label: Label,
+    pub protocol: policies::DPProtocol,
+    pub port: u16,
+    #[serde(default)]
+    pub upstreams: Vec<std::net::SocketAddr>,
+    #[serde(default)]
+    pub load_balancing: Option<LoadBalancing>,
+}
+
+impl StartRequest {
+    pub fn http_config(&self) -> HttpConfig {
+        match self.upstreams.as_slice() {
+            [] => HttpConfig::Port(self.port),
+            [server] => HttpConfig::Ingress(self.port, *server),
+            servers => HttpConfig::Upstreams(
+                self.port,
+                Upstreams {
+                    servers: servers.to_vec(),
+                    load_balancing: self.load_balancing.clone().unwrap_or_default(),
+                },
+            ),
+        }
+    }
+}
+
+/// Request stop of a proxy (all protocols, if none is given)
+#[derive(Serialize, Deserialize)]
+pub struct StopRequest {
+    pub label: Label,
+    #[serde(default)]
+    pub protocol: Option<policies::DPProtocol>,
+}
+
+/// Request timeout change (HTTP response timeout, or UDP flow idle timeout)
+#[derive(Serialize, Deserialize)]
+pub struct TimeoutUpdate {
+    pub label: Label,
+    pub secs: u16,
+}
+
+/// Request change to the handling of established TCP connections on policy installation
+#[derive(Serialize, Deserialize)]
+pub struct TcpUpdateRequest {
+    pub label: Label,
+    pub mode: TcpUpdate,
+}
+
+/// Request PROXY protocol (metadata over TCP) change
+#[derive(Serialize, Deserialize)]
+pub struct ProxyProtocolUpdate {
+    pub label: Label,
+    pub config: ProxyProtocol,
+}
+
+/// Request change to the labels of hosts (IPs or URIs) known to a proxy
+#[derive(Serialize, Deserialize)]
+pub struct LabelUpdate {
+    pub label: Label,
+    pub op: LabelOp,
+}
+
+/// Request change to the metadata key ring
+#[derive(Serialize, Deserialize)]
+pub enum KeysUpdate {
+    Rotate(String), // new current key (base64), shared by every host
+    Remove(u32),
+    MaxAge(u16),
+    Legacy(bool), // accept metadata without key IDs (deprecated)
 }
 
 /// Query current policy status
@@ -57,38 +167,272 @@ pub struct PolicyQuery {
 
 /// Current policy status
 ///
-/// Consists of proxy `name` and (blake3) hashes of current HTTP and TCP policies
+/// Consists of proxy `name`, and (blake3) hashes and versions of current HTTP, TCP and UDP policies
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PolicyStatus {
     pub label: Label,
     pub http: String, // hash
     pub tcp: String,  // hash
+    #[serde(default)]
+    pub udp: String,  // hash
+    #[serde(default)]
+    pub http_version: u64,
+    #[serde(default)]
+    pub tcp_version: u64,
+    #[serde(default)]
+    pub udp_version: u64,
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceVersions>,
+}
+
+/// Version of an installed policy
+///
+/// The counter increases with each policy installation at a proxy (the initial policies have version 0)
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PolicyVersion {
+    pub counter: u64,
+    pub hash: String, // blake3
+}
+
+impl PolicyVersion {
+    pub fn new(counter: u64, policy: &policies::DPPolicy) -> Self {
+        PolicyVersion {
+            counter,
+            hash: policy.blake3(),
+        }
+    }
+}
+
+impl std::fmt::Display for PolicyVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "v{} ({})", self.counter, self.hash)
+    }
+}
+
+/// Versions of the HTTP, TCP and UDP policies installed for a service
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServiceVersions {
+    pub http: PolicyVersion,
+    pub tcp: PolicyVersion,
+    pub udp: PolicyVersion,
+}
+
+impl std::fmt::Display for ServiceVersions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "http: {}; tcp: {}; udp: {}", self.http, self.tcp, self.udp)
+    }
+}
+
+/// Ports of the HTTP, TCP and UDP proxies (if started)
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Ports {
+    pub http: Option<u16>,
+    pub tcp: Option<u16>,
+    pub udp: Option<u16>,
+}
+
+impl Ports {
+    pub fn set(&mut self, protocol: &policies::DPProtocol, port: Option<u16>) {
+        match protocol {
+            policies::Protocol::HTTP => self.http = port,
+            policies::Protocol::TCP => self.tcp = port,
+            policies::Protocol::UDP => self.udp = port,
+            policies::Protocol::Phantom(_) => unreachable!(),
+        }
+    }
+}
+
+impl std::fmt::Display for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let port = |p: Option<u16>| p.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "http: {}; tcp: {}; udp: {}",
+            port(self.http),
+            port(self.tcp),
+            port(self.udp)
+        )
+    }
+}
+
+/// Resume handshake, sent by a running proxy when it re-connects to a (restarted) host
+#[derive(Serialize, Deserialize)]
+pub struct Resume {
+    pub pid: u32,
+    pub label: Label,
+    pub http: PolicyVersion,
+    pub tcp: PolicyVersion,
+    pub udp: PolicyVersion,
+    pub services: BTreeMap<ServiceId, ServiceVersions>,
+    pub ports: Ports,
 }
 
 /// Message from `proxy` instance to `host`
 #[derive(Serialize, Deserialize, Message)]
 #[rtype("()")]
 pub enum PolicyResponse {
-    Connect(u32, Option<DPID>, Label, String, String), // (PID, name, http hash, tcp hash)
+    Audit(Box<AuditSummary>),
+    Connect(u32, Option<DPID>, Label, PolicyVersion, PolicyVersion, PolicyVersion), // (PID, name, http, tcp, udp)
     CPOnboardingProxy(HashMap<std::net::IpAddr, Labels>), //ip service, labels attached at launch to service
+    Metrics(Box<metrics::Metrics>),
     RequestFailed,
+    Resume(Box<Resume>), // re-connection of a running proxy
+    Shadow(Box<ShadowSummary>),
     ShuttingDown,
-    Started,
+    Started(policies::DPProtocol, u16), // proxy started on port
     Status {
         label: Label,
         labels: BTreeMap<String, Labels>,
         http: Box<Status>,
         tcp: Box<Status>,
+        udp: Box<Status>,
+        services: BTreeMap<ServiceId, ServiceStatus>,
     },
-    Stopped,
-    UpdatedPolicy(policies::DPProtocol, String), // hash of new policy
+    Stopped(policies::DPProtocol),
+    UpdatedPolicy(Option<ServiceId>, policies::DPProtocol, PolicyVersion), // version of new policy (for a service)
+}
+
+/// Would-be denial, recorded by a proxy in audit mode
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub time: u64, // seconds since Unix epoch
+    pub function: String,
+    pub from: String,
+    pub to: String,
+    pub error: Option<String>, // set if the denial was due to a policy error
+}
+
+/// Summary of would-be denials for a proxy running in audit (dry-run) mode
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditSummary {
+    pub enabled: bool,
+    pub denials: BTreeMap<String, u64>, // counts by policy function
+    pub recent: Vec<AuditRecord>,       // most recent denials (oldest first)
+}
+
+impl AuditSummary {
+    pub const MAX_RECENT: usize = 20;
+    pub fn record(&mut self, record: AuditRecord) {
+        *self.denials.entry(record.function.clone()).or_default() += 1;
+        if self.recent.len() >= AuditSummary::MAX_RECENT {
+            self.recent.remove(0);
+        }
+        self.recent.push(record)
+    }
+}
+
+impl std::fmt::Display for AuditSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "audit mode: {}", if self.enabled { "on" } else { "off" })?;
+        for (function, count) in self.denials.iter() {
+            writeln!(f, "{}: {} would-be denials", function, count)?
+        }
+        for r in self.recent.iter() {
+            write!(f, "[{}] {}: {} -> {}", r.time, r.function, r.from, r.to)?;
+            if let Some(err) = &r.error {
+                write!(f, " ({})", err)?
+            }
+            writeln!(f)?
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of evaluating a policy function
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    Error(String),
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Decision::Allow => write!(f, "allow"),
+            Decision::Deny => write!(f, "deny"),
+            Decision::Error(err) => write!(f, "error ({})", err),
+        }
+    }
+}
+
+impl From<&Result<bool, expressions::Error>> for Decision {
+    fn from(res: &Result<bool, expressions::Error>) -> Self {
+        match res {
+            Ok(true) => Decision::Allow,
+            Ok(false) => Decision::Deny,
+            Err(err) => Decision::Error(err.to_string()),
+        }
+    }
+}
+
+/// Disagreement between the active and shadow policies
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShadowRecord {
+    pub time: u64, // seconds since Unix epoch
+    pub function: String,
+    pub request: String, // summary of the request/connection
+    pub active: Decision,
+    pub shadow: Decision,
+}
+
+/// Summary of shadow policy evaluation for a proxy
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ShadowSummary {
+    pub hashes: BTreeMap<String, String>, // shadow policy hashes, by protocol (empty if no shadow policy)
+    pub evaluations: u64,
+    pub disagreements: BTreeMap<String, u64>, // counts by policy function
+    pub recent: Vec<ShadowRecord>,            // most recent disagreements (oldest first)
+    pub skipped: u64,                          // evaluations skipped because too many were queued
+}
+
+impl ShadowSummary {
+    pub const MAX_RECENT: usize = 20;
+    pub fn is_active(&self) -> bool {
+        !self.hashes.is_empty()
+    }
+    pub fn record(&mut self, record: ShadowRecord) {
+        *self.disagreements.entry(record.function.clone()).or_default() += 1;
+        if self.recent.len() >= ShadowSummary::MAX_RECENT {
+            self.recent.remove(0);
+        }
+        self.recent.push(record)
+    }
+}
+
+impl std::fmt::Display for ShadowSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.is_active() {
+            return writeln!(f, "no shadow policy");
+        }
+        for (protocol, hash) in self.hashes.iter() {
+            writeln!(f, "shadow {} policy: {}", protocol, hash)?
+        }
+        writeln!(f, "evaluations: {}", self.evaluations)?;
+        if self.skipped != 0 {
+            writeln!(f, "skipped (under load): {}", self.skipped)?
+        }
+        for (function, count) in self.disagreements.iter() {
+            writeln!(f, "{}: {} disagreements", function, count)?
+        }
+        for r in self.recent.iter() {
+            writeln!(
+                f,
+                "[{}] {}: {} (active: {}, shadow: {})",
+                r.time, r.function, r.request, r.active, r.shadow
+            )?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Status {
     pub policy: policies::DPPolicy,
+    pub on_error: policies::OnError, // not part of the policy encoding
+    pub version: u64,
     pub port: Option<u16>,
-    pub ingress: Option<std::net::SocketAddrV4>,
+    pub ingress: Option<std::net::SocketAddr>,
 }
 
 impl std::fmt::Display for Status {
@@ -101,12 +445,80 @@ impl std::fmt::Display for Status {
         if let Some(ingress) = self.ingress {
             writeln!(f, "ingress for: {}", ingress)?
         }
+        writeln!(f, "policy version: {}", self.version)?;
+        writeln!(f, "on policy error: {}", self.on_error)?;
         write!(f, "policy is: {}", self.policy)
     }
 }
 
+/// Policies installed for a service behind a proxy
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServiceStatus {
+    pub http: Status,
+    pub tcp: Status,
+    pub udp: Status,
+}
+
+impl std::fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "=== HTTP ===\n{}\n=== TCP ===\n{}\n=== UDP ===\n{}",
+            self.http, self.tcp, self.udp
+        )
+    }
+}
+
 pub type Proxies = Vec<Proxy>;
 
+/// Current state of a proxy, as known by its host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyStatus {
+    pub pid: u32,
+    pub ports: Ports,
+    pub ingress: Option<std::net::SocketAddr>,
+    pub http: PolicyVersion,
+    pub tcp: PolicyVersion,
+    pub udp: PolicyVersion,
+    pub services: BTreeMap<String, ServiceVersions>,
+    pub requests: BTreeMap<String, u64>, // HTTP requests, TCP connections and UDP flows received
+    pub connections: BTreeMap<String, u64>, // open TCP connections and UDP flows
+}
+
+/// State of a proxy process launched by a host
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Restarting, // waiting to restart, after the proxy exited unexpectedly
+    CrashLoop,  // too many restarts in quick succession, so no longer restarted
+}
+
+/// Entry of a host's proxy listing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxySummary {
+    pub label: Label,
+    pub pid: Option<u32>,
+    pub state: ProcessState,
+    pub restarts: u32,
+    pub last_exit: Option<String>, // exit status of the previous process
+}
+
+impl std::fmt::Display for ProxySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, r#""{}"; state: {:?}"#, self.label, self.state)?;
+        if let Some(pid) = self.pid {
+            write!(f, "; pid: {}", pid)?
+        }
+        if self.restarts > 0 {
+            write!(f, "; restarts: {}", self.restarts)?
+        }
+        if let Some(exit) = self.last_exit.as_ref() {
+            write!(f, "; last exit: {}", exit)?
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Proxy {
     pub label: Label,
@@ -115,6 +527,14 @@ pub struct Proxy {
     #[serde(default)]
     pub debug: bool,
     ingress: Option<String>,
+    /// further ingress servers, balanced with `ingress`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    upstreams: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_balancing: Option<LoadBalancing>,
+    /// credentials for onboarding the proxy's services (instead of the host's)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
 }
 
 impl Proxy {
@@ -126,31 +546,61 @@ impl Proxy {
     }
     pub fn set_ingress(
         &mut self,
-        hosts: &BTreeMap<String, std::net::Ipv4Addr>,
+        hosts: &BTreeMap<String, std::net::IpAddr>,
     ) -> Result<(), String> {
-        if self.ingress().is_none() {
-            if let Some(ingress) = self.ingress.as_mut() {
-                match ingress.split(':').collect::<Vec<&str>>().as_slice() {
-                    [host, port] => match (hosts.get(&(*host).to_string()), port.parse::<u16>()) {
-                        (Some(host_ip), Ok(port)) => {
-                            *ingress = std::net::SocketAddrV4::new(*host_ip, port).to_string();
-                        }
-                        _ => return Err(format!("failed to set ingress: {}", ingress)),
-                    },
+        for ingress in self.ingress.iter_mut().chain(self.upstreams.iter_mut()) {
+            Proxy::resolve(ingress, hosts)?
+        }
+        Ok(())
+    }
+    // replace "host:port" with a socket address
+    fn resolve(
+        ingress: &mut String,
+        hosts: &BTreeMap<String, std::net::IpAddr>,
+    ) -> Result<(), String> {
+        if ingress.parse::<std::net::SocketAddr>().is_err() {
+            match ingress.rsplitn(2, ':').collect::<Vec<&str>>().as_slice() {
+                [port, host] => match (hosts.get(&(*host).to_string()), port.parse::<u16>()) {
+                    (Some(host_ip), Ok(port)) => {
+                        *ingress = std::net::SocketAddr::new(*host_ip, port).to_string();
+                    }
                     _ => return Err(format!("failed to set ingress: {}", ingress)),
-                }
+                },
+                _ => return Err(format!("failed to set ingress: {}", ingress)),
             }
         };
         Ok(())
     }
-    pub fn ingress(&self) -> Option<std::net::SocketAddrV4> {
+    pub fn ingress(&self) -> Option<std::net::SocketAddr> {
         self.ingress
             .as_ref()
-            .map(|s| s.parse::<std::net::SocketAddrV4>().ok())
+            .map(|s| s.parse::<std::net::SocketAddr>().ok())
             .flatten()
     }
+    pub fn upstreams(&self) -> Vec<std::net::SocketAddr> {
+        self.ingress()
+            .into_iter()
+            .chain(
+                self.upstreams
+                    .iter()
+                    .filter_map(|s| s.parse::<std::net::SocketAddr>().ok()),
+            )
+            .collect()
+    }
     pub fn config(&self, p: u16) -> HttpConfig {
         let port = self.port(p);
+        if !self.upstreams.is_empty() || self.load_balancing.is_some() {
+            let servers = self.upstreams();
+            if !servers.is_empty() {
+                return HttpConfig::Upstreams(
+                    port,
+                    Upstreams {
+                        servers,
+                        load_balancing: self.load_balancing.clone().unwrap_or_default(),
+                    },
+                );
+            }
+        }
         if let Some(ingress) = self.ingress() {
             HttpConfig::Ingress(port, ingress)
         } else {
@@ -167,6 +617,9 @@ impl From<Label> for Proxy {
             timeout: None,
             debug: false,
             ingress: None,
+            upstreams: Vec::new(),
+            load_balancing: None,
+            credentials: None,
         }
     }
 }
@@ -174,7 +627,7 @@ impl From<Label> for Proxy {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OnboardInformation {
     pub proxies: Proxies,
-    pub labels: Vec<(std::net::Ipv4Addr, Labels)>,
+    pub labels: Vec<(std::net::IpAddr, Labels)>,
 }
 
 impl OnboardInformation {
@@ -29,18 +29,23 @@ use bytes::{Buf, BufMut, BytesMut};
 
 pub mod control;
 pub mod host;
+pub mod metrics;
+pub mod openapi;
 pub mod proxy;
 
+// messages (e.g. label caches, metrics and status reports) can be larger than 64KB, so use a u32 length prefix
+const LENGTH_PREFIX: usize = 4;
+
 trait DeserializeDecoder<T: serde::de::DeserializeOwned, E: std::convert::From<std::io::Error>> {
     fn deserialize_decode(&mut self, src: &mut BytesMut) -> Result<Option<T>, E> {
         let size = {
-            if src.len() < 2 {
+            if src.len() < LENGTH_PREFIX {
                 return Ok(None);
             }
-            BigEndian::read_u16(src.as_ref()) as usize
+            BigEndian::read_u32(src.as_ref()) as usize
         };
-        if src.len() >= size + 2 {
-            src.advance(2);
+        if src.len() >= size + LENGTH_PREFIX {
+            src.advance(LENGTH_PREFIX);
             let buf = src.split_to(size);
             Ok(Some(bincode::deserialize::<T>(&buf).map_err(|e| {
                 std::io::Error::new(std::io::ErrorKind::Other, e)
@@ -55,8 +60,15 @@ trait SerializeEncoder<T: serde::Serialize, E: std::convert::From<std::io::Error
     fn serialize_encode(&mut self, msg: T, dst: &mut BytesMut) -> Result<(), E> {
         let msg = bincode::serialize(&msg).unwrap();
         let msg_ref: &[u8] = msg.as_ref();
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
+        if msg_ref.len() > u32::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("message too large: {} bytes", msg_ref.len()),
+            )
+            .into());
+        }
+        dst.reserve(msg_ref.len() + LENGTH_PREFIX);
+        dst.put_u32(msg_ref.len() as u32);
         dst.put(msg_ref);
         Ok(())
     }
@@ -0,0 +1,469 @@
+//! Proxy metrics, and their rendering in the Prometheus text exposition format
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_lang::{externals::ExternalStats, labels::Label, policies::DPProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the policy evaluation latency histogram buckets
+pub const BUCKETS: [f64; 10] = [
+    0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>, // (non-cumulative) counts for each of the `BUCKETS`
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if self.counts.len() != BUCKETS.len() {
+            self.counts.resize(BUCKETS.len(), 0)
+        }
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.counts[i] += 1
+        }
+        self.sum += secs;
+        self.count += 1
+    }
+}
+
+/// Number of policy decisions, by outcome
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Decisions {
+    pub allow: u64,
+    pub deny: u64,
+    pub error: u64,
+}
+
+/// Metrics collected by a proxy instance
+///
+/// Protocol keys are `http`, `tcp` and `udp`; function keys are policy function names.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Metrics {
+    /// HTTP requests, TCP connections and UDP flows received
+    pub requests: BTreeMap<String, u64>,
+    pub decisions: BTreeMap<String, Decisions>,
+    /// failed evaluations, by function and error kind (`timeout`, `external`, `type` or `other`)
+    pub errors: BTreeMap<String, BTreeMap<String, u64>>,
+    /// policy evaluation latency (only for functions that are evaluated)
+    pub evaluation: BTreeMap<String, Histogram>,
+    /// external calls, indexed by `external::method`
+    pub external: BTreeMap<String, ExternalStats>,
+    /// currently open TCP connections and UDP flows
+    pub active: BTreeMap<String, u64>,
+    pub bytes_sent: BTreeMap<String, u64>,
+    pub bytes_received: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn request(&mut self, protocol: &DPProtocol) {
+        *self.requests.entry(protocol.to_string()).or_default() += 1
+    }
+    /// Record a decision: `Some(allowed)`, or `None` if evaluation failed
+    pub fn decision(&mut self, function: &str, result: Option<bool>, elapsed: Option<Duration>) {
+        let decisions = self.decisions.entry(function.to_string()).or_default();
+        match result {
+            Some(true) => decisions.allow += 1,
+            Some(false) => decisions.deny += 1,
+            None => decisions.error += 1,
+        }
+        if let Some(elapsed) = elapsed {
+            self.evaluation
+                .entry(function.to_string())
+                .or_default()
+                .observe(elapsed)
+        }
+    }
+    pub fn error(&mut self, function: &str, kind: &str) {
+        *self
+            .errors
+            .entry(function.to_string())
+            .or_default()
+            .entry(kind.to_string())
+            .or_default() += 1
+    }
+    pub fn opened(&mut self, protocol: &DPProtocol) {
+        *self.active.entry(protocol.to_string()).or_default() += 1
+    }
+    pub fn closed(&mut self, protocol: &DPProtocol, sent: usize, received: usize) {
+        if let Some(active) = self.active.get_mut(&protocol.to_string()) {
+            *active = active.saturating_sub(1)
+        }
+        *self.bytes_sent.entry(protocol.to_string()).or_default() += sent as u64;
+        *self.bytes_received.entry(protocol.to_string()).or_default() += received as u64
+    }
+}
+
+// escape a Prometheus label value
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+// write a metric family: help and type lines, followed by one sample per proxy
+fn family<'a, F>(
+    out: &mut String,
+    name: &str,
+    typ: &str,
+    help: &str,
+    proxies: &[(&'a Label, &'a Metrics)],
+    samples: F,
+) where
+    F: Fn(&mut String, &str, &'a Metrics),
+{
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, typ);
+    for (label, metrics) in proxies {
+        samples(
+            out,
+            &format!(r#"proxy="{}""#, escape(&label.to_string())),
+            metrics,
+        )
+    }
+}
+
+fn counters(out: &mut String, name: &str, proxy: &str, key: &str, map: &BTreeMap<String, u64>) {
+    for (k, v) in map {
+        let _ = writeln!(
+            out,
+            r#"{}{{{},{}="{}"}} {}"#,
+            name,
+            proxy,
+            key,
+            escape(k),
+            v
+        );
+    }
+}
+
+/// Render host and proxy metrics in the Prometheus text exposition format
+pub fn prometheus(host: &Label, proxies: &[(&Label, &Metrics)]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP armour_proxies Number of connected proxy instances"
+    );
+    let _ = writeln!(out, "# TYPE armour_proxies gauge");
+    let _ = writeln!(
+        out,
+        r#"armour_proxies{{host="{}"}} {}"#,
+        escape(&host.to_string()),
+        proxies.len()
+    );
+    family(
+        &mut out,
+        "armour_requests_total",
+        "counter",
+        "HTTP requests, TCP connections and UDP flows received",
+        proxies,
+        |out, proxy, m| counters(out, "armour_requests_total", proxy, "protocol", &m.requests),
+    );
+    family(
+        &mut out,
+        "armour_decisions_total",
+        "counter",
+        "Policy decisions by function and result",
+        proxies,
+        |out, proxy, m| {
+            for (function, d) in &m.decisions {
+                for (result, n) in &[("allow", d.allow), ("deny", d.deny), ("error", d.error)] {
+                    let _ = writeln!(
+                        out,
+                        r#"armour_decisions_total{{{},function="{}",result="{}"}} {}"#,
+                        proxy, function, result, n
+                    );
+                }
+            }
+        },
+    );
+    family(
+        &mut out,
+        "armour_policy_errors_total",
+        "counter",
+        "Failed policy evaluations by function and error kind",
+        proxies,
+        |out, proxy, m| {
+            for (function, kinds) in &m.errors {
+                for (kind, n) in kinds {
+                    let _ = writeln!(
+                        out,
+                        r#"armour_policy_errors_total{{{},function="{}",kind="{}"}} {}"#,
+                        proxy, function, kind, n
+                    );
+                }
+            }
+        },
+    );
+    family(
+        &mut out,
+        "armour_evaluation_seconds",
+        "histogram",
+        "Policy evaluation latency",
+        proxies,
+        |out, proxy, m| {
+            for (function, h) in &m.evaluation {
+                let mut cumulative = 0;
+                for (i, le) in BUCKETS.iter().enumerate() {
+                    cumulative += h.counts.get(i).cloned().unwrap_or_default();
+                    let _ = writeln!(
+                        out,
+                        r#"armour_evaluation_seconds_bucket{{{},function="{}",le="{}"}} {}"#,
+                        proxy, function, le, cumulative
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    r#"armour_evaluation_seconds_bucket{{{},function="{}",le="+Inf"}} {}"#,
+                    proxy, function, h.count
+                );
+                let _ = writeln!(
+                    out,
+                    r#"armour_evaluation_seconds_sum{{{},function="{}"}} {}"#,
+                    proxy, function, h.sum
+                );
+                let _ = writeln!(
+                    out,
+                    r#"armour_evaluation_seconds_count{{{},function="{}"}} {}"#,
+                    proxy, function, h.count
+                );
+            }
+        },
+    );
+    family(
+        &mut out,
+        "armour_external_call_seconds",
+        "summary",
+        "External call latency",
+        proxies,
+        |out, proxy, m| {
+            for (external, stats) in &m.external {
+                let _ = writeln!(
+                    out,
+                    r#"armour_external_call_seconds_sum{{{},external="{}"}} {}"#,
+                    proxy,
+                    escape(external),
+                    stats.seconds
+                );
+                let _ = writeln!(
+                    out,
+                    r#"armour_external_call_seconds_count{{{},external="{}"}} {}"#,
+                    proxy,
+                    escape(external),
+                    stats.calls
+                );
+            }
+        },
+    );
+    family(
+        &mut out,
+        "armour_external_errors_total",
+        "counter",
+        "Failed external calls",
+        proxies,
+        |out, proxy, m| {
+            for (external, stats) in &m.external {
+                let _ = writeln!(
+                    out,
+                    r#"armour_external_errors_total{{{},external="{}"}} {}"#,
+                    proxy,
+                    escape(external),
+                    stats.errors
+                );
+            }
+        },
+    );
+    family(
+        &mut out,
+        "armour_active_connections",
+        "gauge",
+        "Open TCP connections and UDP flows",
+        proxies,
+        |out, proxy, m| {
+            counters(
+                out,
+                "armour_active_connections",
+                proxy,
+                "protocol",
+                &m.active,
+            )
+        },
+    );
+    family(
+        &mut out,
+        "armour_sent_bytes_total",
+        "counter",
+        "Bytes sent from clients to servers (TCP and UDP)",
+        proxies,
+        |out, proxy, m| {
+            counters(
+                out,
+                "armour_sent_bytes_total",
+                proxy,
+                "protocol",
+                &m.bytes_sent,
+            )
+        },
+    );
+    family(
+        &mut out,
+        "armour_received_bytes_total",
+        "counter",
+        "Bytes received by clients from servers (TCP and UDP)",
+        proxies,
+        |out, proxy, m| {
+            counters(
+                out,
+                "armour_received_bytes_total",
+                proxy,
+                "protocol",
+                &m.bytes_received,
+            )
+        },
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn samples<'a>(out: &'a str, name: &str) -> Vec<&'a str> {
+        out.lines()
+            .filter(|line| line.starts_with(name) && !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        let mut h = Histogram::default();
+        // bucket bounds are inclusive
+        h.observe(Duration::from_micros(100));
+        h.observe(Duration::from_micros(101));
+        h.observe(Duration::from_millis(1));
+        // beyond the last bucket: only in the count (`+Inf`)
+        h.observe(Duration::from_secs(10));
+        assert_eq!(h.counts, vec![1, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(h.count, 4);
+        assert!((h.sum - 10.001_201).abs() < 1e-9)
+    }
+
+    #[test]
+    fn exposition_format() {
+        let host = Label::from_str("host").unwrap();
+        let proxy = Label::from_str("proxy").unwrap();
+        let mut m = Metrics::default();
+        m.request(&DPProtocol::HTTP);
+        m.decision(
+            "allow_rest_request",
+            Some(true),
+            Some(Duration::from_millis(2)),
+        );
+        m.decision(
+            "allow_rest_request",
+            Some(false),
+            Some(Duration::from_secs(10)),
+        );
+        m.error("allow_rest_request", "timeout");
+        m.opened(&DPProtocol::TCP);
+        m.opened(&DPProtocol::TCP);
+        m.closed(&DPProtocol::TCP, 10, 20);
+        let out = prometheus(&host, &[(&proxy, &m)]);
+
+        assert!(out.contains("# HELP armour_proxies Number of connected proxy instances\n"));
+        assert!(out.contains("# TYPE armour_evaluation_seconds histogram\n"));
+        assert!(out.contains("armour_proxies{host=\"host\"} 1\n"));
+        assert_eq!(
+            samples(&out, "armour_requests_total"),
+            vec![r#"armour_requests_total{proxy="proxy",protocol="http"} 1"#]
+        );
+        assert!(out.contains(
+            r#"armour_decisions_total{proxy="proxy",function="allow_rest_request",result="deny"} 1"#
+        ));
+        assert!(out.contains(
+            r#"armour_policy_errors_total{proxy="proxy",function="allow_rest_request",kind="timeout"} 1"#
+        ));
+        // histogram buckets are cumulative, and `+Inf` is the total count
+        let buckets = samples(&out, "armour_evaluation_seconds_bucket");
+        assert_eq!(buckets.len(), BUCKETS.len() + 1);
+        assert!(buckets[3].ends_with(r#"le="0.005"} 1"#));
+        assert!(buckets[9].ends_with(r#"le="5"} 1"#));
+        assert!(buckets[10].ends_with(r#"le="+Inf"} 2"#));
+        assert!(out.contains(
+            r#"armour_evaluation_seconds_count{proxy="proxy",function="allow_rest_request"} 2"#
+        ));
+        assert_eq!(
+            samples(&out, "armour_active_connections"),
+            vec![r#"armour_active_connections{proxy="proxy",protocol="tcp"} 1"#]
+        );
+        assert!(out.contains(r#"armour_received_bytes_total{proxy="proxy",protocol="tcp"} 20"#));
+        // every sample line is `name{labels} value`
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(series.ends_with('}'), "{}", line);
+            assert!(value.parse::<f64>().is_ok(), "{}", line)
+        }
+    }
+
+    #[test]
+    fn large_metrics_message() {
+        use crate::{host::HostCodec, host::PolicyResponse, proxy::PolicyCodec};
+        use tokio_util::codec::{Decoder, Encoder};
+        let mut m = Metrics::default();
+        for i in 0..10_000 {
+            m.decision(&format!("function_{}", i), Some(true), None)
+        }
+        let mut buf = bytes::BytesMut::new();
+        PolicyCodec
+            .encode(PolicyResponse::Metrics(Box::new(m)), &mut buf)
+            .unwrap();
+        assert!(buf.len() > 64 * 1024);
+        // a partial message is not decoded
+        let mut partial = buf.split_to(buf.len() / 2);
+        assert!(HostCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        match HostCodec.decode(&mut partial).unwrap() {
+            Some(PolicyResponse::Metrics(m)) => {
+                assert_eq!(m.decisions.len(), 10_000);
+                assert_eq!(m.decisions["function_9999"].allow, 1)
+            }
+            _ => panic!("expecting metrics"),
+        }
+        assert!(partial.is_empty())
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), r#"a\"b\\c\nd"#)
+    }
+}
@@ -0,0 +1,1137 @@
+//! OpenAPI description of the data plane host REST API, built from the `host` types
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::host::{
+    AuditRecord, AuditSummary, AuditUpdate, KeysUpdate, LabelUpdate, LaunchRequest,
+    OnboardInformation, PolicyStatus, PolicyUpdate, PolicyVersion, Ports, ProcessState, Proxy,
+    ProxyProtocolUpdate, ProxyStatus, ProxySummary, ServiceVersions, ShadowRecord, ShadowSummary,
+    ShadowUpdate, StartRequest, StopRequest, TcpUpdateRequest, TimeoutUpdate,
+};
+use crate::proxy::{LabelCacheUpdate, LabelDelta, LabelSnapshot, LoadBalancing, ServiceId};
+use serde_json::{json, Map, Value};
+
+/// JSON schema of a type in the REST API
+pub trait Schema {
+    const NAME: &'static str;
+    fn schema() -> Value;
+    /// Add the schema (and those of the types it refers to) to the OpenAPI components
+    fn component(components: &mut Map<String, Value>) {
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+fn reference<T: Schema>() -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", T::NAME) })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn nullable(mut schema: Value) -> Value {
+    schema["nullable"] = Value::Bool(true);
+    schema
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn map(values: Value) -> Value {
+    json!({ "type": "object", "additionalProperties": values })
+}
+
+fn enumeration(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+// object with required and optional properties
+fn object(required: &[(&str, Value)], optional: &[(&str, Value)]) -> Value {
+    let properties: Map<String, Value> = required
+        .iter()
+        .chain(optional.iter())
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    let required: Vec<&str> = required.iter().map(|(name, _)| *name).collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+// externally tagged enum variant, e.g. `{"Remove": 1}`
+fn variant(name: &str, value: Value) -> Value {
+    object(&[(name, value)], &[])
+}
+
+fn label() -> Value {
+    json!({ "type": "string", "example": "Service::example" })
+}
+
+fn protocol() -> Value {
+    enumeration(&["HTTP", "TCP", "UDP"])
+}
+
+fn socket() -> Value {
+    json!({ "type": "string", "example": "127.0.0.1:8080" })
+}
+
+fn policies() -> Value {
+    json!({
+        "type": "object",
+        "description": "policies by protocol (HTTP, TCP or UDP), each encoded with bincode",
+        "additionalProperties": { "type": "string" }
+    })
+}
+
+fn label_op() -> Value {
+    // (IP or URI, labels) and (IP or URI, optional label) pairs
+    let add = array(json!({ "type": "array", "items": [string(), array(label())] }));
+    let remove = json!({ "type": "array", "items": [string(), nullable(label())] });
+    json!({
+        "oneOf": [
+            variant("AddIp", add.clone()),
+            variant("AddUri", add),
+            variant("RemoveIp", remove.clone()),
+            variant("RemoveUri", remove),
+            enumeration(&["Clear"]),
+        ]
+    })
+}
+
+impl Schema for AuditUpdate {
+    const NAME: &'static str = "AuditUpdate";
+    fn schema() -> Value {
+        object(&[("label", label()), ("enabled", boolean())], &[])
+    }
+}
+
+impl Schema for AuditRecord {
+    const NAME: &'static str = "AuditRecord";
+    fn schema() -> Value {
+        object(
+            &[
+                ("time", integer()),
+                ("function", string()),
+                ("from", string()),
+                ("to", string()),
+                ("error", nullable(string())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for AuditSummary {
+    const NAME: &'static str = "AuditSummary";
+    fn schema() -> Value {
+        object(
+            &[
+                ("enabled", boolean()),
+                ("denials", map(integer())),
+                ("recent", array(reference::<AuditRecord>())),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        AuditRecord::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for KeysUpdate {
+    const NAME: &'static str = "KeysUpdate";
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                variant("Rotate", json!({ "type": "string", "format": "byte" })),
+                variant("Remove", integer()),
+                variant("MaxAge", integer()),
+                variant("Legacy", boolean()),
+            ]
+        })
+    }
+}
+
+// labels by IP address
+fn ip_labels() -> Value {
+    map(array(label()))
+}
+
+impl Schema for LabelCacheUpdate {
+    const NAME: &'static str = "LabelCacheUpdate";
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                variant("Snapshot", reference::<LabelSnapshot>()),
+                variant("Delta", reference::<LabelDelta>()),
+            ]
+        })
+    }
+    fn component(components: &mut Map<String, Value>) {
+        LabelDelta::component(components);
+        LabelSnapshot::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for LabelDelta {
+    const NAME: &'static str = "LabelDelta";
+    fn schema() -> Value {
+        object(
+            &[
+                ("from", integer()),
+                ("to", integer()),
+                ("add", ip_labels()),
+                ("remove", ip_labels()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for LabelSnapshot {
+    const NAME: &'static str = "LabelSnapshot";
+    fn schema() -> Value {
+        object(&[("version", integer()), ("ips", ip_labels())], &[])
+    }
+}
+
+impl Schema for LabelUpdate {
+    const NAME: &'static str = "LabelUpdate";
+    fn schema() -> Value {
+        object(&[("label", label()), ("op", label_op())], &[])
+    }
+}
+
+impl Schema for LaunchRequest {
+    const NAME: &'static str = "LaunchRequest";
+    fn schema() -> Value {
+        object(
+            &[("label", label())],
+            &[
+                ("force", boolean()),
+                ("debug", boolean()),
+                ("timeout", nullable(integer())),
+            ],
+        )
+    }
+}
+
+impl Schema for LoadBalancing {
+    const NAME: &'static str = "LoadBalancing";
+    fn schema() -> Value {
+        object(
+            &[],
+            &[
+                ("balance", enumeration(&["round_robin", "least_request"])),
+                ("retries", integer()),
+                ("retry_budget", integer()),
+                ("eject_after", integer()),
+                ("eject_secs", integer()),
+            ],
+        )
+    }
+}
+
+impl Schema for OnboardInformation {
+    const NAME: &'static str = "OnboardInformation";
+    fn schema() -> Value {
+        let ip_labels = json!({ "type": "array", "items": [string(), array(label())] });
+        object(
+            &[
+                ("proxies", array(reference::<Proxy>())),
+                ("labels", array(ip_labels)),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        Proxy::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for PolicyStatus {
+    const NAME: &'static str = "PolicyStatus";
+    fn schema() -> Value {
+        object(
+            &[("label", label()), ("http", string()), ("tcp", string())],
+            &[
+                ("udp", string()),
+                ("http_version", integer()),
+                ("tcp_version", integer()),
+                ("udp_version", integer()),
+                ("services", map(reference::<ServiceVersions>())),
+            ],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        ServiceVersions::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for PolicyUpdate {
+    const NAME: &'static str = "PolicyUpdate";
+    fn schema() -> Value {
+        object(
+            &[("label", label()), ("policy", policies())],
+            &[("service", nullable(reference::<ServiceId>()))],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        ServiceId::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for PolicyVersion {
+    const NAME: &'static str = "PolicyVersion";
+    fn schema() -> Value {
+        object(&[("counter", integer()), ("hash", string())], &[])
+    }
+}
+
+impl Schema for Ports {
+    const NAME: &'static str = "Ports";
+    fn schema() -> Value {
+        let port = nullable(integer());
+        object(
+            &[("http", port.clone()), ("tcp", port.clone()), ("udp", port)],
+            &[],
+        )
+    }
+}
+
+impl Schema for ProcessState {
+    const NAME: &'static str = "ProcessState";
+    fn schema() -> Value {
+        enumeration(&["Running", "Restarting", "CrashLoop"])
+    }
+}
+
+impl Schema for Proxy {
+    const NAME: &'static str = "Proxy";
+    fn schema() -> Value {
+        object(
+            &[("label", label())],
+            &[
+                ("port", nullable(integer())),
+                ("timeout", nullable(integer())),
+                ("debug", boolean()),
+                ("ingress", nullable(socket())),
+                ("upstreams", array(socket())),
+                ("load_balancing", nullable(reference::<LoadBalancing>())),
+                ("credentials", nullable(string())),
+            ],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        LoadBalancing::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for ProxyProtocolUpdate {
+    const NAME: &'static str = "ProxyProtocolUpdate";
+    fn schema() -> Value {
+        let config = object(
+            &[
+                ("listen", boolean()),
+                (
+                    "destinations",
+                    array(json!({ "type": "string", "format": "ip" })),
+                ),
+            ],
+            &[],
+        );
+        object(&[("label", label()), ("config", config)], &[])
+    }
+}
+
+impl Schema for ProxyStatus {
+    const NAME: &'static str = "ProxyStatus";
+    fn schema() -> Value {
+        object(
+            &[
+                ("pid", integer()),
+                ("ports", reference::<Ports>()),
+                ("ingress", nullable(socket())),
+                ("http", reference::<PolicyVersion>()),
+                ("tcp", reference::<PolicyVersion>()),
+                ("udp", reference::<PolicyVersion>()),
+                ("services", map(reference::<ServiceVersions>())),
+                ("requests", map(integer())),
+                ("connections", map(integer())),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        Ports::component(components);
+        ServiceVersions::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for ProxySummary {
+    const NAME: &'static str = "ProxySummary";
+    fn schema() -> Value {
+        object(
+            &[
+                ("label", label()),
+                ("pid", nullable(integer())),
+                ("state", reference::<ProcessState>()),
+                ("restarts", integer()),
+                ("last_exit", nullable(string())),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        ProcessState::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for ServiceId {
+    const NAME: &'static str = "ServiceId";
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                variant("Ip", string()),
+                variant("Socket", socket()),
+                variant("Label", label()),
+            ]
+        })
+    }
+}
+
+impl Schema for ServiceVersions {
+    const NAME: &'static str = "ServiceVersions";
+    fn schema() -> Value {
+        object(
+            &[
+                ("http", reference::<PolicyVersion>()),
+                ("tcp", reference::<PolicyVersion>()),
+                ("udp", reference::<PolicyVersion>()),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        PolicyVersion::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for ShadowRecord {
+    const NAME: &'static str = "ShadowRecord";
+    fn schema() -> Value {
+        let decision = json!({
+            "oneOf": [enumeration(&["Allow", "Deny"]), variant("Error", string())]
+        });
+        object(
+            &[
+                ("time", integer()),
+                ("function", string()),
+                ("request", string()),
+                ("active", decision.clone()),
+                ("shadow", decision),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ShadowSummary {
+    const NAME: &'static str = "ShadowSummary";
+    fn schema() -> Value {
+        object(
+            &[
+                ("hashes", map(string())),
+                ("evaluations", integer()),
+                ("disagreements", map(integer())),
+                ("recent", array(reference::<ShadowRecord>())),
+                ("skipped", integer()),
+            ],
+            &[],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        ShadowRecord::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for ShadowUpdate {
+    const NAME: &'static str = "ShadowUpdate";
+    fn schema() -> Value {
+        object(&[("label", label()), ("policy", nullable(policies()))], &[])
+    }
+}
+
+impl Schema for StartRequest {
+    const NAME: &'static str = "StartRequest";
+    fn schema() -> Value {
+        object(
+            &[
+                ("label", label()),
+                ("protocol", protocol()),
+                ("port", integer()),
+            ],
+            &[
+                ("upstreams", array(socket())),
+                ("load_balancing", nullable(reference::<LoadBalancing>())),
+            ],
+        )
+    }
+    fn component(components: &mut Map<String, Value>) {
+        LoadBalancing::component(components);
+        components.insert(Self::NAME.to_string(), Self::schema());
+    }
+}
+
+impl Schema for StopRequest {
+    const NAME: &'static str = "StopRequest";
+    fn schema() -> Value {
+        object(&[("label", label())], &[("protocol", nullable(protocol()))])
+    }
+}
+
+impl Schema for TcpUpdateRequest {
+    const NAME: &'static str = "TcpUpdateRequest";
+    fn schema() -> Value {
+        object(
+            &[
+                ("label", label()),
+                ("mode", enumeration(&["Keep", "Reevaluate", "Drain"])),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for TimeoutUpdate {
+    const NAME: &'static str = "TimeoutUpdate";
+    fn schema() -> Value {
+        object(&[("label", label()), ("secs", integer())], &[])
+    }
+}
+
+// a JSON request body
+fn body(schema: Value) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schema } } })
+}
+
+// an operation, with an optional JSON request body and a response
+fn operation(summary: &str, request: Option<Value>, response: Value) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "responses": {
+            "200": response,
+            "400": { "description": "bad request, or no matching proxy" }
+        }
+    });
+    if let Some(request) = request {
+        operation["requestBody"] = body(request)
+    }
+    operation
+}
+
+fn ok() -> Value {
+    json!({ "description": "success" })
+}
+
+fn json_response(schema: Value) -> Value {
+    json!({ "description": "success", "content": { "application/json": { "schema": schema } } })
+}
+
+fn text_response() -> Value {
+    json!({ "description": "success", "content": { "text/plain": { "schema": string() } } })
+}
+
+/// OpenAPI (3.0) document for the data plane host REST API
+pub fn host_api() -> Value {
+    let mut components = Map::new();
+    AuditSummary::component(&mut components);
+    AuditUpdate::component(&mut components);
+    KeysUpdate::component(&mut components);
+    LabelCacheUpdate::component(&mut components);
+    LabelUpdate::component(&mut components);
+    LaunchRequest::component(&mut components);
+    OnboardInformation::component(&mut components);
+    PolicyStatus::component(&mut components);
+    PolicyUpdate::component(&mut components);
+    ProxyProtocolUpdate::component(&mut components);
+    ProxyStatus::component(&mut components);
+    ProxySummary::component(&mut components);
+    ShadowSummary::component(&mut components);
+    ShadowUpdate::component(&mut components);
+    StartRequest::component(&mut components);
+    StopRequest::component(&mut components);
+    TcpUpdateRequest::component(&mut components);
+    TimeoutUpdate::component(&mut components);
+    let by_label = |schema: Value| json_response(map(schema));
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Armour data plane host", "version": env!("CARGO_PKG_VERSION") },
+        "paths": {
+            "/host/audit": {
+                "get": operation(
+                    "audit summaries, by proxy",
+                    None,
+                    by_label(reference::<AuditSummary>())
+                ),
+                "post": operation(
+                    "enable/disable audit (dry-run) mode",
+                    Some(reference::<AuditUpdate>()),
+                    ok()
+                )
+            },
+            "/host/keys": {
+                "get": operation("metadata key ring", None, text_response()),
+                "post": operation(
+                    "change metadata key ring",
+                    Some(reference::<KeysUpdate>()),
+                    text_response()
+                )
+            },
+            "/host/label": {
+                "get": operation("host label", None, text_response())
+            },
+            "/host/label-cache": {
+                "get": operation(
+                    "labels of remote identities, from the control plane",
+                    None,
+                    json_response(reference::<LabelSnapshot>())
+                ),
+                "post": operation(
+                    "update label cache (409 if a delta does not apply to the current version)",
+                    Some(reference::<LabelCacheUpdate>()),
+                    json_response(reference::<LabelSnapshot>())
+                )
+            },
+            "/host/proxies": {
+                "get": operation(
+                    "proxies and their processes",
+                    None,
+                    json_response(array(reference::<ProxySummary>()))
+                )
+            },
+            "/host/quit": {
+                "post": operation(
+                    "shut down host (proxies wait to resume with a restarted host)",
+                    None,
+                    ok()
+                )
+            },
+            "/host/shadow": {
+                "get": operation(
+                    "shadow policy summaries, by proxy",
+                    None,
+                    by_label(reference::<ShadowSummary>())
+                ),
+                "post": operation(
+                    "set/remove shadow policy",
+                    Some(reference::<ShadowUpdate>()),
+                    ok()
+                )
+            },
+            "/metrics": {
+                "get": operation("metrics, in Prometheus text format", None, text_response())
+            },
+            "/policy/allow-all": {
+                "post": operation("install allow all policy", Some(label()), ok())
+            },
+            "/policy/deny-all": {
+                "post": operation("install deny all policy", Some(label()), ok())
+            },
+            "/policy/query": {
+                "get": operation(
+                    "policy status",
+                    Some(label()),
+                    json_response(array(reference::<PolicyStatus>()))
+                )
+            },
+            "/policy/update": {
+                "post": operation("install policy", Some(reference::<PolicyUpdate>()), ok())
+            },
+            "/proxy/labels": {
+                "post": operation("add or remove labels", Some(reference::<LabelUpdate>()), ok())
+            },
+            "/proxy/launch": {
+                "post": operation("launch proxy", Some(reference::<LaunchRequest>()), ok())
+            },
+            "/proxy/proxy-protocol": {
+                "post": operation(
+                    "exchange metadata using PROXY protocol (TCP)",
+                    Some(reference::<ProxyProtocolUpdate>()),
+                    ok()
+                )
+            },
+            "/proxy/shutdown": {
+                "post": operation("shut down proxy", Some(label()), ok())
+            },
+            "/proxy/start": {
+                "post": operation(
+                    "start HTTP, TCP or UDP proxy",
+                    Some(reference::<StartRequest>()),
+                    ok()
+                )
+            },
+            "/proxy/status": {
+                "get": operation(
+                    "proxy status, by proxy",
+                    None,
+                    by_label(reference::<ProxyStatus>())
+                )
+            },
+            "/proxy/stop": {
+                "post": operation("stop proxy", Some(reference::<StopRequest>()), ok())
+            },
+            "/proxy/tcp-update": {
+                "post": operation(
+                    "handling of established TCP connections on policy installation",
+                    Some(reference::<TcpUpdateRequest>()),
+                    ok()
+                )
+            },
+            "/proxy/timeout": {
+                "post": operation(
+                    "HTTP response timeout (up to 255 seconds)",
+                    Some(reference::<TimeoutUpdate>()),
+                    ok()
+                )
+            },
+            "/proxy/udp-timeout": {
+                "post": operation("UDP flow idle timeout", Some(reference::<TimeoutUpdate>()), ok())
+            },
+            "/service/drop": {
+                "delete": operation("shut down proxies", Some(array(reference::<Proxy>())), ok())
+            },
+            "/service/on-board": {
+                "post": operation(
+                    "launch and on-board proxies",
+                    Some(reference::<OnboardInformation>()),
+                    ok()
+                )
+            }
+        },
+        "components": { "schemas": components }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Decision, ProcessState};
+    use crate::proxy::{Balance, LabelOp, ProxyProtocol, TcpUpdate};
+    use armour_lang::{labels::Label, policies::DPPolicies};
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    // check a JSON value against a schema (the subset of OpenAPI schemas used above)
+    fn validate(
+        components: &Map<String, Value>,
+        schema: &Value,
+        value: &Value,
+    ) -> Result<(), String> {
+        if value.is_null() {
+            return if schema["nullable"] == true {
+                Ok(())
+            } else {
+                Err(format!("null is not nullable: {}", schema))
+            };
+        }
+        if let Some(name) = schema["$ref"].as_str() {
+            let name = name.trim_start_matches("#/components/schemas/");
+            let schema = components
+                .get(name)
+                .ok_or_else(|| format!("missing component: {}", name))?;
+            return validate(components, schema, value);
+        }
+        if let Some(alternatives) = schema["oneOf"].as_array() {
+            let matches = alternatives
+                .iter()
+                .filter(|schema| validate(components, schema, value).is_ok())
+                .count();
+            return if matches == 1 {
+                Ok(())
+            } else {
+                Err(format!("{} matches {} of {}", value, matches, schema))
+            };
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            if !values.contains(value) {
+                return Err(format!("{} is not one of {:?}", value, values));
+            }
+        }
+        let valid = match schema["type"].as_str() {
+            Some("string") => value.is_string(),
+            Some("boolean") => value.is_boolean(),
+            Some("integer") => value.is_u64(),
+            Some("array") => {
+                let values = value
+                    .as_array()
+                    .ok_or_else(|| format!("{} is not an array", value))?;
+                match &schema["items"] {
+                    // tuple
+                    Value::Array(items) if items.len() == values.len() => {
+                        for (schema, value) in items.iter().zip(values) {
+                            validate(components, schema, value)?
+                        }
+                        true
+                    }
+                    Value::Array(_) => false,
+                    items => {
+                        for value in values {
+                            validate(components, items, value)?
+                        }
+                        true
+                    }
+                }
+            }
+            Some("object") => {
+                let object = value
+                    .as_object()
+                    .ok_or_else(|| format!("{} is not an object", value))?;
+                for name in schema["required"].as_array().into_iter().flatten() {
+                    if !object.contains_key(name.as_str().unwrap()) {
+                        return Err(format!("{} is missing {}", value, name));
+                    }
+                }
+                for (name, value) in object {
+                    match (
+                        schema["properties"].get(name),
+                        schema.get("additionalProperties"),
+                    ) {
+                        (Some(schema), _) | (None, Some(schema)) => {
+                            validate(components, schema, value)?
+                        }
+                        (None, None) => return Err(format!("unexpected property: {}", name)),
+                    }
+                }
+                true
+            }
+            _ => return Err(format!("unsupported schema: {}", schema)),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("{} does not match {}", value, schema))
+        }
+    }
+
+    fn check<T: Schema + Serialize>(value: T) {
+        let mut components = Map::new();
+        T::component(&mut components);
+        let value = serde_json::to_value(value).unwrap();
+        if let Err(err) = validate(&components, &reference::<T>(), &value) {
+            panic!("{}: {}", T::NAME, err)
+        }
+    }
+
+    fn label() -> Label {
+        "Proxy::example".parse().unwrap()
+    }
+
+    fn version() -> PolicyVersion {
+        PolicyVersion {
+            counter: 1,
+            hash: "hash".to_string(),
+        }
+    }
+
+    fn versions() -> ServiceVersions {
+        ServiceVersions {
+            http: version(),
+            tcp: version(),
+            udp: version(),
+        }
+    }
+
+    fn ip_labels() -> BTreeMap<std::net::IpAddr, armour_lang::labels::Labels> {
+        let mut ips = BTreeMap::new();
+        ips.insert([10, 0, 0, 1].into(), vec![label()].into_iter().collect());
+        ips
+    }
+
+    fn counts() -> BTreeMap<String, u64> {
+        vec![("allow_rest_request".to_string(), 1)]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn requests() {
+        let socket: std::net::SocketAddr = ([127, 0, 0, 1], 8080).into();
+        check(AuditUpdate {
+            label: label(),
+            enabled: true,
+        });
+        check(KeysUpdate::Rotate("a2V5".to_string()));
+        check(KeysUpdate::Remove(1));
+        check(KeysUpdate::MaxAge(60));
+        check(KeysUpdate::Legacy(false));
+        check(LabelCacheUpdate::Snapshot(LabelSnapshot::new(
+            1,
+            ip_labels(),
+        )));
+        check(LabelCacheUpdate::Delta(LabelDelta {
+            from: 1,
+            to: 2,
+            add: ip_labels(),
+            remove: ip_labels(),
+        }));
+        for op in [
+            LabelOp::AddIp(ip_labels().into_iter().collect()),
+            LabelOp::AddUri(vec![("http://example".to_string(), Default::default())]),
+            LabelOp::RemoveIp([10, 0, 0, 1].into(), Some(label())),
+            LabelOp::RemoveUri("http://example".to_string(), None),
+            LabelOp::Clear,
+        ] {
+            check(LabelUpdate { label: label(), op })
+        }
+        check(LaunchRequest {
+            label: label(),
+            force: false,
+            debug: true,
+            timeout: Some(5),
+        });
+        let mut proxy = Proxy::from(label());
+        proxy.load_balancing = Some(LoadBalancing {
+            balance: Balance::LeastRequest,
+            ..Default::default()
+        });
+        proxy.credentials = Some("token".to_string());
+        check(OnboardInformation {
+            proxies: vec![proxy, Proxy::from(label())],
+            labels: ip_labels().into_iter().collect(),
+        });
+        for service in [
+            None,
+            Some(ServiceId::Ip([10, 0, 0, 1].into())),
+            Some(ServiceId::Socket(socket)),
+            Some(ServiceId::Label(label())),
+        ] {
+            check(PolicyUpdate {
+                label: label(),
+                policy: DPPolicies::allow_all(),
+                service,
+            })
+        }
+        check(ProxyProtocolUpdate {
+            label: label(),
+            config: ProxyProtocol::default(),
+        });
+        check(ProxyProtocolUpdate {
+            label: label(),
+            config: ProxyProtocol {
+                listen: true,
+                destinations: vec!["10.0.0.2".parse().unwrap(), "::1".parse().unwrap()]
+                    .into_iter()
+                    .collect(),
+            },
+        });
+        check(ShadowUpdate {
+            label: label(),
+            policy: Some(DPPolicies::deny_all()),
+        });
+        check(ShadowUpdate {
+            label: label(),
+            policy: None,
+        });
+        check(StartRequest {
+            label: label(),
+            protocol: armour_lang::policies::Protocol::TCP,
+            port: 6000,
+            upstreams: vec![socket],
+            load_balancing: Some(LoadBalancing::default()),
+        });
+        check(StopRequest {
+            label: label(),
+            protocol: Some(armour_lang::policies::Protocol::UDP),
+        });
+        check(StopRequest {
+            label: label(),
+            protocol: None,
+        });
+        for mode in [TcpUpdate::Keep, TcpUpdate::Reevaluate, TcpUpdate::Drain] {
+            check(TcpUpdateRequest {
+                label: label(),
+                mode,
+            })
+        }
+        check(TimeoutUpdate {
+            label: label(),
+            secs: 10,
+        })
+    }
+
+    #[test]
+    fn responses() {
+        let record = AuditRecord {
+            time: 1,
+            function: "allow_rest_request".to_string(),
+            from: "a".to_string(),
+            to: "b".to_string(),
+            error: Some("timeout".to_string()),
+        };
+        check(AuditSummary {
+            enabled: true,
+            denials: counts(),
+            recent: vec![
+                record.clone(),
+                AuditRecord {
+                    error: None,
+                    ..record
+                },
+            ],
+        });
+        check(LabelSnapshot::new(1, ip_labels()));
+        check(PolicyStatus {
+            label: label(),
+            http: "hash".to_string(),
+            tcp: "hash".to_string(),
+            udp: "hash".to_string(),
+            http_version: 1,
+            tcp_version: 1,
+            udp_version: 1,
+            services: vec![("10.0.0.1".to_string(), versions())]
+                .into_iter()
+                .collect(),
+        });
+        check(ProxyStatus {
+            pid: 1,
+            ports: Ports {
+                http: Some(6000),
+                tcp: None,
+                udp: None,
+            },
+            ingress: Some(([127, 0, 0, 1], 8080).into()),
+            http: version(),
+            tcp: version(),
+            udp: version(),
+            services: vec![("Service::a".to_string(), versions())]
+                .into_iter()
+                .collect(),
+            requests: counts(),
+            connections: counts(),
+        });
+        for state in [
+            ProcessState::Running,
+            ProcessState::Restarting,
+            ProcessState::CrashLoop,
+        ] {
+            check(ProxySummary {
+                label: label(),
+                pid: Some(1),
+                state,
+                restarts: 1,
+                last_exit: Some("exit status: 1".to_string()),
+            })
+        }
+        let record = ShadowRecord {
+            time: 1,
+            function: "allow_tcp_connection".to_string(),
+            request: "a -> b".to_string(),
+            active: Decision::Allow,
+            shadow: Decision::Error("timeout".to_string()),
+        };
+        check(ShadowSummary {
+            hashes: vec![("TCP".to_string(), "hash".to_string())]
+                .into_iter()
+                .collect(),
+            evaluations: 2,
+            disagreements: counts(),
+            recent: vec![
+                record.clone(),
+                ShadowRecord {
+                    shadow: Decision::Deny,
+                    ..record
+                },
+            ],
+            skipped: 1,
+        })
+    }
+
+    #[test]
+    fn mismatches() {
+        let components = Map::new();
+        let schema = AuditUpdate::schema();
+        let valid = json!({ "label": "Proxy::a", "enabled": true });
+        assert!(validate(&components, &schema, &valid).is_ok());
+        for value in &[
+            json!({ "label": "Proxy::a" }),
+            json!({ "label": "Proxy::a", "enabled": "yes" }),
+            json!({ "label": "Proxy::a", "enabled": true, "other": 1 }),
+            json!(null),
+        ] {
+            assert!(validate(&components, &schema, value).is_err(), "{}", value)
+        }
+        let schema = KeysUpdate::schema();
+        assert!(validate(&components, &schema, &json!({ "Remove": 1 })).is_ok());
+        assert!(validate(&components, &schema, &json!({ "Remove": -1 })).is_err());
+        assert!(validate(&components, &schema, &json!("Remove")).is_err());
+        assert!(validate(&components, &reference::<Ports>(), &json!({})).is_err())
+    }
+
+    #[test]
+    fn references() {
+        // every reference in the document has a component
+        fn refs(value: &Value, found: &mut Vec<String>) {
+            match value {
+                Value::Object(object) => {
+                    if let Some(Value::String(name)) = object.get("$ref") {
+                        found.push(name.trim_start_matches("#/components/schemas/").to_string())
+                    }
+                    object.values().for_each(|value| refs(value, found))
+                }
+                Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+                _ => (),
+            }
+        }
+        let api = host_api();
+        let mut found = Vec::new();
+        refs(&api, &mut found);
+        assert!(!found.is_empty());
+        for name in found {
+            assert!(
+                api["components"]["schemas"].get(&name).is_some(),
+                "{}",
+                name
+            )
+        }
+    }
+}
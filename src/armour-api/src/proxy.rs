@@ -26,25 +26,124 @@
 
 use super::{host, DeserializeDecoder, SerializeEncoder};
 use actix::prelude::*;
-use armour_lang::{labels, policies};
+use armour_lang::{labels, literals::DPID, policies};
 use bytes::BytesMut;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum LabelOp {
-    AddIp(Vec<(std::net::Ipv4Addr, labels::Labels)>),
+    AddIp(Vec<(std::net::IpAddr, labels::Labels)>),
     AddUri(Vec<(String, labels::Labels)>),
-    RemoveIp(std::net::Ipv4Addr, Option<labels::Label>),
+    RemoveIp(std::net::IpAddr, Option<labels::Label>),
     RemoveUri(String, Option<labels::Label>),
     Clear,
 }
 
+/// Labels of remote identities, as needed by the `has_label` calls of a host's policies
+///
+/// Snapshots are computed and versioned by the control plane.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LabelSnapshot {
+    pub version: u64,
+    pub ips: BTreeMap<std::net::IpAddr, labels::Labels>,
+}
+
+/// Difference between two versions of a label snapshot
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LabelDelta {
+    pub from: u64,
+    pub to: u64,
+    pub add: BTreeMap<std::net::IpAddr, labels::Labels>,
+    pub remove: BTreeMap<std::net::IpAddr, labels::Labels>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum LabelCacheUpdate {
+    Snapshot(LabelSnapshot),
+    Delta(LabelDelta),
+}
+
+impl LabelSnapshot {
+    pub fn new(version: u64, ips: BTreeMap<std::net::IpAddr, labels::Labels>) -> Self {
+        LabelSnapshot { version, ips }
+    }
+    pub fn get(&self, ip: &std::net::IpAddr) -> Option<&labels::Labels> {
+        self.ips.get(ip)
+    }
+    pub fn len(&self) -> usize {
+        self.ips.values().map(|labels| labels.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty()
+    }
+    // labels of `self` that are not in `other`
+    fn difference(&self, other: &Self) -> BTreeMap<std::net::IpAddr, labels::Labels> {
+        self.ips
+            .iter()
+            .filter_map(|(ip, labels)| {
+                let labels: labels::Labels = match other.ips.get(ip) {
+                    Some(others) => labels.difference(others).cloned().collect(),
+                    None => labels.clone(),
+                };
+                if labels.is_empty() {
+                    None
+                } else {
+                    Some((*ip, labels))
+                }
+            })
+            .collect()
+    }
+    /// Changes that take `self` to `next`
+    pub fn delta(&self, next: &Self) -> LabelDelta {
+        LabelDelta {
+            from: self.version,
+            to: next.version,
+            add: next.difference(self),
+            remove: self.difference(next),
+        }
+    }
+    /// Apply a delta, provided that it is based on the current version
+    pub fn apply(&mut self, delta: &LabelDelta) -> bool {
+        if delta.from != self.version {
+            return false;
+        }
+        for (ip, labels) in delta.remove.iter() {
+            if let Some(current) = self.ips.get_mut(ip) {
+                for label in labels {
+                    current.remove(label);
+                }
+                if current.is_empty() {
+                    self.ips.remove(ip);
+                }
+            }
+        }
+        for (ip, labels) in delta.add.iter() {
+            self.ips
+                .entry(*ip)
+                .or_default()
+                .extend(labels.iter().cloned())
+        }
+        self.version = delta.to;
+        true
+    }
+}
+
+impl LabelCacheUpdate {
+    pub fn version(&self) -> u64 {
+        match self {
+            LabelCacheUpdate::Snapshot(snapshot) => snapshot.version,
+            LabelCacheUpdate::Delta(delta) => delta.to,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum HttpConfig {
     Port(u16),
-    Ingress(u16, std::net::SocketAddrV4),
+    Ingress(u16, std::net::SocketAddr),
+    Upstreams(u16, Upstreams), // ingress, with a group of servers
 }
 
 impl HttpConfig {
@@ -52,29 +151,276 @@ impl HttpConfig {
         match self {
             HttpConfig::Port(p) => *p,
             HttpConfig::Ingress(p, _) => *p,
+            HttpConfig::Upstreams(p, _) => *p,
         }
     }
-    pub fn ingress(&self) -> Option<std::net::SocketAddrV4> {
+    pub fn ingress(&self) -> Option<std::net::SocketAddr> {
         match self {
             HttpConfig::Port(_p) => None,
             HttpConfig::Ingress(_p, socket) => Some(*socket),
+            HttpConfig::Upstreams(_p, upstreams) => upstreams.servers.first().cloned(),
+        }
+    }
+    pub fn upstreams(&self) -> Option<&Upstreams> {
+        match self {
+            HttpConfig::Upstreams(_p, upstreams) => Some(upstreams),
+            _ => None,
+        }
+    }
+}
+
+/// How an ingress proxy chooses between upstream servers
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    RoundRobin,
+    LeastRequest,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Balance::RoundRobin
+    }
+}
+
+/// Load balancing, retry and outlier ejection settings for an upstream group
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct LoadBalancing {
+    pub balance: Balance,
+    /// maximum number of retries for requests with idempotent methods
+    pub retries: u8,
+    /// retries, as a percentage of all requests
+    pub retry_budget: u8,
+    /// consecutive failures (5xx or connection errors) before a server is ejected
+    pub eject_after: u8,
+    /// seconds for which an ejected server is not used
+    pub eject_secs: u16,
+}
+
+impl Default for LoadBalancing {
+    fn default() -> Self {
+        LoadBalancing {
+            balance: Balance::default(),
+            retries: 2,
+            retry_budget: 20,
+            eject_after: 5,
+            eject_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Upstreams {
+    pub servers: Vec<std::net::SocketAddr>,
+    pub load_balancing: LoadBalancing,
+}
+
+/// Exchange of metadata with other Armour proxies, using PROXY protocol (v2) headers over TCP
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ProxyProtocol {
+    // read headers from (upstream) clients, which delays connections from clients that send nothing
+    pub listen: bool,
+    // destinations proxied by Armour (other destinations are not sent headers)
+    pub destinations: BTreeSet<std::net::IpAddr>,
+}
+
+impl std::fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if *self == ProxyProtocol::default() {
+            return write!(f, "off");
+        }
+        write!(f, "listen: {}", if self.listen { "on" } else { "off" })?;
+        let destinations: Vec<String> = self.destinations.iter().map(|ip| ip.to_string()).collect();
+        write!(f, ", send to: [{}]", destinations.join(", "))
+    }
+}
+
+/// What happens to established TCP connections when a new TCP policy is installed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TcpUpdate {
+    Keep,       // connections continue under the policy that admitted them
+    Reevaluate, // connections that the new policy denies are closed
+    Drain,      // connections admitted by previous policies are closed
+}
+
+impl Default for TcpUpdate {
+    fn default() -> Self {
+        TcpUpdate::Keep
+    }
+}
+
+impl std::str::FromStr for TcpUpdate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(TcpUpdate::Keep),
+            "reevaluate" | "re-evaluate" => Ok(TcpUpdate::Reevaluate),
+            "drain" => Ok(TcpUpdate::Drain),
+            _ => Err(format!("expecting keep, reevaluate or drain, got {}", s)),
+        }
+    }
+}
+
+/// Identity of a service behind a proxy, used to address per-service policies
+///
+/// Traffic belongs to a service when its destination (or, failing that, its source)
+/// has the IP address, socket address or label of the service.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ServiceId {
+    Ip(std::net::IpAddr),
+    Socket(std::net::SocketAddr),
+    Label(labels::Label),
+}
+
+impl ServiceId {
+    pub fn matches(&self, id: &DPID) -> bool {
+        match self {
+            ServiceId::Ip(ip) => id.has_ip(ip),
+            ServiceId::Socket(socket) => id.has_ip(&socket.ip()) && id.port() == Some(socket.port()),
+            ServiceId::Label(label) => id.has_label(label),
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServiceId::Ip(ip) => write!(f, "{}", ip),
+            ServiceId::Socket(socket) => write!(f, "{}", socket),
+            ServiceId::Label(label) => write!(f, "{}", label),
         }
     }
 }
 
+impl std::str::FromStr for ServiceId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            Ok(ServiceId::Ip(ip))
+        } else if let Ok(socket) = s.parse() {
+            Ok(ServiceId::Socket(socket))
+        } else {
+            s.parse()
+                .map(ServiceId::Label)
+                .map_err(|_| format!("expecting IP address, socket address or label, got {}", s))
+        }
+    }
+}
+
+/// Keys for encrypting and decrypting `x-armour` metadata, identified by key ID
+///
+/// Metadata is encrypted with the current key. During a rotation the previous key is
+/// still accepted for decryption, so that proxies can be updated one at a time.
+/// Key IDs are derived from the keys, so hosts that rotate to the same key agree on its ID.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct KeyRing {
+    pub current: u32,
+    pub keys: BTreeMap<u32, [u8; 32]>,
+    /// maximum age (in seconds) of received metadata
+    pub max_age: u16,
+    /// key for metadata without a key ID (sent by proxies that predate key IDs).
+    ///
+    /// Deprecated: this is off unless explicitly enabled, and will be removed once
+    /// all proxies send key IDs.
+    #[serde(default)]
+    pub legacy: Option<u32>,
+}
+
+impl KeyRing {
+    pub const DEFAULT_MAX_AGE: u16 = 60;
+    pub fn new(key: [u8; 32]) -> Self {
+        let id = KeyRing::id(&key);
+        let mut keys = BTreeMap::new();
+        keys.insert(id, key);
+        KeyRing {
+            current: id,
+            keys,
+            max_age: KeyRing::DEFAULT_MAX_AGE,
+            legacy: None,
+        }
+    }
+    /// Key ID: the first four bytes of the key's (blake3) hash
+    pub fn id(key: &[u8; 32]) -> u32 {
+        let hash = blake3::hash(key);
+        let bytes = hash.as_bytes();
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+    /// Fingerprint (for comparing keys without revealing them)
+    pub fn fingerprint(key: &[u8; 32]) -> String {
+        blake3::hash(key).to_hex()[..16].to_string()
+    }
+    /// Make `key` the current key, keeping the previous key for decryption only
+    pub fn rotate(&mut self, key: [u8; 32]) -> u32 {
+        let previous = self.current;
+        let id = KeyRing::id(&key);
+        self.keys.insert(id, key);
+        self.keys.retain(|k, _| *k == id || *k == previous);
+        self.current = id;
+        if self.legacy.map(|legacy| !self.keys.contains_key(&legacy)).unwrap_or(false) {
+            self.legacy = None
+        }
+        id
+    }
+    /// Stop accepting a key (the current key cannot be removed)
+    pub fn remove(&mut self, id: u32) -> bool {
+        if id != self.current && self.keys.remove(&id).is_some() {
+            if self.legacy == Some(id) {
+                self.legacy = None
+            }
+            true
+        } else {
+            false
+        }
+    }
+    /// Accept (or stop accepting) metadata without a key ID, decrypted with the current key
+    pub fn set_legacy(&mut self, enabled: bool) {
+        self.legacy = if enabled { Some(self.current) } else { None }
+    }
+    pub fn current_key(&self) -> Option<&[u8; 32]> {
+        self.keys.get(&self.current)
+    }
+}
+
+impl std::fmt::Display for KeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (id, key) in self.keys.iter() {
+            let status = if *id == self.current {
+                "current"
+            } else {
+                "decrypt only"
+            };
+            write!(f, "key {} [{}] ({})", id, KeyRing::fingerprint(key), status)?;
+            if self.legacy == Some(*id) {
+                write!(f, " (legacy metadata)")?
+            }
+            writeln!(f)?
+        }
+        write!(f, "max age: {}s", self.max_age)
+    }
+}
+
 /// Message to proxy instance
 #[derive(Serialize, Deserialize, Message, Clone)]
 #[rtype("()")]
 pub enum PolicyRequest {
+    Audit(bool), // enable/disable audit (dry-run) mode
     CPOnboard(HashMap<std::net::IpAddr, labels::Labels>),
     Label(LabelOp),
-    SetPolicy(policies::DPPolicies),
+    LabelCache(LabelSnapshot), // labels of remote identities, from the control plane
+    ProxyProtocol(ProxyProtocol), // exchange metadata over TCP using PROXY protocol (v2) headers
+    SetKeys(KeyRing), // install a key ring for `x-armour` metadata
+    SetPolicy(Option<ServiceId>, policies::DPPolicies), // policy for a service (or for the whole proxy)
+    SetShadowPolicy(Option<policies::DPPolicies>), // install (or remove) a shadow policy
     Shutdown,
     StartHttp(HttpConfig),
     StartTcp(u16),
+    StartUdp(u16),
     Status,
     Stop(policies::DPProtocol),
+    TcpUpdate(TcpUpdate), // handling of established connections on TCP policy installation
     Timeout(u8),
+    UdpTimeout(u16),
 }
 
 /// Transport codec for Host to Proxy instance communication
@@ -98,3 +444,189 @@ impl Encoder<host::PolicyResponse> for PolicyCodec {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_ring() {
+        let mut ring = KeyRing::new([0; 32]);
+        let first = ring.current;
+        assert_eq!(first, KeyRing::id(&[0; 32]));
+        // key IDs depend only on the key
+        let second = ring.rotate([1; 32]);
+        assert_eq!(second, KeyRing::new([1; 32]).current);
+        assert_eq!(ring.current_key(), Some(&[1; 32]));
+        assert_eq!(ring.keys.keys().cloned().collect::<Vec<u32>>().len(), 2);
+        // only the previous key is kept
+        let third = ring.rotate([2; 32]);
+        assert!(!ring.keys.contains_key(&first));
+        assert!(ring.keys.contains_key(&second) && ring.keys.contains_key(&third));
+        // the current key cannot be removed
+        assert!(!ring.remove(third));
+        assert!(ring.remove(second));
+        assert!(!ring.remove(second));
+        assert_eq!(ring.keys.len(), 1)
+    }
+
+    #[test]
+    fn legacy_keys() {
+        let mut ring = KeyRing::new([0; 32]);
+        assert_eq!(ring.legacy, None);
+        ring.set_legacy(true);
+        assert_eq!(ring.legacy, Some(ring.current));
+        let first = ring.current;
+        ring.rotate([1; 32]);
+        assert_eq!(ring.legacy, Some(first));
+        ring.rotate([2; 32]);
+        assert_eq!(ring.legacy, None)
+    }
+
+    #[test]
+    fn display_keys() {
+        let key = [7; 32];
+        let ring = KeyRing::new(key);
+        let display = ring.to_string();
+        assert!(display.contains(&KeyRing::fingerprint(&key)));
+        assert!(!display.contains("0707070707070707"));
+        assert!(display.contains("(current)"))
+    }
+
+    #[test]
+    fn status_error_strategy() {
+        let mut policy = policies::DPPolicy::deny_all(policies::Protocol::TCP);
+        policy.set_on_error(policies::OnError::FailOpen);
+        let status = host::Status {
+            on_error: policy.on_error,
+            policy,
+            version: 1,
+            port: None,
+            ingress: None,
+        };
+        let mut buf = BytesMut::new();
+        PolicyCodec
+            .encode(
+                host::PolicyResponse::Status {
+                    label: "proxy".parse().unwrap(),
+                    labels: BTreeMap::new(),
+                    http: Box::new(status.clone()),
+                    tcp: Box::new(status.clone()),
+                    udp: Box::new(status),
+                    services: BTreeMap::new(),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        match host::HostCodec.decode(&mut buf).unwrap() {
+            Some(host::PolicyResponse::Status { tcp, .. }) => {
+                // the strategy is not part of the policy encoding, but is reported with it
+                assert_eq!(tcp.policy.on_error, policies::OnError::FailClosed);
+                assert_eq!(tcp.on_error, policies::OnError::FailOpen);
+                assert!(tcp.to_string().contains("on policy error: fail-open"))
+            }
+            _ => panic!("expecting status"),
+        }
+    }
+
+    #[test]
+    fn large_label_cache() {
+        let ips: BTreeMap<std::net::IpAddr, labels::Labels> = (0..5_000u32)
+            .map(|i| {
+                let ip = std::net::IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i));
+                let label = format!("Service::service{}", i).parse().unwrap();
+                (ip, vec![label].into_iter().collect())
+            })
+            .collect();
+        let snapshot = LabelSnapshot::new(1, ips);
+        let mut buf = BytesMut::new();
+        host::HostCodec
+            .encode(PolicyRequest::LabelCache(snapshot.clone()), &mut buf)
+            .unwrap();
+        assert!(buf.len() > 64 * 1024);
+        // a partial message is not decoded
+        let mut partial = buf.split_to(buf.len() / 2);
+        assert!(PolicyCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        match PolicyCodec.decode(&mut partial).unwrap() {
+            Some(PolicyRequest::LabelCache(decoded)) => assert_eq!(decoded, snapshot),
+            _ => panic!("expecting label cache"),
+        }
+        assert!(partial.is_empty())
+    }
+
+    #[test]
+    fn large_status() {
+        let policy = policies::DPPolicy::deny_all(policies::Protocol::TCP);
+        let status = host::Status {
+            on_error: policy.on_error,
+            policy,
+            version: 1,
+            port: None,
+            ingress: None,
+        };
+        // labels of the proxy's hosts, including those from the control plane's label cache
+        let labels: BTreeMap<String, labels::Labels> = (0..5_000)
+            .map(|i| {
+                let label = format!("Service::service{}", i).parse().unwrap();
+                (format!("10.0.{}.{}", i / 256, i % 256), vec![label].into_iter().collect())
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        PolicyCodec
+            .encode(
+                host::PolicyResponse::Status {
+                    label: "proxy".parse().unwrap(),
+                    labels,
+                    http: Box::new(status.clone()),
+                    tcp: Box::new(status.clone()),
+                    udp: Box::new(status),
+                    services: BTreeMap::new(),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert!(buf.len() > 64 * 1024);
+        // a partial message is not decoded
+        let mut partial = buf.split_to(buf.len() / 2);
+        assert!(host::HostCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        match host::HostCodec.decode(&mut partial).unwrap() {
+            Some(host::PolicyResponse::Status { labels, .. }) => {
+                assert_eq!(labels.len(), 5_000);
+                assert!(labels["10.0.19.135"].contains(&"Service::service4999".parse().unwrap()))
+            }
+            _ => panic!("expecting status"),
+        }
+        assert!(partial.is_empty())
+    }
+
+    #[test]
+    fn service_id() {
+        let ip: std::net::IpAddr = "10.0.0.1".parse().unwrap();
+        let label: labels::Label = "Service::frontend".parse().unwrap();
+        for s in &["10.0.0.1", "10.0.0.1:443", "[::1]:443", "Service::frontend"] {
+            assert_eq!(s.parse::<ServiceId>().unwrap().to_string(), *s)
+        }
+        assert_eq!("10.0.0.1".parse(), Ok(ServiceId::Ip(ip)));
+        assert_eq!(
+            "10.0.0.1:443".parse(),
+            Ok(ServiceId::Socket(std::net::SocketAddr::new(ip, 443)))
+        );
+        assert_eq!("Service::frontend".parse(), Ok(ServiceId::Label(label.clone())));
+        assert!("not a label!".parse::<ServiceId>().is_err());
+        // IDs with the IP, socket address or label of the service
+        let id = DPID::new(
+            Default::default(),
+            vec![ip].into_iter().collect(),
+            Some(443),
+            vec![label.clone()].into_iter().collect(),
+        );
+        assert!(ServiceId::Ip(ip).matches(&id));
+        assert!("10.0.0.1:443".parse::<ServiceId>().unwrap().matches(&id));
+        assert!(!"10.0.0.1:80".parse::<ServiceId>().unwrap().matches(&id));
+        assert!(!"10.0.0.2".parse::<ServiceId>().unwrap().matches(&id));
+        assert!(ServiceId::Label(label).matches(&id));
+        assert!(!"Service::backend".parse::<ServiceId>().unwrap().matches(&id));
+        assert!(!ServiceId::Ip(ip).matches(&DPID::default()))
+    }
+}
@@ -34,6 +34,8 @@ static ARMOUR_ALT_NAMES: &[AltName] = &[
     AltName::DNS("localhost"),
     AltName::IP(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))),
 ];
+// control plane roles are carried as `armour-role:<role>` URIs
+static ARMOUR_ROLE: &str = "armour-role:";
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -58,6 +60,7 @@ fn build_x509_name(common_name: &str) -> Result<openssl::x509::X509Name, Error>
 enum AltName<'a> {
     DNS(&'a str),
     IP(std::net::IpAddr),
+    URI(&'a str),
 }
 
 impl<'a> std::fmt::Display for AltName<'a> {
@@ -65,6 +68,7 @@ impl<'a> std::fmt::Display for AltName<'a> {
         match self {
             AltName::DNS(domain) => write!(f, "DNS:{}", domain),
             AltName::IP(ip_addr) => write!(f, "IP:{}", ip_addr),
+            AltName::URI(uri) => write!(f, "URI:{}", uri),
         }
     }
 }
@@ -238,6 +242,21 @@ fn ip_dns_arg(name: &'static str) -> clap::Arg<'static, 'static> {
         .value_name("IPv4 or DNS name")
 }
 
+// roles of the generated certificates
+fn default_roles(name: &str) -> &'static [&'static str] {
+    match name {
+        "ctl" => &["admin"],
+        "host" => &["host"],
+        _ => &[],
+    }
+}
+
+fn role_uris<'a, I: Iterator<Item = &'a str>>(roles: I) -> Vec<String> {
+    roles
+        .map(|role| format!("{}{}", ARMOUR_ROLE, role))
+        .collect()
+}
+
 fn main() -> Result<(), Error> {
     let matches = App::new("armour-certs")
         .version(crate_version!())
@@ -253,6 +272,24 @@ fn main() -> Result<(), Error> {
         )
         .arg(ip_dns_arg("control"))
         .arg(ip_dns_arg("host"))
+        .arg(
+            Arg::with_name("issue")
+                .long("issue")
+                .required(false)
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Only issue a certificate for NAME (signed by the CA)"),
+        )
+        .arg(
+            Arg::with_name("role")
+                .long("role")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .requires("issue")
+                .value_name("ROLE")
+                .help("Control plane role of the issued certificate: viewer, host, service-owner:<label> or admin"),
+        )
         .get_matches();
 
     let dir = std::path::PathBuf::from(matches.value_of("directory").unwrap_or(ARMOUR_DIR));
@@ -290,12 +327,33 @@ fn main() -> Result<(), Error> {
         println!("created {}", ca_path.display())
     }
 
+    // issue a single certificate
+    if let Some(common_name) = matches.value_of("issue") {
+        let roles = role_uris(matches.values_of("role").into_iter().flatten());
+        let mut alt_names = ARMOUR_ALT_NAMES.to_vec();
+        alt_names.extend(roles.iter().map(|role| AltName::URI(role)));
+        let pass = password(common_name)?;
+        signed_x509(
+            &pass,
+            &dir,
+            common_name,
+            &ca_name,
+            &ca_key,
+            &ca_x509,
+            &alt_names,
+        )?;
+        println!("created {}/{}.p12", dir.display(), common_name);
+        return Ok(());
+    }
+
     // build, sign and save certificates
     for name in &["control", "ctl", "host", "launch"] {
-        let alt_names: Vec<AltName> = matches
+        let roles = role_uris(default_roles(name).iter().cloned());
+        let mut alt_names: Vec<AltName> = matches
             .values_of(name)
             .map(|names| names.map(|n| n.into()).collect())
             .unwrap_or_else(|| ARMOUR_ALT_NAMES.to_vec());
+        alt_names.extend(roles.iter().map(|role| AltName::URI(role)));
         let pass = password(name)?;
         let common_name = format!("{}{}", ARMOUR_PREFIX, name);
         signed_x509(
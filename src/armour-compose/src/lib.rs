@@ -53,6 +53,11 @@ pub struct Compose {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub proxies: armour_api::host::Proxies,
 
+    /// dual-stack (IPv4 and IPv6) bridge networks for services
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub ipv6: bool,
+
     // capture everything else (future proofing)
     #[serde(skip_serializing)]
     #[serde(flatten)]
@@ -72,6 +77,8 @@ pub struct ServiceInfo {
     // pub container_labels: armour_serde::array_dict::ArrayDict,
     // pub network: String,
     pub ipv4_address: Option<std::net::Ipv4Addr>,
+    #[serde(default)]
+    pub ipv6_address: Option<std::net::Ipv6Addr>,
 }
 
 impl From<&OnboardInfo> for OnboardInformation {
@@ -79,7 +86,10 @@ impl From<&OnboardInfo> for OnboardInformation {
         let mut labels = Vec::new();
         for service in info.services.values() {
             if let Some(ip) = service.ipv4_address {
-                labels.push((ip, service.armour_labels.clone()));
+                labels.push((ip.into(), service.armour_labels.clone()));
+            }
+            if let Some(ip) = service.ipv6_address {
+                labels.push((ip.into(), service.armour_labels.clone()));
             }
         }
         OnboardInformation {
@@ -103,11 +113,7 @@ impl Compose {
     }
     fn convert_for_armour(&mut self) -> Result<Vec<OnboardInfo>, String> {
         // iterator for network subnets
-        let mut subnets = ipnet::Ipv4Subnets::new(
-            "172.18.0.0".parse().unwrap(),
-            "172.31.255.0".parse().unwrap(),
-            24,
-        );
+        let mut subnets = network::Subnets::new(self.ipv6);
         let mut services = Map::new();
         let mut networks = Map::new();
         let mut extra_hosts = Map::new();
@@ -127,7 +133,7 @@ impl Compose {
                 }
             }
             service.container_name = Some(service_name.to_string());
-            let (service_info, network, ipv4_addr) =
+            let (service_info, network, ip_addr) =
                 service.convert_for_armour(service_name, &mut subnets);
             services.insert(service_name.to_string(), service_info);
             networks.insert(
@@ -135,8 +141,8 @@ impl Compose {
                 network,
             );
             if let Some(hostname) = &service.hostname {
-                if let Some(ipv4_addr) = ipv4_addr {
-                    extra_hosts.insert(hostname.to_string(), ipv4_addr);
+                if let Some(ip_addr) = ip_addr {
+                    extra_hosts.insert(hostname.to_string(), ip_addr);
                 }
             }
         }
@@ -52,7 +52,7 @@ deserialize_from_str!(Driver);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpamConfig {
-    pub subnet: ipnet::Ipv4Net,
+    pub subnet: ipnet::IpNet,
     //#[serde(skip_serializing)]
     //#[serde(flatten)]
     //#[serde(default)]
@@ -96,6 +96,10 @@ pub struct Network {
     #[serde(skip_serializing_if = "is_default")]
     pub internal: bool,
 
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub enable_ipv6: bool,
+
     #[serde(default)]
     #[serde(skip_serializing_if = "is_default")]
     pub attachable: bool,
@@ -110,6 +114,45 @@ pub struct Network {
     pub _extras: Map<String, serde_yaml::Value>,
 }
 
+/// Subnets for the Armour bridge networks (IPv4, and optionally IPv6)
+pub struct Subnets {
+    ipv4: ipnet::Ipv4Subnets,
+    ipv6: Option<ipnet::Ipv6Subnets>,
+}
+
+impl Subnets {
+    pub fn new(ipv6: bool) -> Self {
+        Subnets {
+            ipv4: ipnet::Ipv4Subnets::new(
+                "172.18.0.0".parse().unwrap(),
+                "172.31.255.0".parse().unwrap(),
+                24,
+            ),
+            // unique local addresses
+            ipv6: if ipv6 {
+                Some(ipnet::Ipv6Subnets::new(
+                    "fd00:a4a0:0:1::".parse().unwrap(),
+                    "fd00:a4a0:0:ffff::".parse().unwrap(),
+                    64,
+                ))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Iterator for Subnets {
+    type Item = (ipnet::Ipv4Net, Option<ipnet::Ipv6Net>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let ipv4 = self.ipv4.next()?;
+        match self.ipv6.as_mut() {
+            Some(ipv6) => ipv6.next().map(|ipv6| (ipv4, Some(ipv6))),
+            None => Some((ipv4, None)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
 pub struct NetworkRecord {
     #[serde(default)]
@@ -118,7 +161,7 @@ pub struct NetworkRecord {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ipv4_address: Option<std::net::Ipv4Addr>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    ipv6_address: Option<std::net::Ipv6Addr>,
+    pub ipv6_address: Option<std::net::Ipv6Addr>,
     // capture everything else (future proofing)
     #[serde(skip_serializing)]
     #[serde(flatten)]
@@ -187,11 +187,11 @@ impl Service {
     }
     fn network(
         name: &str,
-        subnets: &mut ipnet::Ipv4Subnets,
+        subnets: &mut network::Subnets,
     ) -> (
         network::Network,
         network::Networks,
-        Option<std::net::Ipv4Addr>,
+        Option<std::net::IpAddr>,
     ) {
         let armour_bridge_network = Service::armour_bridge_network(name);
         let mut network = network::Network::default();
@@ -201,16 +201,29 @@ impl Service {
             armour_bridge_network.clone(),
         );
         network.internal = true;
-        if let Some(subnet) = subnets.next() {
+        if let Some((subnet, subnet6)) = subnets.next() {
             let mut ipam = network::Ipam::default();
-            ipam.config = vec![network::IpamConfig { subnet }];
-            network.ipam = Some(ipam);
+            ipam.config = vec![network::IpamConfig {
+                subnet: subnet.into(),
+            }];
             let mut network_record = network::NetworkRecord::default();
             let ipv4_addr = subnet.hosts().nth(1);
             network_record.ipv4_address = ipv4_addr;
+            if let Some(subnet6) = subnet6 {
+                // skip the subnet-router anycast and gateway addresses
+                network_record.ipv6_address = subnet6.hosts().nth(2);
+                ipam.config.push(network::IpamConfig {
+                    subnet: subnet6.into(),
+                });
+                network.enable_ipv6 = true
+            }
+            network.ipam = Some(ipam);
+            let ip_addr = ipv4_addr
+                .map(std::net::IpAddr::from)
+                .or_else(|| network_record.ipv6_address.map(std::net::IpAddr::from));
             let mut dict = Map::new();
             dict.insert(armour_bridge_network, network_record);
-            (network, network::Networks::Dict(dict), ipv4_addr)
+            (network, network::Networks::Dict(dict), ip_addr)
         } else {
             (
                 network,
@@ -222,22 +235,23 @@ impl Service {
     pub fn convert_for_armour(
         &mut self,
         name: &str,
-        subnets: &mut ipnet::Ipv4Subnets,
-    ) -> (ServiceInfo, network::Network, Option<std::net::Ipv4Addr>) {
+        subnets: &mut network::Subnets,
+    ) -> (ServiceInfo, network::Network, Option<std::net::IpAddr>) {
         let info = ServiceInfo {
             armour_labels: self.armour.labels.clone(),
             armour_proxies: self.armour.proxies.clone(),
             // container_labels: self.labels.clone(),
             // network: armour_bridge_network.clone(),
             ipv4_address: None,
+            ipv6_address: None,
         };
         // create a new (internal) bridge network for the service
-        let (network, networks, ipv4_addr) = Service::network(name, subnets);
+        let (network, networks, ip_addr) = Service::network(name, subnets);
         // wipe armour field
         self.armour = Armour::default();
         // use internal bridge network
         self.networks = networks;
-        (info, network, ipv4_addr)
+        (info, network, ip_addr)
     }
 }
 
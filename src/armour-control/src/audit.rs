@@ -0,0 +1,208 @@
+//! Tamper-evident (hash chained) audit log of control plane changes
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::rest_api::AUDIT_COL;
+use super::storage::{self, Collection, Storage};
+use armour_api::control::{AuditKey, AuditRecord, AuditTarget};
+use bson::doc;
+use futures::lock::{Mutex, MutexGuard};
+use std::sync::Arc;
+
+/// Append-only log of mutating REST calls, in the `audit` collection.
+///
+/// A call is recorded before it is made (with the result [`STARTED`]), and again with its
+/// result once it has completed. The log is broken if its chain does not verify, or if a record could not be appended.
+/// Changes are refused while the log is broken.
+#[derive(Default)]
+pub struct AuditLog {
+    last: Mutex<Option<(i64, String)>>, // sequence number and hash of the last record
+    key: Option<AuditKey>,
+    broken: std::sync::Mutex<Option<String>>,
+    // held while an audited call is made
+    changes: Mutex<()>,
+}
+
+/// Result of the record that is appended before a call is made
+pub const STARTED: &str = "started";
+
+impl AuditLog {
+    pub fn new(key: Option<AuditKey>) -> Self {
+        AuditLog {
+            key,
+            ..Default::default()
+        }
+    }
+    pub fn is_keyed(&self) -> bool {
+        self.key.is_some()
+    }
+    /// Reason that the log is broken (if it is)
+    pub fn broken(&self) -> Option<String> {
+        self.broken.lock().unwrap().clone()
+    }
+    fn set_broken(&self, reason: String) {
+        *self.broken.lock().unwrap() = Some(reason)
+    }
+    /// Wait for other audited calls to complete, so that the policy hashes recorded for a call
+    /// are not affected by concurrent changes
+    pub async fn changes(&self) -> MutexGuard<'_, ()> {
+        self.changes.lock().await
+    }
+    /// Append a record, chained to the last record
+    pub async fn append(
+        &self,
+        storage: &Arc<dyn Storage>,
+        endpoint: &str,
+        identity: &str,
+        targets: Vec<AuditTarget>,
+        result: String,
+    ) -> Result<AuditRecord, storage::Error> {
+        // hold the lock until the record is stored, so that records are chained in order
+        let mut last = self.last.lock().await;
+        let (sequence, previous) = match last.as_ref() {
+            Some(last) => last.clone(),
+            None => match AuditLog::head(storage).await {
+                Ok(head) => head,
+                Err(err) => {
+                    self.set_broken(err.to_string());
+                    return Err(err);
+                }
+            },
+        };
+        let mut record = AuditRecord {
+            sequence: sequence + 1,
+            time: chrono::Utc::now(),
+            endpoint: endpoint.to_string(),
+            identity: identity.to_string(),
+            targets,
+            result,
+            previous,
+            hash: String::new(),
+        };
+        record.hash = record.blake3(self.key.as_ref());
+        if let bson::Bson::Document(document) = bson::to_bson(&record)? {
+            if let Err(err) = Collection::new(storage.clone(), AUDIT_COL)
+                .insert_one(document)
+                .await
+            {
+                self.set_broken(err.to_string());
+                return Err(err);
+            }
+        }
+        *last = Some((record.sequence, record.hash.clone()));
+        Ok(record)
+    }
+    /// Check the chain of the stored log, a page at a time, returning the number of records.
+    /// The log is broken if the chain does not verify.
+    pub async fn verify(&self, storage: &Arc<dyn Storage>) -> Result<usize, String> {
+        let mut last = self.last.lock().await;
+        let mut count = 0;
+        let mut previous: Option<AuditRecord> = None;
+        let result = loop {
+            let after = previous
+                .as_ref()
+                .map(|record| record.sequence)
+                .unwrap_or_default();
+            let page = match AuditLog::page(storage, after, PAGE).await {
+                Ok(page) => page,
+                Err(err) => break Err(err.to_string()),
+            };
+            let done = page.len() < PAGE;
+            for record in page {
+                if let Err(err) = record.verify_next(previous.as_ref(), self.key.as_ref()) {
+                    self.set_broken(err.clone());
+                    return Err(err);
+                }
+                previous = Some(record);
+                count += 1
+            }
+            if done {
+                break Ok(count);
+            }
+        };
+        match result {
+            Ok(count) => {
+                *last = Some(
+                    previous
+                        .map(|record| (record.sequence, record.hash))
+                        .unwrap_or_default(),
+                );
+                Ok(count)
+            }
+            Err(err) => {
+                self.set_broken(err.clone());
+                Err(err)
+            }
+        }
+    }
+    /// At most `limit` records that follow the record with sequence number `after`, in sequence order
+    pub async fn page(
+        storage: &Arc<dyn Storage>,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<AuditRecord>, storage::Error> {
+        let mut records = Vec::new();
+        for doc in Collection::new(storage.clone(), AUDIT_COL)
+            .find_after(doc! {}, "sequence", after, limit)
+            .await?
+        {
+            records.push(bson::from_bson::<AuditRecord>(bson::Bson::Document(doc))?)
+        }
+        Ok(records)
+    }
+    /// All records, in sequence order
+    pub async fn records(storage: &Arc<dyn Storage>) -> Result<Vec<AuditRecord>, storage::Error> {
+        let mut records = Vec::new();
+        loop {
+            let after = records
+                .last()
+                .map(|record: &AuditRecord| record.sequence)
+                .unwrap_or_default();
+            let page = AuditLog::page(storage, after, PAGE).await?;
+            let done = page.len() < PAGE;
+            records.extend(page);
+            if done {
+                return Ok(records);
+            }
+        }
+    }
+    // sequence number and hash of the last record
+    async fn head(storage: &Arc<dyn Storage>) -> Result<(i64, String), storage::Error> {
+        let mut head = (0, String::new());
+        loop {
+            let page = AuditLog::page(storage, head.0, PAGE).await?;
+            let done = page.len() < PAGE;
+            if let Some(record) = page.into_iter().last() {
+                head = (record.sequence, record.hash)
+            }
+            if done {
+                return Ok(head);
+            }
+        }
+    }
+}
+
+// records read from storage at a time
+const PAGE: usize = armour_api::control::AUDIT_PAGE_LIMIT;
@@ -0,0 +1,400 @@
+//! Verification of the credentials presented by hosts and services during onboarding
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::identity::Identity;
+use armour_lang::labels::Label;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix of HMAC signed join tokens
+pub const JOIN_TOKEN: &str = "join";
+/// Prefix of the identity label added to global IDs
+pub const AUTH_TOKEN: &str = "AuthToken";
+/// Maximum age (and clock skew) of certificate bound credentials
+pub const CERTIFICATE_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// A strategy for checking onboarding credentials, presented for `claimant` by the client `peer`.
+///
+/// On success, returns the identity that the credentials establish.
+pub trait Verifier: Send + Sync {
+    fn verify(&self, claimant: &str, credentials: &str, peer: &Identity) -> Result<String, String>;
+}
+
+/// Static tokens, read from a file of `<name> <token>` lines
+pub struct StaticTokens(HashMap<String, String>);
+
+impl StaticTokens {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let mut tokens = HashMap::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [name, token] => {
+                    tokens.insert((*token).to_string(), (*name).to_string());
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("expecting `<name> <token>`, got: {}", line),
+                    ))
+                }
+            }
+        }
+        Ok(StaticTokens(tokens))
+    }
+}
+
+impl Verifier for StaticTokens {
+    fn verify(
+        &self,
+        _claimant: &str,
+        credentials: &str,
+        _peer: &Identity,
+    ) -> Result<String, String> {
+        // compare every token, so that timing does not reveal a near match
+        self.0
+            .iter()
+            .fold(None, |found, (token, name)| {
+                if token.len() == credentials.len()
+                    && openssl::memcmp::eq(token.as_bytes(), credentials.as_bytes())
+                {
+                    Some(name.clone())
+                } else {
+                    found
+                }
+            })
+            .ok_or_else(|| "unknown token".to_string())
+    }
+}
+
+/// Join tokens of the form `join:<name>:<expiry>:<hmac>`, signed with a shared key.
+/// The HMAC also covers the label of the host or service that the token is issued for.
+pub struct JoinTokens(Vec<u8>);
+
+impl JoinTokens {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let key = std::fs::read(path)?;
+        if key.is_empty() {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "empty join token key",
+            ))
+        } else {
+            Ok(JoinTokens(key))
+        }
+    }
+    /// Issue a token for `name`, to be presented for `claimant`, that is valid for `ttl`
+    pub fn issue(&self, name: &str, claimant: &str, ttl: Duration) -> Result<String, String> {
+        if name.is_empty() || name.contains(':') {
+            return Err(format!("bad token name: {}", name));
+        }
+        let expiry = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        let mac = self
+            .mac(name, claimant, expiry)
+            .map_err(|e| e.to_string())?;
+        Ok(format!("{}:{}:{}:{}", JOIN_TOKEN, name, expiry, hex(&mac)))
+    }
+    fn mac(
+        &self,
+        name: &str,
+        claimant: &str,
+        expiry: u64,
+    ) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let key = PKey::hmac(&self.0)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        // the claimant is last, since labels may contain `:`
+        signer.update(format!("{}:{}:{}", name, expiry, claimant).as_bytes())?;
+        signer.sign_to_vec()
+    }
+}
+
+impl Verifier for JoinTokens {
+    fn verify(
+        &self,
+        claimant: &str,
+        credentials: &str,
+        _peer: &Identity,
+    ) -> Result<String, String> {
+        match credentials.split(':').collect::<Vec<&str>>().as_slice() {
+            [JOIN_TOKEN, name, expiry, mac] => {
+                let expiry = expiry
+                    .parse::<u64>()
+                    .map_err(|_| "bad join token expiry".to_string())?;
+                let expected = hex(&self
+                    .mac(name, claimant, expiry)
+                    .map_err(|e| e.to_string())?);
+                if expected.len() != mac.len()
+                    || !openssl::memcmp::eq(expected.as_bytes(), mac.as_bytes())
+                {
+                    Err("bad join token signature".to_string())
+                } else if now()? > expiry {
+                    Err(format!("join token for {} has expired", name))
+                } else {
+                    Ok((*name).to_string())
+                }
+            }
+            _ => Err("not a join token".to_string()),
+        }
+    }
+}
+
+/// Credentials bound to a client certificate issued by a trusted CA
+/// (see `armour_utils::certificate_credentials`). The identity is the certificate's common name.
+///
+/// The certificate must be the one that the client presented for mTLS, and each nonce is only accepted once.
+pub struct ClientCertificates {
+    store: openssl::x509::store::X509Store,
+    nonces: Mutex<HashMap<String, u64>>, // accepted nonces, with the time of their credentials
+}
+
+impl ClientCertificates {
+    pub fn from_ca<P: AsRef<Path>>(
+        ca: P,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut store = openssl::x509::store::X509StoreBuilder::new()?;
+        for cert in openssl::x509::X509::stack_from_pem(&std::fs::read(ca)?)? {
+            store.add_cert(cert)?
+        }
+        Ok(ClientCertificates {
+            store: store.build(),
+            nonces: Mutex::new(HashMap::new()),
+        })
+    }
+    fn check(
+        &self,
+        claimant: &str,
+        peer: &Identity,
+        [cert, time, nonce, signature]: [&str; 4],
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        use openssl::x509::{X509StoreContext, X509};
+        let cert = X509::from_der(&openssl::base64::decode_block(cert)?)?;
+        let chain = openssl::stack::Stack::new()?;
+        let mut context = X509StoreContext::new()?;
+        if !context.init(&self.store, &cert, &chain, |c| c.verify_cert())? {
+            return Err("certificate not issued by a trusted CA".into());
+        }
+        if peer.fingerprint.is_none() || peer.fingerprint != Some(Identity::fingerprint(&cert)?) {
+            return Err("certificate is not the client's mTLS certificate".into());
+        }
+        let secs = time.parse::<u64>()?;
+        let now = now()?;
+        if now.max(secs) - now.min(secs) > CERTIFICATE_MAX_AGE.as_secs() {
+            return Err("certificate credentials are stale".into());
+        }
+        let key = cert.public_key()?;
+        let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(format!("{}:{}:{}", claimant, time, nonce).as_bytes())?;
+        if !verifier.verify(&openssl::base64::decode_block(signature)?)? {
+            return Err("bad certificate signature".into());
+        }
+        // nonces are remembered for as long as their credentials could be accepted
+        let mut nonces = self.nonces.lock().map_err(|_| "nonce cache is poisoned")?;
+        nonces.retain(|_, secs| *secs + CERTIFICATE_MAX_AGE.as_secs() >= now);
+        if nonces.insert(nonce.to_string(), secs).is_some() {
+            return Err("certificate credentials have already been used".into());
+        }
+        let cn = cert
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .ok_or("certificate has no common name")?;
+        Ok(cn.data().as_utf8()?.to_string())
+    }
+}
+
+impl Verifier for ClientCertificates {
+    fn verify(&self, claimant: &str, credentials: &str, peer: &Identity) -> Result<String, String> {
+        match credentials.split(':').collect::<Vec<&str>>().as_slice() {
+            [armour_utils::CERTIFICATE_CREDENTIALS, cert, time, nonce, signature] => self
+                .check(claimant, peer, [cert, time, nonce, signature])
+                .map_err(|e| e.to_string()),
+            _ => Err("not certificate credentials".to_string()),
+        }
+    }
+}
+
+/// The verifiers configured at startup. Credentials are accepted if any verifier accepts them,
+/// and when there are no verifiers all credentials are accepted (without an identity).
+#[derive(Default)]
+pub struct Verifiers {
+    verifiers: Vec<Box<dyn Verifier>>,
+    /// add an `AuthToken::<identity>` label to the global ID of verified services
+    pub auth_label: bool,
+}
+
+impl Verifiers {
+    pub fn push<V: Verifier + 'static>(&mut self, verifier: V) {
+        self.verifiers.push(Box::new(verifier))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.verifiers.is_empty()
+    }
+    /// Verify credentials, returning the identity label `AuthToken::<identity>`
+    pub fn verify(
+        &self,
+        claimant: &str,
+        credentials: &str,
+        peer: &Identity,
+    ) -> Result<Option<Label>, String> {
+        if self.verifiers.is_empty() {
+            return Ok(None);
+        }
+        let mut errors = Vec::new();
+        for verifier in self.verifiers.iter() {
+            match verifier.verify(claimant, credentials, peer) {
+                Ok(identity) => {
+                    let mut label = identity
+                        .parse::<Label>()
+                        .map_err(|_| format!("identity is not a valid label: {}", identity))?;
+                    label.prefix(AUTH_TOKEN.to_string());
+                    return Ok(Some(label));
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(errors.join("; "))
+    }
+}
+
+fn now() -> Result<u64, String> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|e| e.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join_tokens() -> JoinTokens {
+        JoinTokens(b"secret join key".to_vec())
+    }
+
+    #[test]
+    fn join_token() {
+        let tokens = join_tokens();
+        let peer = Identity::new("host");
+        let token = tokens
+            .issue("worker", "Host::one", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            tokens.verify("Host::one", &token, &peer),
+            Ok("worker".to_string())
+        );
+        assert!(tokens
+            .issue("bad:name", "Host::one", Duration::from_secs(60))
+            .is_err());
+        assert!(tokens.verify("Host::one", "join:worker", &peer).is_err())
+    }
+
+    #[test]
+    fn join_token_expiry() {
+        let tokens = join_tokens();
+        let peer = Identity::new("host");
+        let expiry = now().unwrap() - 10;
+        let token = format!(
+            "{}:worker:{}:{}",
+            JOIN_TOKEN,
+            expiry,
+            hex(&tokens.mac("worker", "Host::one", expiry).unwrap())
+        );
+        assert_eq!(
+            tokens.verify("Host::one", &token, &peer),
+            Err("join token for worker has expired".to_string())
+        )
+    }
+
+    #[test]
+    fn join_token_tampering() {
+        let tokens = join_tokens();
+        let peer = Identity::new("host");
+        let token = tokens
+            .issue("worker", "Host::one", Duration::from_secs(60))
+            .unwrap();
+        let parts: Vec<&str> = token.split(':').collect();
+        // altered name
+        let renamed = format!("{}:admin:{}:{}", JOIN_TOKEN, parts[2], parts[3]);
+        assert!(tokens.verify("Host::one", &renamed, &peer).is_err());
+        // extended expiry
+        let extended = format!("{}:worker:{}:{}", JOIN_TOKEN, u64::MAX, parts[3]);
+        assert!(tokens.verify("Host::one", &extended, &peer).is_err());
+        // altered signature
+        let mut mac = parts[3].to_string();
+        let last = if mac.ends_with('0') { "1" } else { "0" };
+        mac.replace_range(mac.len() - 1.., last);
+        let resigned = format!("{}:worker:{}:{}", JOIN_TOKEN, parts[2], mac);
+        assert!(tokens.verify("Host::one", &resigned, &peer).is_err());
+        // different key
+        let other = JoinTokens(b"another key".to_vec());
+        assert!(other.verify("Host::one", &token, &peer).is_err())
+    }
+
+    #[test]
+    fn join_token_claimant() {
+        let tokens = join_tokens();
+        let peer = Identity::new("host");
+        let token = tokens
+            .issue("worker", "ServiceID::server::**", Duration::from_secs(60))
+            .unwrap();
+        assert!(tokens
+            .verify("ServiceID::server::**", &token, &peer)
+            .is_ok());
+        assert_eq!(
+            tokens.verify("ServiceID::client::**", &token, &peer),
+            Err("bad join token signature".to_string())
+        )
+    }
+
+    #[test]
+    fn verifiers() {
+        let peer = Identity::new("host");
+        let mut verifiers = Verifiers::default();
+        assert_eq!(verifiers.verify("Host::one", "anything", &peer), Ok(None));
+        verifiers.push(join_tokens());
+        let token = join_tokens()
+            .issue("worker", "Host::one", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            verifiers.verify("Host::one", &token, &peer),
+            Ok(Some("AuthToken::worker".parse().unwrap()))
+        );
+        assert!(verifiers.verify("Host::two", &token, &peer).is_err())
+    }
+}
@@ -0,0 +1,194 @@
+//! Stream of control plane events, for tools that watch for changes
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_api::control::{ControlEvent, EventRecord, EVENTS_MISSED_SEQUENCE};
+use futures::channel::mpsc;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Number of past events kept, for clients that resume
+pub const EVENT_BACKLOG: usize = 1024;
+/// Number of events queued for a subscriber. Subscribers that fall further behind are dropped
+/// (and can resume from the backlog).
+pub const SUBSCRIBER_BUFFER: usize = 256;
+
+#[derive(Default)]
+struct EventsInner {
+    sequence: i64,
+    backlog: VecDeque<EventRecord>,
+    subscribers: Vec<mpsc::Sender<EventRecord>>,
+}
+
+/// Events are numbered from 1 and are not persisted, so numbering restarts with the control plane
+#[derive(Default)]
+pub struct Events(Mutex<EventsInner>);
+
+impl Events {
+    pub fn publish(&self, event: ControlEvent) {
+        let mut inner = self.0.lock().unwrap();
+        inner.sequence += 1;
+        let record = EventRecord {
+            sequence: inner.sequence,
+            time: chrono::Utc::now(),
+            event,
+        };
+        log::debug!("event: {}", record);
+        if inner.backlog.len() == EVENT_BACKLOG {
+            inner.backlog.pop_front();
+        }
+        inner.backlog.push_back(record.clone());
+        // forget subscribers that have gone away, or that are not keeping up
+        let subscribers = std::mem::take(&mut inner.subscribers);
+        inner.subscribers = subscribers
+            .into_iter()
+            .filter_map(|mut subscriber| match subscriber.try_send(record.clone()) {
+                Ok(()) => Some(subscriber),
+                Err(err) => {
+                    if err.is_full() {
+                        log::warn!("dropping event subscriber that is not keeping up")
+                    }
+                    None
+                }
+            })
+            .collect()
+    }
+    /// Subscribe to new events. When resuming `after` a sequence number, the past events that are
+    /// still in the backlog are returned first. If some of those events are no longer available
+    /// (or the control plane has restarted) then they start with an `EventsMissed` event, which
+    /// is numbered `EVENTS_MISSED_SEQUENCE`.
+    pub fn subscribe(&self, after: Option<i64>) -> (Vec<EventRecord>, mpsc::Receiver<EventRecord>) {
+        let mut inner = self.0.lock().unwrap();
+        let mut past = Vec::new();
+        if let Some(requested) = after {
+            // numbering restarts with the control plane
+            let restarted = requested > inner.sequence;
+            let after = if restarted { 0 } else { requested };
+            // sequence number of the last event that is no longer available
+            let missed = inner
+                .backlog
+                .front()
+                .map(|record| record.sequence - 1)
+                .unwrap_or(inner.sequence);
+            if restarted || after < missed {
+                past.push(EventRecord {
+                    sequence: EVENTS_MISSED_SEQUENCE,
+                    time: chrono::Utc::now(),
+                    event: ControlEvent::EventsMissed { after: requested },
+                })
+            }
+            past.extend(
+                inner
+                    .backlog
+                    .iter()
+                    .filter(|record| record.sequence > after)
+                    .cloned(),
+            )
+        }
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        inner.subscribers.push(sender);
+        (past, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(i: usize) -> ControlEvent {
+        ControlEvent::PolicyDropped {
+            label: format!("ServiceID::s{}", i).parse().unwrap(),
+        }
+    }
+
+    fn sequences(records: &[EventRecord]) -> Vec<i64> {
+        records.iter().map(|record| record.sequence).collect()
+    }
+
+    #[test]
+    fn resume() {
+        let events = Events::default();
+        let (past, _) = events.subscribe(Some(0));
+        assert!(past.is_empty());
+        for i in 0..3 {
+            events.publish(event(i))
+        }
+        assert!(events.subscribe(None).0.is_empty());
+        assert_eq!(sequences(&events.subscribe(Some(0)).0), vec![1, 2, 3]);
+        assert_eq!(sequences(&events.subscribe(Some(2)).0), vec![3]);
+        assert!(events.subscribe(Some(3)).0.is_empty());
+    }
+
+    #[test]
+    fn missed() {
+        let events = Events::default();
+        let n = EVENT_BACKLOG + 10;
+        for i in 0..n {
+            events.publish(event(i))
+        }
+        // events 1..=10 are no longer available
+        let (past, _) = events.subscribe(Some(5));
+        assert_eq!(past.len(), EVENT_BACKLOG + 1);
+        assert_eq!(past[0].sequence, EVENTS_MISSED_SEQUENCE);
+        match past[0].event {
+            ControlEvent::EventsMissed { after } => assert_eq!(after, 5),
+            ref event => panic!("unexpected event: {}", event),
+        }
+        assert_eq!(past[1].sequence, 11);
+        // no gap when resuming from the start of the backlog
+        let (past, _) = events.subscribe(Some(10));
+        assert_eq!(past.len(), EVENT_BACKLOG);
+        assert_eq!(past[0].sequence, 11);
+        // numbering has restarted
+        let (past, _) = events.subscribe(Some(n as i64 + 1));
+        assert_eq!(past[0].sequence, EVENTS_MISSED_SEQUENCE);
+        assert_eq!(past.len(), EVENT_BACKLOG + 1)
+    }
+
+    #[test]
+    fn lagging() {
+        let events = Events::default();
+        let (_, mut fast) = events.subscribe(None);
+        let (_, mut slow) = events.subscribe(None);
+        for i in 0..SUBSCRIBER_BUFFER + 10 {
+            events.publish(event(i));
+            assert_eq!(fast.try_next().unwrap().unwrap().sequence, i as i64 + 1)
+        }
+        assert_eq!(events.0.lock().unwrap().subscribers.len(), 1);
+        // the slow subscriber receives the queued events, and then the stream ends
+        let mut received = 0;
+        loop {
+            match slow.try_next() {
+                Ok(Some(_)) => received += 1,
+                Ok(None) => break,
+                Err(_) => panic!("stream of slow subscriber has not ended"),
+            }
+        }
+        assert!((SUBSCRIBER_BUFFER..SUBSCRIBER_BUFFER + 10).contains(&received));
+        // and can resume from the backlog
+        let (past, _) = events.subscribe(Some(received as i64));
+        assert_eq!(past.len(), SUBSCRIBER_BUFFER + 10 - received)
+    }
+}
@@ -0,0 +1,111 @@
+//! Identity of control plane clients, taken from their TLS client certificates
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use actix_web::{dev, FromRequest, HttpRequest};
+use futures::future::{ok, Ready};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ssl::SslRef;
+use openssl::x509::X509Ref;
+
+/// Subject of the certificate presented by a client
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub common_name: Option<String>,
+    pub alt_names: Vec<String>,
+    pub fingerprint: Option<String>, // SHA-256 of the certificate
+}
+
+impl Identity {
+    pub fn new(common_name: &str) -> Self {
+        Identity {
+            common_name: Some(common_name.to_string()),
+            alt_names: Vec::new(),
+            fingerprint: None,
+        }
+    }
+    /// SHA-256 fingerprint (hex) of a certificate
+    pub fn fingerprint(cert: &X509Ref) -> Result<String, openssl::error::ErrorStack> {
+        Ok(cert
+            .digest(MessageDigest::sha256())?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+    /// Get identity from the peer certificate of a TLS session (called once per connection)
+    pub fn from_ssl(ssl: &SslRef) -> Self {
+        if let Some(cert) = ssl.peer_certificate() {
+            let common_name = cert
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|entry| entry.data().as_utf8().ok())
+                .map(|name| name.to_string());
+            let alt_names = cert
+                .subject_alt_names()
+                .map(|names| {
+                    names
+                        .iter()
+                        .filter_map(|name| name.dnsname().or_else(|| name.email()).or_else(|| name.uri()))
+                        .map(|name| name.to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            Identity {
+                common_name,
+                alt_names,
+                fingerprint: Identity::fingerprint(&cert).ok(),
+            }
+        } else {
+            Identity::default()
+        }
+    }
+    /// Name recorded against changes made by the client
+    pub fn name(&self) -> String {
+        self.common_name
+            .as_ref()
+            .or_else(|| self.alt_names.first())
+            .cloned()
+            .unwrap_or_else(|| "anonymous".to_string())
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// the identity is stored in the request extensions by the HTTP service `on_connect` callback
+impl FromRequest for Identity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        ok(req.extensions().get::<Identity>().cloned().unwrap_or_default())
+    }
+}
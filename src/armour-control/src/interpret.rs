@@ -43,6 +43,7 @@ use bson::doc;
 use futures::future::{BoxFuture};
 use super::rest_api::{collection, POLICIES_COL, SERVICES_COL};
 use super::specialize;
+use super::storage;
 use super::State;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -55,16 +56,13 @@ where
 }
 
 pub async fn present(
-    col: &mongodb::Collection,
+    col: &storage::Collection,
     filter: impl Into<Option<bson::Document>>,
 ) -> Result<bool, self::Error> {
-    use futures::StreamExt;
     Ok(col
-        .find(filter, None)
-        .await
-        .on_err("MongoDB query error")?
-        .next()
+        .find_one(filter)
         .await
+        .on_err("database query error")?
         .is_some())
 }
 
@@ -95,12 +93,12 @@ where
 }
 impl<T> OnErr<T, bson::de::Error> for bson::de::Result<T> {}
 impl<T> OnErr<T, bson::ser::Error> for bson::ser::Result<T> {}
-impl<T> OnErr<T, mongodb::error::Error> for mongodb::error::Result<T> {}
+impl<T> OnErr<T, storage::Error> for Result<T, storage::Error> {}
 
 pub async fn get_global_pol(state: State) ->  Result<control::CPPolicyUpdateRequest, self::Error> {
     let col = collection(&state, POLICIES_COL);
     if let Ok(Some(doc)) = col
-        .find_one(Some(doc! {"label" : to_bson(&global_policy_label())?}), None)
+        .find_one(Some(doc! {"label" : to_bson(&global_policy_label())?}))
         .await
     {
         match bson::from_bson::<control::CPPolicyUpdateRequest>(bson::Bson::Document(doc.clone())) {
@@ -114,7 +112,8 @@ pub async fn get_global_pol(state: State) ->  Result<control::CPPolicyUpdateRequ
             label:global_policy_label(),
             policy: GlobalPolicies::default(),
             labels: control::LabelMap::default(),
-            selector: None 
+            selector: None,
+            comment: None,
         })
     }
 }
@@ -152,7 +151,7 @@ async fn helper_onboarded(state: Arc<State>, obd: &OnboardingData) ->  Result<CP
     let mock_service_id = new_ID(obd);
 
     if let Ok(Some(doc)) = col
-        .find_one(Some(doc! { "service_id.labels" : to_bson(&mock_service_id)? }), None)
+        .find_one(Some(doc! { "service_id.labels" : to_bson(&mock_service_id)? }))
         .await
     {
         let request =
@@ -202,10 +201,10 @@ async fn helper_onboard(state: Arc<State>, id: &CPID) ->  Result<CPLiteral, self
         Ok(cpdplit!(Bool(true)))
 
     } else if let bson::Bson::Document(document) = to_bson(&request)? {
-        // Insert into a MongoDB collection
-        col.insert_one(document, None) 
+        // Insert into a collection
+        col.insert_one(document) 
             .await
-            .on_err("error inserting in MongoDB")?;
+            .on_err("error inserting in database")?;
         Ok(cpdplit!(Bool(true)))
 
     } else {
@@ -247,6 +246,11 @@ impl TSLitInterpret for CPLiteral {
                     Box::new(literals::Primitive::new("allow_tcp_connection"))
                 )))
             },
+            "Primitive::allow_udp_flow" => {
+                Some(literals::Literal::FlatLiteral(CPFlatLiteral::Primitive(
+                    Box::new(literals::Primitive::new("allow_udp_flow"))
+                )))
+            },
             "deny_egress" => {
                 Some(literals::Literal::FlatLiteral(CPFlatLiteral::Policy(
                     Box::new(literals::Policy{
@@ -286,9 +290,14 @@ impl TSLitInterpret for CPLiteral {
 
                 let mut id = CPID::default();
                 id.port = obd.port();
+                id.ips = obd.ips().clone();
                 let id = id.add_label(&service_id);
                 let id = id.add_label(&service);
                 let id = id.add_label(&host);
+                let id = match obd.credentials().identity() {
+                    Some(identity) if state.verifiers.auth_label => id.add_label(identity),
+                    _ => id
+                };
 
                 Ok(Some(cpdplit!(ID(id.into()))))
             }
@@ -304,7 +313,13 @@ impl TSLitInterpret for CPLiteral {
                 Ok(Some(helper_compile_egress(state, &p.function(), &id.clone().into()).await?))
             },
             ("verify_credentials", cplit!(OnboardingData(obd)), cpdplit!(Label(label))) => {
-                Ok(Some(CPLiteral::bool(true)))//TODO
+                //credentials are checked by the verifiers before the policy is evaluated,
+                //the label is a pattern for the identity, e.g., AuthToken::**
+                let credentials = obd.credentials();
+                Ok(Some(CPLiteral::bool(
+                    credentials.is_verified() && 
+                    credentials.identity().map_or(true, |identity| label.matches_with(identity))
+                )))
             },
             _ => Ok(self.eval_call2(f, other)),
         }
@@ -0,0 +1,115 @@
+//! Label caches of data plane hosts, derived from the `has_label` calls in their policies
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_api::proxy::{LabelCacheUpdate, LabelSnapshot};
+use armour_lang::{
+    labels::{Label, Labels},
+    literals::CPID,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Label facts needed to evaluate `has_label` calls with the given patterns,
+/// i.e. the matching labels of the services, by IP address
+pub fn facts<'a, I>(patterns: &BTreeSet<Label>, services: I) -> BTreeMap<IpAddr, Labels>
+where
+    I: Iterator<Item = &'a CPID>,
+{
+    let mut facts: BTreeMap<IpAddr, Labels> = BTreeMap::new();
+    if patterns.is_empty() {
+        return facts;
+    }
+    for id in services {
+        let labels: Labels = id
+            .labels
+            .iter()
+            .filter(|label| patterns.iter().any(|pattern| pattern.matches_with(*label)))
+            .cloned()
+            .collect();
+        if !labels.is_empty() {
+            for ip in id.ips.iter() {
+                facts
+                    .entry(*ip)
+                    .or_default()
+                    .extend(labels.iter().cloned())
+            }
+        }
+    }
+    facts
+}
+
+/// Last label snapshot sent to each host, so that changes can be sent as deltas
+#[derive(Default)]
+pub struct LabelCaches(Mutex<BTreeMap<Label, LabelSnapshot>>);
+
+impl LabelCaches {
+    /// Version of the snapshot most recently sent to a host (0 if none)
+    pub fn version(&self, host: &Label) -> u64 {
+        self.0
+            .lock()
+            .unwrap()
+            .get(host)
+            .map(|snapshot| snapshot.version)
+            .unwrap_or_default()
+    }
+    pub fn snapshot(&self, host: &Label) -> Option<LabelSnapshot> {
+        self.0.lock().unwrap().get(host).cloned()
+    }
+    pub fn dropped(&self, host: &Label) {
+        self.0.lock().unwrap().remove(host);
+    }
+    /// Record new label facts for a host, returning the update to send (if any).
+    /// A full snapshot is sent to hosts that have not had one, or when `full` is set.
+    pub fn update(
+        &self,
+        host: &Label,
+        ips: BTreeMap<IpAddr, Labels>,
+        full: bool,
+    ) -> Option<LabelCacheUpdate> {
+        let mut caches = self.0.lock().unwrap();
+        match caches.get_mut(host) {
+            Some(current) => {
+                if current.ips == ips && !full {
+                    return None;
+                }
+                let next = LabelSnapshot::new(current.version + 1, ips);
+                let update = if full {
+                    LabelCacheUpdate::Snapshot(next.clone())
+                } else {
+                    LabelCacheUpdate::Delta(current.delta(&next))
+                };
+                *current = next;
+                Some(update)
+            }
+            None => {
+                let snapshot = LabelSnapshot::new(1, ips);
+                caches.insert(host.clone(), snapshot.clone());
+                Some(LabelCacheUpdate::Snapshot(snapshot))
+            }
+        }
+    }
+}
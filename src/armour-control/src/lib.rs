@@ -22,18 +22,31 @@
  * SOFTWARE.
  */
 
-use mongodb::{options::ClientOptions, Client};
 use actix_web::web;
+use std::sync::Arc;
 
 pub struct ControlPlaneState {
-	pub db_endpoint: ClientOptions,
-	pub db_con: Client,
+	pub storage: Arc<dyn storage::Storage>,
+	pub audit: audit::AuditLog,
+	pub events: events::Events,
+	pub verifiers: credentials::Verifiers,
+	pub liveness: liveness::Liveness,
+	pub label_caches: label_cache::LabelCaches,
+	pub roles: rbac::Roles,
 }
 
 pub type State = web::Data<ControlPlaneState>;
 
+pub mod audit;
+pub mod credentials;
 // pub mod data_model;
+pub mod events;
+pub mod identity;
 pub mod interpret;
+pub mod label_cache;
+pub mod liveness;
 pub mod policy;
+pub mod rbac;
 pub mod rest_api;
-pub mod specialize;
\ No newline at end of file
+pub mod specialize;
+pub mod storage;
\ No newline at end of file
@@ -0,0 +1,230 @@
+//! Liveness of onboarded hosts, tracked from their heartbeats
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_api::control::{HostHeartbeat, HostLiveness, ProxyHeartbeat};
+use armour_lang::labels::Label;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time to wait for a pushed policy to be reported by a host, before pushing it again
+pub const RECONCILE_RETRY: Duration = Duration::from_secs(30);
+
+struct HostRecord {
+    last_seen: Instant,
+    liveness: HostLiveness, // as of the last check
+    proxies: Vec<ProxyHeartbeat>,
+    reconciled: BTreeMap<String, (String, Instant)>, // policy hashes pushed to the host (and when), by service
+}
+
+impl HostRecord {
+    fn new() -> Self {
+        HostRecord {
+            last_seen: Instant::now(),
+            liveness: HostLiveness::Healthy,
+            proxies: Vec::new(),
+            reconciled: BTreeMap::new(),
+        }
+    }
+}
+
+/// Liveness of a host, as reported by `/host/list`
+pub struct HostSummary {
+    pub liveness: HostLiveness,
+    pub since_heartbeat: Duration,
+    pub proxies: usize,
+}
+
+#[derive(Default)]
+pub struct Liveness(Mutex<BTreeMap<Label, HostRecord>>);
+
+impl Liveness {
+    pub fn onboarded(&self, host: &Label) {
+        self.0.lock().unwrap().insert(host.clone(), HostRecord::new());
+    }
+    pub fn dropped(&self, host: &Label) {
+        self.0.lock().unwrap().remove(host);
+    }
+    /// Record a heartbeat, returning `false` if the host has not onboarded
+    pub fn heartbeat(&self, heartbeat: HostHeartbeat) -> bool {
+        let mut hosts = self.0.lock().unwrap();
+        if let Some(record) = hosts.get_mut(&heartbeat.label) {
+            if record.liveness != HostLiveness::Healthy
+                || HostLiveness::new(record.last_seen.elapsed()) != HostLiveness::Healthy
+            {
+                log::info!("host {} is back", heartbeat.label);
+                // policies may have been missed while the host was away
+                record.reconciled.clear()
+            }
+            record.last_seen = Instant::now();
+            record.liveness = HostLiveness::Healthy;
+            record.proxies = heartbeat.proxies;
+            true
+        } else {
+            false
+        }
+    }
+    /// Hosts that are not known (e.g. onboarded before a restart) are assumed to be healthy
+    pub fn liveness(&self, host: &Label) -> HostLiveness {
+        self.0
+            .lock()
+            .unwrap()
+            .get(host)
+            .map(|record| HostLiveness::new(record.last_seen.elapsed()))
+            .unwrap_or(HostLiveness::Healthy)
+    }
+    pub fn summary(&self, host: &Label) -> Option<HostSummary> {
+        self.0.lock().unwrap().get(host).map(|record| {
+            let since_heartbeat = record.last_seen.elapsed();
+            HostSummary {
+                liveness: HostLiveness::new(since_heartbeat),
+                since_heartbeat,
+                proxies: record.proxies.len(),
+            }
+        })
+    }
+    /// Check whether the policy with hash `expected` should be pushed to a host, for a service
+    /// that reports `installed`. A policy is pushed again if the host has still not installed it
+    /// after `RECONCILE_RETRY`, or if the push failed.
+    pub fn reconcile(&self, host: &Label, service: &str, installed: &str, expected: &str) -> bool {
+        self.reconcile_at(host, service, installed, expected, Instant::now())
+    }
+    fn reconcile_at(
+        &self,
+        host: &Label,
+        service: &str,
+        installed: &str,
+        expected: &str,
+        now: Instant,
+    ) -> bool {
+        if let Some(record) = self.0.lock().unwrap().get_mut(host) {
+            if installed == expected {
+                record.reconciled.remove(service);
+                return false;
+            }
+            match record.reconciled.get(service) {
+                Some((hash, pushed))
+                    if hash == expected && now.saturating_duration_since(*pushed) < RECONCILE_RETRY => {}
+                _ => {
+                    record
+                        .reconciled
+                        .insert(service.to_string(), (expected.to_string(), now));
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    /// Record that pushing a policy for a service failed, so that it is retried on the next heartbeat
+    pub fn push_failed(&self, host: &Label, service: &str) {
+        if let Some(record) = self.0.lock().unwrap().get_mut(host) {
+            record.reconciled.remove(service);
+        }
+    }
+    /// Update liveness, returning the hosts whose liveness has changed
+    pub fn check(&self) -> Vec<(Label, HostLiveness)> {
+        let mut changed = Vec::new();
+        for (host, record) in self.0.lock().unwrap().iter_mut() {
+            let liveness = HostLiveness::new(record.last_seen.elapsed());
+            if liveness != record.liveness {
+                record.liveness = liveness;
+                changed.push((host.clone(), liveness))
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use armour_api::control::{HOST_DEAD_AFTER, HOST_STALE_AFTER};
+
+    fn heartbeat(host: &Label) -> HostHeartbeat {
+        HostHeartbeat {
+            label: host.clone(),
+            proxies: Vec::new(),
+            label_cache: 0,
+        }
+    }
+
+    // pretend that the last heartbeat was `secs` ago
+    fn age(liveness: &Liveness, host: &Label, secs: u64) {
+        let mut hosts = liveness.0.lock().unwrap();
+        let record = hosts.get_mut(host).unwrap();
+        record.last_seen = Instant::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn transitions() {
+        let liveness = Liveness::default();
+        let host: Label = "Host::one".parse().unwrap();
+        assert!(!liveness.heartbeat(heartbeat(&host)));
+        liveness.onboarded(&host);
+        assert_eq!(liveness.liveness(&host), HostLiveness::Healthy);
+        assert!(liveness.check().is_empty());
+        age(&liveness, &host, HOST_STALE_AFTER);
+        assert_eq!(liveness.liveness(&host), HostLiveness::Stale);
+        assert_eq!(liveness.check(), vec![(host.clone(), HostLiveness::Stale)]);
+        assert!(liveness.check().is_empty());
+        age(&liveness, &host, HOST_DEAD_AFTER);
+        assert_eq!(liveness.check(), vec![(host.clone(), HostLiveness::Dead)]);
+        assert!(liveness.heartbeat(heartbeat(&host)));
+        assert_eq!(liveness.liveness(&host), HostLiveness::Healthy);
+        assert!(liveness.check().is_empty()); // the heartbeat records that the host is back
+        liveness.dropped(&host);
+        assert!(liveness.summary(&host).is_none());
+        assert!(!liveness.heartbeat(heartbeat(&host)))
+    }
+
+    #[test]
+    fn reconcile() {
+        let liveness = Liveness::default();
+        let host: Label = "Host::one".parse().unwrap();
+        // unknown hosts are not reconciled
+        assert!(!liveness.reconcile(&host, "service", "old", "new"));
+        liveness.onboarded(&host);
+        assert!(!liveness.reconcile(&host, "service", "new", "new"));
+        let now = Instant::now();
+        assert!(liveness.reconcile_at(&host, "service", "old", "new", now));
+        // wait for the host to install the policy
+        assert!(!liveness.reconcile_at(&host, "service", "old", "new", now + Duration::from_secs(1)));
+        // ...but not for ever
+        assert!(liveness.reconcile_at(&host, "service", "old", "new", now + RECONCILE_RETRY));
+        // a newer policy is pushed straight away
+        assert!(liveness.reconcile_at(&host, "service", "old", "newer", now + RECONCILE_RETRY));
+        // failed pushes are retried on the next heartbeat
+        liveness.push_failed(&host, "service");
+        assert!(liveness.reconcile_at(&host, "service", "old", "newer", now + RECONCILE_RETRY));
+        // once installed, the policy is pushed again if it changes back
+        assert!(!liveness.reconcile(&host, "service", "newer", "newer"));
+        assert!(liveness.reconcile(&host, "service", "newer", "new"));
+        // hosts that come back are reconciled again
+        age(&liveness, &host, HOST_DEAD_AFTER);
+        assert!(liveness.heartbeat(heartbeat(&host)));
+        assert!(liveness.reconcile(&host, "service", "newer", "new"))
+    }
+}
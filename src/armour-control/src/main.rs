@@ -22,14 +22,19 @@
  * SOFTWARE.
  */
 
-// For MongoDB installation see: https://docs.mongodb.com/manual/tutorial/install-mongodb-on-os-x
+// For MongoDB installation (optional) see: https://docs.mongodb.com/manual/tutorial/install-mongodb-on-os-x
 
-use actix_web::{error, middleware, web, App, FromRequest, HttpRequest, HttpResponse, HttpServer};
-use armour_control::{rest_api, ControlPlaneState};
-use mongodb::{options::ClientOptions, Client};
+use actix_http::HttpService;
+use actix_rt::net::TcpStream;
+use actix_service::map_config;
+use actix_tls::openssl::{AlpnError, SslAcceptor, SslAcceptorBuilder, SslStream};
+use actix_web::dev::AppConfig;
+use actix_web::{error, middleware, web, App, FromRequest, HttpRequest, HttpResponse};
+use armour_control::{audit, credentials, identity::Identity, rbac, rest_api, storage, ControlPlaneState};
 use tokio::stream::StreamExt;
 
-const DEFAULT_MONGO_DB: &str = "mongodb://localhost:27017";
+const DEFAULT_STORAGE: &str = "file://armour-db";
+const DEFAULT_TOKEN_TTL: u64 = 86400;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -40,13 +45,60 @@ async fn main() -> Result<(), Error> {
     let matches = clap::App::from_yaml(yaml)
         .version(clap::crate_version!())
         .get_matches();
-    let mongo_url = matches.value_of("MONGODBURL").unwrap_or(DEFAULT_MONGO_DB);
+    let storage_url = matches.value_of("STORAGE").unwrap_or(DEFAULT_STORAGE);
     let port = matches
         .value_of("PORT")
         .map(|s| s.parse().ok())
         .flatten()
         .unwrap_or(armour_api::control::TCP_PORT);
     let control_plane = std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(0, 0, 0, 0), port);
+    let ca = matches
+        .value_of("CA")
+        .unwrap_or("certificates/armour-ca.pem");
+
+    // onboarding credentials
+    let mut verifiers = credentials::Verifiers::default();
+    if let Some(tokens) = matches.value_of("TOKENS") {
+        verifiers.push(credentials::StaticTokens::from_file(tokens)?)
+    }
+    if let Some(key) = matches.value_of("JOIN_KEY") {
+        let join_tokens = credentials::JoinTokens::from_file(key)?;
+        if let (Some(name), Some(claimant)) = (matches.value_of("ISSUE"), matches.value_of("CLAIMANT")) {
+            let ttl = matches
+                .value_of("TTL")
+                .map(|s| s.parse().ok())
+                .flatten()
+                .unwrap_or(DEFAULT_TOKEN_TTL);
+            println!(
+                "{}",
+                join_tokens.issue(name, claimant, std::time::Duration::from_secs(ttl))?
+            );
+            return Ok(());
+        }
+        verifiers.push(join_tokens)
+    }
+    if matches.is_present("CERT_CREDENTIALS") {
+        verifiers.push(credentials::ClientCertificates::from_ca(ca)?)
+    }
+    verifiers.auth_label = matches.is_present("AUTH_LABEL");
+
+    // roles of control plane clients
+    let roles = match matches.value_of("ROLES") {
+        Some(roles) => rbac::Roles::from_file(roles)?,
+        None => rbac::Roles::default(),
+    };
+
+    // key for the audit log hash chain
+    let audit_key = match matches.value_of("AUDIT_KEY") {
+        Some(key) => {
+            let key = std::fs::read(key)?;
+            if key.is_empty() {
+                return Err("empty audit log key".into());
+            }
+            Some(armour_api::control::audit_key(&key))
+        }
+        None => None,
+    };
 
     // enable logging
     std::env::set_var(
@@ -56,29 +108,61 @@ async fn main() -> Result<(), Error> {
     std::env::set_var("RUST_BACKTRACE", "0");
     env_logger::init();
 
-    // connect to MongoDB
-    let mut db_endpoint = ClientOptions::parse(mongo_url).await.map_err(|e| {
-        log::warn!("failed to get db_endpoint");
-        e
-    })?;
-    db_endpoint.app_name = Some("armour".to_string());
-    let db_con = Client::with_options(db_endpoint.clone()).map_err(|e| {
-        log::info!("Failed to connect to Mongo. Start MongoDB");
-        e
-    })?;
-
-    // start from empty database
-    db_con.database("armour").drop(None).await?;
-    log::info!("reset armour database");
+    // open storage (MongoDB, or embedded)
+    let storage = storage::open(storage_url).await?;
+    log::info!("storage: {}", storage_url);
+
+    // hosts, services and policies persist across restarts, unless reset
+    // (the policy history and the audit log are always kept)
+    if matches.is_present("RESET") {
+        for col in &[
+            rest_api::HOSTS_COL,
+            rest_api::SERVICES_COL,
+            rest_api::POLICIES_COL,
+        ] {
+            storage.drop_collection(col).await?
+        }
+        log::info!("reset armour database");
+    }
+    let audit = audit::AuditLog::new(audit_key);
+    if !audit.is_keyed() {
+        log::warn!("audit log is not keyed")
+    }
+    match audit.verify(&storage).await {
+        Ok(records) => log::info!("audit log: {} records", records),
+        Err(err) => log::error!("audit log cannot be trusted, changes will be refused: {}", err),
+    }
+    if verifiers.is_empty() {
+        log::warn!("onboarding credentials are not checked")
+    }
+    if !roles.is_enabled() {
+        log::warn!("access control is disabled: all clients have the admin role")
+    }
     let state = web::Data::new(ControlPlaneState {
-        db_endpoint,
-        db_con,
+        storage,
+        audit,
+        events: Default::default(),
+        verifiers,
+        liveness: Default::default(),
+        label_caches: Default::default(),
+        roles,
+    });
+
+    // track host liveness
+    let liveness = state.clone();
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            armour_api::control::HEARTBEAT_INTERVAL,
+        ));
+        loop {
+            interval.tick().await;
+            for (host, liveness) in liveness.liveness.check() {
+                log::warn!("host {} is {}", host, liveness)
+            }
+        }
     });
 
     // start HTTP server
-    let ca = matches
-        .value_of("CA")
-        .unwrap_or("certificates/armour-ca.pem");
     let certificate_password = matches.value_of("CERTIFICATE_PASSWORD").unwrap_or("armour");
     let certificate = matches
         .value_of("CERTIFICATE")
@@ -89,53 +173,70 @@ async fn main() -> Result<(), Error> {
         certificate,
         !matches.is_present("NO_MTLS"),
     )?;
+    let acceptor = ssl_acceptor(ssl_builder)?;
     let ca = ca.to_string();
     let certificate_password = certificate_password.to_string();
     let certificate = certificate.to_string();
-    HttpServer::new(move || {
-        let client = armour_utils::client(&ca, &certificate_password, &certificate)
-            .expect("failed to build HTTP client");
-        App::new()
-            .data(client)
-            .app_data(state.clone())
-            .wrap(middleware::Logger::default())
-            .service(
-                web::scope("/host")
-                    .service(rest_api::host::list)
-                    .service(rest_api::host::on_board)
-                    .service(rest_api::host::drop)
-                    .default_service(web::to(index)),
-            )
-            .service(
-                web::scope("/service")
-                    .service(rest_api::service::list)
-                    .service(rest_api::service::on_board)
-                    .service(rest_api::service::drop)
-                    .default_service(web::to(index)),
-            )
-            .service(
-                web::scope("/policy")
-                    .service(rest_api::policy::list)
-                    .service(rest_api::policy::update)
-                    .service(rest_api::policy::update_onboarding)
-                    .service(rest_api::policy::update_global)
-                    .service(rest_api::policy::query)
-                    .service(rest_api::policy::query_onboarding)
-                    .service(rest_api::policy::query_global)
-                    .service(rest_api::policy::drop)
-                    .service(rest_api::policy::drop_all)
-                    .service(rest_api::policy::specialize)
-                    .default_service(web::to(index)),
-            )
-            .app_data(
-                web::Json::<armour_api::control::PolicyUpdateRequest>::configure(|cfg| {
-                    cfg.error_handler(json_error_handler)
-                }),
-            )
-            .default_service(web::to(index))
-    })
-    .bind_openssl(control_plane, ssl_builder)?
-    .run();
+    actix_server::Server::build()
+        .bind("armour-control", control_plane, move || {
+            let client = armour_utils::client(&ca, &certificate_password, &certificate)
+                .expect("failed to build HTTP client");
+            let app = App::new()
+                .data(client)
+                .app_data(state.clone())
+                .wrap(middleware::Logger::default())
+                .service(
+                    web::scope("/host")
+                        .service(rest_api::host::heartbeat)
+                        .service(rest_api::host::list)
+                        .service(rest_api::host::on_board)
+                        .service(rest_api::host::drop)
+                        .default_service(web::to(index)),
+                )
+                .service(
+                    web::scope("/service")
+                        .service(rest_api::service::list)
+                        .service(rest_api::service::on_board)
+                        .service(rest_api::service::drop)
+                        .default_service(web::to(index)),
+                )
+                .service(
+                    web::scope("/policy")
+                        .service(rest_api::policy::list)
+                        .service(rest_api::policy::update)
+                        .service(rest_api::policy::update_onboarding)
+                        .service(rest_api::policy::update_global)
+                        .service(rest_api::policy::query)
+                        .service(rest_api::policy::query_services)
+                        .service(rest_api::policy::query_onboarding)
+                        .service(rest_api::policy::query_global)
+                        .service(rest_api::policy::drop)
+                        .service(rest_api::policy::drop_all)
+                        .service(rest_api::policy::specialize)
+                        .service(rest_api::policy::history)
+                        .service(rest_api::policy::rollback)
+                        .default_service(web::to(index)),
+                )
+                .service(
+                    web::scope("/audit")
+                        .service(rest_api::audit::query)
+                        .service(rest_api::audit::export)
+                        .default_service(web::to(index)),
+                )
+                .service(rest_api::events::stream)
+                .app_data(
+                    web::Json::<armour_api::control::PolicyUpdateRequest>::configure(|cfg| {
+                        cfg.error_handler(json_error_handler)
+                    }),
+                )
+                .default_service(web::to(index));
+            // record the identity from the client certificate of each connection
+            HttpService::build()
+                .on_connect(|io: &SslStream<TcpStream>| Identity::from_ssl(io.ssl()))
+                .finish(map_config(app, |_| AppConfig::default()))
+                .openssl(acceptor.clone())
+        })?
+        .run();
 
     log::info!("listening on: https://{}", control_plane);
 
@@ -144,6 +245,23 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+// negotiate HTTP/2 or HTTP/1.1 (as for `HttpServer::bind_openssl`)
+fn ssl_acceptor(mut builder: SslAcceptorBuilder) -> std::io::Result<SslAcceptor> {
+    builder.set_alpn_select_callback(|_, protocols| {
+        const H2: &[u8] = b"\x02h2";
+        const H11: &[u8] = b"\x08http/1.1";
+        if protocols.windows(3).any(|window| window == H2) {
+            Ok(b"h2")
+        } else if protocols.windows(9).any(|window| window == H11) {
+            Ok(b"http/1.1")
+        } else {
+            Err(AlpnError::NOACK)
+        }
+    });
+    builder.set_alpn_protos(b"\x08http/1.1\x02h2")?;
+    Ok(builder.build())
+}
+
 async fn index(
     req: HttpRequest,
     mut payload: actix_web::web::Payload,
@@ -0,0 +1,314 @@
+//! Role based access control for the control plane REST API
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::identity::Identity;
+use armour_api::control::{global_policy_label, onboarding_policy_label};
+use armour_lang::labels::Label;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Prefix of the certificate subject alternative names (URIs) that carry a role
+pub const ROLE_URI: &str = "armour-role:";
+
+/// Role of a control plane client
+#[derive(Clone, Debug, PartialEq)]
+pub enum Role {
+    /// list and query hosts, services and policies
+    Viewer,
+    /// on-board (and drop) hosts and services
+    Host,
+    /// drop the services selected by the label, and update, drop and roll back their policies
+    ServiceOwner(Label),
+    /// everything, including the global and onboarding policies
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "viewer" => Ok(Role::Viewer),
+            "host" => Ok(Role::Host),
+            "admin" => Ok(Role::Admin),
+            s => match s.strip_prefix("service-owner:") {
+                Some(selector) => Label::from_str(selector.trim())
+                    .map(Role::ServiceOwner)
+                    .map_err(|err| format!("bad service-owner selector: {}", err)),
+                None => Err(format!(
+                    "unknown role `{}` (expecting viewer, host, service-owner:<label> or admin)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Host => write!(f, "host"),
+            Role::ServiceOwner(selector) => write!(f, "service-owner:{}", selector),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Access needed by a REST call
+#[derive(Debug)]
+pub enum Access<'a> {
+    Read,
+    Host,
+    Service(&'a Label),
+    Policy(&'a Label),
+    Admin,
+}
+
+impl<'a> std::fmt::Display for Access<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "query the control plane"),
+            Access::Host => write!(f, "on-board or drop hosts and services"),
+            Access::Service(label) => write!(f, "drop the service {}", label),
+            Access::Policy(label) => write!(f, "change the policy for {}", label),
+            Access::Admin => write!(f, "administer the control plane"),
+        }
+    }
+}
+
+impl Role {
+    pub fn allows(&self, access: &Access) -> bool {
+        match (self, access) {
+            (Role::Admin, _) | (_, Access::Read) => true,
+            (Role::Host, Access::Host) | (Role::Host, Access::Service(_)) => true,
+            // the global and onboarding policies affect every service
+            (Role::ServiceOwner(selector), Access::Policy(label))
+            | (Role::ServiceOwner(selector), Access::Service(label)) => {
+                **label != global_policy_label()
+                    && **label != onboarding_policy_label()
+                    && selector.matches_with(label)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Roles of control plane clients.
+///
+/// Roles are assigned to names (certificate common names or subject alternative names) by a
+/// YAML file of `<name>: [<role>, ...]` entries, and by `armour-role:<role>` URIs in client
+/// certificates. Access control is disabled when there is no roles file.
+#[derive(Default)]
+pub struct Roles(Option<BTreeMap<String, Vec<Role>>>);
+
+impl Roles {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let invalid = |err: String| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        let s = std::fs::read_to_string(path)?;
+        let names: BTreeMap<String, Vec<String>> = if s.trim().is_empty() {
+            BTreeMap::new()
+        } else {
+            serde_yaml::from_str(&s).map_err(|err| invalid(err.to_string()))?
+        };
+        let mut roles = BTreeMap::new();
+        for (name, name_roles) in names {
+            let name_roles = name_roles
+                .iter()
+                .map(|role| role.parse())
+                .collect::<Result<Vec<Role>, String>>()
+                .map_err(|err| invalid(format!("{}: {}", name, err)))?;
+            roles.insert(name, name_roles);
+        }
+        Ok(Roles(Some(roles)))
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+    /// Roles from the configuration, for the client's names, and from its certificate
+    pub fn roles(&self, identity: &Identity) -> Vec<Role> {
+        let mut roles = Vec::new();
+        if let Some(names) = &self.0 {
+            for name in identity.common_name.iter().chain(identity.alt_names.iter()) {
+                if let Some(role) = name.strip_prefix(ROLE_URI) {
+                    match role.parse() {
+                        Ok(role) => roles.push(role),
+                        Err(err) => log::warn!("{}: {}", identity, err),
+                    }
+                } else if let Some(name_roles) = names.get(name) {
+                    roles.extend(name_roles.iter().cloned())
+                }
+            }
+        }
+        roles
+    }
+    /// Whether the client owns a service through a service-owner role (rather than as the
+    /// owner of the service's host)
+    pub fn owns(&self, identity: &Identity, service: &Label) -> bool {
+        self.roles(identity).iter().any(|role| {
+            matches!(role, Role::ServiceOwner(_)) && role.allows(&Access::Service(service))
+        })
+    }
+    pub fn authorize(&self, identity: &Identity, access: Access) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let roles = self.roles(identity);
+        if roles.iter().any(|role| role.allows(&access)) {
+            Ok(())
+        } else {
+            let roles = if roles.is_empty() {
+                "none".to_string()
+            } else {
+                roles
+                    .iter()
+                    .map(|role| role.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            Err(format!(
+                r#"forbidden: "{}" (roles: {}) is not allowed to {}"#,
+                identity, roles, access
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(s: &str) -> Label {
+        s.parse().unwrap()
+    }
+
+    fn owner(selector: &str) -> Role {
+        Role::ServiceOwner(label(selector))
+    }
+
+    #[test]
+    fn allows() {
+        let service = label("ServiceID::teamA::server");
+        let all = [
+            Access::Read,
+            Access::Host,
+            Access::Service(&service),
+            Access::Policy(&service),
+            Access::Admin,
+        ];
+        let allowed = |role: &Role| all.iter().map(|access| role.allows(access)).collect::<Vec<bool>>();
+        assert_eq!(allowed(&Role::Viewer), vec![true, false, false, false, false]);
+        assert_eq!(allowed(&Role::Host), vec![true, true, true, false, false]);
+        assert_eq!(allowed(&owner("ServiceID::teamA::**")), vec![true, false, true, true, false]);
+        assert_eq!(allowed(&owner("ServiceID::teamB::**")), vec![true, false, false, false, false]);
+        assert_eq!(allowed(&Role::Admin), vec![true, true, true, true, true]);
+    }
+
+    #[test]
+    fn service_owner_selectors() {
+        let server = label("ServiceID::teamA::server");
+        let policy = |role: &Role, service: &str| role.allows(&Access::Policy(&label(service)));
+        // exact
+        assert!(policy(&owner("ServiceID::teamA::server"), "ServiceID::teamA::server"));
+        assert!(!policy(&owner("ServiceID::teamA::server"), "ServiceID::teamA::client"));
+        // one component
+        assert!(policy(&owner("ServiceID::*::server"), "ServiceID::teamB::server"));
+        assert!(!policy(&owner("ServiceID::*"), "ServiceID::teamA::server"));
+        // any suffix
+        assert!(policy(&owner("ServiceID::teamA::**"), "ServiceID::teamA::server::v2"));
+        assert!(!policy(&owner("ServiceID::teamA::**"), "ServiceID::teamB::server"));
+        assert!(!policy(&owner("ServiceID::teamA::**"), "ServiceID::teamA"));
+        // the selector does not grant anything else
+        assert!(!owner("ServiceID::**").allows(&Access::Host));
+        assert!(!owner("ServiceID::**").allows(&Access::Admin));
+        assert!(owner("ServiceID::**").allows(&Access::Policy(&server)))
+    }
+
+    #[test]
+    fn global_and_onboarding_policies() {
+        for selector in &["**", "global_policy", "onboarding_policy"] {
+            let role = owner(selector);
+            assert!(!role.allows(&Access::Policy(&global_policy_label())));
+            assert!(!role.allows(&Access::Policy(&onboarding_policy_label())))
+        }
+        assert!(Role::Admin.allows(&Access::Policy(&global_policy_label())));
+        assert!(Role::Admin.allows(&Access::Policy(&onboarding_policy_label())));
+        assert!(!Role::Host.allows(&Access::Policy(&onboarding_policy_label())))
+    }
+
+    #[test]
+    fn authorize() {
+        let mut names = BTreeMap::new();
+        names.insert("alice".to_string(), vec![owner("ServiceID::teamA::**")]);
+        names.insert(
+            "bob".to_string(),
+            vec![Role::Viewer, owner("ServiceID::teamB::**")],
+        );
+        names.insert("ops".to_string(), vec![Role::Host]);
+        let roles = Roles(Some(names));
+        let team_a = label("ServiceID::teamA::server");
+        let team_b = label("ServiceID::teamB::server");
+
+        let alice = Identity::new("alice");
+        assert!(roles.authorize(&alice, Access::Policy(&team_a)).is_ok());
+        assert!(roles.authorize(&alice, Access::Policy(&team_b)).is_err());
+        assert!(roles.authorize(&alice, Access::Policy(&global_policy_label())).is_err());
+        assert!(roles.authorize(&alice, Access::Policy(&onboarding_policy_label())).is_err());
+        assert!(roles.authorize(&alice, Access::Service(&team_a)).is_ok());
+        assert!(roles.authorize(&alice, Access::Service(&team_b)).is_err());
+        // service owners own their services, hosts only the services of the hosts they own
+        assert!(roles.owns(&alice, &team_a));
+        assert!(!roles.owns(&alice, &team_b));
+        assert!(!roles.owns(&Identity::new("ops"), &team_a));
+        // any of the client's roles may grant access
+        let bob = Identity::new("bob");
+        assert!(roles.authorize(&bob, Access::Read).is_ok());
+        assert!(roles.authorize(&bob, Access::Policy(&team_b)).is_ok());
+        assert!(roles.authorize(&bob, Access::Host).is_err());
+        // roles for all of the client's names, and from its certificate
+        let client = Identity {
+            common_name: Some("unknown".to_string()),
+            alt_names: vec![
+                "ops".to_string(),
+                "armour-role:service-owner:ServiceID::teamA::**".to_string(),
+                "armour-role:superuser".to_string(),
+            ],
+            fingerprint: None,
+        };
+        assert_eq!(
+            roles.roles(&client),
+            vec![Role::Host, owner("ServiceID::teamA::**")]
+        );
+        assert!(roles.authorize(&client, Access::Host).is_ok());
+        assert!(roles.authorize(&client, Access::Policy(&team_a)).is_ok());
+        assert!(roles.authorize(&client, Access::Admin).is_err());
+        let err = roles.authorize(&alice, Access::Host).unwrap_err();
+        assert!(err.contains(r#""alice""#) && err.contains("service-owner:ServiceID::teamA::**"));
+        // certificate roles need a roles file
+        assert!(Roles::default().roles(&client).is_empty());
+        assert!(Roles::default().authorize(&client, Access::Admin).is_ok())
+    }
+}
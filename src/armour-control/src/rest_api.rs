@@ -29,6 +29,7 @@ use armour_api::control::{
     onboarding_policy_label
 };
 use armour_api::host::PolicyUpdate;
+use armour_api::proxy::{LabelCacheUpdate, ServiceId};
 use armour_lang::{
     expressions,
     labels::{Label, Labels}, 
@@ -40,8 +41,15 @@ use bson::doc;
 use std::collections::BTreeSet;
 use std::str::FromStr;
 use std::sync::Arc;
+use super::audit::{AuditLog, STARTED};
+use super::identity::Identity;
+use super::label_cache;
+use super::rbac::Access;
 use super::policy::OnboardingPolicy;
-use super::specialize::{compile_egress, compile_ingress};
+use super::specialize::{
+    compile_egress, compile_ingress, unspecialized, EGRESS_FUNCTIONS, INGRESS_FUNCTIONS,
+};
+use super::storage;
 use super::State;
 
 
@@ -49,25 +57,38 @@ pub const ARMOUR_DB: &str = "armour";
 pub const HOSTS_COL: &str = "hosts";
 pub const SERVICES_COL: &str = "services";
 pub const POLICIES_COL: &str = "policies";
+pub const HISTORY_COL: &str = "history";
+pub const AUDIT_COL: &str = "audit";
 
 pub mod host {
     use super::*;
 
     #[get("/list")]
-    pub async fn list(state: State) -> Result<HttpResponse, actix_web::Error> {
-        use futures::StreamExt;
+    pub async fn list(state: State, identity: Identity) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let col = collection(&state, HOSTS_COL);
-        let mut docs = col
-            .find(doc! {}, None)
+        let docs = col
+            .find(doc! {})
             .await
             .on_err("error listing hosts")?;
         let mut s = String::new();
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let host =
-                    bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?;
-                s.push_str(format!("{} ({})\n", host.label, host.host).as_str())
+        for doc in docs {
+            let host =
+                bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?;
+            match state.liveness.summary(&host.label) {
+                Some(summary) => s.push_str(
+                    format!(
+                        "{} ({}) {}, {} proxies, last heartbeat {}s ago\n",
+                        host.label,
+                        host.host,
+                        summary.liveness,
+                        summary.proxies,
+                        summary.since_heartbeat.as_secs()
+                    )
+                    .as_str(),
+                ),
+                None => s.push_str(format!("{} ({}) unknown\n", host.label, host.host).as_str()),
             }
         }
         Ok(HttpResponse::Ok().body(s))
@@ -76,52 +97,339 @@ pub mod host {
     #[post("/on-board")]
     pub async fn on_board(
         state: State,
+        identity: Identity,
         request: Json<control::OnboardHostRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let label = &request.label;
-        let host = &request.host;
-        log::info!("Onboarding host: {} ({})", label, host);
-        let col = collection(&state, HOSTS_COL);
+        let targets = vec![request.label.clone()];
+        audited(&state, &identity, "host/on-board", targets, async {
+            authorize(&state, &identity, Access::Host)?;
+            let label = request.label.clone();
+            let host = &request.host;
+            log::info!("Onboarding host: {} ({})", label, host);
+            if let Err(response) = verify(&state, &identity, &request) {
+                return Ok(response);
+            }
+            let col = collection(&state, HOSTS_COL);
+            let filter = doc! { "label" : to_bson(&label)? };
 
-        // Check if the host is already there
-        if present(&col, doc! { "label" : to_bson(label)? }).await? {
-            Ok(internal(format!(
-                r#"host label "{}" already present"#,
-                label
-            )))
-        } else if let bson::Bson::Document(document) = to_bson(&request.into_inner())? {
-            col.insert_one(document, None)
-                .await
-                .on_err("error inserting in MongoDB")?;
-            Ok(HttpResponse::Ok().body("success"))
-        } else {
-            Ok(internal("error extracting document"))
-        }
+            // Check if the host is already there (a host that restarted, or went away, may return)
+            if let Some(doc) = col.find_one(filter.clone()).await.on_err("database query error")? {
+                if let Err(response) = owned(&doc, &identity, &label, true) {
+                    return Ok(response);
+                }
+                let existing = bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?;
+                if existing.host != *host
+                    && state.liveness.liveness(&label) == control::HostLiveness::Healthy
+                {
+                    return Ok(internal(format!(
+                        r#"host label "{}" already present"#,
+                        label
+                    )));
+                }
+                log::info!("host {} has returned", label);
+                col.delete_one(filter)
+                    .await
+                    .on_err("error removing host from database")?;
+            }
+            let event = control::ControlEvent::HostOnboarded {
+                label: label.clone(),
+                host: host.clone(),
+            };
+            if let bson::Bson::Document(mut document) = to_bson(&control::OnboardHostRequest {
+                // do not keep the credentials
+                credentials: String::new(),
+                ..request.into_inner()
+            })? {
+                document.insert(OWNER, identity.name());
+                col.insert_one(document)
+                    .await
+                    .on_err("error inserting in database")?;
+                state.liveness.onboarded(&label);
+                state.events.publish(event);
+                Ok(HttpResponse::Ok().body("success"))
+            } else {
+                Ok(internal("error extracting document"))
+            }
+        })
+        .await
     }
 
     #[delete("/drop")]
     pub async fn drop(
         state: State,
+        identity: Identity,
         request: Json<control::OnboardHostRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let label = request.label.clone();
-        let host = &request.host;
-        log::info!("dropping host: {} ({})", label, host);
-
-        let col = collection(&state, HOSTS_COL);
-        if let bson::Bson::Document(document) = to_bson(&request.into_inner())? {
-            col.delete_one(document, None)
+        let targets = vec![request.label.clone()];
+        audited(&state, &identity, "host/drop", targets, async {
+            authorize(&state, &identity, Access::Host)?;
+            let label = request.label.clone();
+            let host = &request.host;
+            log::info!("dropping host: {} ({})", label, host);
+            if let Err(response) = verify(&state, &identity, &request) {
+                return Ok(response);
+            }
+            if let Some(doc) = collection(&state, HOSTS_COL)
+                .find_one(doc! { "label" : to_bson(&label)? })
                 .await
-                .on_err("error removing host from MongoDB")?;
-            let col = collection(&state, SERVICES_COL);
-            let filter = doc! { "label" : to_bson(&label)? };
-            col.delete_many(filter, None)
+                .on_err("database query error")?
+            {
+                if let Err(response) = owned(&doc, &identity, &label, true) {
+                    return Ok(response);
+                }
+            }
+
+            let event = control::ControlEvent::HostDropped {
+                label: label.clone(),
+                host: host.clone(),
+            };
+            let col = collection(&state, HOSTS_COL);
+            if let bson::Bson::Document(document) = to_bson(&control::OnboardHostRequest {
+                credentials: String::new(),
+                ..request.into_inner()
+            })? {
+                col.delete_one(document)
+                    .await
+                    .on_err("error removing host from database")?;
+                let col = collection(&state, SERVICES_COL);
+                let filter = doc! { "label" : to_bson(&label)? };
+                col.delete_many(filter)
+                    .await
+                    .on_err("error removing services from database")?;
+                state.liveness.dropped(&label);
+                state.label_caches.dropped(&label);
+                state.events.publish(event);
+
+                Ok(HttpResponse::Ok().body("success"))
+            } else {
+                Ok(internal("error extracting document"))
+            }
+        })
+        .await
+    }
+
+    #[post("/heartbeat")]
+    pub async fn heartbeat(
+        client: web::Data<client::Client>,
+        state: State,
+        identity: Identity,
+        request: Json<control::HostHeartbeat>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Host)?;
+        let heartbeat = request.into_inner();
+        let label = heartbeat.label.clone();
+        let host = match collection(&state, HOSTS_COL)
+            .find_one(Some(doc! { "label" : to_bson(&label)? }))
+            .await
+            .on_err("database query error")?
+        {
+            Some(doc) => {
+                if let Err(response) = owned(&doc, &identity, &label, false) {
+                    return Ok(response);
+                }
+                bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?
+                    .host
+            }
+            None => {
+                return Ok(HttpResponse::NotFound()
+                    .body(format!(r#"host label "{}" not present"#, label)))
+            }
+        };
+        let label_cache = heartbeat.label_cache;
+        let services: Vec<(String, String)> = heartbeat
+            .proxies
+            .iter()
+            .flat_map(|proxy| proxy.services.clone().into_iter())
+            .collect();
+        if !state.liveness.heartbeat(heartbeat.clone()) {
+            // on-boarded, but not yet tracked
+            state.liveness.onboarded(&label);
+            state.liveness.heartbeat(heartbeat);
+        }
+
+        // push policies that are out-of-date at the host
+        let col = collection(&state, POLICIES_COL);
+        for (service, installed) in services {
+            let service_label = match Label::from_str(&service) {
+                Ok(service_label) => service_label,
+                Err(_) => continue,
+            };
+            if let Ok(Some(doc)) = col
+                .find_one(Some(doc! { "label" : to_bson(&service_label)? }))
                 .await
-                .on_err("error removing services from MongoDB")?;
+            {
+                let policy =
+                    bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                        .on_err("Bson conversion error")?
+                        .policy;
+                if state
+                    .liveness
+                    .reconcile(&label, &service, &installed, &policy.blake3())
+                {
+                    log::info!("reconciling policy for {} on host {}", service, label);
+                    let local_label =
+                        super::policy::get_local_service_label(&state, &service_label).await?;
+                    if !super::policy::push_policy(&client, &state, &host, &service_label, &local_label, &policy)
+                        .await
+                    {
+                        state.liveness.push_failed(&label, &service)
+                    }
+                }
+            }
+        }
 
-            Ok(HttpResponse::Ok().body("success"))
-        } else {
-            Ok(internal("error extracting document"))
+        // the host missed a label cache update (or either side restarted)
+        if label_cache != state.label_caches.version(&label) {
+            log::info!("resending label cache to host {}", label);
+            if let Err(err) = refresh_label_caches(&client, &state, Some(&label)).await {
+                log::warn!("failed to refresh label cache: {}", err)
+            }
+        }
+        Ok(HttpResponse::Ok().body("success"))
+    }
+
+    /// Push label facts to hosts whose label cache is out-of-date. The facts for a host are
+    /// the labels of services that match the `has_label` patterns of its services' policies.
+    /// If `full` is set then only that host is updated, with a full snapshot.
+    pub async fn refresh_label_caches(
+        client: &client::Client,
+        state: &State,
+        full: Option<&Label>,
+    ) -> Result<(), actix_web::Error> {
+        let services = super::policy::services_full(state).await?;
+        let policies = collection(state, POLICIES_COL);
+        let docs = collection(state, HOSTS_COL)
+            .find(doc! {})
+            .await
+            .on_err("error finding hosts")?;
+        for doc in docs {
+            let host = bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
+                .on_err("Bson conversion error")?;
+            if matches!(full, Some(label) if *label != host.label)
+                || state.liveness.liveness(&host.label) == control::HostLiveness::Dead
+            {
+                continue;
+            }
+            let mut patterns = BTreeSet::new();
+            for service in services.iter().filter(|service| {
+                Label::from_str("Host::<<host>>")
+                    .unwrap()
+                    .match_with(&service.host)
+                    .and_then(|m| m.get_label("host").cloned())
+                    .as_ref()
+                    == Some(&host.label)
+            }) {
+                if let Ok(Some(doc)) = policies
+                    .find_one(Some(doc! { "label" : to_bson(&service.service)? }))
+                    .await
+                {
+                    let policy =
+                        bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                            .on_err("Bson conversion error")?
+                            .policy;
+                    patterns.extend(policy.has_label_patterns())
+                }
+            }
+            let ips = label_cache::facts(&patterns, services.iter().map(|service| &service.service_id));
+            if let Some(update) = state.label_caches.update(&host.label, ips, full.is_some()) {
+                push_label_cache(client, state, &host, update).await
+            }
+        }
+        Ok(())
+    }
+
+    async fn push_label_cache(
+        client: &client::Client,
+        state: &State,
+        host: &control::OnboardHostRequest,
+        update: LabelCacheUpdate,
+    ) {
+        let host_str = match host.host.host_str() {
+            Some(host_str) => host_str,
+            None => {
+                log::warn!("failed to contact host: {}", host.host);
+                push_failed(state, &host.host, None, "no host name".to_string());
+                return;
+            }
+        };
+        let url = format!(
+            "https://{}:{}/host/label-cache",
+            host_str,
+            host.host.port().unwrap_or(8090)
+        );
+        let mut update = update;
+        loop {
+            match client.post(&url).send_json(&update).await {
+                Ok(res) if res.status().is_success() => {
+                    log::info!("pushed label cache (version {}) to {}", update.version(), host.host);
+                    return;
+                }
+                // the host missed an earlier delta, so send everything
+                Ok(res) if res.status() == actix_web::http::StatusCode::CONFLICT => {
+                    match (update, state.label_caches.snapshot(&host.label)) {
+                        (LabelCacheUpdate::Delta(_), Some(snapshot)) => {
+                            update = LabelCacheUpdate::Snapshot(snapshot)
+                        }
+                        _ => {
+                            log::info!("failed to push label cache to {}", host.host);
+                            push_failed(state, &host.host, None, "conflict".to_string());
+                            return;
+                        }
+                    }
+                }
+                Ok(res) => {
+                    log::info!("failed to push label cache to {}", host.host);
+                    push_failed(state, &host.host, None, res.status().to_string());
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("{}: {}", host.host, err);
+                    push_failed(state, &host.host, None, err.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    // field of a host document with the name of the identity that on-boarded the host
+    const OWNER: &str = "owner";
+
+    // Only the identity that on-boarded a host may act for its label (and its services). Hosts
+    // recorded without an owner can be claimed by on-boarding (or dropped), if `unowned` is set.
+    pub(super) fn owned(
+        doc: &bson::Document,
+        identity: &Identity,
+        label: &Label,
+        unowned: bool,
+    ) -> Result<(), HttpResponse> {
+        match doc.get_str(OWNER) {
+            Ok(owner) if owner == identity.name() => Ok(()),
+            Err(_) if unowned => Ok(()),
+            _ => {
+                log::warn!("{} is not the owner of host {}", identity, label);
+                Err(HttpResponse::Forbidden()
+                    .body(format!(r#"host label "{}" belongs to another identity"#, label)))
+            }
+        }
+    }
+
+    fn verify(
+        state: &State,
+        identity: &Identity,
+        request: &control::OnboardHostRequest,
+    ) -> Result<(), HttpResponse> {
+        match state.verifiers.verify(&request.label.to_string(), &request.credentials, identity) {
+            Ok(Some(identity)) => {
+                log::info!("host {} authenticated as {}", request.label, identity);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(err) => {
+                log::warn!("host {} failed to authenticate: {}", request.label, err);
+                Err(HttpResponse::Unauthorized().body(format!("credentials rejected: {}", err)))
+            }
         }
     }
 }
@@ -131,21 +439,19 @@ pub mod service {
     use super::*;
 
     #[get("/list")]
-    pub async fn list(state: State) -> Result<HttpResponse, actix_web::Error> {
-        use futures::StreamExt;
+    pub async fn list(state: State, identity: Identity) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let col = collection(&state, SERVICES_COL);
-        let mut docs = col
-            .find(doc! {}, None)
+        let docs = col
+            .find(doc! {})
             .await
             .on_err("error listing services")?;
         let mut s = String::new();
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let service =
-                    bson::from_bson::<control::OnboardServiceRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?;
-                s.push_str(format!("{} ({})\n", service.service, service.host).as_str())
-            }
+        for doc in docs {
+            let service =
+                bson::from_bson::<control::OnboardServiceRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?;
+            s.push_str(format!("{} ({})\n", service.service, service.host).as_str())
         }
         Ok(HttpResponse::Ok().body(s))
     }
@@ -154,7 +460,7 @@ pub mod service {
         let pol_col = collection(&state, POLICIES_COL);
 
         if let Ok(Some(doc)) = pol_col
-            .find_one(Some(doc! { "label" : to_bson(&onboarding_policy_label())? }), None)
+            .find_one(Some(doc! { "label" : to_bson(&onboarding_policy_label())? }))
             .await
         {
             let request = bson::from_bson::<control::CPPolicyUpdateRequest>(bson::Bson::Document(doc))
@@ -166,9 +472,44 @@ pub mod service {
         }
     }
 
+    // only the owner of a host may on-board (or drop) its services
+    async fn host_owned(
+        state: &State,
+        identity: &Identity,
+        host: &Label,
+    ) -> Result<Result<(), HttpResponse>, actix_web::Error> {
+        match collection(state, HOSTS_COL)
+            .find_one(doc! { "label" : to_bson(host)? })
+            .await
+            .on_err("database query error")?
+        {
+            Some(doc) => Ok(host::owned(&doc, identity, host, true)),
+            None => Ok(Err(HttpResponse::NotFound()
+                .body(format!(r#"host label "{}" not present"#, host)))),
+        }
+    }
+
+    // services with credentials that fail verification are not on-boarded
+    fn verify(
+        state: &State,
+        identity: &Identity,
+        request: &control::OnboardServiceRequest,
+    ) -> Result<literals::Credentials, HttpResponse> {
+        let service = &request.service;
+        match state.verifiers.verify(&service.to_string(), &request.credentials, identity) {
+            Ok(identity) => Ok(literals::Credentials::verified(identity)),
+            Err(err) => {
+                log::warn!("service {} failed to authenticate: {}", service, err);
+                Err(HttpResponse::Unauthorized().body(format!("credentials rejected: {}", err)))
+            }
+        }
+    }
+
+    /// On-board a service, with verified `credentials` (which are available to the onboarding policy)
     pub async fn helper_on_board(
         state: &State,
         request: control::OnboardServiceRequest,
+        credentials: literals::Credentials,
     ) -> Result<Result<(Label, control::PolicyUpdateRequest, control::PolicyUpdateRequest), String>, actix_web::Error> {
         let service = &request.service;
         log::info!("onboarding service: {}", service);
@@ -182,7 +523,7 @@ pub mod service {
                 Box::new(literals::OnboardingData::new(
                     request.host.clone(),
                     request.service.clone(),
-                    literals::Credentials::new(request.credentials.clone()),
+                    credentials,
                     match request.tmp_dpid { Some(ref x) => x.port(), _ => None},
                     match request.tmp_dpid { Some(ref x) => x.labels.clone(), _=> Labels::default()},
                     match request.tmp_dpid { Some(ref x) => x.ips.clone(), _=> BTreeSet::default()},
@@ -203,12 +544,14 @@ pub mod service {
                         control::PolicyUpdateRequest{
                             label: service_id.clone(),
                             policy: *local_pol.0.pol,
-                            labels: control::LabelMap::default()
+                            labels: control::LabelMap::default(),
+                            comment: None,
                         },
                         control::PolicyUpdateRequest{
                             label: service_id,
                             policy: *local_pol.1.pol, 
-                            labels: control::LabelMap::default()
+                            labels: control::LabelMap::default(),
+                            comment: None,
                         }
                     ))
                 },
@@ -220,50 +563,109 @@ pub mod service {
 
     #[post("/on-board")]
     pub async fn on_board(
+        client: web::Data<client::Client>,
         state: State,
+        identity: Identity,
         request: Json<control::OnboardServiceRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        match helper_on_board(&state, request.into_inner()).await? {
-            Ok((service_id, ingress_req, egress_req)) =>{
-                let merged_request = control::PolicyUpdateRequest{
-                    label: service_id.clone(),
-                    policy: ingress_req.policy.merge(&egress_req.policy), 
-                    labels: ingress_req.labels.into_iter().chain(egress_req.labels.into_iter()).collect()
-                };
+        let targets = vec![request.service.clone()];
+        audited(&state, &identity, "service/on-board", targets, async {
+            authorize(&state, &identity, Access::Host)?;
+            let host = request.host.clone();
+            if let Err(response) = host_owned(&state, &identity, &host).await? {
+                return Ok(response);
+            }
+            let credentials = match verify(&state, &identity, &request) {
+                Ok(credentials) => credentials,
+                Err(response) => return Ok(response),
+            };
+            match helper_on_board(&state, request.into_inner(), credentials).await? {
+                Ok((service_id, ingress_req, egress_req)) =>{
+                    let merged_request = control::PolicyUpdateRequest{
+                        label: service_id.clone(),
+                        policy: ingress_req.policy.merge(&egress_req.policy), 
+                        labels: ingress_req.labels.into_iter().chain(egress_req.labels.into_iter()).collect(),
+                        comment: Some("on-boarded service".to_string()),
+                    };
 
-                policy::save_policy(state.clone(), merged_request).await?;
+                    policy::save_policy(state.clone(), &identity, merged_request).await?;
+                    state.events.publish(control::ControlEvent::ServiceOnboarded {
+                        service: service_id.clone(),
+                        host,
+                    });
+                    // the new service may carry labels that other hosts test for
+                    if let Err(err) = host::refresh_label_caches(&client, &state, None).await {
+                        log::warn!("failed to refresh label caches: {}", err)
+                    }
 
-                Ok(HttpResponse::Ok().json(control::OnboardServiceResponse{
-                    service_id: service_id,
-                }))
-            }, 
-            Err(s)=> Ok(internal(s))  
-        }
+                    Ok(HttpResponse::Ok().json(control::OnboardServiceResponse{
+                        service_id: service_id,
+                    }))
+                }, 
+                Err(s)=> Ok(internal(s))  
+            }
+        })
+        .await
     }
 
     #[delete("/drop")]
     pub async fn drop(
+        client: web::Data<client::Client>,
         state: State,
+        identity: Identity,
         request: Json<control::OnboardServiceRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let request = request.into_inner();
+        let targets = vec![request.service.clone()];
+        audited(&state, &identity, "service/drop", targets, async {
+            let request = request.into_inner();
+
+            if let Some(dpid) = request.tmp_dpid {
+                let service = match dpid.find_label(&Label::from_str("ServiceID::**").unwrap()) {
+                    Some(l) => l.clone(),
+                    _ =>  return Ok(internal("error no global id provided"))
+                };
 
-        if let Some(dpid) = request.tmp_dpid {
-            let service = match dpid.find_label(&Label::from_str("ServiceID::**").unwrap()) {
-                Some(l) => l.clone(),
-                _ =>  return Ok(internal("error no global id provided"))
-            };
+                authorize(&state, &identity, Access::Service(&service))?;
+                // the service is dropped by its owner, or by the owner of the host that on-boarded it
+                let col = collection(&state, SERVICES_COL);
+                let host = match col
+                    .find_one(doc! { "service": service.to_string() })
+                    .await
+                    .on_err("database query error")?
+                {
+                    Some(doc) => {
+                        let host = bson::from_bson::<control::POnboardServiceRequest>(bson::Bson::Document(doc))
+                            .on_err("Bson conversion error")?
+                            .host;
+                        Label::from_str("Host::<<host>>")
+                            .unwrap()
+                            .match_with(&host)
+                            .and_then(|m| m.get_label("host").cloned())
+                            .unwrap_or(host)
+                    }
+                    None => request.host.clone(),
+                };
+                if !state.roles.owns(&identity, &service) {
+                    if let Err(response) = host_owned(&state, &identity, &host).await? {
+                        return Ok(response);
+                    }
+                }
 
-            log::info!("dropping service: {}", service);
-            let col = collection(&state, SERVICES_COL);
+                log::info!("dropping service: {}", service);
 
-            col.delete_one(doc!{"service": service.to_string()}, None) // Insert into a MongoDB collection
-                .await
-                .on_err("error inserting in MongoDB")?;
-            Ok(HttpResponse::Ok().body("success"))
-        } else { 
-            Ok(internal("error no global id provided"))
-        }
+                col.delete_one(doc!{"service": service.to_string()}) // Insert into a collection
+                    .await
+                    .on_err("error inserting in database")?;
+                state.events.publish(control::ControlEvent::ServiceDropped { service });
+                if let Err(err) = host::refresh_label_caches(&client, &state, None).await {
+                    log::warn!("failed to refresh label caches: {}", err)
+                }
+                Ok(HttpResponse::Ok().body("success"))
+            } else { 
+                Ok(internal("error no global id provided"))
+            }
+        })
+        .await
     }
 }
 
@@ -273,70 +675,65 @@ pub mod policy {
     use std::collections::BTreeSet;
 
     async fn services(state: &State) -> Result<BTreeSet<Label>, actix_web::Error> {
-        use futures::StreamExt;
         let mut services = BTreeSet::new();
-        let mut docs = collection(state, POLICIES_COL)
-            .find(doc! {}, None)
+        let docs = collection(state, POLICIES_COL)
+            .find(doc! {})
             .await
             .on_err("error finding services")?;
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let label =
-                    bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?
-                        .label;
-                services.insert(label);
-            }
+        for doc in docs {
+            let label =
+                bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?
+                    .label;
+            services.insert(label);
         }
         Ok(services)
     }
 
-    async fn services_full(state: &State) -> Result<Vec<control::POnboardServiceRequest>, actix_web::Error> {
-        use futures::StreamExt;
+    pub async fn services_full(state: &State) -> Result<Vec<control::POnboardServiceRequest>, actix_web::Error> {
         let mut services = Vec::new();
-        let mut docs = collection(state, SERVICES_COL)
-            .find(doc! {}, None)
+        let docs = collection(state, SERVICES_COL)
+            .find(doc! {})
             .await
             .on_err("error finding services")?;
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let service =
-                    bson::from_bson::<control::POnboardServiceRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?;
-                services.push(service);
-            }
+        for doc in docs {
+            let service =
+                bson::from_bson::<control::POnboardServiceRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?;
+            services.push(service);
         }
         Ok(services)
     }
 
     async fn hosts(state: &State, label: &Label) -> Result<BTreeSet<url::Url>, actix_web::Error> {
-        use futures::StreamExt;
         let hosts_col = collection(state, HOSTS_COL);
         let mut hosts = BTreeSet::new();
         // find hosts for service
-        let mut docs = collection(state, SERVICES_COL)
-            .find(doc! { "service" : to_bson(label)? }, None)
+        let docs = collection(state, SERVICES_COL)
+            .find(doc! { "service" : to_bson(label)? })
             .await
             .on_err("error notifying hosts")?;
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let host =
-                    bson::from_bson::<control::OnboardServiceRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?
-                        .host;
-                let host = Label::from_str("Host::<<host>>").unwrap().match_with(&host).unwrap().get_label("host").unwrap().clone();
+        for doc in docs {
+            let host =
+                bson::from_bson::<control::OnboardServiceRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?
+                    .host;
+            let host = Label::from_str("Host::<<host>>").unwrap().match_with(&host).unwrap().get_label("host").unwrap().clone();
+            if state.liveness.liveness(&host) == control::HostLiveness::Dead {
+                log::warn!("not updating dead host: {}", host);
+                continue;
+            }
 
-                // find host
-                if let Ok(Some(doc)) = hosts_col
-                    .find_one(Some(doc! { "label" : to_bson(&host)? }), None)
-                    .await
-                {
-                    hosts.insert(
-                        bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
-                            .on_err("Bson conversion error")?
-                            .host,
-                    );
-                }
+            // find host
+            if let Ok(Some(doc)) = hosts_col
+                .find_one(Some(doc! { "label" : to_bson(&host)? }))
+                .await
+            {
+                hosts.insert(
+                    bson::from_bson::<control::OnboardHostRequest>(bson::Bson::Document(doc))
+                        .on_err("Bson conversion error")?
+                        .host,
+                );
             }
         }
         Ok(hosts)
@@ -352,255 +749,438 @@ pub mod policy {
         let hosts = hosts(state, label).await?;
         
         for host in hosts {
-            if let Some(host_str) = host.host_str() {
-                let req = PolicyUpdate {
-                    label: local_label.clone(),
-                    policy: policy.clone(),
-                };
-                let url = format!(
-                    "https://{}:{}/policy/update",
-                    host_str,
-                    host.port().unwrap_or(8090)
-                );
-                match client.post(url).send_json(&req).await {
-                    Ok(res) => {
-                        if res.status().is_success() {
-                            log::info!("pushed policy to {}", host)
-                        } else {
-                            log::info!("failed to push policy to {}", host)
-                        }
+            push_policy(client, state, &host, label, local_label, policy).await;
+        }
+        Ok(())
+    }
+
+    /// Push a policy to a host, returning whether the host accepted it
+    pub async fn push_policy(
+        client: &client::Client,
+        state: &State,
+        host: &url::Url,
+        label: &Label,
+        local_label: &Label,
+        policy: &DPPolicies,
+    ) -> bool {
+        if let Some(host_str) = host.host_str() {
+            // the policy is for the service (with global ID `label`) behind the proxy
+            let req = PolicyUpdate {
+                label: local_label.clone(),
+                policy: policy.clone(),
+                service: Some(ServiceId::Label(label.clone())),
+            };
+            let url = format!(
+                "https://{}:{}/policy/update",
+                host_str,
+                host.port().unwrap_or(8090)
+            );
+            let error = match client.post(url).send_json(&req).await {
+                Ok(res) => {
+                    if res.status().is_success() {
+                        log::info!("pushed policy to {}", host);
+                        return true;
+                    } else {
+                        log::info!("failed to push policy to {}", host);
+                        res.status().to_string()
                     }
-                    Err(err) => log::warn!("{}: {}", host, err),
                 }
-            } else {
-                log::warn!("failed to contact host: {}", host)
-            }
+                Err(err) => {
+                    log::warn!("{}: {}", host, err);
+                    err.to_string()
+                }
+            };
+            push_failed(state, host, Some(label), error)
+        } else {
+            log::warn!("failed to contact host: {}", host);
+            push_failed(state, host, Some(label), "no host name".to_string())
         }
-        Ok(())
+        false
     }
 
     #[get("/list")]
-    pub async fn list(state: State) -> Result<HttpResponse, actix_web::Error> {
-        use futures::StreamExt;
+    pub async fn list(state: State, identity: Identity) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let col = collection(&state, POLICIES_COL);
-        let mut docs = col
-            .find(doc! {}, None)
+        let docs = col
+            .find(doc! {})
             .await
             .on_err("error listing policies")?;
         let mut s = String::new();
-        while let Some(doc) = docs.next().await {
-            if let Ok(doc) = doc {
-                let policy =
-                    bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
-                        .on_err("Bson conversion error")?;
-                let pol = if policy.policy.is_allow_all() {
-                    "(allow all)".to_owned()
-                } else if policy.policy.is_deny_all() {
-                    "(deny all)".to_owned()
-                } else {
-                    let mut pol : String = "".to_owned();
-                    if policy.policy.is_allow_egress() {
-                        pol += "(allow egress)";
-                    }
-                    if policy.policy.is_allow_ingress() {
-                        pol += "(allow ingress)";
-                    }
-                    if policy.policy.is_deny_ingress() {
-                        pol += "(deny ingress)";
-                    }
-                    if policy.policy.is_deny_ingress() {
-                        pol += "(deny ingress)";
-                    }
-                    pol
-                };
-                s.push_str(format!("{}{}\n", policy.label, pol).as_str())
-            }
+        for doc in docs {
+            let policy =
+                bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?;
+            let pol = if policy.policy.is_allow_all() {
+                "(allow all)".to_owned()
+            } else if policy.policy.is_deny_all() {
+                "(deny all)".to_owned()
+            } else {
+                let mut pol : String = "".to_owned();
+                if policy.policy.is_allow_egress() {
+                    pol += "(allow egress)";
+                }
+                if policy.policy.is_allow_ingress() {
+                    pol += "(allow ingress)";
+                }
+                if policy.policy.is_deny_ingress() {
+                    pol += "(deny ingress)";
+                }
+                if policy.policy.is_deny_ingress() {
+                    pol += "(deny ingress)";
+                }
+                pol
+            };
+            s.push_str(format!("{}{}\n", policy.label, pol).as_str())
         }
         Ok(HttpResponse::Ok().body(s))
     }
 
-    pub async fn save_policy(
-        state: State,
-        request: control::PolicyUpdateRequest,
-    ) -> Result<HttpResponse, actix_web::Error> {
-        let label = &request.label.clone();
+    // replace the current policy for a label and record the update as a new revision
+    pub async fn store_policy(
+        state: &State,
+        author: &Identity,
+        policy: control::RevisionPolicy,
+    ) -> Result<control::PolicyRevision, actix_web::Error> {
+        let label = policy.label().clone();
         log::info!(r#"updating policy for label "{}""#, label);
 
-        if let bson::Bson::Document(document) = to_bson(&request)? {
+        let document = match &policy {
+            control::RevisionPolicy::Local(request) => to_bson(request)?,
+            control::RevisionPolicy::Global(request) => to_bson(request)?,
+        };
+        if let bson::Bson::Document(document) = document {
             // update policy in database
-            let col = collection(&state, POLICIES_COL);
-            let filter = doc! { "label" : to_bson(label)? };
-            col.delete_many(filter, None)
+            let col = collection(state, POLICIES_COL);
+            let filter = doc! { "label" : to_bson(&label)? };
+            col.delete_many(filter)
                 .await
                 .on_err("error removing old policies")?;
-            col.insert_one(document, None)
+            col.insert_one(document)
                 .await
                 .on_err("error inserting new policy")?;
-            // push policy to hosts
-            Ok(HttpResponse::Ok().finish())
+            // record the revision
+            let revision = control::PolicyRevision {
+                revision: last_revision(state, &label).await? + 1,
+                time: chrono::Utc::now(),
+                author: author.name(),
+                hash: policy.blake3(),
+                comment: policy.comment().cloned(),
+                label,
+                policy,
+            };
+            if let bson::Bson::Document(document) = to_bson(&revision)? {
+                collection(state, HISTORY_COL)
+                    .insert_one(document)
+                    .await
+                    .on_err("error recording policy revision")?;
+            }
+            log::info!(
+                "policy for {} is at revision {} ({})",
+                revision.label,
+                revision.revision,
+                revision.author
+            );
+            state.events.publish(control::ControlEvent::PolicyUpdated {
+                label: revision.label.clone(),
+                revision: revision.revision,
+                hash: revision.hash.clone(),
+            });
+            Ok(revision)
+        } else {
+            log::warn!("error converting the BSON object into a document");
+            Err(internal("error inserting policy").into())
+        }
+    }
+
+    /// Revisions of the policy for a label, oldest first
+    pub async fn revisions(
+        state: &State,
+        label: &Label,
+    ) -> Result<Vec<control::PolicyRevision>, actix_web::Error> {
+        let docs = collection(state, HISTORY_COL)
+            .find(doc! { "label" : to_bson(label)? })
+            .await
+            .on_err("error finding policy history")?;
+        let mut revisions = Vec::new();
+        for doc in docs {
+            revisions.push(
+                bson::from_bson::<control::PolicyRevision>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?,
+            )
+        }
+        revisions.sort_by_key(|revision| revision.revision);
+        Ok(revisions)
+    }
+
+    pub async fn revision(
+        state: &State,
+        label: &Label,
+        revision: i64,
+    ) -> Result<Option<control::PolicyRevision>, actix_web::Error> {
+        if let Some(doc) = collection(state, HISTORY_COL)
+            .find_one(doc! { "label" : to_bson(label)?, "revision" : revision })
+            .await
+            .on_err("error finding policy revision")?
+        {
+            Ok(Some(
+                bson::from_bson::<control::PolicyRevision>(bson::Bson::Document(doc))
+                    .on_err("Bson conversion error")?,
+            ))
         } else {
-            log::warn!("error converting the BSON object into a MongoDB document");
-            Ok(internal("error inserting policy"))
+            Ok(None)
         }
     }
+
+    /// Hash of the current policy for a label
+    pub async fn policy_hash(state: &State, label: &Label) -> Option<String> {
+        let doc = collection(state, POLICIES_COL)
+            .find_one(doc! { "label" : to_bson(label).ok()? })
+            .await
+            .ok()??;
+        if *label == global_policy_label() || *label == onboarding_policy_label() {
+            bson::from_bson::<control::CPPolicyUpdateRequest>(bson::Bson::Document(doc))
+                .ok()
+                .map(|request| request.policy.blake3())
+        } else {
+            bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                .ok()
+                .map(|request| request.policy.blake3())
+        }
+    }
+
+    async fn last_revision(state: &State, label: &Label) -> Result<i64, actix_web::Error> {
+        Ok(revisions(state, label)
+            .await?
+            .last()
+            .map(|revision| revision.revision)
+            .unwrap_or(0))
+    }
+
+    pub async fn save_policy(
+        state: State,
+        author: &Identity,
+        request: control::PolicyUpdateRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        store_policy(&state, author, control::RevisionPolicy::Local(request)).await?;
+        Ok(HttpResponse::Ok().finish())
+    }
     pub async fn helper_update(
         client: web::Data<client::Client>,
         state: State,
+        author: &Identity,
         local_label: &Label,
         request: control::PolicyUpdateRequest,
     ) -> Result<HttpResponse, actix_web::Error> {
         let label = &request.label.clone();
-        log::info!(r#"updating policy for label "{}""#, label);
-
-        if let bson::Bson::Document(document) = to_bson(&request)? {
-            // update policy in database
-            let col = collection(&state, POLICIES_COL);
-            let filter = doc! { "label" : to_bson(label)? };
-            col.delete_many(filter, None)
-                .await
-                .on_err("error removing old policies")?;
-            col.insert_one(document, None)
-                .await
-                .on_err("error inserting new policy")?;
-            // push policy to hosts
-            update_hosts(&client.into_inner(), &state, label, local_label, &request.policy).await?;
-            Ok(HttpResponse::Ok().finish())
-        } else {
-            log::warn!("error converting the BSON object into a MongoDB document");
-            Ok(internal("error inserting policy"))
+        let policy = request.policy.clone();
+        store_policy(&state, author, control::RevisionPolicy::Local(request)).await?;
+        // push policy to hosts
+        let client = client.into_inner();
+        update_hosts(&client, &state, label, local_label, &policy).await?;
+        // the policy may test for different labels
+        if let Err(err) = super::host::refresh_label_caches(&client, &state, None).await {
+            log::warn!("failed to refresh label caches: {}", err)
         }
+        Ok(HttpResponse::Ok().finish())
     }
-    #[post("/update-global")]
-    pub async fn update_global(
+    pub async fn helper_update_global(
         client: web::Data<client::Client>,
         state: State,
-        request: Json<control::CPPolicyUpdateRequest>,
+        author: &Identity,
+        request: control::CPPolicyUpdateRequest,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let mut request = request.into_inner();
-        request.label = global_policy_label();
-
-        let label = &request.label.clone();
-        log::info!(r#"updating policy for label "{}""#, label);
-
-        if let bson::Bson::Document(document) = to_bson(&request)? {
-            // update policy in database
-            let col = collection(&state, POLICIES_COL);
-            let filter = doc! { "label" : to_bson(label)? };
-            col.delete_many(filter, None)
-                .await
-                .on_err("error removing old policies")?;
-            col.insert_one(document, None)
-                .await
-                .on_err("error inserting new policy")?;
+        // refuse functions that would be silently dropped from the policies of services
+        let functions = unspecialized(&request.policy);
+        if !functions.is_empty() {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "global policy functions cannot be specialized: {}",
+                functions.join(", ")
+            )));
+        }
+        let revision = store_policy(
+            &state,
+            author,
+            control::RevisionPolicy::Global(request.clone()),
+        )
+        .await?;
+        // specialized policies refer back to the global revision
+        let comment = format!("specialized from {} revision {}", revision.label, revision.revision);
+
+        //by default, all onboarded services are concerned
+        let selector = &request.selector.unwrap_or(Label::from_str("ServiceID::**").unwrap());
+        log::info!("propagating to proxies according to selector: {}", selector);    
+        let services = services_full(&state).await?.into_iter().filter(|service|
+            service.service_id.has_label(&selector)
+        );
+        let global_policy = request.policy.clone();
+        for service in services {
+            let local_label = get_local_service_label(&state, &service.service).await?;
+
+            log::info!("updating policy for {}", service.service);
+            let mut local_pol : Option<DPPolicies> = None;
+            let arc_state = Arc::new(state.clone()); 
+            for function in EGRESS_FUNCTIONS.iter() { 
+                let tmp_egress_pol = compile_egress(
+                    arc_state.clone(), 
+                    global_policy.clone(), 
+                    function,
+                    &service.service_id
+                ).await.map_err(|e| specialization_failed(&state, &service.service, e.to_string()))?;
+                
+                //NB map_or can not be use, it implies one clone
+                local_pol = match local_pol {
+                    None => Some(tmp_egress_pol), 
+                    Some(pol) => Some(pol.merge(&tmp_egress_pol))
+                };
+            }
 
-            
-            //by default, all onboarded services are concerned
-            let selector = &request.selector.unwrap_or(Label::from_str("ServiceID::**").unwrap());
-            log::info!("propagating to proxies according to selector: {}", selector);    
-            let services = services_full(&state).await?.into_iter().filter(|service|
-                service.service_id.has_label(&selector)
-            );
-            let global_policy = request.policy.clone();
-            for service in services {
-                let local_label = get_local_service_label(&state, &service.service).await?;
-
-                log::info!("updating policy for {}", service.service);
-                let mut local_pol : Option<DPPolicies> = None;
-                let arc_state = Arc::new(state.clone()); 
-                for function in vec![
-                    policies::ALLOW_REST_REQUEST,
-                    policies::ALLOW_TCP_CONNECTION,
-                ]{ 
-                    let tmp_egress_pol = compile_egress(
-                        arc_state.clone(), 
-                        global_policy.clone(), 
-                        function,
-                        &service.service_id
-                    ).await.map_err(|e| internal(e.to_string()))?;
-                    
-                    //NB map_or can not be use, it implies one clone
-                    local_pol = match local_pol {
-                        None => Some(tmp_egress_pol), 
-                        Some(pol) => Some(pol.merge(&tmp_egress_pol))
-                    };
+            for function in INGRESS_FUNCTIONS.iter() { 
+                let tmp_ingress_pol = compile_ingress(
+                    arc_state.clone(), 
+                    global_policy.clone(), 
+                    function,
+                    &service.service_id
+                ).await.map_err(|e| specialization_failed(&state, &service.service, e.to_string()))?;
+
+                local_pol = match local_pol {
+                    None => Some(tmp_ingress_pol), 
+                    Some(pol) => Some(pol.merge(&tmp_ingress_pol))
+                };
+            }
+            match local_pol {
+                None =>{ 
+                    log::warn!("error no main function in global policy");
+                    specialization_failed(&state, &service.service, "no main function".to_string());
+                    return Ok(internal("error updating policy of selected services"))
                 }
+                Some(local_pol) =>{
+                    helper_update(
+                        client.clone(),
+                        state.clone(),
+                        author,
+                        &local_label,
+                        control::PolicyUpdateRequest{
+                            label: service.service.clone(),
+                            policy: local_pol,
+                            labels: request.labels.clone(),
+                            comment: Some(comment.clone()),
+                        }
+                    ).await?;
 
-                for function in vec![
-                    policies::ALLOW_REST_RESPONSE,
-                    policies::ON_TCP_DISCONNECT,
-                ]{ 
-                    let tmp_ingress_pol = compile_ingress(
-                        arc_state.clone(), 
-                        global_policy.clone(), 
-                        function,
-                        &service.service_id
-                    ).await.map_err(|e| internal(e.to_string()))?;
-
-                    local_pol = match local_pol {
-                        None => Some(tmp_ingress_pol), 
-                        Some(pol) => Some(pol.merge(&tmp_ingress_pol))
-                    };
-                }
-                match local_pol {
-                    None =>{ 
-                        log::warn!("error no main function in global policy");
-                        return Ok(internal("error updating policy of selected services"))
-                    }
-                    Some(local_pol) =>{
-                        helper_update(
-                            client.clone(),
-                            state.clone(),
-                            &local_label,
-                            control::PolicyUpdateRequest{
-                                label: service.service.clone(),
-                                policy: local_pol,
-                                labels: request.labels.clone(),
-                            }
-                        ).await?;
-
-                    } 
-                }
+                } 
             }
-            Ok(HttpResponse::Ok().finish())
-        } else {
-            log::warn!("error converting the BSON object into a MongoDB document");
-            Ok(internal("error inserting policy"))
         }
+        Ok(HttpResponse::Ok().finish())
+    }
+    // report a global policy that could not be specialized for a service
+    fn specialization_failed(state: &State, label: &Label, error: String) -> HttpResponse {
+        log::warn!("failed to specialize policy for {}: {}", label, error);
+        state.events.publish(control::ControlEvent::SpecializationFailed {
+            label: label.clone(),
+            error: error.clone(),
+        });
+        internal(error)
+    }
+
+    #[post("/update-global")]
+    pub async fn update_global(
+        client: web::Data<client::Client>,
+        state: State,
+        identity: Identity,
+        request: Json<control::CPPolicyUpdateRequest>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let targets = vec![global_policy_label()];
+        audited(&state, &identity, "policy/update-global", targets, async {
+            let mut request = request.into_inner();
+            request.label = global_policy_label();
+            authorize(&state, &identity, Access::Policy(&request.label))?;
+            helper_update_global(client, state.clone(), &identity, request).await
+        })
+        .await
     }
 
     #[post("/update-onboarding")]
     pub async fn update_onboarding(
         state: State,
+        identity: Identity,
         request: Json<control::OnboardingUpdateRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let mut request = request.into_inner().pack();
-        request.label = onboarding_policy_label();
-        let label = &request.label.clone();
-
-        log::info!(r#"updating policy for label "{}""#, label);
-        if let bson::Bson::Document(document) = to_bson(&request)? {
-            // update policy in database
-            let col = collection(&state, POLICIES_COL);
-            let filter = doc! { "label" : to_bson(label)? };
-            col.delete_many(filter, None)
-                .await
-                .on_err("error removing old policies")?;
-            col.insert_one(document, None)
-                .await
-                .on_err("error inserting new policy")?;
+        let targets = vec![onboarding_policy_label()];
+        audited(&state, &identity, "policy/update-onboarding", targets, async {
+            let mut request = request.into_inner().pack();
+            request.label = onboarding_policy_label();
+            authorize(&state, &identity, Access::Policy(&request.label))?;
+            store_policy(&state, &identity, control::RevisionPolicy::Global(request)).await?;
             Ok(HttpResponse::Ok().finish())
-        } else {
-            log::warn!("error converting the BSON object into a MongoDB document");
-            Ok(internal("error inserting policy"))
-        }
+        })
+        .await
     }
 
-    async fn get_local_service_label(
+    #[get("/history")]
+    async fn history(
+        state: State,
+        identity: Identity,
+        request: Json<control::PolicyQueryRequest>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
+        let label = &request.label;
+        log::info!("querying policy history for {}", label);
+        Ok(HttpResponse::Ok().json(revisions(&state, label).await?))
+    }
+
+    #[post("/rollback")]
+    pub async fn rollback(
+        client: web::Data<client::Client>,
+        state: State,
+        identity: Identity,
+        request: Json<control::PolicyRollbackRequest>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let targets = vec![request.label.clone()];
+        audited(&state, &identity, "policy/rollback", targets, async {
+            let request = request.into_inner();
+            let label = &request.label;
+            authorize(&state, &identity, Access::Policy(label))?;
+            log::info!("rolling back policy for {} to revision {}", label, request.revision);
+            let mut policy = match revision(&state, label, request.revision).await? {
+                Some(revision) => revision.policy,
+                None => {
+                    return Ok(HttpResponse::NotFound().body(format!(
+                        "no revision {} of policy for {}",
+                        request.revision, label
+                    )))
+                }
+            };
+            // the rollback is itself recorded as a new revision
+            let comment = match request.comment {
+                Some(comment) => format!("rollback to revision {}: {}", request.revision, comment),
+                None => format!("rollback to revision {}", request.revision),
+            };
+            policy.set_comment(Some(comment));
+            match policy {
+                control::RevisionPolicy::Local(request) => {
+                    let local_label = get_local_service_label(&state, &request.label).await?;
+                    helper_update(client, state.clone(), &identity, &local_label, request).await
+                }
+                control::RevisionPolicy::Global(request) if request.label == global_policy_label() => {
+                    // re-specialize for the on-boarded services
+                    helper_update_global(client, state.clone(), &identity, request).await
+                }
+                control::RevisionPolicy::Global(request) => {
+                    store_policy(&state, &identity, control::RevisionPolicy::Global(request)).await?;
+                    Ok(HttpResponse::Ok().finish())
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn get_local_service_label(
         state: &State, 
         label: &Label
     ) -> Result<Label, actix_web::Error> {
         if let Some(doc) = collection(state, SERVICES_COL)
-            .find_one(doc! { "service": to_bson(label)?}, None)
+            .find_one(doc! { "service": to_bson(label)?})
             .await
             .on_err("error finding services")?{
 
@@ -622,23 +1202,31 @@ pub mod policy {
     pub async fn update(
         client: web::Data<client::Client>,
         state: State,
+        identity: Identity,
         request: Json<control::PolicyUpdateRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let request = request.into_inner();
-        let local_label = &get_local_service_label(&state, &request.label).await?;
-        helper_update(client, state, local_label, request).await 
+        let targets = vec![request.label.clone()];
+        audited(&state, &identity, "policy/update", targets, async {
+            let request = request.into_inner();
+            authorize(&state, &identity, Access::Policy(&request.label))?;
+            let local_label = &get_local_service_label(&state, &request.label).await?;
+            helper_update(client, state.clone(), &identity, local_label, request).await 
+        })
+        .await
     }
 
     #[get("/query")]
     async fn query(
         state: State,
+        identity: Identity,
         request: Json<control::PolicyQueryRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let label = &request.label;
         log::info!("querying policy for {}", label);
         let col = collection(&state, POLICIES_COL);
         if let Ok(Some(doc)) = col
-            .find_one(Some(doc! { "label" : label.to_string() }), None)
+            .find_one(Some(doc! { "label" : label.to_string() }))
             .await
         {
             
@@ -649,16 +1237,46 @@ pub mod policy {
             Ok(HttpResponse::NotFound().body(format!("no policy for {}", label)))
         }
     }
+    #[get("/query-services")]
+    async fn query_services(
+        state: State,
+        identity: Identity,
+        request: Json<control::ServicesQueryRequest>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
+        let selector = &request.selector;
+        log::info!("querying service policies for {}", selector);
+        let col = collection(&state, POLICIES_COL);
+        let mut policies = Vec::new();
+        for service in services_full(&state).await? {
+            if !service.service_id.has_label(selector) {
+                continue;
+            }
+            if let Some(doc) = col
+                .find_one(Some(doc! { "label" : service.service.to_string() }))
+                .await
+                .on_err("error finding policy")?
+            {
+                policies.push(
+                    bson::from_bson::<control::PolicyUpdateRequest>(bson::Bson::Document(doc))
+                        .on_err("Bson conversion error")?,
+                )
+            }
+        }
+        Ok(HttpResponse::Ok().json(policies))
+    }
     #[get("/query-global")]
     async fn query_global(
         state: State,
+        identity: Identity,
         request: Json<control::PolicyQueryRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let label = &request.label;
         log::info!("querying policy for {}", label);
         let col = collection(&state, POLICIES_COL);
         if let Ok(Some(doc)) = col
-            .find_one(Some(doc! { "label" : label.to_string() }), None)
+            .find_one(Some(doc! { "label" : label.to_string() }))
             .await
         {
             
@@ -672,15 +1290,18 @@ pub mod policy {
     #[get("/query-onboarding")]
     async fn query_onboarding(
         state: State,
+        identity: Identity,
         request: Json<control::PolicyQueryRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let label = &request.label;
         log::info!("querying policy for {}", label);
 
         let current = control::OnboardingUpdateRequest{
             label: onboarding_policy_label(),
             policy: service::get_onboarding_policy(&state).await?.policy(),
-            labels: control::LabelMap::default() 
+            labels: control::LabelMap::default(),
+            comment: None,
         };
 
         Ok(HttpResponse::Ok().json(current))
@@ -690,39 +1311,61 @@ pub mod policy {
     async fn drop(
         client: web::Data<client::Client>,
         state: State,
+        identity: Identity,
         request: Json<control::PolicyQueryRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let label = &request.label;
-        log::info!("dropping policy for {}", label);
-        let col = collection(&state, POLICIES_COL);
-        let res = col
-            .delete_one(doc! { "label" : label.to_string() }, None)
-            .await
-            .on_err("failed to drop policy")?;
-        update_hosts(&client.into_inner(), &state, label, &get_local_service_label(&state, label).await?, &DPPolicies::deny_all()).await?;
-        Ok(HttpResponse::Ok().body(format!("dropped {}", res.deleted_count)))
+        let targets = vec![request.label.clone()];
+        audited(&state, &identity, "policy/drop", targets, async {
+            let label = &request.label;
+            authorize(&state, &identity, Access::Policy(label))?;
+            log::info!("dropping policy for {}", label);
+            let col = collection(&state, POLICIES_COL);
+            let res = col
+                .delete_one(doc! { "label" : label.to_string() })
+                .await
+                .on_err("failed to drop policy")?;
+            if res > 0 {
+                state.events.publish(control::ControlEvent::PolicyDropped { label: label.clone() })
+            }
+            update_hosts(&client.into_inner(), &state, label, &get_local_service_label(&state, label).await?, &DPPolicies::deny_all()).await?;
+            Ok(HttpResponse::Ok().body(format!("dropped {}", res)))
+        })
+        .await
     }
 
     #[delete("/drop-all")]
     async fn drop_all(
         client: web::Data<client::Client>,
         state: State,
+        identity: Identity,
     ) -> Result<HttpResponse, actix_web::Error> {
-        log::info!("dropping all policies");
-        let services = services(&state).await?;
-        let client = client.into_inner();
-        if collection(&state, POLICIES_COL).drop(None).await.is_ok() {
-            for label in services {
-                update_hosts(&client, &state, &label, &get_local_service_label(&state, &label).await?, &DPPolicies::deny_all()).await?;
+        // forbidden calls are recorded without listing the services
+        let targets = match authorize(&state, &identity, Access::Admin) {
+            Ok(()) => services(&state).await?.into_iter().collect(),
+            Err(_) => Vec::new(),
+        };
+        audited(&state, &identity, "policy/drop-all", targets, async {
+            authorize(&state, &identity, Access::Admin)?;
+            log::info!("dropping all policies");
+            let services = services(&state).await?;
+            let client = client.into_inner();
+            if collection(&state, POLICIES_COL).drop().await.is_ok() {
+                for label in services {
+                    state.events.publish(control::ControlEvent::PolicyDropped { label: label.clone() });
+                    update_hosts(&client, &state, &label, &get_local_service_label(&state, &label).await?, &DPPolicies::deny_all()).await?;
+                }
             }
-        }
-        Ok(HttpResponse::Ok().body("dropped all policies"))
+            Ok(HttpResponse::Ok().body("dropped all policies"))
+        })
+        .await
     }
     #[post("/specialize")]
     pub async fn specialize(
         state: State,
+        identity: Identity,
         request: Json<control::SpecializationRequest>,
     ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
         let request = request.into_inner();
 
         log::info!(r#"Specializing policy"#);
@@ -741,6 +1384,7 @@ pub mod policy {
         for function in vec![
             policies::ALLOW_REST_REQUEST,
             policies::ALLOW_TCP_CONNECTION,
+            policies::ALLOW_UDP_FLOW,
         ]{ 
             let tmp_egress_pol = compile_egress(
                 arc_state.clone(), 
@@ -774,22 +1418,200 @@ pub mod policy {
     }
 }
 
+pub mod audit {
+    use super::*;
+
+    #[get("/query")]
+    pub async fn query(
+        state: State,
+        identity: Identity,
+        request: Option<Json<control::AuditQuery>>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
+        let query = request.map(|request| request.into_inner()).unwrap_or_default();
+        let limit = control::AuditPage::limit(query.limit);
+        // read pages of the log until there are enough matching records
+        let mut after = query.after.unwrap_or_default();
+        let mut records = Vec::new();
+        loop {
+            let page = AuditLog::page(&state.storage, after, limit)
+                .await
+                .on_err("error reading audit log")?;
+            let done = page.len() < limit;
+            if let Some(last) = page.last() {
+                after = last.sequence
+            }
+            records.extend(page.into_iter().filter(|record| query.matches(record)));
+            if done || records.len() >= limit {
+                break;
+            }
+        }
+        records.truncate(limit);
+        Ok(HttpResponse::Ok().json(records))
+    }
+
+    // a page of the complete log as JSON lines (e.g. for archiving and independent verification)
+    #[get("/export")]
+    pub async fn export(
+        state: State,
+        identity: Identity,
+        page: web::Query<control::AuditPage>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
+        let mut s = String::new();
+        for record in AuditLog::page(
+            &state.storage,
+            page.after.unwrap_or_default(),
+            control::AuditPage::limit(page.limit),
+        )
+        .await
+        .on_err("error reading audit log")?
+        {
+            s.push_str(&serde_json::to_string(&record)?);
+            s.push('\n')
+        }
+        Ok(HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .body(s))
+    }
+}
+
+pub mod events {
+    use super::*;
+    use futures::StreamExt;
+
+    // stream events as they happen, optionally resuming after a sequence number
+    #[get("/events")]
+    pub async fn stream(
+        state: State,
+        identity: Identity,
+        request: web::HttpRequest,
+        query: web::Query<control::EventsQuery>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        authorize(&state, &identity, Access::Read)?;
+        let headers = request.headers();
+        // SSE clients (e.g. `EventSource`) resume with the `Last-Event-ID` header
+        let last_event_id = headers
+            .get("Last-Event-ID")
+            .and_then(|id| id.to_str().ok())
+            .and_then(|id| id.parse().ok());
+        let sse = match query.format {
+            Some(format) => format == control::EventFormat::Sse,
+            None => headers
+                .get(actix_web::http::header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map(|accept| accept.contains("text/event-stream"))
+                .unwrap_or(false),
+        };
+        log::info!("{} is watching events", identity);
+        let (past, new) = state.events.subscribe(query.after.or(last_event_id));
+        let events = futures::stream::iter(past).chain(new).map(move |record| {
+            let json = serde_json::to_string(&record)?;
+            Ok::<_, actix_web::Error>(bytes::Bytes::from(if sse {
+                // clients do not resume from missed events
+                if record.sequence == control::EVENTS_MISSED_SEQUENCE {
+                    format!("data: {}\n\n", json)
+                } else {
+                    format!("id: {}\ndata: {}\n\n", record.sequence, json)
+                }
+            } else {
+                json + "\n"
+            }))
+        });
+        Ok(HttpResponse::Ok()
+            .content_type(if sse {
+                "text/event-stream"
+            } else {
+                "application/x-ndjson"
+            })
+            .streaming(events))
+    }
+}
+
+fn push_failed(state: &State, host: &url::Url, service: Option<&Label>, error: String) {
+    state.events.publish(control::ControlEvent::PushFailed {
+        host: host.clone(),
+        service: service.cloned(),
+        error,
+    })
+}
+
+// run a mutating REST call and record it in the audit log, together with the policy hashes of its
+// target labels before and after the call. The call is refused if it cannot be recorded first.
+async fn audited<F>(
+    state: &State,
+    identity: &Identity,
+    endpoint: &str,
+    labels: Vec<Label>,
+    call: F,
+) -> Result<HttpResponse, actix_web::Error>
+where
+    F: std::future::Future<Output = Result<HttpResponse, actix_web::Error>>,
+{
+    // changes are only made if they can be audited
+    if let Some(err) = state.audit.broken() {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .body(format!("audit log is unavailable, changes are refused: {}", err)));
+    }
+    let _changes = state.audit.changes().await;
+    let mut targets = Vec::new();
+    for label in labels {
+        targets.push(control::AuditTarget {
+            before: policy::policy_hash(state, &label).await,
+            after: None,
+            label,
+        })
+    }
+    if let Err(err) = state
+        .audit
+        .append(
+            &state.storage,
+            endpoint,
+            &identity.name(),
+            targets.clone(),
+            STARTED.to_string(),
+        )
+        .await
+    {
+        log::error!("failed to append to audit log: {}", err);
+        return Ok(HttpResponse::ServiceUnavailable()
+            .body(format!("audit log is unavailable, {} was refused: {}", endpoint, err)));
+    }
+    let result = call.await;
+    for target in targets.iter_mut() {
+        target.after = policy::policy_hash(state, &target.label).await
+    }
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    if let Err(err) = state
+        .audit
+        .append(&state.storage, endpoint, &identity.name(), targets, status.to_string())
+        .await
+    {
+        log::error!("failed to append to audit log: {}", err);
+        return Ok(internal(format!(
+            "the result of {} was not recorded in the audit log: {}",
+            endpoint, err
+        )));
+    }
+    result
+}
+
 pub async fn present(
-    col: &mongodb::Collection,
+    col: &storage::Collection,
     filter: impl Into<Option<bson::Document>>,
 ) -> Result<bool, actix_web::Error> {
-    use futures::StreamExt;
     Ok(col
-        .find(filter, None)
-        .await
-        .on_err("MongoDB query error")?
-        .next()
+        .find_one(filter)
         .await
+        .on_err("database query error")?
         .is_some())
 }
 
-pub fn collection(state: &State, collection: &str) -> mongodb::Collection {
-    state.db_con.database(ARMOUR_DB).collection(collection)
+pub fn collection(state: &State, collection: &str) -> storage::Collection {
+    storage::Collection::new(state.storage.clone(), collection)
 }
 
 pub fn to_bson<T: ?Sized>(value: &T) -> Result<bson::Bson, actix_web::Error>
@@ -803,6 +1625,14 @@ fn internal<B: Into<actix_web::body::Body>>(b: B) -> HttpResponse {
     HttpResponse::InternalServerError().body(b)
 }
 
+// check that the roles of the caller allow the access
+fn authorize(state: &State, identity: &Identity, access: Access) -> Result<(), actix_web::Error> {
+    state.roles.authorize(identity, access).map_err(|err| {
+        log::warn!("{}", err);
+        HttpResponse::Forbidden().body(err).into()
+    })
+}
+
 trait OnErr<T, E>
 where
     Self: Into<Result<T, E>>,
@@ -814,4 +1644,4 @@ where
 
 impl<T> OnErr<T, bson::de::Error> for bson::de::Result<T> {}
 impl<T> OnErr<T, bson::ser::Error> for bson::ser::Result<T> {}
-impl<T> OnErr<T, mongodb::error::Error> for mongodb::error::Result<T> {}
+impl<T> OnErr<T, storage::Error> for Result<T, storage::Error> {}
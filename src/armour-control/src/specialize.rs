@@ -709,10 +709,14 @@ fn check_header(
                 vec![ Typ::id(), Typ::id(), Typ::http_response(), Typ::data() ], 
                 Typ::bool()
             ),
-            policies::ALLOW_TCP_CONNECTION => Signature::new(
+            policies::ALLOW_TCP_CONNECTION | policies::ALLOW_UDP_FLOW => Signature::new(
                 vec![Typ::id(), Typ::id(), Typ::connection()],
                 Typ::bool()
             ),
+            policies::ALLOW_TCP_PAYLOAD | policies::ALLOW_UDP_DATAGRAM => Signature::new(
+                vec![Typ::id(), Typ::id(), Typ::connection(), Typ::data()],
+                Typ::bool()
+            ),
             policies::ON_TCP_DISCONNECT => Signature::new(
                 vec![ Typ::id(), Typ::id(), Typ::connection(), Typ::i64(), Typ::i64() ],
                 Typ::bool()
@@ -752,8 +756,10 @@ fn compile_helper(
             Signature::new(vec![Typ::http_request(), Typ::data()], ret_typ),
         policies::ALLOW_REST_RESPONSE =>
             Signature::new(vec![Typ::http_response(), Typ::data()], ret_typ),
-        policies::ALLOW_TCP_CONNECTION =>
+        policies::ALLOW_TCP_CONNECTION | policies::ALLOW_UDP_FLOW =>
             Signature::new(vec![Typ::connection()], ret_typ),
+        policies::ALLOW_TCP_PAYLOAD | policies::ALLOW_UDP_DATAGRAM =>
+            Signature::new(vec![Typ::connection(), Typ::data()], ret_typ),
         policies::ON_TCP_DISCONNECT =>
             Signature::new(vec![Typ::connection(), Typ::i64(), Typ::i64()], ret_typ),
         _ => return Err(Error::from(format!(
@@ -828,7 +834,7 @@ async fn compile_egress_ingress(
                                     ))
                                 )
                             },
-                            policies::ALLOW_TCP_CONNECTION => {                                    
+                            policies::ALLOW_TCP_CONNECTION | policies::ALLOW_UDP_FLOW => {
                                 let e = e.subst(1,
                                     &Expr::call(
                                         &format!("Connection::{}", if f_egress {"to"} else {"from"} )[..],
@@ -842,6 +848,23 @@ async fn compile_egress_ingress(
                                     Box::new(e)
                                 )
                             }, 
+                            policies::ALLOW_TCP_PAYLOAD | policies::ALLOW_UDP_DATAGRAM => {
+                                let e = e.subst(2,
+                                    &Expr::call(
+                                        &format!("Connection::{}", if f_egress {"to"} else {"from"} )[..],
+                                        vec![Expr::bvar("conn", 1)]
+                                    ),
+                                    false
+                                );
+
+                                Expr::Closure(
+                                    Ident("conn".to_string()), 
+                                    Box::new(Expr::Closure(
+                                        Ident("payload".to_string()), 
+                                        Box::new(e)
+                                    ))
+                                )
+                            },
                             policies::ON_TCP_DISCONNECT => {                                    
                                 let e = Expr::Closure(
                                     Ident((if f_egress {"to"} else {"from"}).to_string()),
@@ -897,6 +920,40 @@ async fn compile_egress_ingress(
     Ok(policies::DPPolicies::from(new_gpol))
 }
 
+// protocol table functions that are specialized for the sender (egress) or receiver (ingress)
+pub const EGRESS_FUNCTIONS: [&str; 5] = [
+    policies::ALLOW_REST_REQUEST,
+    policies::ALLOW_TCP_CONNECTION,
+    policies::ALLOW_TCP_PAYLOAD,
+    policies::ALLOW_UDP_FLOW,
+    policies::ALLOW_UDP_DATAGRAM,
+];
+pub const INGRESS_FUNCTIONS: [&str; 2] = [
+    policies::ALLOW_REST_RESPONSE,
+    policies::ON_TCP_DISCONNECT,
+];
+
+/// Functions of a global policy that cannot be specialized (e.g. `on_http_complete`), and so
+/// would be missing from the policies of services
+pub fn unspecialized(global_pol: &policies::GlobalPolicies) -> Vec<String> {
+    let mut functions = Vec::new();
+    for (_proto, pol) in global_pol.policies() {
+        for (function, fn_policy) in pol.fn_policies.0.iter() {
+            if let policies::FnPolicy::Args(_) = fn_policy {
+                if !EGRESS_FUNCTIONS.contains(&function.as_str())
+                    && !INGRESS_FUNCTIONS.contains(&function.as_str())
+                {
+                    functions.push(function.clone())
+                }
+            }
+        }
+        if pol.program.code.get(policies::ON_POLICY_ERROR.to_string()).is_some() {
+            functions.push(policies::ON_POLICY_ERROR.to_string())
+        }
+    }
+    functions
+}
+
 pub async fn compile_egress(
     state: Arc<State>, 
     global_pol: policies::GlobalPolicies, 
@@ -0,0 +1,760 @@
+//! Storage backends for the control plane's hosts, services and policies collections
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(err: mongodb::error::Error) -> Self {
+        Error(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error(err.to_string())
+    }
+}
+
+impl From<bson::ser::Error> for Error {
+    fn from(err: bson::ser::Error) -> Self {
+        Error(err.to_string())
+    }
+}
+
+impl From<bson::de::Error> for Error {
+    fn from(err: bson::de::Error) -> Self {
+        Error(err.to_string())
+    }
+}
+
+/// Collections of BSON documents.
+///
+/// Filters are MongoDB style equality filters: a document matches if, for every key of the
+/// filter, the value at that (dotted) path is equal to, or is an array containing, the value.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn find(&self, collection: &str, filter: Document) -> Result<Vec<Document>, Error>;
+    async fn find_one(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> Result<Option<Document>, Error> {
+        Ok(self.find(collection, filter).await?.into_iter().next())
+    }
+    /// At most `limit` documents, in order of their (integer) `field`, with `field` greater than `after`
+    async fn find_after(
+        &self,
+        collection: &str,
+        filter: Document,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<Document>, Error>;
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), Error>;
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64, Error>;
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64, Error>;
+    async fn drop_collection(&self, collection: &str) -> Result<(), Error>;
+    /// Remove all collections
+    async fn clear(&self) -> Result<(), Error>;
+}
+
+/// Open storage, with the backend selected by the URL scheme:
+/// `mongodb://` (MongoDB server), `file://<directory>` (embedded) or `memory://` (not persisted)
+pub async fn open(url: &str) -> Result<Arc<dyn Storage>, Error> {
+    if url.starts_with("mongodb://") || url.starts_with("mongodb+srv://") {
+        Ok(Arc::new(MongoStorage::new(url).await?))
+    } else if let Some(path) = url.strip_prefix("file://") {
+        let path = path.to_string();
+        let storage = actix_web::web::block(move || FileStorage::open(path))
+            .await
+            .map_err(|err| match err {
+                actix_web::error::BlockingError::Error(err) => err,
+                actix_web::error::BlockingError::Canceled => {
+                    Error("file storage open cancelled".to_string())
+                }
+            })?;
+        Ok(Arc::new(storage))
+    } else if url == "memory://" || url == "memory:" {
+        Ok(Arc::new(MemoryStorage::default()))
+    } else {
+        Err(Error(format!(
+            "unsupported storage URL (expecting mongodb://, file:// or memory://): {}",
+            url
+        )))
+    }
+}
+
+/// A named collection of a storage backend
+pub struct Collection {
+    storage: Arc<dyn Storage>,
+    name: String,
+}
+
+impl Collection {
+    pub fn new(storage: Arc<dyn Storage>, name: &str) -> Self {
+        Collection {
+            storage,
+            name: name.to_string(),
+        }
+    }
+    pub async fn find(&self, filter: impl Into<Option<Document>>) -> Result<Vec<Document>, Error> {
+        self.storage
+            .find(&self.name, filter.into().unwrap_or_default())
+            .await
+    }
+    pub async fn find_one(
+        &self,
+        filter: impl Into<Option<Document>>,
+    ) -> Result<Option<Document>, Error> {
+        self.storage
+            .find_one(&self.name, filter.into().unwrap_or_default())
+            .await
+    }
+    pub async fn find_after(
+        &self,
+        filter: impl Into<Option<Document>>,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<Document>, Error> {
+        self.storage
+            .find_after(
+                &self.name,
+                filter.into().unwrap_or_default(),
+                field,
+                after,
+                limit,
+            )
+            .await
+    }
+    pub async fn insert_one(&self, document: Document) -> Result<(), Error> {
+        self.storage.insert_one(&self.name, document).await
+    }
+    pub async fn delete_one(&self, filter: Document) -> Result<u64, Error> {
+        self.storage.delete_one(&self.name, filter).await
+    }
+    pub async fn delete_many(&self, filter: Document) -> Result<u64, Error> {
+        self.storage.delete_many(&self.name, filter).await
+    }
+    pub async fn drop(&self) -> Result<(), Error> {
+        self.storage.drop_collection(&self.name).await
+    }
+}
+
+pub struct MongoStorage(mongodb::Database);
+
+impl MongoStorage {
+    pub async fn new(url: &str) -> Result<Self, Error> {
+        let mut options = mongodb::options::ClientOptions::parse(url).await?;
+        options.app_name = Some("armour".to_string());
+        let client = mongodb::Client::with_options(options)?;
+        Ok(MongoStorage(client.database(super::rest_api::ARMOUR_DB)))
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    async fn find(&self, collection: &str, filter: Document) -> Result<Vec<Document>, Error> {
+        use futures::StreamExt;
+        let mut cursor = self.0.collection(collection).find(filter, None).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?)
+        }
+        Ok(documents)
+    }
+    async fn find_one(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> Result<Option<Document>, Error> {
+        Ok(self.0.collection(collection).find_one(filter, None).await?)
+    }
+    async fn find_after(
+        &self,
+        collection: &str,
+        mut filter: Document,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<Document>, Error> {
+        use futures::StreamExt;
+        let mut range = Document::new();
+        range.insert("$gt", after);
+        filter.insert(field, range);
+        let mut sort = Document::new();
+        sort.insert(field, 1);
+        let options = mongodb::options::FindOptions::builder()
+            .sort(sort)
+            .limit(limit as i64)
+            .build();
+        let mut cursor = self.0.collection(collection).find(filter, options).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?)
+        }
+        Ok(documents)
+    }
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), Error> {
+        self.0
+            .collection(collection)
+            .insert_one(document, None)
+            .await?;
+        Ok(())
+    }
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        let res = self
+            .0
+            .collection(collection)
+            .delete_one(filter, None)
+            .await?;
+        Ok(res.deleted_count as u64)
+    }
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        let res = self
+            .0
+            .collection(collection)
+            .delete_many(filter, None)
+            .await?;
+        Ok(res.deleted_count as u64)
+    }
+    async fn drop_collection(&self, collection: &str) -> Result<(), Error> {
+        Ok(self.0.collection(collection).drop(None).await?)
+    }
+    async fn clear(&self) -> Result<(), Error> {
+        Ok(self.0.drop(None).await?)
+    }
+}
+
+// value at a dotted path, e.g. "service_id.labels"
+fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut keys = path.split('.');
+    let mut value = document.get(keys.next()?)?;
+    for key in keys {
+        match value {
+            Bson::Document(document) => value = document.get(key)?,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+fn matches(document: &Document, filter: &Document) -> bool {
+    filter
+        .iter()
+        .all(|(path, expected)| match get_path(document, path) {
+            Some(Bson::Array(values)) if !matches!(expected, Bson::Array(_)) => {
+                values.contains(expected)
+            }
+            Some(value) => value == expected,
+            None => false,
+        })
+}
+
+/// Collections held in memory (for testing)
+#[derive(Default)]
+pub struct MemoryStorage(Mutex<BTreeMap<String, Vec<Document>>>);
+
+impl MemoryStorage {
+    fn find(&self, collection: &str, filter: &Document) -> Vec<Document> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(collection)
+            .map(|documents| {
+                documents
+                    .iter()
+                    .filter(|document| matches(document, filter))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    fn find_after(
+        &self,
+        collection: &str,
+        filter: &Document,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Vec<Document> {
+        let collections = self.0.lock().unwrap();
+        let mut documents: Vec<(i64, &Document)> = collections
+            .get(collection)
+            .map(|documents| {
+                documents
+                    .iter()
+                    .filter(|document| matches(document, filter))
+                    .filter_map(|document| match get_path(document, field) {
+                        Some(Bson::Int64(n)) if *n > after => Some((*n, document)),
+                        Some(Bson::Int32(n)) if i64::from(*n) > after => {
+                            Some((i64::from(*n), document))
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        documents.sort_by_key(|(n, _)| *n);
+        documents
+            .into_iter()
+            .take(limit)
+            .map(|(_, document)| document.clone())
+            .collect()
+    }
+    fn insert_one(&self, collection: &str, document: Document) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(collection.to_string())
+            .or_default()
+            .push(document)
+    }
+    fn delete(&self, collection: &str, filter: &Document, many: bool) -> u64 {
+        let mut collections = self.0.lock().unwrap();
+        let documents = match collections.get_mut(collection) {
+            Some(documents) => documents,
+            None => return 0,
+        };
+        let mut deleted = 0;
+        documents.retain(|document| {
+            if (many || deleted == 0) && matches(document, filter) {
+                deleted += 1;
+                false
+            } else {
+                true
+            }
+        });
+        deleted
+    }
+    fn drop_collection(&self, collection: &str) {
+        self.0.lock().unwrap().remove(collection);
+    }
+    fn clear(&self) {
+        self.0.lock().unwrap().clear()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn find(&self, collection: &str, filter: Document) -> Result<Vec<Document>, Error> {
+        Ok(MemoryStorage::find(self, collection, &filter))
+    }
+    async fn find_after(
+        &self,
+        collection: &str,
+        filter: Document,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<Document>, Error> {
+        Ok(MemoryStorage::find_after(
+            self, collection, &filter, field, after, limit,
+        ))
+    }
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), Error> {
+        MemoryStorage::insert_one(self, collection, document);
+        Ok(())
+    }
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        Ok(self.delete(collection, &filter, false))
+    }
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        Ok(self.delete(collection, &filter, true))
+    }
+    async fn drop_collection(&self, collection: &str) -> Result<(), Error> {
+        MemoryStorage::drop_collection(self, collection);
+        Ok(())
+    }
+    async fn clear(&self) -> Result<(), Error> {
+        MemoryStorage::clear(self);
+        Ok(())
+    }
+}
+
+/// Embedded storage: collections are held in memory and written to `<collection>.bson` files.
+/// Inserted documents are appended to the files, which are only rewritten after deletions.
+/// File operations run on the blocking thread pool.
+pub struct FileStorage(Arc<Files>);
+
+struct Files {
+    directory: PathBuf,
+    memory: MemoryStorage,
+    // held while collections are changed and written, so that files are written in order
+    writing: Mutex<()>,
+}
+
+impl FileStorage {
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self, Error> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        let memory = MemoryStorage::default();
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension() == Some(std::ffi::OsStr::new("bson")) {
+                if let Some(collection) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    for document in FileStorage::read(&path)? {
+                        memory.insert_one(collection, document)
+                    }
+                }
+            }
+        }
+        Ok(FileStorage(Arc::new(Files {
+            directory,
+            memory,
+            writing: Mutex::new(()),
+        })))
+    }
+    // documents of a collection file, dropping a final document whose append was interrupted
+    fn read(path: &Path) -> Result<Vec<Document>, Error> {
+        let bytes = std::fs::read(path)?;
+        let mut documents = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            // a BSON document starts with its (little-endian, i32) length, which includes itself
+            let length = bytes
+                .get(offset..offset + 4)
+                .map(|length| i32::from_le_bytes([length[0], length[1], length[2], length[3]]));
+            let end = match length {
+                Some(length) if length < 5 => {
+                    return Err(Error(format!(
+                        "{}: invalid document length {} at offset {}",
+                        path.display(),
+                        length,
+                        offset
+                    )))
+                }
+                Some(length) if offset + length as usize <= bytes.len() => offset + length as usize,
+                _ => {
+                    log::warn!(
+                        "{}: ignoring truncated document at offset {}",
+                        path.display(),
+                        offset
+                    );
+                    // so that later appends follow the last complete document
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_len(offset as u64)?;
+                    break;
+                }
+            };
+            documents.push(Document::from_reader(&mut &bytes[offset..end])?);
+            offset = end
+        }
+        Ok(documents)
+    }
+    // run a file operation on the blocking thread pool
+    async fn blocking<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Files) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let files = self.0.clone();
+        actix_web::web::block(move || f(&files))
+            .await
+            .map_err(|err| match err {
+                actix_web::error::BlockingError::Error(err) => err,
+                actix_web::error::BlockingError::Canceled => {
+                    Error("file operation cancelled".to_string())
+                }
+            })
+    }
+}
+
+impl Files {
+    fn path(&self, collection: &str) -> PathBuf {
+        self.directory.join(collection).with_extension("bson")
+    }
+    fn append(&self, collection: &str, bytes: &[u8]) -> Result<(), Error> {
+        use std::io::Write;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(collection))?
+            .write_all(bytes)?;
+        Ok(())
+    }
+    // write a collection, replacing the file atomically (called with the `writing` lock held)
+    fn save(&self, collection: &str) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        for document in self.memory.find(collection, &Document::new()) {
+            document.to_writer(&mut bytes)?
+        }
+        let path = self.path(collection);
+        let tmp = path.with_extension("bson.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+    fn remove(&self, collection: &str) -> Result<(), Error> {
+        match std::fs::remove_file(self.path(collection)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+    fn insert_one(&self, collection: &str, document: Document) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        document.to_writer(&mut bytes)?;
+        let _writing = self.writing.lock().unwrap();
+        // the document is only visible once it has been written
+        self.append(collection, &bytes)?;
+        self.memory.insert_one(collection, document);
+        Ok(())
+    }
+    fn delete(&self, collection: &str, filter: &Document, many: bool) -> Result<u64, Error> {
+        let _writing = self.writing.lock().unwrap();
+        let deleted = self.memory.delete(collection, filter, many);
+        if deleted > 0 {
+            self.save(collection)?
+        }
+        Ok(deleted)
+    }
+    fn drop_collection(&self, collection: &str) -> Result<(), Error> {
+        let _writing = self.writing.lock().unwrap();
+        self.memory.drop_collection(collection);
+        self.remove(collection)
+    }
+    fn clear(&self) -> Result<(), Error> {
+        let _writing = self.writing.lock().unwrap();
+        let collections: Vec<String> = self.memory.0.lock().unwrap().keys().cloned().collect();
+        self.memory.clear();
+        for collection in collections {
+            self.remove(&collection)?
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn find(&self, collection: &str, filter: Document) -> Result<Vec<Document>, Error> {
+        Ok(self.0.memory.find(collection, &filter))
+    }
+    async fn find_after(
+        &self,
+        collection: &str,
+        filter: Document,
+        field: &str,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<Document>, Error> {
+        Ok(self
+            .0
+            .memory
+            .find_after(collection, &filter, field, after, limit))
+    }
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), Error> {
+        let collection = collection.to_string();
+        self.blocking(move |files| files.insert_one(&collection, document))
+            .await
+    }
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        let collection = collection.to_string();
+        self.blocking(move |files| files.delete(&collection, &filter, false))
+            .await
+    }
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64, Error> {
+        let collection = collection.to_string();
+        self.blocking(move |files| files.delete(&collection, &filter, true))
+            .await
+    }
+    async fn drop_collection(&self, collection: &str) -> Result<(), Error> {
+        let collection = collection.to_string();
+        self.blocking(move |files| files.drop_collection(&collection))
+            .await
+    }
+    async fn clear(&self) -> Result<(), Error> {
+        self.blocking(Files::clear).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn matching() {
+        let document = doc! {
+            "label": "Host::one",
+            "port": 80,
+            "service_id": { "labels": ["a", "b"], "name": "server" },
+        };
+        assert!(matches(&document, &doc! {}));
+        assert!(matches(&document, &doc! { "label": "Host::one" }));
+        assert!(matches(
+            &document,
+            &doc! { "label": "Host::one", "port": 80 }
+        ));
+        assert!(!matches(
+            &document,
+            &doc! { "label": "Host::one", "port": 81 }
+        ));
+        assert!(!matches(&document, &doc! { "label": "Host::two" }));
+        assert!(!matches(&document, &doc! { "missing": "Host::one" }));
+        // dotted paths
+        assert!(matches(&document, &doc! { "service_id.name": "server" }));
+        assert!(!matches(&document, &doc! { "service_id.other": "server" }));
+        assert!(!matches(&document, &doc! { "label.name": "server" }));
+        // arrays contain values, or are equal to arrays
+        assert!(matches(&document, &doc! { "service_id.labels": "b" }));
+        assert!(!matches(&document, &doc! { "service_id.labels": "c" }));
+        assert!(matches(
+            &document,
+            &doc! { "service_id.labels": ["a", "b"] }
+        ));
+        assert!(!matches(&document, &doc! { "service_id.labels": ["a"] }));
+        // sub-documents are compared as a whole
+        assert!(matches(
+            &document,
+            &doc! { "service_id": { "labels": ["a", "b"], "name": "server" } }
+        ));
+        assert!(!matches(
+            &document,
+            &doc! { "service_id": { "name": "server" } }
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn files() {
+        let directory =
+            std::env::temp_dir().join(format!("armour-control-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = Arc::new(FileStorage::open(&directory).unwrap());
+        let inserts: Vec<_> = (0..20)
+            .map(|i| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    futures::executor::block_on(
+                        storage.insert_one("col", doc! { "i": i, "even": i % 2 == 0 }),
+                    )
+                })
+            })
+            .collect();
+        for insert in inserts {
+            insert.join().unwrap().unwrap()
+        }
+        assert_eq!(
+            storage
+                .delete_many("col", doc! { "even": true })
+                .await
+                .unwrap(),
+            10
+        );
+        storage.insert_one("col", doc! { "i": 20 }).await.unwrap();
+        storage.insert_one("other", doc! { "i": 0 }).await.unwrap();
+        storage.drop_collection("other").await.unwrap();
+        let reopened = FileStorage::open(&directory).unwrap();
+        let mut found: Vec<i32> = reopened
+            .find("col", Document::new())
+            .await
+            .unwrap()
+            .iter()
+            .map(|document| document.get_i32("i").unwrap())
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![1, 3, 5, 7, 9, 11, 13, 15, 17, 19, 20]);
+        assert!(reopened
+            .find("other", Document::new())
+            .await
+            .unwrap()
+            .is_empty());
+        // an interrupted append is ignored
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(directory.join("col.bson"))
+            .and_then(|mut file| std::io::Write::write_all(&mut file, &[42, 0, 0]))
+            .unwrap();
+        assert_eq!(
+            FileStorage::open(&directory)
+                .unwrap()
+                .0
+                .memory
+                .find("col", &Document::new())
+                .len(),
+            11
+        );
+        std::fs::remove_dir_all(&directory).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn truncated() {
+        let directory =
+            std::env::temp_dir().join(format!("armour-control-truncated-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = FileStorage::open(&directory).unwrap();
+        for i in 0..3 {
+            storage
+                .insert_one("col", doc! { "i": i, "name": "document" })
+                .await
+                .unwrap()
+        }
+        // interrupt the append of the last document, part way through its contents
+        let path = directory.join("col.bson");
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(len - 5))
+            .unwrap();
+        let reopened = FileStorage::open(&directory).unwrap();
+        assert_eq!(
+            reopened.find("col", Document::new()).await.unwrap().len(),
+            2
+        );
+        // the partial document is removed, so later appends can be read
+        reopened.insert_one("col", doc! { "i": 3 }).await.unwrap();
+        let found: Vec<i32> = FileStorage::open(&directory)
+            .unwrap()
+            .find("col", Document::new())
+            .await
+            .unwrap()
+            .iter()
+            .map(|document| document.get_i32("i").unwrap())
+            .collect();
+        assert_eq!(found, vec![0, 1, 3]);
+        // a corrupt length is an error
+        std::fs::write(&path, [1, 0, 0, 0]).unwrap();
+        assert!(FileStorage::open(&directory).is_err());
+        std::fs::remove_dir_all(&directory).unwrap()
+    }
+}
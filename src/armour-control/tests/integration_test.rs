@@ -39,7 +39,6 @@ use armour_lang::literals::{self, *};
 use armour_lang::policies::{self, *};
 
 use bson::doc;
-use mongodb::{options::ClientOptions, Client};
 
 use std::path::{PathBuf};
 use std::sync::Arc;
@@ -63,23 +62,17 @@ fn get_policies_path(name: &str) -> PathBuf{
 }
 
 async fn mock_state () -> Result<State, Error> {
-    let mongo_url = "mongodb://localhost:27017" ;
-
-    // connect to MongoDB
-    let mut db_endpoint = ClientOptions::parse(mongo_url).await.map_err(|e| {
-        log::warn!("failed to get db_endpoint");
-        e
-    })?;
-    db_endpoint.app_name = Some("armour".to_string());
-    let db_con = Client::with_options(db_endpoint.clone()).map_err(|e| {
-        log::info!("Failed to connect to Mongo. Start MongoDB");
-        e
-    })?;
-
+    // each test has its own (in-memory) storage
+    let storage = armour_control::storage::open("memory://").await?;
 
     Ok(web::Data::new(ControlPlaneState {
-        db_endpoint,
-        db_con,
+        storage,
+        audit: Default::default(),
+        events: Default::default(),
+        verifiers: Default::default(),
+        liveness: Default::default(),
+        label_caches: Default::default(),
+        roles: Default::default(),
     }))
 }
 
@@ -91,7 +84,8 @@ async fn register_policy(
         label: global_policy_label(),
         policy: policies::Policies::from_file(raw_pol)?,
         labels: LabelMap::default(),
-        selector: None 
+        selector: None,
+        comment: None,
     };
     let label = &request.label.clone();
     //println!(r#"updating policy for label "{}""#, label);
@@ -101,15 +95,15 @@ async fn register_policy(
         // update policy in database
         let col = collection(&state, POLICIES_COL);
         let filter = doc! { "label" : to_bson(label).map_err(|x|expressions::Error::from(format!("{:?}", x)))? };
-        col.delete_many(filter, None)
+        col.delete_many(filter)
             .await
             .map_err(|_| expressions::Error::from(format!("error removing old policies")))?;
-        col.insert_one(document, None)
+        col.insert_one(document)
             .await
             .map_err(|_| expressions::Error::from(format!("error inserting new policy")))?;
         Ok(true)
     } else {
-        println!("error converting the BSON object into a MongoDB document");
+        println!("error converting the BSON object into a document");
         Ok(false)
     }
 }
@@ -122,7 +116,8 @@ async fn register_onboarding_policy(
     let request = OnboardingUpdateRequest {
         label: onboarding_policy_label(),
         policy: policies::OnboardingPolicy::from_file(raw_pol)?,
-        labels: LabelMap::default()
+        labels: LabelMap::default(),
+        comment: None,
     }.pack();
     let label = &request.label.clone();
     //println!(r#"updating policy for label "{}""#, label);
@@ -131,15 +126,15 @@ async fn register_onboarding_policy(
         // update policy in database
         let col = collection(&state, POLICIES_COL);
         let filter = doc! { "label" : to_bson(label).map_err(|x|expressions::Error::from(format!("{:?}", x)))? };
-        col.delete_many(filter, None)
+        col.delete_many(filter)
             .await
             .map_err(|_| expressions::Error::from(format!("error removing old policies")))?;
-        col.insert_one(document, None)
+        col.insert_one(document)
             .await
             .map_err(|_| expressions::Error::from(format!("error inserting new policy")))?;
         Ok(true)
     } else {
-        println!("error converting the BSON object into a MongoDB document");
+        println!("error converting the BSON object into a document");
         Ok(false)
     }
 }
@@ -305,11 +300,11 @@ mod tests_control {
     #[actix_rt::test]
     async fn test_helper_compile_ingress() -> Result<(),  expressions::Error> {
         let state = mock_state().await.map_err(|x|expressions::Error::from(format!("{:?}", x)))?;
-        state.db_con.database("armour").drop(None).await.map_err(|x|expressions::Error::from(format!("{:?}", x)))?;
+        state.storage.clear().await.map_err(|x|expressions::Error::from(format!("{:?}", x)))?;
         register_policy(&state, get_policies_path("global1.policy").to_str().unwrap()).await?;
         
         if let Ok(Some(doc)) = collection(&state.clone(), POLICIES_COL)
-            .find_one(Some(doc! {"label" : to_bson(&global_policy_label()).unwrap()}), None)
+            .find_one(Some(doc! {"label" : to_bson(&global_policy_label()).unwrap()}))
             .await
         {
             assert!(true)
@@ -344,11 +339,11 @@ mod tests_control {
     #[actix_rt::test]
     async fn test_seval_onboarding() -> Result<(),  expressions::Error> {
         let state = mock_state().await.map_err(|x|expressions::Error::from(format!("{:?}", x)))?;
-        state.db_con.database("armour").drop(None).await.unwrap();
+        state.storage.clear().await.unwrap();
         register_policy(&state, get_policies_path("global1.policy").to_str().unwrap()).await?;
 
         if let Ok(Some(doc)) = collection(&state.clone(), POLICIES_COL)
-            .find_one(Some(doc! {"label" : to_bson(&global_policy_label()).unwrap()}), None)
+            .find_one(Some(doc! {"label" : to_bson(&global_policy_label()).unwrap()}))
             .await
         {
             assert!(true)
@@ -371,7 +366,7 @@ mod tests_control {
     
     async fn aux_test_onboard(global_file: &str, onboard_file: &str, service: &str, host: &str) -> Result<DPPolicies,  actix_web::Error> {
         let state = mock_state().await.unwrap();
-        state.db_con.database("armour").drop(None).await.unwrap();
+        state.storage.clear().await.unwrap();
         register_policy(&state, get_policies_path(global_file).to_str().unwrap()).await.unwrap();
         register_onboarding_policy(&state, get_policies_path(onboard_file).to_str().unwrap()).await.unwrap();
 
@@ -387,7 +382,7 @@ mod tests_control {
             )),
         };
 
-        Ok(match service::helper_on_board(&state, request).await? {
+        Ok(match service::helper_on_board(&state, request, literals::Credentials::verified(None)).await? {
             Ok((_, ingress_req, egress_req)) =>{
                 ingress_req.policy.merge(&egress_req.policy)
             },
@@ -410,7 +405,7 @@ mod tests_control {
     #[actix_rt::test]
     async fn test_eval_specialize() -> Result<(),  actix_web::Error> {
         let state = mock_state().await.unwrap();
-        state.db_con.database("armour").drop(None).await.unwrap();
+        state.storage.clear().await.unwrap();
         register_policy(&state, get_policies_path("global-id.policy").to_str().unwrap()).await.unwrap();
         register_onboarding_policy(&state, get_policies_path("onboard1.policy").to_str().unwrap()).await.unwrap();
 
@@ -426,7 +421,7 @@ mod tests_control {
             ))
         };
 
-        Ok(match service::helper_on_board(&state, request).await? {
+        Ok(match service::helper_on_board(&state, request, literals::Credentials::verified(None)).await? {
             Ok((service_id, ingress_req, egress_req)) =>{
                 //println!("Updating policy for label {}\n{:#?}", ingress_req.label, ingress_req.policy);
                 let (from, to) = get_from_to().unwrap();
@@ -448,6 +443,28 @@ mod tests_control {
         })
     }
 
+    #[actix_rt::test]
+    async fn test_specialize_payload() -> Result<(),  actix_web::Error> {
+        let state = Arc::new(mock_state().await.unwrap());
+        let global = policies::GlobalPolicies::from_buf(r#"
+            fn allow_tcp_connection(from: ID, to: ID, c: Connection) -> bool { true }
+            fn allow_tcp_payload(from: ID, to: ID, c: Connection, payload: data) -> bool { true }
+        "#).unwrap();
+        assert!(unspecialized(&global).is_empty());
+        let id = literals::ID::new(BTreeSet::new(), BTreeSet::new(), Some(80), BTreeSet::new());
+        let local = compile_egress(state, global, policies::ALLOW_TCP_PAYLOAD, &id).await.unwrap();
+        let tcp = local.policy(policies::Protocol::TCP).unwrap();
+        assert_eq!(tcp.get(policies::ALLOW_TCP_PAYLOAD), Some(&FnPolicy::Args(2)));
+
+        // functions without IDs cannot be specialized for services
+        let global = policies::GlobalPolicies::from_buf(r#"
+            fn allow_rest_request(from: ID, to: ID, req: HttpRequest, payload: data) -> bool { true }
+            fn on_http_complete(req: HttpRequest, res: HttpResponse) {}
+        "#).unwrap();
+        assert_eq!(unspecialized(&global), vec![policies::ON_HTTP_COMPLETE.to_string()]);
+        Ok(())
+    }
+
     //FIXME De Bruijn indices not tested
     //TODO write some helper fct to test only expr simplification
 
@@ -634,4 +651,329 @@ mod tests_control {
             "true"
         );
     }
-}
\ No newline at end of file
+}
+mod tests_storage {
+    use super::*;
+    use armour_control::storage;
+
+    fn service(name: &str, host: &str) -> POnboardServiceRequest {
+        let mut service_id = Label::from_str(name).unwrap();
+        service_id.prefix("ServiceID".to_string());
+        let id = CPID::default()
+            .add_label(&service_id)
+            .add_label(&Label::from_str("Tier::backend").unwrap());
+        POnboardServiceRequest {
+            service: service_id,
+            service_id: id,
+            host: Label::from_str(host).unwrap(),
+        }
+    }
+
+    fn document<T: serde::Serialize>(value: &T) -> bson::Document {
+        match bson::to_bson(value).unwrap() {
+            bson::Bson::Document(document) => document,
+            _ => panic!("not a document"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_storage_filters() -> Result<(), Error> {
+        let storage = storage::open("memory://").await?;
+        let col = storage::Collection::new(storage, SERVICES_COL);
+        col.insert_one(document(&service("s1", "Host::h1"))).await?;
+        col.insert_one(document(&service("s2", "Host::h1"))).await?;
+        col.insert_one(document(&service("s3", "Host::h2"))).await?;
+        assert_eq!(col.find(doc! {}).await?.len(), 3);
+        assert_eq!(col.find(doc! { "host": "Host::h1" }).await?.len(), 2);
+        // dotted paths, and arrays that contain the value
+        let found = col
+            .find_one(doc! { "service_id.labels": "ServiceID::s3" })
+            .await?
+            .expect("service s3");
+        assert_eq!(found.get_str("host")?, "Host::h2");
+        assert_eq!(col.find(doc! { "service_id.labels": "Tier::backend" }).await?.len(), 3);
+        assert!(col.find_one(doc! { "service_id.labels": "Tier::frontend" }).await?.is_none());
+        assert_eq!(col.delete_one(doc! { "host": "Host::h1" }).await?, 1);
+        assert_eq!(col.delete_many(doc! { "service_id.labels": "Tier::backend" }).await?, 2);
+        assert!(col.find(None).await?.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_file_storage() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("armour-storage-{}", std::process::id()));
+        let url = format!("file://{}", dir.display());
+        {
+            let storage = storage::open(&url).await?;
+            let col = storage::Collection::new(storage, SERVICES_COL);
+            col.insert_one(document(&service("s1", "Host::h1"))).await?;
+            col.insert_one(document(&service("s2", "Host::h2"))).await?;
+            col.delete_one(doc! { "host": "Host::h2" }).await?;
+        }
+        // re-open
+        let storage = storage::open(&url).await?;
+        let col = storage::Collection::new(storage.clone(), SERVICES_COL);
+        let services = col.find(None).await?;
+        assert_eq!(services.len(), 1);
+        let service = bson::from_bson::<POnboardServiceRequest>(bson::Bson::Document(services[0].clone()))?;
+        assert_eq!(service.service, Label::from_str("ServiceID::s1")?);
+        storage.clear().await?;
+        assert!(storage::open(&url).await?.find(SERVICES_COL, doc! {}).await?.is_empty());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_storage_url() {
+        assert!(storage::open("memory://").await.is_ok());
+        assert!(storage::open("redis://localhost").await.is_err());
+    }
+}
+
+mod tests_history {
+    use super::*;
+    use armour_control::identity::Identity;
+
+    fn update(label: &Label, policy: DPPolicies, comment: &str) -> PolicyUpdateRequest {
+        PolicyUpdateRequest {
+            label: label.clone(),
+            policy,
+            labels: LabelMap::default(),
+            comment: Some(comment.to_string()),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_policy_history() -> Result<(), actix_web::Error> {
+        let state = mock_state().await.unwrap();
+        let label = Label::from_str("ServiceID::server").unwrap();
+        let author = Identity::new("armour-ctl");
+        policy::save_policy(state.clone(), &author, update(&label, DPPolicies::allow_all(), "open")).await?;
+        policy::save_policy(state.clone(), &author, update(&label, DPPolicies::deny_all(), "close")).await?;
+
+        // every update is a revision, but only the latest is current
+        let revisions = policy::revisions(&state, &label).await?;
+        assert_eq!(revisions.iter().map(|r| r.revision).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(revisions[0].author, "armour-ctl");
+        assert_eq!(revisions[0].hash, DPPolicies::allow_all().blake3());
+        assert_eq!(revisions[1].comment, Some("close".to_string()));
+        assert_eq!(collection(&state, POLICIES_COL).find(None).await.unwrap().len(), 1);
+
+        match policy::revision(&state, &label, 1).await?.expect("revision 1").policy {
+            RevisionPolicy::Local(request) => assert!(request.policy.is_allow_all()),
+            RevisionPolicy::Global(_) => panic!("expecting a service policy"),
+        }
+        assert!(policy::revision(&state, &label, 3).await?.is_none());
+        assert!(policy::revisions(&state, &global_policy_label()).await?.is_empty());
+        Ok(())
+    }
+}
+
+mod tests_rbac {
+    use super::*;
+    use armour_control::identity::Identity;
+    use armour_control::rbac::{Access, Role, Roles};
+
+    #[test]
+    fn test_roles() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("armour-roles-{}.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            "armour-ctl: [admin]\ndashboard: [viewer]\nalice: [\"service-owner:ServiceID::teamA::**\"]\n",
+        )?;
+        let roles = Roles::from_file(&path)?;
+        std::fs::remove_file(&path)?;
+        let service = Label::from_str("ServiceID::teamA::server")?;
+        let other = Label::from_str("ServiceID::teamB::server")?;
+
+        assert!(roles.authorize(&Identity::new("armour-ctl"), Access::Admin).is_ok());
+        let viewer = Identity::new("dashboard");
+        assert!(roles.authorize(&viewer, Access::Read).is_ok());
+        assert!(roles.authorize(&viewer, Access::Policy(&service)).is_err());
+        // service owners are limited to their selector, and cannot change the global policy
+        let owner = Identity::new("alice");
+        assert!(roles.authorize(&owner, Access::Policy(&service)).is_ok());
+        assert!(roles.authorize(&owner, Access::Policy(&other)).is_err());
+        assert!(roles.authorize(&owner, Access::Policy(&global_policy_label())).is_err());
+        assert!(roles.authorize(&owner, Access::Host).is_err());
+        // and may only drop the services they own
+        assert!(roles.authorize(&owner, Access::Service(&service)).is_ok());
+        assert!(roles.authorize(&owner, Access::Service(&other)).is_err());
+        assert!(roles.authorize(&viewer, Access::Service(&service)).is_err());
+        // roles carried by the certificate
+        let host = Identity {
+            common_name: Some("armour-host".to_string()),
+            alt_names: vec!["localhost".to_string(), "armour-role:host".to_string()],
+            fingerprint: None,
+        };
+        assert_eq!(roles.roles(&host), vec![Role::Host]);
+        assert!(roles.authorize(&host, Access::Host).is_ok());
+        assert!(roles.authorize(&host, Access::Service(&other)).is_ok());
+        assert!(!roles.owns(&host, &other));
+        assert!(roles
+            .authorize(&Identity::default(), Access::Read)
+            .unwrap_err()
+            .contains("roles: none"));
+        // without a roles file, access control is disabled
+        assert!(Roles::default().authorize(&Identity::default(), Access::Admin).is_ok());
+        assert!("owner".parse::<Role>().is_err());
+        Ok(())
+    }
+}
+
+mod tests_audit {
+    use super::*;
+    use armour_control::audit::AuditLog;
+
+    fn target(label: &str, before: Option<&str>, after: Option<&str>) -> AuditTarget {
+        AuditTarget {
+            label: Label::from_str(label).unwrap(),
+            before: before.map(|s| s.to_string()),
+            after: after.map(|s| s.to_string()),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log() -> Result<(), Error> {
+        let state = mock_state().await?;
+        let storage = &state.storage;
+        state.audit.append(storage, "host/on-board", "armour-host", vec![target("Host::h1", None, None)], "200 OK".to_string()).await?;
+        state.audit.append(storage, "policy/update", "armour-ctl", vec![target("ServiceID::s1", None, Some("abc"))], "200 OK".to_string()).await?;
+        state.audit.append(storage, "policy/drop-all", "alice", vec![target("ServiceID::s1", Some("abc"), Some("abc"))], "403 Forbidden".to_string()).await?;
+
+        let records = AuditLog::records(storage).await?;
+        assert_eq!(records.iter().map(|r| r.sequence).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(records[1].previous, records[0].hash);
+        assert_eq!(AuditRecord::verify_chain(&records, None), Ok(3));
+
+        // query by label and time
+        let query = AuditQuery {
+            label: Some(Label::from_str("ServiceID::**")?),
+            ..Default::default()
+        };
+        assert_eq!(records.iter().filter(|r| query.matches(r)).count(), 2);
+        let query = AuditQuery {
+            to: Some(records[0].time),
+            ..Default::default()
+        };
+        assert_eq!(records.iter().filter(|r| query.matches(r)).count(), 1);
+
+        // the log continues after a restart
+        AuditLog::default().append(storage, "host/drop", "armour-host", Vec::new(), "200 OK".to_string()).await?;
+        let records = AuditLog::records(storage).await?;
+        assert_eq!(AuditRecord::verify_chain(&records, None), Ok(4));
+
+        // tampering is detected
+        let mut tampered = records.clone();
+        tampered[2].identity = "armour-ctl".to_string();
+        assert!(AuditRecord::verify_chain(&tampered, None).is_err());
+        let mut tampered = records.clone();
+        tampered.remove(1);
+        assert!(AuditRecord::verify_chain(&tampered, None).is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log_key() -> Result<(), Error> {
+        let state = mock_state().await?;
+        let storage = &state.storage;
+        let key = audit_key(b"secret");
+        let audit = AuditLog::new(Some(key));
+        for i in 0..3 {
+            audit.append(storage, "policy/update", "armour-ctl", Vec::new(), format!("{}", i)).await?;
+        }
+        let records = AuditLog::records(storage).await?;
+        assert_eq!(AuditRecord::verify_chain(&records, Some(&key)), Ok(3));
+        assert!(AuditRecord::verify_chain(&records, None).is_err());
+        assert!(AuditRecord::verify_chain(&records, Some(&audit_key(b"guess"))).is_err());
+        // the chain cannot be rebuilt without the key
+        let mut rebuilt = records.clone();
+        rebuilt[1].identity = "alice".to_string();
+        rebuilt[1].hash = rebuilt[1].blake3(None);
+        rebuilt[2].previous = rebuilt[1].hash.clone();
+        rebuilt[2].hash = rebuilt[2].blake3(None);
+        assert!(AuditRecord::verify_chain(&rebuilt, Some(&key)).is_err());
+        // the log is verified at startup
+        assert_eq!(AuditLog::new(Some(key)).verify(storage).await, Ok(3));
+        let unkeyed = AuditLog::default();
+        assert!(unkeyed.verify(storage).await.is_err());
+        assert!(unkeyed.broken().is_some());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log_pages() -> Result<(), Error> {
+        let state = mock_state().await?;
+        let storage = &state.storage;
+        let n = AUDIT_PAGE_LIMIT as i64 + 5;
+        for i in 0..n {
+            state.audit.append(storage, "policy/update", "armour-ctl", Vec::new(), format!("{}", i)).await?;
+        }
+        let page = AuditLog::page(storage, 0, 10).await?;
+        assert_eq!(page.iter().map(|r| r.sequence).collect::<Vec<_>>(), (1..=10).collect::<Vec<_>>());
+        let page = AuditLog::page(storage, n - 2, AUDIT_PAGE_LIMIT).await?;
+        assert_eq!(page.iter().map(|r| r.sequence).collect::<Vec<_>>(), vec![n - 1, n]);
+        assert!(AuditLog::page(storage, n, 10).await?.is_empty());
+        // the head of the log is found a page at a time
+        assert_eq!(AuditLog::default().verify(storage).await, Ok(n as usize));
+        let record = AuditLog::default().append(storage, "host/drop", "armour-host", Vec::new(), "200 OK".to_string()).await?;
+        assert_eq!(record.sequence, n + 1);
+        assert_eq!(AuditPage::limit(None), AUDIT_PAGE_LIMIT);
+        assert_eq!(AuditPage::limit(Some(0)), 1);
+        assert_eq!(AuditPage::limit(Some(AUDIT_PAGE_LIMIT + 1)), AUDIT_PAGE_LIMIT);
+        Ok(())
+    }
+}
+
+mod tests_events {
+    use super::*;
+    use armour_control::identity::Identity;
+
+    #[actix_rt::test]
+    async fn test_events() -> Result<(), actix_web::Error> {
+        let state = mock_state().await.unwrap();
+        let label = Label::from_str("ServiceID::server").unwrap();
+        let author = Identity::new("armour-ctl");
+        let (past, mut new) = state.events.subscribe(None);
+        assert!(past.is_empty());
+        for policy in [DPPolicies::allow_all(), DPPolicies::deny_all()] {
+            policy::save_policy(state.clone(), &author, PolicyUpdateRequest {
+                label: label.clone(),
+                policy,
+                labels: LabelMap::default(),
+                comment: None,
+            }).await?;
+        }
+
+        let record = new.try_next().unwrap().expect("event");
+        assert_eq!(record.sequence, 1);
+        match &record.event {
+            ControlEvent::PolicyUpdated { label: updated, revision, hash } => {
+                assert_eq!(updated, &label);
+                assert_eq!(*revision, 1);
+                assert_eq!(*hash, DPPolicies::allow_all().blake3())
+            }
+            event => panic!("unexpected event: {}", event),
+        }
+        assert_eq!(new.try_next().unwrap().expect("event").sequence, 2);
+
+        // events are typed JSON objects
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["type"], "policy-updated");
+        assert_eq!(json["sequence"], 1);
+        let record: EventRecord = serde_json::from_value(json).unwrap();
+        assert_eq!(record.sequence, 1);
+
+        // resume after the last event received
+        let (past, _) = state.events.subscribe(Some(1));
+        assert_eq!(past.iter().map(|r| r.sequence).collect::<Vec<_>>(), vec![2]);
+        // the control plane has restarted since
+        let (past, _) = state.events.subscribe(Some(100));
+        assert_eq!(past.iter().map(|r| r.sequence).collect::<Vec<_>>(), vec![0, 1, 2]);
+        match &past[0].event {
+            ControlEvent::EventsMissed { after } => assert_eq!(*after, 100),
+            event => panic!("unexpected event: {}", event),
+        }
+        Ok(())
+    }
+}
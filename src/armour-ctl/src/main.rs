@@ -28,11 +28,19 @@ use armour_lang::literals::CPID;
 use armour_lang::policies;
 use armour_utils::parse_https_url;
 use clap::{crate_version, App};
+use futures::StreamExt;
 use std::collections::{BTreeSet};
 use std::str::FromStr;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
+// maximum size of a downloaded page of the audit log
+const AUDIT_LOG_LIMIT: usize = 1 << 28;
+// for connecting to the event stream
+const WATCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// delay before reconnecting to the event stream
+const WATCH_RETRY: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[actix_rt::main]
 async fn main() -> Result<(), Error> {
     let yaml = clap::load_yaml!("../resources/cli.yml");
@@ -75,6 +83,7 @@ async fn main() -> Result<(), Error> {
                 label: service.parse().unwrap(),
                 policy,
                 labels,
+                comment: comment(update_matches),
             };
             client
             .post(url("policy/update"))
@@ -107,6 +116,7 @@ async fn main() -> Result<(), Error> {
                 policy,
                 labels,
                 selector,
+                comment: comment(update_matches),
             };
             client
             .post(url("policy/update-global"))
@@ -131,6 +141,7 @@ async fn main() -> Result<(), Error> {
                 label: control::onboarding_policy_label(),
                 policy,
                 labels,
+                comment: comment(update_matches),
             };
             client
             .post(url("policy/update-onboarding"))
@@ -144,6 +155,39 @@ async fn main() -> Result<(), Error> {
         }
     }
     // Request to query a policy
+    else if let Some(selector) = matches
+        .subcommand_matches("query")
+        .and_then(|query_matches| query_matches.value_of("SELECTOR"))
+    {
+        let query_payload = control::ServicesQueryRequest {
+            selector: selector.parse()?,
+        };
+
+        match client
+            .get(url("policy/query-services"))
+            .send_json(&query_payload)
+            .await
+        {
+            Ok(mut response) => {
+                let body = response.body().await.map_err(|_| "Payload error")?;
+                if response.status().is_success() {
+                    let reqs: Vec<armour_api::control::PolicyUpdateRequest> =
+                        serde_json::from_slice(body.as_ref())?;
+                    if reqs.is_empty() {
+                        println!("no service policies for {}", selector)
+                    }
+                    for req in reqs {
+                        println!("=== {} ===", req.label);
+                        println!("{}", req.policy);
+                        println!("labels: {:?}", req.labels)
+                    }
+                } else {
+                    println!("{}", string_from_bytes(body))
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+    }
     else if let Some(query_matches) = matches.subcommand_matches("query") {
         let service = query_matches.value_of("SERVICE").unwrap();
         let query_payload = control::PolicyQueryRequest {
@@ -217,6 +261,215 @@ async fn main() -> Result<(), Error> {
             Err(err) => println!("{}", err),
         }
     }
+    // policy history
+    else if let Some(history_matches) = matches.subcommand_matches("history") {
+        let history_payload = control::PolicyQueryRequest {
+            label: policy_label(history_matches)?,
+        };
+
+        match client
+            .get(url("policy/history"))
+            .send_json(&history_payload)
+            .await
+        {
+            Ok(mut response) => {
+                let body = response.body().await.map_err(|_| "Payload error")?;
+                if response.status().is_success() {
+                    let revisions: Vec<control::PolicyRevision> =
+                        serde_json::from_slice(body.as_ref())?;
+                    if revisions.is_empty() {
+                        println!("<none>")
+                    }
+                    for revision in revisions {
+                        println!("{}", revision)
+                    }
+                } else {
+                    println!("{}", string_from_bytes(body))
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+    }
+    else if let Some(rollback_matches) = matches.subcommand_matches("rollback") {
+        let rollback_payload = control::PolicyRollbackRequest {
+            label: policy_label(rollback_matches)?,
+            revision: rollback_matches.value_of("REVISION").unwrap().parse()?,
+            comment: comment(rollback_matches),
+        };
+
+        match client
+            .post(url("policy/rollback"))
+            .send_json(&rollback_payload)
+            .await
+        {
+            Ok(mut response) => {
+                if response.status().is_success() {
+                    println!("success: true")
+                } else {
+                    let body = response.body().await.map_err(|_| "Payload error")?;
+                    println!("{}", string_from_bytes(body))
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+    }
+    // audit log
+    else if let Some(audit_matches) = matches.subcommand_matches("audit") {
+        if audit_matches.is_present("VERIFY") {
+            let key = match audit_matches.value_of("KEY") {
+                Some(key) => Some(control::audit_key(&std::fs::read(key)?)),
+                None => None,
+            };
+            // check the hash chain of the complete log, a page at a time
+            let mut last: Option<control::AuditRecord> = None;
+            let mut count = 0;
+            let result = 'pages: loop {
+                let after = last.as_ref().map(|record| record.sequence).unwrap_or_default();
+                let query = format!("audit/export?after={}&limit={}", after, control::AUDIT_PAGE_LIMIT);
+                match client.get(url(&query)).send().await {
+                    Ok(mut response) => {
+                        let body = response
+                            .body()
+                            .limit(AUDIT_LOG_LIMIT)
+                            .await
+                            .map_err(|_| "Payload error")?;
+                        if !response.status().is_success() {
+                            break Err(string_from_bytes(body));
+                        }
+                        let records = string_from_bytes(body)
+                            .lines()
+                            .map(serde_json::from_str::<control::AuditRecord>)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let done = records.len() < control::AUDIT_PAGE_LIMIT;
+                        for record in records {
+                            if let Err(err) = record.verify_next(last.as_ref(), key.as_ref()) {
+                                break 'pages Err(err);
+                            }
+                            last = Some(record);
+                            count += 1
+                        }
+                        if done {
+                            break Ok(count);
+                        }
+                    }
+                    Err(err) => break Err(err.to_string()),
+                }
+            };
+            match result {
+                Ok(n) => println!("audit log verified: {} records", n),
+                Err(err) => println!("audit log verification failed: {}", err),
+            }
+        } else {
+            let time = |name| -> Result<Option<chrono::DateTime<chrono::Utc>>, Error> {
+                match audit_matches.value_of(name) {
+                    Some(s) => Ok(Some(s.parse()?)),
+                    None => Ok(None),
+                }
+            };
+            let mut audit_payload = control::AuditQuery {
+                from: time("FROM")?,
+                to: time("TO")?,
+                label: match audit_matches.value_of("LABEL") {
+                    Some(label) => Some(label.parse()?),
+                    None => None,
+                },
+                after: None,
+                limit: Some(control::AUDIT_PAGE_LIMIT),
+            };
+            // fetch pages of records, until a page is not full
+            let mut count = 0;
+            loop {
+                match client
+                    .get(url("audit/query"))
+                    .send_json(&audit_payload)
+                    .await
+                {
+                    Ok(mut response) => {
+                        let body = response
+                            .body()
+                            .limit(AUDIT_LOG_LIMIT)
+                            .await
+                            .map_err(|_| "Payload error")?;
+                        if response.status().is_success() {
+                            let records: Vec<control::AuditRecord> =
+                                serde_json::from_slice(body.as_ref())?;
+                            let done = records.len() < control::AUDIT_PAGE_LIMIT;
+                            count += records.len();
+                            audit_payload.after = records.last().map(|record| record.sequence);
+                            for record in records {
+                                if audit_matches.is_present("JSONL") {
+                                    println!("{}", serde_json::to_string(&record)?)
+                                } else {
+                                    println!("{}", record)
+                                }
+                            }
+                            if done {
+                                if count == 0 && !audit_matches.is_present("JSONL") {
+                                    println!("<none>")
+                                }
+                                break;
+                            }
+                        } else {
+                            println!("{}", string_from_bytes(body));
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        println!("{}", err);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    // stream events, reconnecting (and resuming) when the connection is lost
+    else if let Some(watch_matches) = matches.subcommand_matches("watch") {
+        let mut after: Option<i64> = match watch_matches.value_of("AFTER") {
+            Some(after) => Some(after.parse()?),
+            None => None,
+        };
+        loop {
+            let query = match after {
+                Some(after) => format!("events?format=ndjson&after={}", after),
+                None => "events?format=ndjson".to_string(),
+            };
+            match client.get(url(&query)).timeout(WATCH_TIMEOUT).send().await {
+                Ok(mut response) if response.status().is_success() => {
+                    let mut buffer = Vec::new();
+                    while let Some(chunk) = response.next().await {
+                        let chunk = match chunk {
+                            Ok(chunk) => chunk,
+                            Err(err) => {
+                                log::warn!("{}", err);
+                                break;
+                            }
+                        };
+                        buffer.extend_from_slice(chunk.as_ref());
+                        while let Some(i) = buffer.iter().position(|b| *b == b'\n') {
+                            let line: Vec<u8> = buffer.drain(..=i).collect();
+                            let record: control::EventRecord = serde_json::from_slice(&line)?;
+                            if watch_matches.is_present("JSONL") {
+                                println!("{}", serde_json::to_string(&record)?)
+                            } else {
+                                println!("{}", record)
+                            }
+                            if record.sequence != control::EVENTS_MISSED_SEQUENCE {
+                                after = Some(record.sequence)
+                            }
+                        }
+                    }
+                    log::info!("lost connection to control plane")
+                }
+                Ok(mut response) => {
+                    let body = response.body().await.map_err(|_| "Payload error")?;
+                    println!("{}", string_from_bytes(body));
+                    break;
+                }
+                Err(err) => log::warn!("{}", err),
+            }
+            actix_rt::time::delay_for(WATCH_RETRY).await
+        }
+    }
     // drop
     else if let Some(drop_matches) = matches.subcommand_matches("drop") {
         let service = drop_matches.value_of("SERVICE").unwrap();
@@ -354,6 +607,23 @@ fn labels(matches: &clap::ArgMatches) -> control::LabelMap {
     labels
 }
 
+fn comment(matches: &clap::ArgMatches) -> Option<String> {
+    matches.value_of("COMMENT").map(|s| s.to_string())
+}
+
+// label of a service policy, or of the global or onboarding policy
+fn policy_label(matches: &clap::ArgMatches) -> Result<Label, Error> {
+    if matches.is_present("GLOBAL") {
+        Ok(control::global_policy_label())
+    } else if matches.is_present("ONBOARDING") {
+        Ok(control::onboarding_policy_label())
+    } else if let Some(service) = matches.value_of("SERVICE") {
+        Ok(service.parse()?)
+    } else {
+        Err("expecting a service label, --global or --onboarding".into())
+    }
+}
+
 fn string_from_bytes(b: bytes::Bytes) -> String {
     std::str::from_utf8(b.as_ref())
         .unwrap_or_default()
@@ -25,14 +25,17 @@
  */
 
 use super::{
-    host::{ArmourDataHost, Launch, List, PolicyCommand, Quit},
+    host::{ArmourDataHost, GetAudit, GetShadow, KeyOp, Keys, Launch, List, PolicyCommand, Quit},
     instance::InstanceSelector,
 };
 use actix::Addr;
-use armour_api::proxy::{HttpConfig, LabelOp, PolicyRequest};
+use armour_api::proxy::{
+    HttpConfig, LabelOp, LoadBalancing, PolicyRequest, ProxyProtocol, ServiceId, TcpUpdate,
+    Upstreams,
+};
 use armour_lang::{
     labels,
-    policies::{DPPolicies, Protocol},
+    policies::{DPPolicies, OnError, Protocol},
 };
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -46,21 +49,28 @@ lazy_static! {
           (?P<command>
             help |
             list |
+            audit |
+            shadow |
             quit |
             run |
             wait |
             launch (\s (log | debug))? |
             shutdown |
             status |
+            keys (\s (rotate | rm | max-age | legacy))? |
             label \s (add | rm) |
             labels \s rm |
             deny \s all |
             allow \s all |
-            stop (\s (http | tcp))? |
+            stop (\s (http | tcp | udp))? |
             policy |
-            start \s (http | tcp | ingress) |
-            stop (\s (http | tcp))? |
-            timeout)
+            proxy \s protocol |
+            service \s policy |
+            start \s (http | tcp | udp | ingress) |
+            stop (\s (http | tcp | udp))? |
+            timeout |
+            tcp \s update |
+            udp \s timeout)
           (?P<arg>\s+.+)?\s*$"
     )
     .unwrap();
@@ -108,28 +118,47 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
             "COMMANDS:
     help               list commands
     list               list connected instances
-    quit               shutdown host and all instances
+    quit               shutdown host (proxies wait to resume with a restarted host)
     run <file>         run commands from <file>
     wait <seconds>     wait for <seconds> to elapse (up to 5s)
 
+    keys                     list the keys for x-armour metadata
+    keys rotate <key>        new current key (32 bytes, base64), keeping the previous key
+                             (use the same key on every host, e.g. from `openssl rand -base64 32`)
+    keys rm <key id>         stop accepting metadata encrypted with a previous key
+    keys max-age <seconds>   reject metadata that is older than <seconds>
+    keys legacy on|off       accept metadata without key IDs from old proxies (deprecated)
+
     [<id>:] launch [log|debug]         start a new proxy instance
     [<id>:] shutdown                   request proxy shutdown
     [<id>:] start <proto> <port>       start proxy on <port>
     [<id>:] start http <port> <socket> start ingress proxy for <socket> on <port>
+                                       (<socket>,<socket>,... balances over upstream servers)
     [<id>:] stop [<proto>]             stop proxy
     [<id>:] status                     retrieve and print status
     [<id>:] timeout <seconds>          set HTTP server response timeout
+    [<id>:] udp timeout <seconds>      set UDP flow idle timeout
+    [<id>:] tcp update <mode>          on new TCP policy: keep, reevaluate or drain established connections
+    [<id>:] audit [on|off]             enable/disable audit (dry-run) mode, or print would-be denials
+    [<id>:] proxy protocol off|[listen] [send <ip>,<ip>,...]
+                                       exchange metadata with other proxies using PROXY protocol (TCP):
+                                       read headers from upstream proxies (listen) and send headers
+                                       to destinations that are proxied by Armour (send)
     
     [<id>:] allow all                  request allow all policy
     [<id>:] deny all                   request deny all policy
-    [<id>:] policy <file>              read policy <file> and send to instance
+    [<id>:] policy <file> [<on error>] read policy <file> and send to instance
+    [<id>:] shadow [<file>|off]        set/remove shadow policy, or print disagreements
+    [<id>:] service policy <service> <file>
+                                       read policy <file> and send for <service> (IP, socket or label)
 
     [<id>:] label add <host> <label>   add a label
     [<id>:] label rm <host> <label>    remove a label
     [<id>:] labels rm [<host>]         remove labels (for <host> or all)
 
     <id>    instance ID number
-    <proto> http or tcp"
+    <proto> http, tcp or udp
+    <on error> fail-open or fail-closed (overrides the policy's on_policy_error function)"
         ),
         (true, Some("list"), None) => {
             host.do_send(List);
@@ -139,6 +168,24 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
             return true;
         }
         (true, Some("run"), Some(file)) => run_script(host, file),
+        (true, Some("keys"), None) => keys(host, None),
+        (true, Some("keys rotate"), Some(key)) => match parse_key(key) {
+            Ok(key) => keys(host, Some(KeyOp::Rotate(key))),
+            Err(err) => log::warn!("keys rotate <key>: {}", err),
+        },
+        (true, Some("keys rm"), Some(id)) => match id.parse::<u32>() {
+            Ok(id) => keys(host, Some(KeyOp::Remove(id))),
+            Err(_) => log::warn!("keys rm <key id>: expecting u32, got {}", id),
+        },
+        (true, Some("keys max-age"), Some(secs)) => match secs.parse::<u16>() {
+            Ok(secs) => keys(host, Some(KeyOp::MaxAge(secs))),
+            Err(_) => log::warn!("keys max-age <seconds>: expecting u16, got {}", secs),
+        },
+        (true, Some("keys legacy"), Some(arg)) => match arg {
+            "on" => keys(host, Some(KeyOp::Legacy(true))),
+            "off" => keys(host, Some(KeyOp::Legacy(false))),
+            _ => log::warn!("keys legacy on|off: expecting on or off, got {}", arg),
+        },
         (true, Some("wait"), Some(secs)) => {
             if let Ok(delay) = secs.parse::<u8>() {
                 std::thread::sleep(std::time::Duration::from_secs(delay.min(5).into()))
@@ -174,27 +221,42 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
             host.do_send(PolicyCommand::new(instance, PolicyRequest::Status))
         }
         (_, Some(s @ "start tcp"), Some(port_socket))
+        | (_, Some(s @ "start udp"), Some(port_socket))
         | (_, Some(s @ "start http"), Some(port_socket)) => {
             if let Ok(port) = port_socket.parse::<u16>() {
                 let start = if s.ends_with("http") {
                     PolicyRequest::StartHttp(HttpConfig::Port(port))
+                } else if s.ends_with("udp") {
+                    PolicyRequest::StartUdp(port)
                 } else {
                     PolicyRequest::StartTcp(port)
                 };
                 host.do_send(PolicyCommand::new(instance, start))
             } else if s.ends_with("http") {
                 match port_socket.split(' ').collect::<Vec<&str>>().as_slice() {
-                    [port, socket] => {
-                        if let Ok(socket) = socket.parse::<std::net::SocketAddrV4>() {
+                    [port, sockets] => {
+                        let servers: Result<Vec<std::net::SocketAddr>, _> =
+                            sockets.split(',').map(|socket| socket.parse()).collect();
+                        if let Ok(mut servers) = servers {
                             if let Ok(port) = port.parse::<u16>() {
-                                let start =
-                                    PolicyRequest::StartHttp(HttpConfig::Ingress(port, socket));
+                                let config = if servers.len() == 1 {
+                                    HttpConfig::Ingress(port, servers.remove(0))
+                                } else {
+                                    HttpConfig::Upstreams(
+                                        port,
+                                        Upstreams {
+                                            servers,
+                                            load_balancing: LoadBalancing::default(),
+                                        },
+                                    )
+                                };
+                                let start = PolicyRequest::StartHttp(config);
                                 host.do_send(PolicyCommand::new(instance, start))
                             } else {
                                 log::warn!("expecting <port>, got {}", port);
                             }
                         } else {
-                            log::warn!("expecting <socket>, got {}", socket);
+                            log::warn!("expecting <socket>[,<socket>...], got {}", sockets);
                         }
                     }
                     _ => log::warn!("expecting <port> [<socket>], got {}", port_socket),
@@ -209,8 +271,12 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
                 PolicyRequest::Stop(Protocol::HTTP),
             ));
             host.do_send(PolicyCommand::new(
-                instance,
+                instance.clone(),
                 PolicyRequest::Stop(Protocol::TCP),
+            ));
+            host.do_send(PolicyCommand::new(
+                instance,
+                PolicyRequest::Stop(Protocol::UDP),
             ))
         }
         (_, Some("stop http"), None) => host.do_send(PolicyCommand::new(
@@ -221,6 +287,10 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
             instance,
             PolicyRequest::Stop(Protocol::TCP),
         )),
+        (_, Some("stop udp"), None) => host.do_send(PolicyCommand::new(
+            instance,
+            PolicyRequest::Stop(Protocol::UDP),
+        )),
         (_, Some("timeout"), Some(secs)) => {
             if let Ok(secs) = secs.parse::<u8>() {
                 host.do_send(PolicyCommand::new(instance, PolicyRequest::Timeout(secs)))
@@ -228,19 +298,101 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
                 log::warn!("timeout <seconds>: expecting u8, got {}", secs);
             }
         }
-        (_, Some("policy"), Some(file)) => {
+        (_, Some("tcp update"), Some(mode)) => match mode.parse::<TcpUpdate>() {
+            Ok(update) => host.do_send(PolicyCommand::new(instance, PolicyRequest::TcpUpdate(update))),
+            Err(err) => log::warn!("tcp update <mode>: {}", err),
+        },
+        (_, Some("udp timeout"), Some(secs)) => {
+            if let Ok(secs) = secs.parse::<u16>() {
+                host.do_send(PolicyCommand::new(instance, PolicyRequest::UdpTimeout(secs)))
+            } else {
+                log::warn!("udp timeout <seconds>: expecting u16, got {}", secs);
+            }
+        }
+        (_, Some("audit"), Some(arg)) => match arg.to_lowercase().as_str() {
+            "on" => host.do_send(PolicyCommand::new(instance, PolicyRequest::Audit(true))),
+            "off" => host.do_send(PolicyCommand::new(instance, PolicyRequest::Audit(false))),
+            _ => log::warn!("audit [on|off]: expecting on or off, got {}", arg),
+        },
+        (_, Some("proxy protocol"), Some(arg)) => match parse_proxy_protocol(arg) {
+            Ok(config) => host.do_send(PolicyCommand::new(instance, PolicyRequest::ProxyProtocol(config))),
+            Err(err) => log::warn!("proxy protocol off|[listen] [send <ip>,...]: {}", err),
+        },
+        (_, Some("audit"), None) => match futures::executor::block_on(host.send(GetAudit(instance))) {
+            Ok(summaries) if summaries.is_empty() => log::info!("there are no active instances"),
+            Ok(summaries) => {
+                for (label, summary) in summaries {
+                    log::info!("{}:\n{}", label, summary)
+                }
+            }
+            Err(err) => log::warn!("{}", err),
+        },
+        (_, Some("shadow"), Some(arg)) => {
+            let policy = if arg.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                let path = pathbuf(arg);
+                match DPPolicies::from_file(&path) {
+                    Ok(policies) => Some(policies),
+                    Err(err) => {
+                        log::warn!(r#"{:?}: {}"#, path, err);
+                        return false;
+                    }
+                }
+            };
+            host.do_send(PolicyCommand::new(instance, PolicyRequest::SetShadowPolicy(policy)))
+        }
+        (_, Some("shadow"), None) => match futures::executor::block_on(host.send(GetShadow(instance))) {
+            Ok(summaries) if summaries.is_empty() => log::info!("there are no active instances"),
+            Ok(summaries) => {
+                for (label, summary) in summaries {
+                    log::info!("{}:\n{}", label, summary)
+                }
+            }
+            Err(err) => log::warn!("{}", err),
+        },
+        (_, Some("policy"), Some(arg)) => {
+            // optional error strategy, following the file name
+            let (file, on_error) = match arg.rsplitn(2, ' ').collect::<Vec<&str>>().as_slice() {
+                [strategy, file] => match strategy.parse::<OnError>() {
+                    Ok(on_error) => (file.trim_end().trim_matches('"'), Some(on_error)),
+                    Err(_) => (arg, None),
+                },
+                _ => (arg, None),
+            };
             let path = pathbuf(file);
             match DPPolicies::from_file(&path) {
-                Ok(policies) => set_policy(host, instance, policies),
+                Ok(mut policies) => {
+                    if let Some(on_error) = on_error {
+                        policies.set_on_error(on_error)
+                    }
+                    set_policy(host, instance, None, policies)
+                }
                 Err(err) => log::warn!(r#"{:?}: {}"#, path, err),
             }
         }
-        (_, Some("allow all"), None) => set_policy(host, instance, DPPolicies::allow_all()),
-        (_, Some("deny all"), None) => set_policy(host, instance, DPPolicies::deny_all()),
+        (_, Some("service policy"), Some(arg)) => {
+            if let [service, file] = arg.splitn(2, ' ').collect::<Vec<&str>>().as_slice() {
+                match service.parse::<ServiceId>() {
+                    Ok(service) => {
+                        let path = pathbuf(file.trim().trim_matches('"'));
+                        match DPPolicies::from_file(&path) {
+                            Ok(policies) => set_policy(host, instance, Some(service), policies),
+                            Err(err) => log::warn!(r#"{:?}: {}"#, path, err),
+                        }
+                    }
+                    Err(err) => log::warn!("{}", err),
+                }
+            } else {
+                log::warn!("service policy: expecting <service> <file>")
+            }
+        }
+        (_, Some("allow all"), None) => set_policy(host, instance, None, DPPolicies::allow_all()),
+        (_, Some("deny all"), None) => set_policy(host, instance, None, DPPolicies::deny_all()),
         (_, Some(s @ "label add"), Some(arg)) | (_, Some(s @ "label rm"), Some(arg)) => {
             if let [key, value] = arg.split(' ').collect::<Vec<&str>>().as_slice() {
                 if let Ok(label) = value.parse::<labels::Label>() {
-                    if let Ok(ip) = key.parse::<std::net::Ipv4Addr>() {
+                    if let Ok(ip) = key.parse::<std::net::IpAddr>() {
                         let op = if s.ends_with("add") {
                             LabelOp::AddIp(vec![(ip, label.into())])
                         } else {
@@ -263,7 +415,7 @@ fn host_command(host: &Addr<ArmourDataHost>, caps: regex::Captures) -> bool {
             }
         }
         (_, Some("labels rm"), Some(arg)) => {
-            if let Ok(ip) = arg.parse::<std::net::Ipv4Addr>() {
+            if let Ok(ip) = arg.parse::<std::net::IpAddr>() {
                 host.do_send(PolicyCommand::new(
                     instance,
                     PolicyRequest::Label(LabelOp::RemoveIp(ip, None)),
@@ -324,14 +476,59 @@ pub fn run_script(host: &Addr<ArmourDataHost>, script: &str) {
     }
 }
 
-fn set_policy(host: &Addr<ArmourDataHost>, instance: InstanceSelector, policies: DPPolicies) {
+fn set_policy(
+    host: &Addr<ArmourDataHost>,
+    instance: InstanceSelector,
+    service: Option<ServiceId>,
+    policies: DPPolicies,
+) {
     log::info!("sending policy: {}", policies);
     host.do_send(PolicyCommand::new(
         instance,
-        PolicyRequest::SetPolicy(policies),
+        PolicyRequest::SetPolicy(service, policies),
     ))
 }
 
+fn keys(host: &Addr<ArmourDataHost>, op: Option<KeyOp>) {
+    match futures::executor::block_on(host.send(Keys(op))) {
+        Ok(Ok(keys)) => log::info!("metadata keys:\n{}", keys),
+        Ok(Err(err)) => log::warn!("{}", err),
+        Err(err) => log::warn!("{}", err),
+    }
+}
+
+fn parse_key(s: &str) -> Result<[u8; 32], String> {
+    use std::convert::TryInto;
+    base64::decode(s)
+        .map_err(|err| err.to_string())?
+        .as_slice()
+        .try_into()
+        .map_err(|_| "key must be 32 bytes".to_string())
+}
+
+fn parse_proxy_protocol(s: &str) -> Result<ProxyProtocol, String> {
+    let mut config = ProxyProtocol::default();
+    if s.eq_ignore_ascii_case("off") {
+        return Ok(config);
+    }
+    let mut words = s.split_whitespace();
+    while let Some(word) = words.next() {
+        match word.to_lowercase().as_str() {
+            "listen" => config.listen = true,
+            "send" => {
+                let ips = words.next().ok_or_else(|| "expecting IP addresses after send".to_string())?;
+                for ip in ips.split(',') {
+                    config
+                        .destinations
+                        .insert(ip.parse().map_err(|_| format!("bad IP address: {}", ip))?);
+                }
+            }
+            _ => return Err(format!("unexpected {}", word)),
+        }
+    }
+    Ok(config)
+}
+
 fn pathbuf(s: &str) -> std::path::PathBuf {
     PathBuf::from(s)
         .iter()
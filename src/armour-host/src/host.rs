@@ -23,18 +23,23 @@
  */
 
 use super::instance::{ArmourDataInstance, Instance, InstanceSelector, Instances, Meta};
+use super::state::{HostState, ProxyState};
+use super::supervisor::{self, Supervised};
 use actix::prelude::*;
 use armour_api::{
-    control::{OnboardServiceRequest, OnboardServiceResponse, PolicyQueryRequest, PolicyQueryResponse},
+    control::{self, OnboardServiceRequest, OnboardServiceResponse, PolicyQueryRequest, PolicyQueryResponse},
     host::{self, HostCodec},
-    proxy::{LabelOp, PolicyRequest},
+    metrics,
+    proxy::{KeyRing, LabelCacheUpdate, LabelOp, LabelSnapshot, PolicyRequest, ServiceId},
 };
 use armour_lang::{
     labels::{Label, Labels},
-    literals::DPID
+    literals::DPID,
+    policies,
 };
 use log::*;
-use std::collections::{BTreeSet, HashMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 use tokio_util::codec::FramedRead;
 use std::str::FromStr;
@@ -46,14 +51,74 @@ pub struct ArmourDataHost {
     label: Label,         // host label (for communication with control plane)
     onboarded: bool,      // did we succesfully on-board with control plane?
     instances: Instances, // instance actor addresses and info
-    children: HashMap<u32, std::process::Child>, // maps PID to child process
+    children: HashMap<u32, Supervised>, // maps (latest) PID to child process
     count: usize,         // enumerates instances
     socket: std::path::PathBuf, // path to host's UDS socket
     key: [u8; 32],        // host key (for metadata encryption)
+    keys: KeyRing,        // metadata keys, after any rotations
+    state: std::path::PathBuf, // path to host's state file
+    resumable: HashMap<u32, ProxyState>, // proxies (by PID) from a previous run, waiting to resume
+    quitting: bool,       // host is shutting down (keep the saved state)
+    credentials: Credentials, // presented to the control plane when onboarding services
+    proxy_credentials: BTreeMap<Label, String>, // per-proxy credentials (e.g. from armour-compose)
+    label_cache: LabelSnapshot, // labels of remote identities, from the control plane
+}
+
+/// Credentials presented to the control plane when onboarding
+#[derive(Clone)]
+pub enum Credentials {
+    Token(String),
+    /// bound to the host's mTLS certificate
+    Certificate {
+        password: String,
+        certificate: std::path::PathBuf,
+    },
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::Token(String::new())
+    }
+}
+
+impl Credentials {
+    pub fn present(&self, claimant: &Label) -> String {
+        match self {
+            Credentials::Token(token) => token.clone(),
+            Credentials::Certificate {
+                password,
+                certificate,
+            } => armour_utils::certificate_credentials(password, certificate, &claimant.to_string())
+                .unwrap_or_else(|err| {
+                    warn!("failed to create certificate credentials: {}", err);
+                    String::new()
+                }),
+        }
+    }
 }
 
 impl Actor for ArmourDataHost {
     type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(supervisor::SUPERVISE_INTERVAL, |act, ctx| act.supervise(ctx));
+        ctx.run_interval(
+            std::time::Duration::from_secs(control::HEARTBEAT_INTERVAL),
+            |act, ctx| act.heartbeat(ctx),
+        );
+        if !self.resumable.is_empty() {
+            info!("waiting for {} proxies to resume", self.resumable.len());
+            // give up on proxies that have not reconnected
+            ctx.run_later(
+                std::time::Duration::from_secs(host::RESUME_TIMEOUT + 5),
+                |act, _ctx| {
+                    for (pid, proxy) in act.resumable.drain() {
+                        warn!("proxy {} {} did not resume", proxy.label, pid)
+                    }
+                    act.save_state()
+                },
+            );
+        }
+    }
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
         info!("removing socket: {}", self.socket.display());
         std::fs::remove_file(self.socket.clone())
@@ -69,7 +134,16 @@ impl ArmourDataHost {
         onboarded: bool,
         socket: std::path::PathBuf,
         key: [u8; 32],
+        state: std::path::PathBuf,
+        saved: HostState, // loaded from `state`
     ) -> Self {
+        let resumable = saved
+            .proxies
+            .into_iter()
+            .map(|proxy| (proxy.pid, proxy))
+            .collect();
+        // resumed proxies may still be using keys from before the restart
+        let keys = saved.keys.unwrap_or_else(|| KeyRing::new(key));
         ArmourDataHost {
             client,
             url: url.to_owned(),
@@ -80,6 +154,48 @@ impl ArmourDataHost {
             count: 0,
             socket,
             key,
+            keys,
+            state,
+            resumable,
+            quitting: false,
+            credentials: Credentials::default(),
+            proxy_credentials: BTreeMap::new(),
+            label_cache: LabelSnapshot::default(),
+        }
+    }
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+    // credentials for a service of a proxy
+    fn credentials(&self, proxy: &Label, service: &Label) -> String {
+        match self.proxy_credentials.get(proxy) {
+            Some(token) => token.clone(),
+            None => self.credentials.present(service),
+        }
+    }
+    // persist the instance table, so that a restarted host can re-attach to its proxies
+    fn save_state(&self) {
+        if self.quitting {
+            return;
+        }
+        let mut proxies: Vec<ProxyState> = self
+            .instances
+            .0
+            .values()
+            .filter_map(ProxyState::new)
+            .map(|proxy| {
+                let launch = self.children.get(&proxy.pid).map(|child| child.launch.clone());
+                proxy.with_launch(launch)
+            })
+            .collect();
+        proxies.extend(self.resumable.values().cloned());
+        let state = HostState {
+            proxies,
+            keys: Some(self.keys.clone()),
+        };
+        if let Err(err) = state.save(&self.state) {
+            warn!("failed to save state to {}: {}", self.state.display(), err)
         }
     }
     fn update_instances(&mut self, instances:InstanceSelector, tmp_dpid: DPID) {
@@ -165,51 +281,151 @@ impl Handler<Disconnect> for ArmourDataHost {
     fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) -> Self::Result {
         info!("removing instance: {}", msg.0);
         if let Some(instance) = self.instances.0.remove(&msg.0) {
+            self.save_state();
+            let replay = instance.replay();
             if let Some(meta) = instance.meta {
-                if let Some(mut child) = self.children.remove(&meta.pid) {
-                    if let Ok(code) = child.wait() {
-                        log::info!("{} exited with {}", meta, code);
-                        if self.onboarded {
-                            let onboard = OnboardServiceRequest {
-                                service: {
-                                    if let Some(dpid) = meta.tmp_dpid.clone() {
-                                        match dpid.find_label(&Label::from_str("ServiceID::**").unwrap()) {
-                                            Some(l) => l.clone(),
-                                            _ =>  meta.label
-                                        }
-                                    } else {
-                                        meta.label 
-                                    }
-                                },
-                                host: self.label.clone(),
-                                credentials: "".to_string(),//TODO
-                                tmp_dpid: meta.tmp_dpid
-                            };
-                            let url = self.url.clone();
-                            let client = self.client.clone();
-                            async move {
-                                crate::control_plane(
-                                    client,
-                                    &url,
-                                    http::Method::DELETE,
-                                    "service/drop",
-                                    &onboard,
-                                )
-                                .await
+                match self.children.get_mut(&meta.pid) {
+                    // unexpected disconnect: the proxy is restarted once it has exited
+                    Some(child) if !child.stopping => {
+                        child.replay = replay;
+                        child.meta = Some(meta)
+                    }
+                    Some(_) => {
+                        if let Some(mut child) = self.children.remove(&meta.pid) {
+                            if let Some(code) = child.process.as_mut().and_then(|p| p.wait()) {
+                                log::info!("{} exited with {}", meta, code);
+                                self.drop_service(ctx, meta)
                             }
-                            .into_actor(self)
-                            .then(|res, act, _ctx| {
-                                match res {
-                                    Ok(message) => {
-                                        log::info!("control plane dropped proxy: {}", message)
-                                    }
-                                    Err(err) => log::warn!("error dropping proxy: {}", err),
-                                };
-                                async {}.into_actor(act)
-                            })
-                            .wait(ctx)
                         }
                     }
+                    None => (),
+                }
+            }
+        }
+    }
+}
+
+// supervision of launched proxies
+impl ArmourDataHost {
+    // notify control plane that a proxy has gone
+    fn drop_service(&self, ctx: &mut Context<Self>, meta: Meta) {
+        if self.onboarded {
+            let service = {
+                if let Some(dpid) = meta.tmp_dpid.clone() {
+                    match dpid.find_label(&Label::from_str("ServiceID::**").unwrap()) {
+                        Some(l) => l.clone(),
+                        _ =>  meta.label.clone()
+                    }
+                } else {
+                    meta.label.clone()
+                }
+            };
+            let onboard = OnboardServiceRequest {
+                credentials: self.credentials(&meta.label, &service),
+                service,
+                host: self.label.clone(),
+                tmp_dpid: meta.tmp_dpid
+            };
+            let url = self.url.clone();
+            let client = self.client.clone();
+            async move {
+                crate::control_plane(
+                    client,
+                    &url,
+                    http::Method::DELETE,
+                    "service/drop",
+                    &onboard,
+                )
+                .await
+            }
+            .into_actor(self)
+            .then(|res, act, _ctx| {
+                match res {
+                    Ok(message) => {
+                        log::info!("control plane dropped proxy: {}", message)
+                    }
+                    Err(err) => log::warn!("error dropping proxy: {}", err),
+                };
+                async {}.into_actor(act)
+            })
+            .wait(ctx)
+        }
+    }
+    // report the host's proxies and their policies to the control plane
+    fn heartbeat(&self, ctx: &mut Context<Self>) {
+        if !self.onboarded {
+            return;
+        }
+        let heartbeat = control::HostHeartbeat {
+            label: self.label.clone(),
+            proxies: self.instances.0.values().filter_map(Instance::heartbeat).collect(),
+            label_cache: self.label_cache.version,
+        };
+        let url = self.url.clone();
+        let client = self.client.clone();
+        async move {
+            crate::control_plane(
+                client,
+                &url,
+                http::Method::POST,
+                "host/heartbeat",
+                &heartbeat,
+            )
+            .await
+        }
+        .into_actor(self)
+        .then(|res, act, _ctx| {
+            if let Err(err) = res {
+                log::warn!("heartbeat failed: {}", err)
+            }
+            async {}.into_actor(act)
+        })
+        .spawn(ctx);
+    }
+    // reap proxies that have exited and schedule their restart
+    fn supervise(&mut self, ctx: &mut Context<Self>) {
+        let mut exited = Vec::new();
+        for (pid, child) in self.children.iter_mut().filter(|(_, child)| !child.stopping) {
+            if let Some(reason) = child.process.as_mut().and_then(|p| p.try_wait()) {
+                exited.push((*pid, reason))
+            }
+        }
+        for (pid, reason) in exited {
+            self.exited(ctx, pid, reason)
+        }
+    }
+    fn exited(&mut self, ctx: &mut Context<Self>, pid: u32, reason: String) {
+        if let Some(child) = self.children.get_mut(&pid) {
+            match child.exited(reason) {
+                Some(delay) => {
+                    warn!(
+                        r#"proxy "{}" {} exited unexpectedly, restarting in {:?}"#,
+                        child.label, pid, delay
+                    );
+                    ctx.run_later(delay, move |act, ctx| act.restart(ctx, pid));
+                }
+                None => {
+                    warn!(r#"proxy "{}" is crash looping, not restarting"#, child.label);
+                    if let Some(meta) = child.meta.take() {
+                        self.drop_service(ctx, meta)
+                    }
+                }
+            }
+        }
+    }
+    fn restart(&mut self, ctx: &mut Context<Self>, pid: u32) {
+        if let Some(mut child) = self.children.remove(&pid) {
+            match self.spawn(&child.launch) {
+                Ok(process) => {
+                    let new_pid = process.id();
+                    info!(r#"restarted proxy "{}": {}"#, child.label, new_pid);
+                    child.restarted(process);
+                    self.children.insert(new_pid, child);
+                }
+                Err(err) => {
+                    warn!("failed to restart proxy: {}", err);
+                    self.children.insert(pid, child);
+                    self.exited(ctx, pid, err.to_string())
                 }
             }
         }
@@ -224,8 +440,251 @@ impl Handler<RegisterProxy> for ArmourDataHost {
     type Result = ();
     fn handle(&mut self, msg: RegisterProxy, _ctx: &mut Context<Self>) -> Self::Result {
         if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            // bring a restarted proxy back to the state of its predecessor
+            if let Some(child) = self.children.get_mut(&msg.1.pid) {
+                for request in child.replay.drain(..) {
+                    instance.record(&request);
+                    instance.addr.do_send(request)
+                }
+            }
             instance.set_meta(msg.1);
+            // proxies are launched with the host key, so send any rotated keys
+            if self.keys != KeyRing::new(self.key) {
+                instance
+                    .addr
+                    .do_send(PolicyRequest::SetKeys(self.keys.clone()))
+            }
+            if self.label_cache.version != 0 {
+                instance
+                    .addr
+                    .do_send(PolicyRequest::LabelCache(self.label_cache.clone()))
+            }
+            self.save_state()
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct RegisterPort(pub usize, pub policies::DPProtocol, pub Option<u16>);
+
+impl Handler<RegisterPort> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterPort, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            if let Some(meta) = instance.meta.as_mut() {
+                meta.ports.set(&msg.1, msg.2);
+                self.save_state()
+            }
+        }
+    }
+}
+
+/// Re-attachment of a proxy that was launched before the host restarted
+#[derive(Message)]
+#[rtype("()")]
+pub struct ResumeProxy(pub usize, pub Box<host::Resume>);
+
+impl Handler<ResumeProxy> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: ResumeProxy, ctx: &mut Context<Self>) -> Self::Result {
+        let ResumeProxy(id, resume) = msg;
+        let saved = self.resumable.remove(&resume.pid);
+        let onboarded = self.onboarded;
+        let mut query = Vec::new();
+        if let Some(instance) = self.instances.0.get_mut(&id) {
+            let mut meta = Meta::from(*resume);
+            match saved {
+                Some(saved) => {
+                    meta.tmp_dpid = saved.tmp_dpid;
+                    // supervise the proxy, as if this host had launched it
+                    match saved.launch {
+                        Some(launch) => {
+                            if let Some(credentials) = launch.credentials.clone() {
+                                self.proxy_credentials.insert(launch.label.clone(), credentials);
+                            }
+                            self.children.insert(
+                                meta.pid,
+                                Supervised::adopt(launch.label.clone(), launch, meta.pid),
+                            );
+                        }
+                        None => warn!("{}: resumed proxy {} will not be restarted", id, meta),
+                    }
+                    // re-push policies that the proxy is missing
+                    for (service, policies) in saved.policies {
+                        let stale = is_stale(&meta, &service, &policies);
+                        if let (Some(ServiceId::Label(label)), true) = (&service, onboarded) {
+                            // the control plane's policy may have changed while the host was down
+                            query.push((label.clone(), policies.clone(), stale))
+                        } else if stale {
+                            info!("{}: re-sending policy to resumed proxy", id);
+                            instance
+                                .addr
+                                .do_send(PolicyRequest::SetPolicy(service.clone(), policies.clone()))
+                        }
+                        instance.record_policy(&service, &policies)
+                    }
+                }
+                None => warn!("{}: no saved state for resumed proxy {}", id, meta),
+            }
+            instance.set_meta(meta);
+            if self.keys != KeyRing::new(self.key) {
+                instance
+                    .addr
+                    .do_send(PolicyRequest::SetKeys(self.keys.clone()))
+            }
+            if self.label_cache.version != 0 {
+                instance
+                    .addr
+                    .do_send(PolicyRequest::LabelCache(self.label_cache.clone()))
+            }
+            self.save_state();
+            for (service, saved, stale) in query {
+                self.requery_policy(ctx, id, service, saved, stale)
+            }
+        }
+    }
+}
+
+// the proxy does not have (the latest versions of) the policies
+fn is_stale(meta: &Meta, service: &Option<ServiceId>, policies: &policies::DPPolicies) -> bool {
+    policies.policies().any(|(protocol, policy)| {
+        meta.version(service, protocol)
+            .map(|version| version.hash != policy.blake3())
+            .unwrap_or(true)
+    })
+}
+
+impl ArmourDataHost {
+    // query the control plane for the policy of a resumed service, falling back to the saved policy
+    fn requery_policy(
+        &self,
+        ctx: &mut Context<Self>,
+        id: usize,
+        service: Label,
+        saved: policies::DPPolicies,
+        stale: bool,
+    ) {
+        let query = PolicyQueryRequest {
+            label: service.clone(),
+        };
+        let client = self.client.clone();
+        let url = self.url.clone();
+        async move {
+            crate::control_plane_deserialize::<_, PolicyQueryResponse>(
+                client,
+                &url,
+                http::Method::GET,
+                "policy/query",
+                &query,
+            )
+            .await
+        }
+        .into_actor(self)
+        .map(move |res, act, ctx| {
+            let service_id = Some(ServiceId::Label(service.clone()));
+            let policies = match res {
+                Ok(response) => response.policy,
+                Err(err) => {
+                    warn!("{}: failed to query policy for {}: {}", id, service, err);
+                    if !stale {
+                        return;
+                    }
+                    saved
+                }
+            };
+            let update = act
+                .instances
+                .0
+                .get(&id)
+                .and_then(|instance| instance.meta.as_ref())
+                .map(|meta| is_stale(meta, &service_id, &policies))
+                .unwrap_or(false);
+            if update {
+                info!("{}: sending policy for {} to resumed proxy", id, service);
+                ctx.notify(PolicyCommand::new(
+                    InstanceSelector::ID(id),
+                    PolicyRequest::SetPolicy(service_id, policies),
+                ))
+            }
+        })
+        .spawn(ctx);
+    }
+}
+
+/// Change to the metadata key ring
+pub enum KeyOp {
+    Rotate([u8; 32]), // new current key
+    Remove(u32),
+    MaxAge(u16),
+    Legacy(bool), // accept metadata without key IDs (deprecated)
+}
+
+/// Print the metadata key ring (key IDs and fingerprints only), after an optional change (which is sent to all proxies)
+#[derive(Message)]
+#[rtype("Result<String, String>")]
+pub struct Keys(pub Option<KeyOp>);
+
+impl Handler<Keys> for ArmourDataHost {
+    type Result = Result<String, String>;
+    fn handle(&mut self, msg: Keys, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg.0 {
+            None => return Ok(self.keys.to_string()),
+            Some(KeyOp::Rotate(key)) => {
+                // supplied, not generated: proxies on other hosts must be able to decrypt the metadata
+                let id = self.keys.rotate(key);
+                log::info!("new metadata key {} [{}]", id, KeyRing::fingerprint(&key))
+            }
+            Some(KeyOp::Remove(id)) => {
+                if !self.keys.remove(id) {
+                    return Err(format!("cannot remove key {}", id));
+                }
+            }
+            Some(KeyOp::MaxAge(secs)) => self.keys.max_age = secs,
+            Some(KeyOp::Legacy(enabled)) => self.keys.set_legacy(enabled),
+        }
+        self.save_state();
+        for instance in self.instances.0.values() {
+            instance
+                .addr
+                .do_send(PolicyRequest::SetKeys(self.keys.clone()))
+        }
+        Ok(self.keys.to_string())
+    }
+}
+
+/// Print the label cache, after an optional update from the control plane (which is sent to all proxies)
+#[derive(Message)]
+#[rtype("Result<LabelSnapshot, String>")]
+pub struct LabelCache(pub Option<LabelCacheUpdate>);
+
+impl Handler<LabelCache> for ArmourDataHost {
+    type Result = Result<LabelSnapshot, String>;
+    fn handle(&mut self, msg: LabelCache, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg.0 {
+            None => return Ok(self.label_cache.clone()),
+            Some(LabelCacheUpdate::Snapshot(snapshot)) => self.label_cache = snapshot,
+            Some(LabelCacheUpdate::Delta(delta)) => {
+                if !self.label_cache.apply(&delta) {
+                    return Err(format!(
+                        "label cache is at version {}, delta is from version {}",
+                        self.label_cache.version, delta.from
+                    ));
+                }
+            }
         }
+        info!(
+            "label cache: version {} ({} labels)",
+            self.label_cache.version,
+            self.label_cache.len()
+        );
+        // proxies always receive the full snapshot
+        for instance in self.instances.0.values() {
+            instance
+                .addr
+                .do_send(PolicyRequest::LabelCache(self.label_cache.clone()))
+        }
+        Ok(self.label_cache.clone())
     }
 }
 
@@ -240,142 +699,262 @@ impl Handler<CPOnboardProxy> for ArmourDataHost {
             return;
         }
         // if host on-boarded then notify control plane
-        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
-            match &mut instance.meta {
+        if let Some(instance) = self.instances.0.get(&msg.0) {
+            match instance.meta.clone() {
                 None => log::warn!("RegisterProxy should be processed before CPOnboardingProxy"),
                 Some(meta) => {
-                    let label = meta.label.clone();
-
-                    if msg.1.len() > 1 {
-                        log::warn!("Information propagation from proxy to CP assumes there is only one µservice per proxy");
-                    }
-                    
-                    let mut tmp_dpid = meta.tmp_dpid.clone().unwrap_or(DPID::default());
+                    // with several services behind the proxy, each service is on-boarded
+                    // separately and gets its own policy
+                    let per_service = msg.1.len() > 1;
                     for (ip, labels) in msg.1 {
+                        let service = if per_service {
+                            match local_service(&labels) {
+                                Some(service) => service,
+                                None => {
+                                    log::warn!("no Service label for {}, not on-boarding", ip);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            meta.label.clone()
+                        };
+                        let mut tmp_dpid = meta.tmp_dpid.clone().unwrap_or_default();
                         let mut ips = BTreeSet::new();
                         ips.insert(ip);
                         tmp_dpid.ips = ips;
                         tmp_dpid.labels = labels;
-                        break;//FIXME assume only one µservice per proxy
+                        self.onboard_service(ctx, meta.label.clone(), service, ip, tmp_dpid, per_service)
                     }
+                }
+            }
+        }
+    }
+}
 
-                    let instance = InstanceSelector::Label(label.clone());
-                    let onboard = OnboardServiceRequest {
-                        service: label.clone(),
-                        host: self.label.clone(),
-                        credentials: "".to_string(),//TODO
-                        tmp_dpid: Some(tmp_dpid.clone())
-                    };
-                    let url = self.url.clone();
-                    let url_clone = self.url.clone();
-                    let client = self.client.clone();
-                    
-                    // on-board
-                    async move {
-                        (tmp_dpid, crate::control_plane_deserialize::<_, OnboardServiceResponse>(
-                            client,
-                            &url,
-                            http::Method::POST,
-                            "service/on-board",
-                            &onboard,
-                        )
-                        .await)
+// on-board a service with the control plane and install its policy
+impl ArmourDataHost {
+    fn onboard_service(
+        &self,
+        ctx: &mut Context<Self>,
+        label: Label,
+        service: Label,
+        ip: std::net::IpAddr,
+        tmp_dpid: DPID,
+        per_service: bool,
+    ) {
+        let onboard = OnboardServiceRequest {
+            service: service.clone(),
+            host: self.label.clone(),
+            credentials: self.credentials(&label, &service),
+            tmp_dpid: Some(tmp_dpid.clone())
+        };
+        let instance = InstanceSelector::Label(label);
+        let url = self.url.clone();
+        let url_clone = self.url.clone();
+        let client = self.client.clone();
+
+        // on-board
+        async move {
+            (tmp_dpid, crate::control_plane_deserialize::<_, OnboardServiceResponse>(
+                client,
+                &url,
+                http::Method::POST,
+                "service/on-board",
+                &onboard,
+            )
+            .await)
+        }
+        .into_actor(self)
+        .then(move |(mut tmp_dpid, on_board_res), act, _ctx| {
+            let client = act.client.clone();
+            async move {
+                let service_id = match on_board_res {
+                    Ok(req) => {
+                        log::info!("registered service with control plane: {}", req.service_id);
+                        req.service_id
                     }
-                    .into_actor(self)
-                    .then(|(mut tmp_dpid, on_board_res), act, _ctx| {
-                        let client = act.client.clone();
-                        async move {
-                            let service_id = match on_board_res {
-                                Ok(req) => {
-                                    log::info!("registered service with control plane: {}", req.service_id);
-                                    req.service_id
-                                }
-                                Err(ref err) => {
-                                    log::warn!("failed to register service with control plane: {}", err);
-                                    label.clone()
-                                }
-                            };
-
-                            let query = PolicyQueryRequest {
-                                label: service_id.clone(),
-                            };
-                            
-                            tmp_dpid.labels.insert(service_id);
-
-                            // query policy
-                            (tmp_dpid, crate::control_plane_deserialize::<_, PolicyQueryResponse>(
-                                client,
-                                &url_clone,
-                                http::Method::GET,
-                                "policy/query",
-                                &query,
-                            )
-                            .await)
+                    Err(ref err) => {
+                        log::warn!("failed to register service with control plane: {}", err);
+                        service.clone()
+                    }
+                };
+
+                let query = PolicyQueryRequest {
+                    label: service_id.clone(),
+                };
+
+                tmp_dpid.labels.insert(service_id.clone());
+
+                // query policy
+                (service_id, tmp_dpid, crate::control_plane_deserialize::<_, PolicyQueryResponse>(
+                    client,
+                    &url_clone,
+                    http::Method::GET,
+                    "policy/query",
+                    &query,
+                )
+                .await)
 
-                        }
-                        .into_actor(act)
-                        .then(|(tmp_dpid, policy_res), act, ctx| {
-                            // log::debug!("got labels: {:?}", policy_res.labels);
-                            match policy_res {
-                                Ok(policy_response) => {
-                                    ctx.notify(PolicyCommand::new(
-                                        instance.clone(),
-                                        PolicyRequest::Label(LabelOp::AddUri(
-                                            policy_response.labels.into_iter().collect(),
-                                        )),
-                                    ));
-                                    ctx.notify(PolicyCommand::new(
-                                        instance.clone(),
-                                        PolicyRequest::SetPolicy(policy_response.policy),
-                                    ));
-                                    ctx.notify(ServiceGlobalID::new(tmp_dpid, instance))
-                                }
-                                Err(err) => log::warn!("failed to obtain policy: {}", err),
-                            };
-                            async {}.into_actor(act)
-                        })
-                    })
-                    .wait(ctx)
-                }
             }
+            .into_actor(act)
+            .then(move |(service_id, tmp_dpid, policy_res), act, ctx| {
+                // log::debug!("got labels: {:?}", policy_res.labels);
+                match policy_res {
+                    Ok(policy_response) => {
+                        ctx.notify(PolicyCommand::new(
+                            instance.clone(),
+                            PolicyRequest::Label(LabelOp::AddUri(
+                                policy_response.labels.into_iter().collect(),
+                            )),
+                        ));
+                        // the service's global ID identifies its traffic at the proxy
+                        ctx.notify(PolicyCommand::new(
+                            instance.clone(),
+                            PolicyRequest::Label(LabelOp::AddIp(vec![(
+                                ip,
+                                std::iter::once(service_id.clone()).collect(),
+                            )])),
+                        ));
+                        let service = if per_service {
+                            Some(ServiceId::Label(service_id))
+                        } else {
+                            None
+                        };
+                        ctx.notify(PolicyCommand::new(
+                            instance.clone(),
+                            PolicyRequest::SetPolicy(service, policy_response.policy),
+                        ));
+                        ctx.notify(ServiceGlobalID::new(tmp_dpid, instance))
+                    }
+                    Err(err) => log::warn!("failed to obtain policy: {}", err),
+                };
+                async {}.into_actor(act)
+            })
+        })
+        .wait(ctx)
+    }
+}
+
+// local name of a service, from its "Service::<name>" label
+fn local_service(labels: &Labels) -> Option<Label> {
+    let pattern = Label::from_str("Service::<<service>>").unwrap();
+    labels
+        .iter()
+        .find_map(|l| pattern.match_with(l))
+        .and_then(|m| m.get_label("service").cloned())
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct RegisterAudit(pub usize, pub Box<host::AuditSummary>);
+
+impl Handler<RegisterAudit> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterAudit, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            info!("{}: audit summary\n{}", msg.0, msg.1);
+            instance.audit = Some(msg.1)
         }
     }
 }
 
 #[derive(Message)]
 #[rtype("()")]
-pub struct RegisterHttpHash(pub usize, pub String);
+pub struct RegisterHttpVersion(pub usize, pub host::PolicyVersion);
 
-impl Handler<RegisterHttpHash> for ArmourDataHost {
+impl Handler<RegisterHttpVersion> for ArmourDataHost {
     type Result = ();
-    fn handle(&mut self, msg: RegisterHttpHash, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: RegisterHttpVersion, _ctx: &mut Context<Self>) -> Self::Result {
         if let Some(instance) = self.instances.0.get_mut(&msg.0) {
-            instance.set_http_hash(&msg.1)
+            instance.set_http_version(msg.1);
+            self.save_state()
         }
     }
 }
 
 #[derive(Message)]
 #[rtype("()")]
-pub struct RegisterTcpHash(pub usize, pub String);
+pub struct RegisterMetrics(pub usize, pub Box<metrics::Metrics>);
 
-impl Handler<RegisterTcpHash> for ArmourDataHost {
+impl Handler<RegisterMetrics> for ArmourDataHost {
     type Result = ();
-    fn handle(&mut self, msg: RegisterTcpHash, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: RegisterMetrics, _ctx: &mut Context<Self>) -> Self::Result {
         if let Some(instance) = self.instances.0.get_mut(&msg.0) {
-            instance.set_tcp_hash(&msg.1)
+            instance.metrics = Some(msg.1)
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct RegisterServiceVersion(
+    pub usize,
+    pub ServiceId,
+    pub policies::DPProtocol,
+    pub host::PolicyVersion,
+);
+
+impl Handler<RegisterServiceVersion> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterServiceVersion, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            instance.set_service_version(msg.1, msg.2, msg.3);
+            self.save_state()
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct RegisterShadow(pub usize, pub Box<host::ShadowSummary>);
+
+impl Handler<RegisterShadow> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterShadow, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            instance.shadow = if msg.1.is_active() { Some(msg.1) } else { None }
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct RegisterTcpVersion(pub usize, pub host::PolicyVersion);
+
+impl Handler<RegisterTcpVersion> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterTcpVersion, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            instance.set_tcp_version(msg.1);
+            self.save_state()
         }
     }
 }
 
-// launch a new proxy
 #[derive(Message)]
 #[rtype("()")]
+pub struct RegisterUdpVersion(pub usize, pub host::PolicyVersion);
+
+impl Handler<RegisterUdpVersion> for ArmourDataHost {
+    type Result = ();
+    fn handle(&mut self, msg: RegisterUdpVersion, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(instance) = self.instances.0.get_mut(&msg.0) {
+            instance.set_udp_version(msg.1);
+            self.save_state()
+        }
+    }
+}
+
+// launch a new proxy
+#[derive(Message, Clone, Serialize, Deserialize)]
+#[rtype("()")]
 pub struct Launch {
     force: bool,
     label: Label,
+    #[serde(with = "level")]
     log: log::Level,
     timeout: Option<u8>,
+    credentials: Option<String>,
 }
 
 impl Launch {
@@ -385,33 +964,53 @@ impl Launch {
             label,
             log,
             timeout,
+            credentials: None,
         }
     }
+    pub fn with_credentials(mut self, credentials: Option<String>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+}
+
+// log levels are saved by name
+mod level {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(level: &log::Level, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(level)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<log::Level, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 impl Handler<Launch> for ArmourDataHost {
     type Result = ();
     fn handle(&mut self, msg: Launch, _ctx: &mut Context<Self>) -> Self::Result {
         let instance = InstanceSelector::Label(msg.label.clone());
-        if msg.force || self.get_instances(&instance).is_empty() {
-            let armour_proxy = armour_proxy();
-            let mut command = std::process::Command::new(&armour_proxy);
-            command
-                .env("ARMOUR_PASS", base64::encode(&self.key))
-                .arg("-l")
-                .arg(msg.log.to_string().to_lowercase())
-                .arg("--label")
-                .arg(&msg.label.to_string());
-            if let Some(secs) = msg.timeout {
-                command.arg("--timeout").arg(secs.to_string());
-            }
-            match command.arg(&self.socket).spawn() {
+        // a proxy that is waiting to restart also counts as existing
+        let restarting = self.children.values().any(|child| {
+            child.state() == host::ProcessState::Restarting && msg.label.matches_with(&child.label)
+        });
+        if msg.force || (self.get_instances(&instance).is_empty() && !restarting) {
+            match self.spawn(&msg) {
                 Ok(child) => {
                     let pid = child.id();
                     log::info!("launched proxy processs: {} {}", msg.label, pid);
-                    self.children.insert(pid, child);
+                    if let Some(credentials) = msg.credentials.clone() {
+                        self.proxy_credentials.insert(msg.label.clone(), credentials);
+                    }
+                    // forget any earlier proxy with this label that was given up on
+                    self.children.retain(|_, child| {
+                        child.label != msg.label || child.state() != host::ProcessState::CrashLoop
+                    });
+                    self.children
+                        .insert(pid, Supervised::new(msg.label.clone(), msg, child));
                 }
-                Err(err) => log::warn!("failed to launch: {}\n{}", armour_proxy.display(), err),
+                Err(err) => log::warn!("failed to launch: {}\n{}", armour_proxy().display(), err),
             }
         } else if !msg.force {
             log::warn!(r#"proxy "{}" already exists"#, msg.label)
@@ -419,6 +1018,22 @@ impl Handler<Launch> for ArmourDataHost {
     }
 }
 
+impl ArmourDataHost {
+    fn spawn(&self, launch: &Launch) -> std::io::Result<std::process::Child> {
+        let mut command = std::process::Command::new(armour_proxy());
+        command
+            .env("ARMOUR_PASS", base64::encode(&self.key))
+            .arg("-l")
+            .arg(launch.log.to_string().to_lowercase())
+            .arg("--label")
+            .arg(&launch.label.to_string());
+        if let Some(secs) = launch.timeout {
+            command.arg("--timeout").arg(secs.to_string());
+        }
+        command.arg(&self.socket).spawn()
+    }
+}
+
 fn armour_proxy() -> std::path::PathBuf {
     if let Ok(Some(path)) =
         std::env::current_exe().map(|path| path.parent().map(|dir| dir.join("armour-proxy")))
@@ -436,6 +1051,9 @@ pub struct Quit;
 impl Handler<Quit> for ArmourDataHost {
     type Result = ();
     fn handle(&mut self, _msg: Quit, _ctx: &mut Context<Self>) -> Self::Result {
+        // proxies keep running and wait for a restarted host
+        self.save_state();
+        self.quitting = true;
         info!("removing socket: {}", self.socket.display());
         std::fs::remove_file(self.socket.clone())
             .unwrap_or_else(|e| warn!("failed to remove socket: {}", e));
@@ -444,24 +1062,130 @@ impl Handler<Quit> for ArmourDataHost {
 }
 
 #[derive(Message)]
-#[rtype("Arc<Vec<String>>")]
+#[rtype("Arc<Vec<host::ProxySummary>>")]
 pub struct List;
 
 impl Handler<List> for ArmourDataHost {
-    type Result = Arc<Vec<String>>;
+    type Result = Arc<Vec<host::ProxySummary>>;
     fn handle(&mut self, _msg: List, _ctx: &mut Context<Self>) -> Self::Result {
         if self.instances.0.is_empty() {
             info!("there are no active instances")
         } else {
             info!("active instances: {}", self.instances)
         }
-        let list: BTreeSet<String> = self
+        // launched proxies, and other connected proxies (e.g. resumed after a restart)
+        let mut list: Vec<host::ProxySummary> =
+            self.children.values().map(Supervised::summary).collect();
+        for meta in self.instances.0.values().filter_map(|i| i.meta.as_ref()) {
+            if !self.children.contains_key(&meta.pid) {
+                list.push(host::ProxySummary {
+                    label: meta.label.clone(),
+                    pid: Some(meta.pid),
+                    state: host::ProcessState::Running,
+                    restarts: 0,
+                    last_exit: None,
+                })
+            }
+        }
+        list.sort_by(|a, b| a.label.cmp(&b.label));
+        for proxy in list
+            .iter()
+            .filter(|p| p.restarts > 0 || p.state != host::ProcessState::Running)
+        {
+            info!("supervised proxy: {}", proxy)
+        }
+        Arc::new(list)
+    }
+}
+
+/// Metrics for the host and its proxies, in Prometheus text format
+#[derive(Message)]
+#[rtype("String")]
+pub struct GetMetrics;
+
+impl Handler<GetMetrics> for ArmourDataHost {
+    type Result = String;
+    fn handle(&mut self, _msg: GetMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        let default = metrics::Metrics::default();
+        let proxies: Vec<(&Label, &metrics::Metrics)> = self
             .instances
             .0
             .values()
-            .filter_map(|i| i.meta.as_ref().map(|m| m.label.to_string()))
+            .filter_map(|i| {
+                i.meta
+                    .as_ref()
+                    .map(|m| (&m.label, i.metrics.as_deref().unwrap_or(&default)))
+            })
             .collect();
-        Arc::new(list.into_iter().collect())
+        metrics::prometheus(&self.label, &proxies)
+    }
+}
+
+/// Audit (dry-run) summaries of the selected proxies, indexed by proxy label
+#[derive(Message)]
+#[rtype("BTreeMap<String, host::AuditSummary>")]
+pub struct GetAudit(pub InstanceSelector);
+
+impl Handler<GetAudit> for ArmourDataHost {
+    type Result = MessageResult<GetAudit>;
+    fn handle(&mut self, msg: GetAudit, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.get_instances(&msg.0)
+                .iter()
+                .filter_map(|i| {
+                    i.meta.as_ref().map(|m| {
+                        (
+                            m.label.to_string(),
+                            i.audit.as_deref().cloned().unwrap_or_default(),
+                        )
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Shadow policy summaries of the selected proxies, indexed by proxy label
+#[derive(Message)]
+#[rtype("BTreeMap<String, host::ShadowSummary>")]
+pub struct GetShadow(pub InstanceSelector);
+
+impl Handler<GetShadow> for ArmourDataHost {
+    type Result = MessageResult<GetShadow>;
+    fn handle(&mut self, msg: GetShadow, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.get_instances(&msg.0)
+                .iter()
+                .filter_map(|i| {
+                    i.meta.as_ref().map(|m| {
+                        (
+                            m.label.to_string(),
+                            i.shadow.as_deref().cloned().unwrap_or_default(),
+                        )
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Status of the selected proxies, indexed by proxy label
+#[derive(Message)]
+#[rtype("BTreeMap<String, host::ProxyStatus>")]
+pub struct GetStatus(pub InstanceSelector);
+
+impl Handler<GetStatus> for ArmourDataHost {
+    type Result = MessageResult<GetStatus>;
+    fn handle(&mut self, msg: GetStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.get_instances(&msg.0)
+                .iter()
+                .filter_map(|i| {
+                    let label = i.meta.as_ref()?.label.to_string();
+                    i.status().map(|status| (label, status))
+                })
+                .collect(),
+        )
     }
 }
 
@@ -495,6 +1219,7 @@ impl Handler<ServiceGlobalID> for ArmourDataHost {
         let ServiceGlobalID(global_id, instances) = msg;
         log::info!("global id is: {:#?} for {:?}", global_id.clone(), instances.clone());
         self.update_instances(instances, global_id);
+        self.save_state()
     }
 }
 
@@ -531,11 +1256,97 @@ impl Handler<PolicyCommand> for ArmourDataHost {
                 Some(MSG)
             }
         } else {
+            let mut stopping = Vec::new();
             for instance in selected {
+                instance.record(request);
+                if let (PolicyRequest::Shutdown, Some(meta)) = (request, instance.meta.as_ref()) {
+                    stopping.push(meta.pid)
+                }
                 instance.addr.do_send(request.clone())
             }
+            // proxies that are asked to shut down are not restarted
+            for pid in stopping {
+                if let Some(child) = self.children.get_mut(&pid) {
+                    child.stopping = true
+                }
+            }
+            self.save_state();
             None
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn resume() {
+        let dir = std::env::temp_dir().join(format!("armour-host-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = dir.join("state.json");
+        let label: Label = "Proxy::resumed".parse().unwrap();
+        // a proxy (that has since exited) launched before the host restarted
+        let pid = u32::MAX;
+        let mut keys = KeyRing::new([0; 32]);
+        let rotated = keys.rotate([1; 32]);
+        let proxy = ProxyState {
+            pid,
+            label: label.clone(),
+            tmp_dpid: None,
+            http: Default::default(),
+            tcp: Default::default(),
+            udp: Default::default(),
+            services: Vec::new(),
+            ports: Default::default(),
+            policies: Vec::new(),
+            launch: Some(Launch::new(label.clone(), false, log::Level::Info, None)),
+        };
+        HostState {
+            proxies: vec![proxy],
+            keys: Some(keys),
+        }
+        .save(&state)
+        .unwrap();
+        let host = ArmourDataHost::new(
+            actix_web::client::Client::default(),
+            &"https://localhost:8088".parse().unwrap(),
+            &"Host::test".parse().unwrap(),
+            false,
+            dir.join("socket"),
+            [0; 32],
+            state.clone(),
+            HostState::load(&state).unwrap(),
+        )
+        .start();
+        // the key ring is restored
+        let printed = host.send(Keys(None)).await.unwrap().unwrap();
+        assert!(printed.contains(&rotated.to_string()));
+        // the proxy re-connects
+        let (stream, _proxy) = tokio::net::UnixStream::pair().unwrap();
+        host.send(UdsConnect(stream)).await.unwrap();
+        actix_rt::time::delay_for(std::time::Duration::from_millis(100)).await;
+        let resume = host::Resume {
+            pid,
+            label: label.clone(),
+            http: Default::default(),
+            tcp: Default::default(),
+            udp: Default::default(),
+            services: BTreeMap::new(),
+            ports: Default::default(),
+        };
+        host.send(ResumeProxy(0, Box::new(resume))).await.unwrap();
+        let saved = HostState::load(&state).unwrap();
+        assert_eq!(saved.proxies.len(), 1);
+        assert!(saved.proxies[0].launch.is_some());
+        // the resumed proxy is supervised, so it is restarted once it has gone
+        actix_rt::time::delay_for(supervisor::SUPERVISE_INTERVAL * 2).await;
+        let list = host.send(List).await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].label, label);
+        assert_ne!(list[0].state, host::ProcessState::Running);
+        assert!(list[0].last_exit.is_some());
+        std::fs::remove_dir_all(dir).unwrap()
+    }
+}
@@ -23,15 +23,26 @@
  */
 
 use super::host::{
-    ArmourDataHost, Connect, CPOnboardProxy, Disconnect, RegisterHttpHash, RegisterProxy, RegisterTcpHash,
+    ArmourDataHost, Connect, CPOnboardProxy, Disconnect, RegisterAudit, RegisterHttpVersion,
+    RegisterPort, RegisterProxy, RegisterServiceVersion, ResumeProxy, RegisterShadow, RegisterTcpVersion, RegisterMetrics, RegisterUdpVersion,
 };
 use actix::prelude::*;
-use armour_api::host::{self, HostCodec, PolicyResponse};
-use armour_api::proxy::PolicyRequest;
-use armour_lang::{labels::Label, literals::DPID, policies::Protocol};
+use armour_api::host::{
+    self, AuditSummary, HostCodec, PolicyResponse, PolicyVersion, Ports, ServiceVersions,
+    ShadowSummary,
+};
+use armour_api::control;
+use armour_api::metrics::Metrics;
+use armour_api::proxy::{LabelOp, PolicyRequest, ServiceId};
+use armour_lang::{
+    labels::Label,
+    literals::DPID,
+    policies::{self, DPPolicies, Protocol},
+};
 use log::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::str::FromStr;
 use tokio::io::WriteHalf;
 
 #[derive(Clone, PartialEq, Debug)]
@@ -46,28 +57,85 @@ pub struct Meta {
     pub pid: u32,
     pub tmp_dpid: Option<DPID>,
     pub label: Label,
-    pub http: String, // hash of HTTP policy
-    pub tcp: String,  // hash of TCP policy
+    pub http: PolicyVersion, // version of HTTP policy
+    pub tcp: PolicyVersion,  // version of TCP policy
+    pub udp: PolicyVersion,  // version of UDP policy
+    pub services: BTreeMap<ServiceId, ServiceVersions>, // versions of per-service policies
+    pub ports: Ports,
 }
 
 impl From<&Meta> for host::PolicyStatus {
     fn from(m: &Meta) -> Self {
         host::PolicyStatus {
             label: m.label.to_owned(),
-            http: m.http.to_string(),
-            tcp: m.tcp.to_string(),
+            http: m.http.hash.to_string(),
+            tcp: m.tcp.hash.to_string(),
+            udp: m.udp.hash.to_string(),
+            http_version: m.http.counter,
+            tcp_version: m.tcp.counter,
+            udp_version: m.udp.counter,
+            services: m
+                .services
+                .iter()
+                .map(|(id, versions)| (id.to_string(), versions.clone()))
+                .collect(),
         }
     }
 }
 
 impl Meta {
-    fn new(pid: u32, tmp_dpid: Option<DPID>, label: Label, http: String, tcp: String) -> Self {
+    fn new(
+        pid: u32,
+        tmp_dpid: Option<DPID>,
+        label: Label,
+        http: PolicyVersion,
+        tcp: PolicyVersion,
+        udp: PolicyVersion,
+    ) -> Self {
         Meta {
             pid,
             tmp_dpid,
             label,
             http,
             tcp,
+            udp,
+            services: BTreeMap::new(),
+            ports: Ports::default(),
+        }
+    }
+    /// Version of an installed policy (proxy-wide or for a service)
+    pub fn version(
+        &self,
+        service: &Option<ServiceId>,
+        protocol: &policies::DPProtocol,
+    ) -> Option<&PolicyVersion> {
+        let (http, tcp, udp) = match service {
+            Some(id) => {
+                let versions = self.services.get(id)?;
+                (&versions.http, &versions.tcp, &versions.udp)
+            }
+            None => (&self.http, &self.tcp, &self.udp),
+        };
+        match protocol {
+            Protocol::HTTP => Some(http),
+            Protocol::TCP => Some(tcp),
+            Protocol::UDP => Some(udp),
+            Protocol::Phantom(_) => None,
+        }
+    }
+}
+
+impl From<host::Resume> for Meta {
+    fn from(resume: host::Resume) -> Self {
+        Meta {
+            pid: resume.pid,
+            tmp_dpid: None,
+            label: resume.label,
+            http: resume.http,
+            tcp: resume.tcp,
+            udp: resume.udp,
+            services: resume.services,
+            ports: resume.ports,
         }
     }
 }
@@ -76,32 +144,157 @@ impl fmt::Display for Meta {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            r#""{}"; pid: {}; http: {}; tcp: {}"#,
-            self.label, self.pid, self.http, self.tcp
-        )
+            r#""{}"; pid: {}; http: {}; tcp: {}; udp: {}"#,
+            self.label, self.pid, self.http, self.tcp, self.udp
+        )?;
+        for (id, versions) in self.services.iter() {
+            write!(f, r#"; service "{}": {}"#, id, versions)?
+        }
+        Ok(())
     }
 }
 
 pub struct Instance {
     pub meta: Option<Meta>,
+    pub metrics: Option<Box<Metrics>>, // latest metrics reported by the proxy
+    pub audit: Option<Box<AuditSummary>>, // latest audit (dry-run) summary reported by the proxy
+    pub shadow: Option<Box<ShadowSummary>>, // latest shadow policy summary reported by the proxy
+    pub policies: BTreeMap<Option<ServiceId>, DPPolicies>, // policies sent by the host (proxy-wide and per-service)
+    pub labels: Vec<LabelOp>,     // label changes sent by the host
+    pub started: Vec<PolicyRequest>, // latest requests to start the HTTP, TCP and UDP proxies
     pub addr: Addr<ArmourDataInstance>,
 }
 
 impl Instance {
     pub fn new(addr: Addr<ArmourDataInstance>) -> Self {
-        Instance { meta: None, addr }
+        Instance {
+            meta: None,
+            metrics: None,
+            audit: None,
+            shadow: None,
+            policies: BTreeMap::new(),
+            labels: Vec::new(),
+            started: Vec::new(),
+            addr,
+        }
     }
     pub fn set_meta(&mut self, meta: Meta) {
         self.meta = Some(meta)
     }
-    pub fn set_http_hash(&mut self, s: &str) {
+    /// Record a policy sent to the proxy, replacing any previous policy for the same protocols
+    pub fn record_policy(&mut self, service: &Option<ServiceId>, policies: &DPPolicies) {
+        let recorded = self.policies.entry(service.clone()).or_default();
+        for (protocol, policy) in policies.policies() {
+            recorded.insert(protocol.clone(), policy.clone())
+        }
+    }
+    /// Record a request sent to the proxy, so that it can be replayed if the proxy restarts
+    pub fn record(&mut self, request: &PolicyRequest) {
+        match request {
+            PolicyRequest::Label(op) => self.labels.push(op.clone()),
+            PolicyRequest::SetPolicy(service, policies) => self.record_policy(service, policies),
+            PolicyRequest::StartHttp(_)
+            | PolicyRequest::StartTcp(_)
+            | PolicyRequest::StartUdp(_) => {
+                let kind = std::mem::discriminant(request);
+                self.started.retain(|r| std::mem::discriminant(r) != kind);
+                self.started.push(request.clone())
+            }
+            PolicyRequest::Stop(protocol) => self.started.retain(|r| {
+                !matches!(
+                    (r, protocol),
+                    (PolicyRequest::StartHttp(_), Protocol::HTTP)
+                        | (PolicyRequest::StartTcp(_), Protocol::TCP)
+                        | (PolicyRequest::StartUdp(_), Protocol::UDP)
+                )
+            }),
+            _ => (),
+        }
+    }
+    /// Current state of the proxy (if it has registered)
+    pub fn status(&self) -> Option<host::ProxyStatus> {
+        let meta = self.meta.as_ref()?;
+        let metrics = self.metrics.as_deref();
+        let ingress = self.started.iter().find_map(|request| match request {
+            PolicyRequest::StartHttp(config) if meta.ports.http.is_some() => config.ingress(),
+            _ => None,
+        });
+        Some(host::ProxyStatus {
+            pid: meta.pid,
+            ports: meta.ports.clone(),
+            ingress,
+            http: meta.http.clone(),
+            tcp: meta.tcp.clone(),
+            udp: meta.udp.clone(),
+            services: host::PolicyStatus::from(meta).services,
+            requests: metrics.map(|m| m.requests.clone()).unwrap_or_default(),
+            connections: metrics.map(|m| m.active.clone()).unwrap_or_default(),
+        })
+    }
+    /// Policy hashes reported to the control plane, keyed by global service ID
+    pub fn heartbeat(&self) -> Option<control::ProxyHeartbeat> {
+        let meta = self.meta.as_ref()?;
+        let service_id = Label::from_str("ServiceID::**").unwrap();
+        let mut services = BTreeMap::new();
+        // a proxy-wide policy belongs to the proxy's (global) service ID, unless overridden
+        if let Some(policies) = self.policies.get(&None) {
+            if let Some(label) = meta
+                .tmp_dpid
+                .as_ref()
+                .and_then(|dpid| dpid.find_label(&service_id))
+            {
+                services.insert(label.to_string(), policies.blake3());
+            }
+        }
+        for (service, policies) in self.policies.iter() {
+            if let Some(ServiceId::Label(label)) = service {
+                services.insert(label.to_string(), policies.blake3());
+            }
+        }
+        Some(control::ProxyHeartbeat {
+            label: meta.label.clone(),
+            pid: meta.pid,
+            services,
+        })
+    }
+    /// Requests that bring a restarted proxy back to the recorded state
+    pub fn replay(&self) -> Vec<PolicyRequest> {
+        let labels = self.labels.iter().cloned().map(PolicyRequest::Label);
+        let policies = self
+            .policies
+            .iter()
+            .map(|(service, policies)| PolicyRequest::SetPolicy(service.clone(), policies.clone()));
+        labels.chain(policies).chain(self.started.iter().cloned()).collect()
+    }
+    pub fn set_http_version(&mut self, version: PolicyVersion) {
         if let Some(mut meta) = self.meta.as_mut() {
-            meta.http = s.to_string();
+            meta.http = version;
         }
     }
-    pub fn set_tcp_hash(&mut self, s: &str) {
+    pub fn set_tcp_version(&mut self, version: PolicyVersion) {
         if let Some(mut meta) = self.meta.as_mut() {
-            meta.tcp = s.to_string();
+            meta.tcp = version;
+        }
+    }
+    pub fn set_udp_version(&mut self, version: PolicyVersion) {
+        if let Some(mut meta) = self.meta.as_mut() {
+            meta.udp = version;
+        }
+    }
+    pub fn set_service_version(
+        &mut self,
+        id: ServiceId,
+        protocol: policies::DPProtocol,
+        version: PolicyVersion,
+    ) {
+        if let Some(meta) = self.meta.as_mut() {
+            let versions = meta.services.entry(id).or_default();
+            match protocol {
+                Protocol::HTTP => versions.http = version,
+                Protocol::TCP => versions.tcp = version,
+                Protocol::UDP => versions.udp = version,
+                Protocol::Phantom(_) => unreachable!(),
+            }
         }
     }
 }
@@ -163,27 +356,55 @@ impl StreamHandler<Result<PolicyResponse, std::io::Error>> for ArmourDataInstanc
     fn handle(&mut self, msg: Result<PolicyResponse, std::io::Error>, ctx: &mut Self::Context) {
         if let Ok(msg) = msg {
             match msg {
-                PolicyResponse::Connect(pid, tmp_dpid, label, http, tcp) => {
+                PolicyResponse::Audit(summary) => {
+                    debug!("{}: audit summary", self.id);
+                    self.host.do_send(RegisterAudit(self.id, summary))
+                }
+                PolicyResponse::Connect(pid, tmp_dpid, label, http, tcp, udp) => {
                     info!(r#"{}: connect with process "{}" {} {:?}"#, self.id, label, pid, tmp_dpid);   
                     self.host
-                        .do_send(RegisterProxy(self.id, Meta::new(pid, tmp_dpid, label, http, tcp)))
+                        .do_send(RegisterProxy(self.id, Meta::new(pid, tmp_dpid, label, http, tcp, udp)))
                 },
                 PolicyResponse::CPOnboardingProxy(ip_labels) => {
                     info!(r#"{}: onboard with CP with information {:#?}"#, self.id, ip_labels);
                     self.host
                         .do_send(CPOnboardProxy(self.id, ip_labels))
                 },
-                PolicyResponse::Started => info!("{}: started a proxy", self.id),
-                PolicyResponse::Stopped => info!("{}: stopped a proxy", self.id),
-                PolicyResponse::UpdatedPolicy(protocol, hash) => {
-                    info!("{}: updated policy", self.id);
+                PolicyResponse::Metrics(metrics) => {
+                    debug!("{}: metrics", self.id);
+                    self.host.do_send(RegisterMetrics(self.id, metrics))
+                }
+                PolicyResponse::Started(protocol, port) => {
+                    info!("{}: started {} proxy on port {}", self.id, protocol, port);
+                    self.host.do_send(RegisterPort(self.id, protocol, Some(port)))
+                }
+                PolicyResponse::Stopped(protocol) => {
+                    info!("{}: stopped {} proxy", self.id, protocol);
+                    self.host.do_send(RegisterPort(self.id, protocol, None))
+                }
+                PolicyResponse::UpdatedPolicy(Some(service), protocol, version) => {
+                    info!(r#"{}: updated policy {} for service "{}""#, self.id, version, service);
+                    self.host
+                        .do_send(RegisterServiceVersion(self.id, service, protocol, version))
+                }
+                PolicyResponse::UpdatedPolicy(None, protocol, version) => {
+                    info!("{}: updated policy {}", self.id, version);
                     match protocol {
-                        Protocol::HTTP => self.host.do_send(RegisterHttpHash(self.id, hash)),
-                        Protocol::TCP => self.host.do_send(RegisterTcpHash(self.id, hash)),
+                        Protocol::HTTP => self.host.do_send(RegisterHttpVersion(self.id, version)),
+                        Protocol::TCP => self.host.do_send(RegisterTcpVersion(self.id, version)),
+                        Protocol::UDP => self.host.do_send(RegisterUdpVersion(self.id, version)),
                         Protocol::Phantom(_) => unreachable!()
                     }
                 }
                 PolicyResponse::RequestFailed => info!("{}: request failed", self.id),
+                PolicyResponse::Resume(resume) => {
+                    info!(r#"{}: resume with process "{}" {}"#, self.id, resume.label, resume.pid);
+                    self.host.do_send(ResumeProxy(self.id, resume))
+                }
+                PolicyResponse::Shadow(summary) => {
+                    debug!("{}: shadow summary", self.id);
+                    self.host.do_send(RegisterShadow(self.id, summary))
+                }
                 PolicyResponse::ShuttingDown => {
                     info!("{}: received shutdown", self.id);
                     self.host.do_send(Disconnect(self.id));
@@ -194,15 +415,40 @@ impl StreamHandler<Result<PolicyResponse, std::io::Error>> for ArmourDataInstanc
                     labels,
                     http,
                     tcp,
+                    udp,
+                    services,
                 } => {
                     info!(
-                        "{} {}:\n=== HTTP ===\n{}\n=== TCP ===\n{}\n=== Labels ===\n{:?}",
-                        self.id, label, http, tcp, labels
+                        "{} {}:\n=== HTTP ===\n{}\n=== TCP ===\n{}\n=== UDP ===\n{}\n=== Labels ===\n{:?}",
+                        self.id, label, http, tcp, udp, labels
                     );
-                    self.host
-                        .do_send(RegisterHttpHash(self.id, http.policy.blake3()));
-                    self.host
-                        .do_send(RegisterTcpHash(self.id, tcp.policy.blake3()))
+                    for (service, status) in services {
+                        info!(r#"{} {} service "{}":\n{}"#, self.id, label, service, status);
+                        for (protocol, status) in [
+                            (Protocol::HTTP, status.http),
+                            (Protocol::TCP, status.tcp),
+                            (Protocol::UDP, status.udp),
+                        ] {
+                            self.host.do_send(RegisterServiceVersion(
+                                self.id,
+                                service.clone(),
+                                protocol,
+                                PolicyVersion::new(status.version, &status.policy),
+                            ))
+                        }
+                    }
+                    self.host.do_send(RegisterHttpVersion(
+                        self.id,
+                        PolicyVersion::new(http.version, &http.policy),
+                    ));
+                    self.host.do_send(RegisterTcpVersion(
+                        self.id,
+                        PolicyVersion::new(tcp.version, &tcp.policy),
+                    ));
+                    self.host.do_send(RegisterUdpVersion(
+                        self.id,
+                        PolicyVersion::new(udp.version, &udp.policy),
+                    ))
                 }
             }
         } else {
@@ -26,6 +26,8 @@ pub mod commands;
 pub mod host;
 pub mod instance;
 pub mod rest_api;
+pub mod state;
+pub mod supervisor;
 
 fn string_from_bytes(b: bytes::Bytes) -> String {
     std::str::from_utf8(b.as_ref())
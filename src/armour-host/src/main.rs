@@ -32,8 +32,9 @@ use actix_web::{http, middleware, web, App, HttpServer};
 use armour_host::{
     commands::{run_command, run_script},
     control_plane,
-    host::{ArmourDataHost, Quit, UdsConnect},
+    host::{ArmourDataHost, Credentials, Quit, UdsConnect},
     rest_api,
+    state::HostState,
 };
 use armour_utils::parse_https_url;
 use clap::{crate_version, App as ClapApp, Arg};
@@ -100,6 +101,21 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .takes_value(true)
                 .help("Name of Armour host"),
         )
+        .arg(
+            Arg::with_name("credentials")
+                .long("credentials")
+                .required(false)
+                .takes_value(true)
+                .help("Credentials for onboarding (default: $ARMOUR_CREDENTIALS)"),
+        )
+        .arg(
+            Arg::with_name("cert credentials")
+                .long("cert-credentials")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("credentials")
+                .help("Onboard with credentials bound to the mTLS certificate"),
+        )
         .arg(
             Arg::with_name("script")
                 .short("r")
@@ -115,6 +131,13 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .takes_value(true)
                 .help("Port for HTTP interface"),
         )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .required(false)
+                .takes_value(true)
+                .help("State file, for re-attaching to proxies after a restart"),
+        )
         .arg(
             Arg::with_name("host socket")
                 .index(1)
@@ -154,6 +177,12 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .to_string();
     let unix_socket = std::fs::canonicalize(&unix_socket)
         .unwrap_or_else(|_| std::path::PathBuf::from(unix_socket));
+    let state = matches
+        .value_of("state")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| unix_socket.with_extension("state"));
+    // refuse to start (rather than lose track of proxies and keys) if the state cannot be read
+    let saved = HostState::load(&state)?;
 
     // TCP socket for REST interface
     let port = matches
@@ -177,12 +206,6 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .unwrap_or(armour_api::host::DATA_PLANE_HOST),
         8090,
     )?;
-    let onboard = armour_api::control::OnboardHostRequest {
-        host,
-        label: label.clone(),
-        credentials: String::new(),
-    };
-    let onboard_clone = onboard.clone();
 
     // HTTP clients
     let ca = matches
@@ -192,6 +215,26 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let certificate = matches
         .value_of("certificate")
         .unwrap_or("certificates/armour-host.p12");
+
+    let credentials = if matches.is_present("cert credentials") {
+        Credentials::Certificate {
+            password: certificate_password.to_string(),
+            certificate: certificate.into(),
+        }
+    } else {
+        matches
+            .value_of("credentials")
+            .map(String::from)
+            .or_else(|| env::var("ARMOUR_CREDENTIALS").ok())
+            .map(Credentials::Token)
+            .unwrap_or_default()
+    };
+    let onboard = armour_api::control::OnboardHostRequest {
+        host,
+        label: label.clone(),
+        credentials: credentials.present(&label),
+    };
+    let mut onboard_clone = onboard.clone();
     let client1 = armour_utils::client(&ca, &certificate_password, &certificate)?;
     let client2 = client1.clone();
     let client3 = armour_utils::client(&ca, &certificate_password, &certificate)?;
@@ -216,7 +259,13 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     };
 
     // start host actor, listening for connections on a Unix socket
+    let credentials_clone = credentials.clone();
     let unix_socket_clone = unix_socket.clone();
+    // remove a socket left behind by a host that did not shut down cleanly
+    if unix_socket.exists() && std::os::unix::net::UnixStream::connect(&unix_socket).is_err() {
+        log::info!("removing stale socket: {}", unix_socket.display());
+        std::fs::remove_file(&unix_socket)?
+    }
     let listener =
         Box::new(sys.block_on(async { tokio::net::UnixListener::bind(unix_socket_clone) })?);
     log::info!("started Data Host on socket: {}", unix_socket.display());
@@ -233,7 +282,10 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             onboarded,
             unix_socket,
             pass_key,
+            state,
+            saved,
         )
+        .with_credentials(credentials_clone)
     });
     let host_clone = host.clone();
 
@@ -256,14 +308,40 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             )
             .service(
                 web::scope("/host")
+                    .service(rest_api::host::audit)
+                    .service(rest_api::host::set_audit)
+                    .service(rest_api::host::keys)
+                    .service(rest_api::host::set_keys)
                     .service(rest_api::host::label)
-                    .service(rest_api::host::proxies),
+                    .service(rest_api::host::label_cache)
+                    .service(rest_api::host::set_label_cache)
+                    .service(rest_api::host::proxies)
+                    .service(rest_api::host::quit)
+                    .service(rest_api::host::set_shadow)
+                    .service(rest_api::host::shadow),
+            )
+            .service(
+                web::scope("/proxy")
+                    .service(rest_api::proxy::labels)
+                    .service(rest_api::proxy::launch)
+                    .service(rest_api::proxy::proxy_protocol)
+                    .service(rest_api::proxy::shutdown)
+                    .service(rest_api::proxy::start)
+                    .service(rest_api::proxy::status)
+                    .service(rest_api::proxy::stop)
+                    .service(rest_api::proxy::tcp_update)
+                    .service(rest_api::proxy::timeout)
+                    .service(rest_api::proxy::udp_timeout),
             )
             .service(
                 web::scope("/policy")
+                    .service(rest_api::policy::allow_all)
+                    .service(rest_api::policy::deny_all)
                     .service(rest_api::policy::query)
                     .service(rest_api::policy::update),
             )
+            .service(rest_api::metrics::metrics)
+            .service(rest_api::openapi::openapi)
     })
     .bind_openssl(tcp_socket, ssl_builder)?
     .run();
@@ -302,6 +380,8 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if onboarded {
         // start new Actix system for sending a "drop-host" message to control plane
         let mut sys = actix_rt::System::new("armour_host");
+        // certificate bound credentials are only valid for a short time
+        onboard_clone.credentials = credentials.present(&onboard_clone.label);
         if let Err(message) = sys.block_on(async move {
             control_plane(
                 client3,
@@ -64,7 +64,8 @@ pub mod service {
 				log::Level::Info
 			},
 			proxy.timeout,
-		))
+		)
+		.with_credentials(proxy.credentials.clone()))
 		.await?;
 		Ok(())
 	}
@@ -72,7 +73,7 @@ pub mod service {
 	async fn add_ip_labels(
 		host: &super::Host,
 		instance: &InstanceSelector,
-		ip_labels: &[(std::net::Ipv4Addr, Labels)],
+		ip_labels: &[(std::net::IpAddr, Labels)],
 	) -> Result<(), MailboxError> {
 		host.send(PolicyCommand::new_with_retry(
 			// retry needed in case proxy process is slow to start up
@@ -86,15 +87,15 @@ pub mod service {
 	async fn start_onboarding(
 		host: &super::Host,
 		instance: InstanceSelector,
-		ip_labels: &[(std::net::Ipv4Addr, Labels)],
+		ip_labels: &[(std::net::IpAddr, Labels)],
 	) -> Result<(), MailboxError> {
 		let mut ip_labels_h : HashMap<std::net::IpAddr, Labels>= HashMap::new();
 
 		for (ip, labels) in ip_labels {
-			if let Some(labels2) = ip_labels_h.get_mut(&std::net::IpAddr::from(ip.clone())) {
+			if let Some(labels2) = ip_labels_h.get_mut(ip) {
 				labels2.extend(labels.clone());
 			} else {
-				ip_labels_h.insert(std::net::IpAddr::from(ip.clone()), labels.clone());
+				ip_labels_h.insert(*ip, labels.clone());
 			}
 		}
 
@@ -160,8 +161,74 @@ pub mod service {
 }
 
 pub mod host {
-	use crate::host::List;
-	use actix_web::{get, web, HttpResponse};
+	use crate::host::{GetAudit, GetShadow, KeyOp, Keys, LabelCache, List, PolicyCommand, Quit};
+	use crate::instance::InstanceSelector;
+	use actix_web::{get, post, web, HttpResponse};
+	use armour_api::{
+		host::{AuditUpdate, KeysUpdate, ShadowUpdate},
+		proxy::{LabelCacheUpdate, PolicyRequest},
+	};
+	use std::convert::TryInto;
+
+	#[get("/audit")]
+	pub async fn audit(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(GetAudit(InstanceSelector::All)).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		Ok(HttpResponse::Ok().json2(&res))
+	}
+
+	#[post("/audit")]
+	pub async fn set_audit(
+		host: web::Data<super::Host>,
+		request: web::Json<AuditUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let instance = InstanceSelector::Label(request.label.clone());
+		let res = host
+			.send(PolicyCommand::new(instance, PolicyRequest::Audit(request.enabled)))
+			.await
+			.map_err(|err| {
+				log::warn!("{}", err);
+				HttpResponse::InternalServerError()
+			})?;
+		match res {
+			None => Ok(HttpResponse::Ok().finish()),
+			Some(err) => Ok(HttpResponse::BadRequest().body(err)),
+		}
+	}
+
+	#[get("/shadow")]
+	pub async fn shadow(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(GetShadow(InstanceSelector::All)).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		Ok(HttpResponse::Ok().json2(&res))
+	}
+
+	#[post("/shadow")]
+	pub async fn set_shadow(
+		host: web::Data<super::Host>,
+		request: web::Json<ShadowUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		let instance = InstanceSelector::Label(request.label);
+		let res = host
+			.send(PolicyCommand::new(
+				instance,
+				PolicyRequest::SetShadowPolicy(request.policy),
+			))
+			.await
+			.map_err(|err| {
+				log::warn!("{}", err);
+				HttpResponse::InternalServerError()
+			})?;
+		match res {
+			None => Ok(HttpResponse::Ok().finish()),
+			Some(err) => Ok(HttpResponse::BadRequest().body(err)),
+		}
+	}
 
 	#[get("/label")]
 	pub async fn label(label: web::Data<armour_lang::labels::Label>) -> HttpResponse {
@@ -175,6 +242,259 @@ pub mod host {
 		})?;
 		Ok(HttpResponse::Ok().json2(&*res))
 	}
+
+	#[get("/keys")]
+	pub async fn keys(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		update_keys(&host, None).await
+	}
+
+	#[post("/keys")]
+	pub async fn set_keys(
+		host: web::Data<super::Host>,
+		request: web::Json<KeysUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let op = match request.into_inner() {
+			KeysUpdate::Rotate(key) => {
+				let key = base64::decode(&key).ok().and_then(|key| key.as_slice().try_into().ok());
+				match key {
+					Some(key) => KeyOp::Rotate(key),
+					None => return Ok(HttpResponse::BadRequest().body("key must be 32 bytes (base64)")),
+				}
+			}
+			KeysUpdate::Remove(id) => KeyOp::Remove(id),
+			KeysUpdate::MaxAge(secs) => KeyOp::MaxAge(secs),
+			KeysUpdate::Legacy(enabled) => KeyOp::Legacy(enabled),
+		};
+		update_keys(&host, Some(op)).await
+	}
+
+	async fn update_keys(host: &super::Host, op: Option<KeyOp>) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(Keys(op)).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		match res {
+			Ok(ring) => Ok(HttpResponse::Ok().body(ring)),
+			Err(err) => Ok(HttpResponse::BadRequest().body(err)),
+		}
+	}
+
+	#[get("/label-cache")]
+	pub async fn label_cache(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		update_label_cache(&host, None).await
+	}
+
+	// a delta that does not apply to the current version is rejected with 409 (Conflict),
+	// in which case the control plane sends a full snapshot
+	#[post("/label-cache")]
+	pub async fn set_label_cache(
+		host: web::Data<super::Host>,
+		request: web::Json<LabelCacheUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		update_label_cache(&host, Some(request.into_inner())).await
+	}
+
+	async fn update_label_cache(
+		host: &super::Host,
+		update: Option<LabelCacheUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(LabelCache(update)).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		match res {
+			Ok(snapshot) => Ok(HttpResponse::Ok().json2(&snapshot)),
+			Err(err) => Ok(HttpResponse::Conflict().body(err)),
+		}
+	}
+
+	#[post("/quit")]
+	pub async fn quit(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		// proxies keep running and wait for a restarted host
+		host.send(Quit).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		Ok(HttpResponse::Ok().finish())
+	}
+}
+
+pub mod proxy {
+	use crate::host::{GetStatus, Launch, PolicyCommand};
+	use crate::instance::InstanceSelector;
+	use actix_web::{get, post, web, HttpResponse};
+	use armour_api::{
+		host::{
+			LabelUpdate, LaunchRequest, ProxyProtocolUpdate, StartRequest, StopRequest,
+			TcpUpdateRequest, TimeoutUpdate,
+		},
+		proxy::PolicyRequest,
+	};
+	use armour_lang::{labels::Label, policies::Protocol};
+
+	// send requests to the proxies with a label
+	async fn command(
+		host: &super::Host,
+		label: Label,
+		requests: Vec<PolicyRequest>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		for request in requests {
+			let instance = InstanceSelector::Label(label.clone());
+			let res = host
+				.send(PolicyCommand::new(instance, request))
+				.await
+				.map_err(|err| {
+					log::warn!("{}", err);
+					HttpResponse::InternalServerError()
+				})?;
+			if let Some(err) = res {
+				return Ok(HttpResponse::BadRequest().body(err));
+			}
+		}
+		Ok(HttpResponse::Ok().finish())
+	}
+
+	#[get("/status")]
+	pub async fn status(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(GetStatus(InstanceSelector::All)).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		Ok(HttpResponse::Ok().json2(&res))
+	}
+
+	#[post("/launch")]
+	pub async fn launch(
+		host: web::Data<super::Host>,
+		request: web::Json<LaunchRequest>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		let log = if request.debug {
+			log::Level::Debug
+		} else {
+			log::Level::Info
+		};
+		host.send(Launch::new(request.label, request.force, log, request.timeout))
+			.await
+			.map_err(|err| {
+				log::warn!("{}", err);
+				HttpResponse::InternalServerError()
+			})?;
+		Ok(HttpResponse::Ok().finish())
+	}
+
+	#[post("/shutdown")]
+	pub async fn shutdown(
+		host: web::Data<super::Host>,
+		request: web::Json<Label>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		command(&host, request.into_inner(), vec![PolicyRequest::Shutdown]).await
+	}
+
+	#[post("/start")]
+	pub async fn start(
+		host: web::Data<super::Host>,
+		request: web::Json<StartRequest>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		let start = match request.protocol {
+			Protocol::HTTP => PolicyRequest::StartHttp(request.http_config()),
+			Protocol::TCP => PolicyRequest::StartTcp(request.port),
+			Protocol::UDP => PolicyRequest::StartUdp(request.port),
+			Protocol::Phantom(_) => return Ok(HttpResponse::BadRequest().finish()),
+		};
+		command(&host, request.label, vec![start]).await
+	}
+
+	#[post("/stop")]
+	pub async fn stop(
+		host: web::Data<super::Host>,
+		request: web::Json<StopRequest>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		let stop = match request.protocol {
+			Some(protocol) => vec![PolicyRequest::Stop(protocol)],
+			None => vec![
+				PolicyRequest::Stop(Protocol::HTTP),
+				PolicyRequest::Stop(Protocol::TCP),
+				PolicyRequest::Stop(Protocol::UDP),
+			],
+		};
+		command(&host, request.label, stop).await
+	}
+
+	#[post("/timeout")]
+	pub async fn timeout(
+		host: web::Data<super::Host>,
+		request: web::Json<TimeoutUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		if request.secs > u8::MAX.into() {
+			return Ok(HttpResponse::BadRequest().body("timeout must be at most 255 seconds"));
+		}
+		command(&host, request.label, vec![PolicyRequest::Timeout(request.secs as u8)]).await
+	}
+
+	#[post("/udp-timeout")]
+	pub async fn udp_timeout(
+		host: web::Data<super::Host>,
+		request: web::Json<TimeoutUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		command(&host, request.label, vec![PolicyRequest::UdpTimeout(request.secs)]).await
+	}
+
+	#[post("/tcp-update")]
+	pub async fn tcp_update(
+		host: web::Data<super::Host>,
+		request: web::Json<TcpUpdateRequest>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		command(&host, request.label, vec![PolicyRequest::TcpUpdate(request.mode)]).await
+	}
+
+	#[post("/proxy-protocol")]
+	pub async fn proxy_protocol(
+		host: web::Data<super::Host>,
+		request: web::Json<ProxyProtocolUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		command(&host, request.label, vec![PolicyRequest::ProxyProtocol(request.config)]).await
+	}
+
+	#[post("/labels")]
+	pub async fn labels(
+		host: web::Data<super::Host>,
+		request: web::Json<LabelUpdate>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let request = request.into_inner();
+		command(&host, request.label, vec![PolicyRequest::Label(request.op)]).await
+	}
+}
+
+pub mod metrics {
+	use crate::host::GetMetrics;
+	use actix_web::{get, web, HttpResponse};
+
+	#[get("/metrics")]
+	pub async fn metrics(host: web::Data<super::Host>) -> Result<HttpResponse, actix_web::Error> {
+		let res = host.send(GetMetrics).await.map_err(|err| {
+			log::warn!("{}", err);
+			HttpResponse::InternalServerError()
+		})?;
+		Ok(HttpResponse::Ok()
+			.content_type("text/plain; version=0.0.4")
+			.body(res))
+	}
+}
+
+pub mod openapi {
+	use actix_web::{get, HttpResponse};
+
+	#[get("/openapi.json")]
+	pub async fn openapi() -> HttpResponse {
+		HttpResponse::Ok().json2(&armour_api::openapi::host_api())
+	}
 }
 
 pub mod policy {
@@ -182,7 +502,7 @@ pub mod policy {
 	use crate::instance::InstanceSelector;
 	use actix_web::{get, post, web, HttpResponse};
 	use armour_api::{host::PolicyUpdate, proxy::PolicyRequest};
-	use armour_lang::labels::Label;
+	use armour_lang::{labels::Label, policies::DPPolicies};
 
 	#[get("/query")]
 	pub async fn query(
@@ -207,7 +527,7 @@ pub mod policy {
 		let res = host
 			.send(PolicyCommand::new(
 				instance,
-				PolicyRequest::SetPolicy(request.policy.clone()),
+				PolicyRequest::SetPolicy(request.service.clone(), request.policy.clone()),
 			))
 			.await
 			.map_err(|err| {
@@ -219,5 +539,39 @@ pub mod policy {
 			Some(err) => Ok(HttpResponse::BadRequest().body(err)),
 		}
 	}
-}
 
+	#[post("/allow-all")]
+	pub async fn allow_all(
+		host: web::Data<super::Host>,
+		request: web::Json<Label>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		set_policy(&host, request.into_inner(), DPPolicies::allow_all()).await
+	}
+
+	#[post("/deny-all")]
+	pub async fn deny_all(
+		host: web::Data<super::Host>,
+		request: web::Json<Label>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		set_policy(&host, request.into_inner(), DPPolicies::deny_all()).await
+	}
+
+	async fn set_policy(
+		host: &super::Host,
+		label: Label,
+		policy: DPPolicies,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let instance = InstanceSelector::Label(label);
+		let res = host
+			.send(PolicyCommand::new(instance, PolicyRequest::SetPolicy(None, policy)))
+			.await
+			.map_err(|err| {
+				log::warn!("{}", err);
+				HttpResponse::InternalServerError()
+			})?;
+		match res {
+			None => Ok(HttpResponse::Ok().finish()),
+			Some(err) => Ok(HttpResponse::BadRequest().body(err)),
+		}
+	}
+}
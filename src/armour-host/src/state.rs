@@ -0,0 +1,183 @@
+//! Persistent host state, for re-attaching to running proxies after a restart
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::host::Launch;
+use super::instance::Instance;
+use armour_api::host::{Ports, PolicyVersion, ServiceVersions};
+use armour_api::proxy::{KeyRing, ServiceId};
+use armour_lang::{labels::Label, literals::DPID, policies::DPPolicies};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// State of the proxies launched by a host
+#[derive(Serialize, Deserialize, Default)]
+pub struct HostState {
+    pub proxies: Vec<ProxyState>,
+    #[serde(default)]
+    pub keys: Option<KeyRing>, // metadata keys, after any rotations
+}
+
+/// State of a proxy, including the policies that the host has sent to it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyState {
+    pub pid: u32,
+    pub label: Label,
+    pub tmp_dpid: Option<DPID>,
+    pub http: PolicyVersion,
+    pub tcp: PolicyVersion,
+    pub udp: PolicyVersion,
+    #[serde(default)]
+    pub services: Vec<(ServiceId, ServiceVersions)>,
+    #[serde(default)]
+    pub ports: Ports,
+    #[serde(default)]
+    pub policies: Vec<(Option<ServiceId>, DPPolicies)>, // proxy-wide (`None`) and per-service policies
+    #[serde(default)]
+    pub launch: Option<Launch>, // for restarting the proxy (if it was launched by the host)
+}
+
+impl ProxyState {
+    pub fn new(instance: &Instance) -> Option<Self> {
+        instance.meta.as_ref().map(|meta| ProxyState {
+            pid: meta.pid,
+            label: meta.label.clone(),
+            tmp_dpid: meta.tmp_dpid.clone(),
+            http: meta.http.clone(),
+            tcp: meta.tcp.clone(),
+            udp: meta.udp.clone(),
+            services: meta
+                .services
+                .iter()
+                .map(|(id, versions)| (id.clone(), versions.clone()))
+                .collect(),
+            ports: meta.ports.clone(),
+            policies: instance
+                .policies
+                .iter()
+                .map(|(id, policies)| (id.clone(), policies.clone()))
+                .collect(),
+            launch: None,
+        })
+    }
+    pub fn with_launch(mut self, launch: Option<Launch>) -> Self {
+        self.launch = launch;
+        self
+    }
+}
+
+impl HostState {
+    /// Read state from a file (a missing file gives the empty state). A file that cannot be read or
+    /// parsed is an error, rather than losing track of running proxies and rotated keys.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("state file {}: {}", path.display(), err),
+                )
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HostState::default()),
+            Err(err) => Err(err),
+        }
+    }
+    /// Write state to a file (replacing it atomically, once it is on disk). The file contains keys, so it is only readable by its owner.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        // make the rename durable
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        std::fs::File::open(dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("armour-host-state-{}.json", std::process::id()));
+        let label: Label = "Proxy::saved".parse().unwrap();
+        let mut keys = KeyRing::new([0; 32]);
+        keys.rotate([1; 32]);
+        let launch = Launch::new(label.clone(), false, log::Level::Debug, Some(10));
+        let proxy = ProxyState {
+            pid: 42,
+            label,
+            tmp_dpid: None,
+            http: PolicyVersion::default(),
+            tcp: PolicyVersion::default(),
+            udp: PolicyVersion::default(),
+            services: Vec::new(),
+            ports: Ports::default(),
+            policies: vec![(None, DPPolicies::allow_all())],
+            launch: Some(launch.clone()),
+        };
+        let state = HostState {
+            proxies: vec![proxy],
+            keys: Some(keys.clone()),
+        };
+        state.save(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = HostState::load(&path).unwrap();
+        assert!(loaded.keys == Some(keys));
+        assert_eq!(loaded.proxies.len(), 1);
+        let proxy = &loaded.proxies[0];
+        assert_eq!(proxy.pid, 42);
+        assert_eq!(proxy.policies.len(), 1);
+        assert_eq!(
+            serde_json::to_string(&proxy.launch).unwrap(),
+            serde_json::to_string(&Some(launch)).unwrap()
+        );
+        // state from before the key ring and launches were saved
+        std::fs::write(&path, r#"{"proxies": []}"#).unwrap();
+        assert!(HostState::load(&path).unwrap().keys.is_none());
+        // unparsable state is an error
+        std::fs::write(&path, "not json").unwrap();
+        assert!(HostState::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(HostState::load(&path).unwrap().proxies.is_empty());
+        // no temporary file is left behind
+        assert!(!path.with_extension("tmp").exists())
+    }
+}
@@ -0,0 +1,253 @@
+//! Supervision of proxy processes launched by a host
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::host::Launch;
+use super::instance::Meta;
+use armour_api::host::{ProcessState, ProxySummary};
+use armour_api::proxy::PolicyRequest;
+use armour_lang::labels::Label;
+use std::time::{Duration, Instant};
+
+/// How often the host checks for proxies that have exited
+pub const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+/// A proxy that exits more than this many times in quick succession is not restarted
+const MAX_CRASHES: u32 = 5;
+/// A proxy that runs for this long is considered stable (and its crash count is reset)
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long to wait for an adopted proxy to exit, once it has been asked to shut down
+const ADOPTED_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Proxy process, either launched by this host or adopted from a previous run of the host
+pub enum Process {
+    Child(std::process::Child),
+    Adopted(u32, Option<u64>), // PID and start time (so that a reused PID is not mistaken for the proxy)
+}
+
+// start time of a process (in clock ticks since boot), from /proc/<pid>/stat
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // fields after the command name (which is in parentheses and may contain spaces)
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+impl Process {
+    pub fn id(&self) -> u32 {
+        match self {
+            Process::Child(child) => child.id(),
+            Process::Adopted(pid, _) => *pid,
+        }
+    }
+    /// Adopt a running process
+    pub fn adopt(pid: u32) -> Self {
+        Process::Adopted(pid, start_time(pid))
+    }
+    /// Reason for exiting, if the process has exited
+    pub fn try_wait(&mut self) -> Option<String> {
+        match self {
+            Process::Child(child) => match child.try_wait() {
+                Ok(Some(status)) => Some(status.to_string()),
+                _ => None,
+            },
+            // adopted processes are reaped by init, so they have exited once they are gone from
+            // /proc (or their PID belongs to a process that started at a different time)
+            Process::Adopted(pid, started) => match start_time(*pid) {
+                Some(time) if Some(time) == *started => None,
+                _ => Some("exited".to_string()),
+            },
+        }
+    }
+    /// Wait for a process that has been asked to shut down
+    pub fn wait(&mut self) -> Option<String> {
+        match self {
+            Process::Child(child) => child.wait().ok().map(|status| status.to_string()),
+            Process::Adopted(..) => {
+                let start = Instant::now();
+                while start.elapsed() < ADOPTED_EXIT_TIMEOUT {
+                    if let Some(reason) = self.try_wait() {
+                        return Some(reason);
+                    }
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                log::warn!("proxy {} has not exited", self.id());
+                None
+            }
+        }
+    }
+}
+
+/// Proxy process launched by the host
+pub struct Supervised {
+    pub label: Label,
+    pub launch: Launch,           // for restarting the proxy
+    pub process: Option<Process>, // none while waiting to restart
+    pub stopping: bool,                       // the host asked the proxy to shut down
+    pub replay: Vec<PolicyRequest>, // requests to re-send once a restarted proxy connects
+    pub meta: Option<Meta>,         // last registration (for dropping from the control plane)
+    restarts: u32,
+    crashes: u32, // exits in quick succession
+    started: Instant,
+    last_exit: Option<String>,
+}
+
+impl Supervised {
+    pub fn new(label: Label, launch: Launch, process: std::process::Child) -> Self {
+        Supervised::supervise(label, launch, Process::Child(process))
+    }
+    /// Supervise a proxy that was launched before the host restarted
+    pub fn adopt(label: Label, launch: Launch, pid: u32) -> Self {
+        Supervised::supervise(label, launch, Process::adopt(pid))
+    }
+    fn supervise(label: Label, launch: Launch, process: Process) -> Self {
+        Supervised {
+            label,
+            launch,
+            process: Some(process),
+            stopping: false,
+            replay: Vec::new(),
+            meta: None,
+            restarts: 0,
+            crashes: 0,
+            started: Instant::now(),
+            last_exit: None,
+        }
+    }
+    pub fn pid(&self) -> Option<u32> {
+        self.process.as_ref().map(Process::id)
+    }
+    pub fn state(&self) -> ProcessState {
+        if self.process.is_some() {
+            ProcessState::Running
+        } else if self.crashes > MAX_CRASHES {
+            ProcessState::CrashLoop
+        } else {
+            ProcessState::Restarting
+        }
+    }
+    /// Record an unexpected exit (or failure to restart), returning the delay before restarting.
+    /// Returns `None` if the proxy is crash looping.
+    pub fn exited(&mut self, reason: String) -> Option<Duration> {
+        self.process = None;
+        self.last_exit = Some(reason);
+        if self.started.elapsed() > STABLE_AFTER {
+            self.crashes = 0
+        }
+        self.crashes += 1;
+        if self.crashes > MAX_CRASHES {
+            None
+        } else {
+            Some(std::cmp::min(MIN_BACKOFF * 2u32.pow(self.crashes - 1), MAX_BACKOFF))
+        }
+    }
+    pub fn restarted(&mut self, process: std::process::Child) {
+        self.process = Some(Process::Child(process));
+        self.restarts += 1;
+        self.started = Instant::now()
+    }
+    pub fn summary(&self) -> ProxySummary {
+        ProxySummary {
+            label: self.label.clone(),
+            pid: self.pid(),
+            state: self.state(),
+            restarts: self.restarts,
+            last_exit: self.last_exit.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervised() -> Supervised {
+        let label: Label = "Proxy::test".parse().unwrap();
+        let launch = Launch::new(label.clone(), false, log::Level::Info, None);
+        Supervised::adopt(label, launch, u32::MAX)
+    }
+
+    #[test]
+    fn backoff() {
+        let mut child = supervised();
+        let delays: Vec<Option<Duration>> = (0..MAX_CRASHES)
+            .map(|_| child.exited("exited".to_string()))
+            .collect();
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(delays, vec![secs(1), secs(2), secs(4), secs(8), secs(16)]);
+        assert_eq!(child.state(), ProcessState::Restarting);
+        assert_eq!(child.pid(), None);
+        assert!(delays.iter().all(|delay| *delay <= Some(MAX_BACKOFF)))
+    }
+
+    #[test]
+    fn crash_loop() {
+        let mut child = supervised();
+        for _ in 0..MAX_CRASHES {
+            assert!(child.exited("exited".to_string()).is_some())
+        }
+        assert_eq!(child.exited("crashed".to_string()), None);
+        assert_eq!(child.state(), ProcessState::CrashLoop);
+        let summary = child.summary();
+        assert_eq!(summary.state, ProcessState::CrashLoop);
+        assert_eq!(summary.last_exit.as_deref(), Some("crashed"));
+        assert_eq!(summary.restarts, 0)
+    }
+
+    #[test]
+    fn reset() {
+        let mut child = supervised();
+        for _ in 0..MAX_CRASHES {
+            child.exited("exited".to_string());
+        }
+        // a proxy that has run for long enough starts again with the minimum backoff
+        child.started = Instant::now() - STABLE_AFTER - Duration::from_secs(1);
+        assert_eq!(child.exited("exited".to_string()), Some(MIN_BACKOFF));
+        assert_eq!(child.state(), ProcessState::Restarting);
+        // a restarted proxy is running again
+        child.restarted(std::process::Command::new("true").spawn().unwrap());
+        assert_eq!(child.state(), ProcessState::Running);
+        assert_eq!(child.summary().restarts, 1);
+        if let Some(Process::Child(mut process)) = child.process.take() {
+            process.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn adopted() {
+        // a running process
+        let mut process = Process::adopt(std::process::id());
+        assert_eq!(process.try_wait(), None);
+        // a reused PID
+        let mut process = Process::Adopted(std::process::id(), Some(0));
+        assert_eq!(process.try_wait(), Some("exited".to_string()));
+        // a process that has gone
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let mut process = Process::adopt(child.id());
+        child.wait().unwrap();
+        assert!(process.wait().is_some())
+    }
+}
@@ -34,38 +34,78 @@ use std::fmt;
 use std::marker::PhantomData;
 use types::{CPFlatTyp, Typ, TTyp, FlatTyp, TFlatTyp};
 
+/// Coarse classification of errors (e.g. for metrics of failed policy evaluations)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Timeout,
+    External,
+    Type,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::External => "external",
+            ErrorKind::Type => "type",
+            ErrorKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Error(String);
+pub struct Error {
+    message: String,
+    kind: ErrorKind,
+}
 
 impl std::error::Error for Error {}
 
 impl std::convert::From<Error> for std::io::Error {
+    // `std::io::Error::other` needs Rust 1.74
+    #[allow(unknown_lints, clippy::io_other_error)]
     fn from(e: Error) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, e.0)
+        std::io::Error::new(std::io::ErrorKind::Other, e.message)
     }
 }
 
 impl Error {
     pub fn new<D: std::fmt::Display>(e: D) -> Error {
-        Error(e.to_string())
+        Error::with_kind(ErrorKind::Other, e)
+    }
+    pub fn with_kind<D: std::fmt::Display>(kind: ErrorKind, e: D) -> Error {
+        Error {
+            message: e.to_string(),
+            kind,
+        }
     }
     pub fn from_display<D: std::fmt::Display>(e: D) -> Error {
-        Error(e.to_string())
+        Error::new(e)
+    }
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
     }
     pub fn to_string(&self) -> String {
-        self.0.clone()
+        self.message.clone()
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message)
     }
 }
 
 impl From<String> for Error {
     fn from(s: String) -> Error {
-        Error(s)
+        Error::new(s)
     }
 }
 
@@ -83,7 +123,7 @@ impl From<regex::Error> for Error {
 
 impl<'a, FlatTyp:TFlatTyp> From<types::Error<FlatTyp>> for Error {
     fn from(err: types::Error<FlatTyp>) -> Error {
-        Error::new(err)
+        Error::with_kind(ErrorKind::Type, err)
     }
 }
 
@@ -452,6 +492,67 @@ where
             Expr::Phantom(_) => true
         }
     }
+    /// Collect the literal label arguments of `ID::has_label` calls
+    pub fn has_label_patterns(&self, patterns: &mut std::collections::BTreeSet<labels::Label>) {
+        match self {
+            Expr::Var(_) | Expr::BVar(_, _) | Expr::LitExpr(_) | Expr::Phantom(_) => (),
+            Expr::Closure(_, e) | Expr::ReturnExpr(e) | Expr::PrefixExpr(_, e) => e.has_label_patterns(patterns),
+            Expr::InfixExpr(_, e1, e2) | Expr::Let(_, e1, e2) => {
+                e1.has_label_patterns(patterns);
+                e2.has_label_patterns(patterns)
+            }
+            Expr::Iter(_, _, e1, e2, acc_opt) => {
+                e1.has_label_patterns(patterns);
+                e2.has_label_patterns(patterns);
+                if let Some((_, acc)) = acc_opt {
+                    acc.has_label_patterns(patterns)
+                }
+            }
+            Expr::BlockExpr(_, es) => es.iter().for_each(|e| e.has_label_patterns(patterns)),
+            Expr::IfExpr {
+                cond: expr,
+                consequence,
+                alternative,
+            }
+            | Expr::IfSomeMatchExpr {
+                expr,
+                consequence,
+                alternative,
+            } => {
+                expr.has_label_patterns(patterns);
+                consequence.has_label_patterns(patterns);
+                if let Some(e) = alternative {
+                    e.has_label_patterns(patterns)
+                }
+            }
+            Expr::IfMatchExpr {
+                variables: _,
+                matches,
+                consequence,
+                alternative,
+            } => {
+                matches.iter().for_each(|(e, _)| e.has_label_patterns(patterns));
+                consequence.has_label_patterns(patterns);
+                if let Some(e) = alternative {
+                    e.has_label_patterns(patterns)
+                }
+            }
+            Expr::CallExpr {
+                function,
+                arguments,
+                is_async: _,
+            } => {
+                if function == "ID::has_label" {
+                    if let Some(Expr::LitExpr(Literal::FlatLiteral(fl))) = arguments.get(1) {
+                        if fl.is_label() {
+                            patterns.insert(fl.get_label().clone());
+                        }
+                    }
+                }
+                arguments.iter().for_each(|e| e.has_label_patterns(patterns))
+            }
+        }
+    }
     pub fn var(v: &str) -> Self {
         Self::Var(parser::Ident(v.to_string()))
     }
@@ -86,7 +86,14 @@ where
 {
     type Result = ResponseFuture<Result<Expr<FlatTyp, FlatLiteral>, expressions::Error>>;
     fn handle(&mut self, call: Call<FlatTyp, FlatLiteral>, _ctx: &mut Context<Self>) -> Self::Result {
-        Box::pin(Externals::call(self.externals.clone(), call))
+        let path = call.path();
+        let now = std::time::Instant::now();
+        Box::pin(
+            Externals::call(self.externals.clone(), call).map(move |res| {
+                ExternalStats::record(&path, now.elapsed(), res.is_ok());
+                res
+            }),
+        )
     }
 }
 
@@ -104,13 +111,46 @@ impl ExternalActor {
 
 impl From<capnp::Error> for expressions::Error {
     fn from(err: capnp::Error) -> expressions::Error {
-        err.to_string().into()
+        expressions::Error::with_kind(expressions::ErrorKind::External, err)
     }
 }
 
 impl From<async_std::future::TimeoutError> for expressions::Error {
     fn from(_err: async_std::future::TimeoutError) -> expressions::Error {
-        "timeout error".to_string().into()
+        expressions::Error::with_kind(expressions::ErrorKind::Timeout, "timeout error")
+    }
+}
+
+/// Number of calls, number of failed calls and total call time (in seconds) for an external method
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ExternalStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub seconds: f64,
+}
+
+lazy_static::lazy_static! {
+    static ref EXTERNAL_STATS: std::sync::Mutex<BTreeMap<String, ExternalStats>> =
+        std::sync::Mutex::new(BTreeMap::new());
+}
+
+impl ExternalStats {
+    fn record(path: &str, elapsed: Duration, ok: bool) {
+        if let Ok(mut stats) = EXTERNAL_STATS.lock() {
+            let entry = stats.entry(path.to_string()).or_default();
+            entry.calls += 1;
+            if !ok {
+                entry.errors += 1
+            }
+            entry.seconds += elapsed.as_secs_f64()
+        }
+    }
+    /// Statistics for all external calls made by this process, indexed by `external::method`
+    pub fn snapshot() -> BTreeMap<String, ExternalStats> {
+        EXTERNAL_STATS
+            .lock()
+            .map(|stats| stats.clone())
+            .unwrap_or_default()
     }
 }
 
@@ -301,7 +341,9 @@ impl Externals {
     }
     pub async fn call<FlatTyp:TFlatTyp, FlatLiteral:TFlatLiteral<FlatTyp>+TExternals<FlatTyp, FlatLiteral>>(externals: Arc<Externals>, call: Call<FlatTyp, FlatLiteral>) -> Result<Expr<FlatTyp, FlatLiteral>, expressions::Error> {
         if let Some(socket) = externals.sockets.get(&call.external) {
-            let (client, disconnector) = Externals::client(socket).await?;
+            let (client, disconnector) = Externals::client(socket)
+                .await
+                .map_err(|err| expressions::Error::with_kind(expressions::ErrorKind::External, err))?;
             // prepare the RPC
             let mut req = client.call_request();
             let mut call_builder = req.get();
@@ -320,7 +362,10 @@ impl Externals {
                 Err(err) => Err(err.into()),
             }
         } else {
-            Err(format!("failed to get external: {}", call.path()).into())
+            Err(expressions::Error::with_kind(
+                expressions::ErrorKind::External,
+                format!("failed to get external: {}", call.path()),
+            ))
         }
     }
 }
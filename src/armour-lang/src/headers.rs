@@ -200,6 +200,8 @@ impl TBuiltin<FlatTyp> for FlatTyp {
             ),
             "data::to_base64" => sig(vec![FlatTyp::Data], FlatTyp::Str),
             "data::len" => sig(vec![FlatTyp::Data], FlatTyp::I64),
+            "data::json_mask" => sig(vec![FlatTyp::Data, FlatTyp::Str], FlatTyp::Data),
+            "data::protocol" => sig(vec![FlatTyp::Data], FlatTyp::Str),
             "i64::pow" => sig(vec![FlatTyp::I64, FlatTyp::I64], FlatTyp::I64),
             "i64::min" => sig(vec![FlatTyp::I64, FlatTyp::I64], FlatTyp::I64),
             "i64::max" => sig(vec![FlatTyp::I64, FlatTyp::I64], FlatTyp::I64),
@@ -442,6 +444,16 @@ impl<FlatTyp:TFlatTyp> TBuiltin<FlatTyp> for Typ<FlatTyp> {
             "ID::hosts" => sig(vec![Typ::id()], Typ::List(Box::new(Typ::str()))),
             "ID::ips" => sig(vec![Typ::id()], Typ::List(Box::new(Typ::ip_addr()))),
             "ID::port" => sig(vec![Typ::id()], Typ::i64().option()),
+            "data::http_request_line" => sig(
+                vec![Typ::data()],
+                Typ::Tuple(vec![Typ::str(), Typ::str(), Typ::str()]).option(),
+            ),
+            "data::postgres_startup" => sig(
+                vec![Typ::data()],
+                Typ::List(Box::new(Typ::Tuple(vec![Typ::str(), Typ::str()]))).option(),
+            ),
+            "data::ssh_banner" => sig(vec![Typ::data()], Typ::str().option()),
+            "data::tls_sni" => sig(vec![Typ::data()], Typ::str().option()),
             "Label::captures" => sig(
                 vec![Typ::label(), Typ::label()],
                 Typ::List(Box::new(Typ::Tuple(vec![Typ::str(), Typ::str()]))).option(),
@@ -497,6 +509,7 @@ impl TBuiltin<CPFlatTyp> for CPFlatTyp {
             "Primitive::allow_rest_request" => sig(vec![], CPTyp::FlatTyp(CPFlatTyp::Primitive)),
             "Primitive::allow_rest_response" => sig(vec![], CPTyp::FlatTyp(CPFlatTyp::Primitive)),
             "Primitive::allow_tcp_connection" => sig(vec![], CPTyp::FlatTyp(CPFlatTyp::Primitive)),
+            "Primitive::allow_udp_flow" => sig(vec![], CPTyp::FlatTyp(CPFlatTyp::Primitive)),
             "compile_egress" => sig(
                 vec![CPTyp::primitive(), CPTyp::id()], 
                 CPTyp::FlatTyp(CPFlatTyp::Policy)
@@ -25,7 +25,7 @@
 /// policy language interpreter
 // NOTE: no optimization
 use async_trait::async_trait;
-use super::expressions::{Block, Error, Expr, Pattern};
+use super::expressions::{Block, Error, ErrorKind, Expr, Pattern};
 use super::externals::{Call, ExternalActor};
 use super::headers::{Headers, THeaders};
 use super::labels::Label;
@@ -37,6 +37,7 @@ use super::literals::{
 };
 use super::meta::{Egress, IngressEgress, Meta};
 use super::parser::{As, Infix, Iter, Pat, PolicyRegex, Prefix};
+use super::sniff;
 use super::types::{self, CPFlatTyp, TFlatTyp};
 use actix::prelude::*;
 use futures::future::{BoxFuture, FutureExt};
@@ -300,6 +301,40 @@ impl TInterpret<types::FlatTyp, DPFlatLiteral> for DPFlatLiteral {
             ("str::to_base64", dpflatlit!(Str(s))) => Some(dplit!(Str(base64::encode(s)))),
             ("data::to_base64", dpflatlit!(Data(d))) => Some(dplit!(Str(base64::encode(d)))),
             ("data::len", dpflatlit!(Data(d))) => Some(dplit!(Int(d.len() as i64))),
+            ("data::protocol", dpflatlit!(Data(d))) => {
+                Some(dplit!(Str(sniff::protocol(d).unwrap_or_default().to_string())))
+            }
+            ("data::tls_sni", dpflatlit!(Data(d))) => Some(match sniff::tls_sni(d) {
+                Some(name) => dplit!(Str(name)).some(),
+                None => Literal::none(),
+            }),
+            ("data::http_request_line", dpflatlit!(Data(d))) => {
+                Some(match sniff::http_request_line(d) {
+                    Some((method, path, version)) => Literal::Tuple(vec![
+                        dplit!(Str(method)),
+                        dplit!(Str(path)),
+                        dplit!(Str(version)),
+                    ])
+                    .some(),
+                    None => Literal::none(),
+                })
+            }
+            ("data::ssh_banner", dpflatlit!(Data(d))) => Some(match sniff::ssh_banner(d) {
+                Some(banner) => dplit!(Str(banner)).some(),
+                None => Literal::none(),
+            }),
+            ("data::postgres_startup", dpflatlit!(Data(d))) => {
+                Some(match sniff::postgres_startup(d) {
+                    Some(params) => Literal::List(
+                        params
+                            .into_iter()
+                            .map(|(k, v)| Literal::Tuple(vec![dplit!(Str(k)), dplit!(Str(v))]))
+                            .collect(),
+                    )
+                    .some(),
+                    None => Literal::none(),
+                })
+            }
             ("HttpRequest::connection", dpflatlit!(HttpRequest(req))) => Some(req.connection()),
             ("HttpRequest::from", dpflatlit!(HttpRequest(req))) => Some(req.from_lit()),
             ("HttpRequest::to", dpflatlit!(HttpRequest(req))) => Some(req.to_lit()),
@@ -390,6 +425,9 @@ impl TInterpret<types::FlatTyp, DPFlatLiteral> for DPFlatLiteral {
             ("str::contains", dpflatlit!(Str(i)), dpflatlit!(Str(j))) => {
                 Some(dplit!(Bool(i.contains(j))))
             }
+            ("data::json_mask", dpflatlit!(Data(d)), dpflatlit!(Str(key))) => {
+                Some(dplit!(Data(json_mask(d, key))))
+            }
             (
                 "HttpRequest::set_path", 
                 dpflatlit!(HttpRequest(req)), 
@@ -564,6 +602,32 @@ impl TInterpret<types::FlatTyp, DPFlatLiteral> for DPFlatLiteral {
     }
 }
 
+// mask every value of a JSON object field (at any depth); data that is not JSON is unchanged
+fn json_mask(data: &[u8], key: &str) -> Vec<u8> {
+    fn mask(v: &mut serde_json::Value, key: &str) {
+        match v {
+            serde_json::Value::Object(map) => {
+                for (k, v) in map.iter_mut() {
+                    if k == key {
+                        *v = serde_json::Value::String("****".to_string())
+                    } else {
+                        mask(v, key)
+                    }
+                }
+            }
+            serde_json::Value::Array(vs) => vs.iter_mut().for_each(|v| mask(v, key)),
+            _ => (),
+        }
+    }
+    match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(mut v) => {
+            mask(&mut v, key);
+            serde_json::to_vec(&v).unwrap_or_else(|_| data.to_vec())
+        }
+        Err(_) => data.to_vec(),
+    }
+}
+
 #[async_trait]
 impl TInterpret<CPFlatTyp, CPFlatLiteral> for CPFlatLiteral {
     fn eval_prefix(
@@ -857,26 +921,26 @@ where
         match args.as_slice() {
             [] => match Literal::eval_call0(function) {
                 Some(r) => Ok(r.into()),
-                None => Err(Error::new("eval, call(0): type error")),
+                None => Err(Error::with_kind(ErrorKind::Type, "eval, call(0): type error")),
             },
             [Expr::LitExpr(l1)] => match l1.eval_call1(&function) {
                 Some(r) => Ok(r.into()),
-                None => Err(Error::new("eval, call(1): type error")),
+                None => Err(Error::with_kind(ErrorKind::Type, "eval, call(1): type error")),
             },
             [Expr::LitExpr(l1), Expr::LitExpr(l2)] => match l1.eval_call2(&function, l2) {
                 Some(r) => Ok(r.into()),
-                None => Err(Error::new("eval, call(2): type error")),
+                None => Err(Error::with_kind(ErrorKind::Type, "eval, call(2): type error")),
             },
             [Expr::LitExpr(l1), Expr::LitExpr(l2), Expr::LitExpr(l3)] => {
                 match l1.eval_call3(&function, l2, l3) {
                     Some(r) => Ok(r.into()),
-                    None => Err(Error::new("eval, call(3): type error")),
+                    None => Err(Error::with_kind(ErrorKind::Type, "eval, call(3): type error")),
                 }
             }
             [Expr::LitExpr(l1), Expr::LitExpr(l2), Expr::LitExpr(l3), Expr::LitExpr(l4)] => {
                 match l1.eval_call4(&function, l2, l3, l4) {
                     Some(r) => Ok(r.into()),
-                    None => Err(Error::new("eval, call(4): type error")),
+                    None => Err(Error::with_kind(ErrorKind::Type, "eval, call(4): type error")),
                 }
             }
             x => Err(Error::from(format!("eval, call ({}): {}: {:?}", x.len(), function, x))),
@@ -900,7 +964,7 @@ where
                     r @ Expr::ReturnExpr(_) => Ok(r),
                     Expr::LitExpr(l) => match l.eval_prefix(&p) {
                         Some(r) => Ok(r.into()),
-                        None => Err(Error::new("eval prefix: type error")),
+                        None => Err(Error::with_kind(ErrorKind::Type, "eval prefix: type error")),
                     },
                     _ => Err(Error::new("eval, prefix")),
                 },
@@ -933,7 +997,7 @@ where
                         (_, r @ Expr::ReturnExpr(_)) => Ok(r),
                         (Expr::LitExpr(l1), Expr::LitExpr(l2)) => match l1.eval_infix(&op, &l2) {
                             Some(r) => Ok(r.into()),
-                            None => Err(Error::new("eval, infix: type error")),
+                            None => Err(Error::with_kind(ErrorKind::Type, "eval, infix: type error")),
                         },
                         _ => Err(Error::new("eval, infix: failed")),
                     }
@@ -1140,7 +1204,7 @@ where
                         if let Some(r) = Self::eval(e, state.clone(), env.clone()).await?.perform_match(re) {
                             rs.push(r)
                         } else {
-                            return Err(Error::new("eval, if-match-expression: type error"));
+                            return Err(Error::with_kind(ErrorKind::Type, "eval, if-match-expression: type error"));
                         }
                     }
                     match rs.iter().find(|(r, _captures)| r.is_return()) {
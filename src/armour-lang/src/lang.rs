@@ -27,7 +27,7 @@ use super::{
     externals,
     expressions::{DPExpr, Error, Expr},
     headers::{Headers, DPHeaders, THeaders},
-    lexer,
+    labels, lexer,
     literals::{self, TFlatLiteral, CPFlatLiteral},
     parser::{self, TParser},
     types::{self, CPFlatTyp, TFlatTyp}
@@ -161,6 +161,14 @@ where
     pub fn is_empty(&self) -> bool {
         self.code.0.is_empty()
     }
+    /// Labels that the program tests identities against using `ID::has_label`
+    pub fn has_label_patterns(&self) -> std::collections::BTreeSet<labels::Label> {
+        let mut patterns = std::collections::BTreeSet::new();
+        for e in self.code.0.values() {
+            e.has_label_patterns(&mut patterns)
+        }
+        patterns
+    }
     pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
         Ok(PreProgram::from_file(path)?.program(&[]))
     }
@@ -57,6 +57,8 @@ pub mod parser;
 pub mod policies;
 /// Pretty-printer
 pub mod pretty;
+/// Protocol sniffers for TCP payloads
+pub mod sniff;
 /// Type system
 pub mod types;
  
@@ -853,10 +853,29 @@ impl Primitive {
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Credentials {
     value: String,
+    //outcome of checking the credentials with the control plane verifiers
+    #[serde(default)]
+    verified: bool,
+    //identity established by the verifiers, i.e., AuthToken::<name>
+    #[serde(default)]
+    identity: Option<labels::Label>,
 }
 impl Credentials {
     pub fn new(value: String) -> Self {
-        Credentials{ value }
+        Credentials{ value, verified: false, identity: None }
+    }
+    //credentials that have been verified (the secret itself is not kept)
+    pub fn verified(identity: Option<labels::Label>) -> Self {
+        Credentials{ value: String::new(), verified: true, identity }
+    }
+    pub fn rejected() -> Self {
+        Credentials{ value: String::new(), verified: false, identity: None }
+    }
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+    pub fn identity(&self) -> Option<&labels::Label> {
+        self.identity.as_ref()
     }
 }
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -924,6 +943,9 @@ impl OnboardingData {
     pub fn has_ip(&self, ip: &std::net::IpAddr) -> bool {
         self.ips.iter().any(|x| x == ip)
     }
+    pub fn ips(&self) -> &BTreeSet<std::net::IpAddr> {
+        &self.ips
+    }
     pub fn service_lit(&self) -> CPLiteral {
         cpdplit!(Label(self.service.clone()))
     }
@@ -1998,6 +2020,21 @@ where
     }
 }
 
+impl<FlatTyp, FlatLiteral> std::convert::TryFrom<Literal<FlatTyp, FlatLiteral>> for Vec<u8>
+where
+    FlatTyp:TFlatTyp,
+    FlatLiteral:TFlatLiteral<FlatTyp>
+{
+    type Error = ();
+    fn try_from(l: Literal<FlatTyp, FlatLiteral>) -> Result<Vec<u8>, Self::Error> {
+        if l.is_data() {
+            Ok(l.get_data())
+        } else {
+            Err(())
+        }
+    }
+}
+
 impl<FlatTyp, FlatLiteral> std::convert::TryFrom<Literal<FlatTyp, FlatLiteral>> for () 
 where
     FlatTyp:TFlatTyp,
@@ -26,7 +26,7 @@
 use super::{
     expressions,
     headers::THeaders,
-    lang,
+    labels, lang,
     literals::{self, TFlatLiteral},
     types::{self, CPSignature, Signature, FlatTyp, Typ, TFlatTyp, TTyp},
 };
@@ -65,6 +65,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
+                .filter(|x| !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Allow))
                 .collect(),
         )
@@ -73,7 +74,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
-                .filter(|x| is_egress(x))
+                .filter(|x| is_egress(x) && !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Allow))
                 .collect(),
         )
@@ -82,7 +83,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
-                .filter(|x| is_ingress(x))
+                .filter(|x| is_ingress(x) && !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Allow))
                 .collect(),
         )
@@ -91,6 +92,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
+                .filter(|x| !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Deny))
                 .collect(),
         )
@@ -99,7 +101,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
-                .filter(|x| is_egress(x))
+                .filter(|x| is_egress(x) && !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Deny))
                 .collect(),
         )
@@ -108,7 +110,7 @@ impl FnPolicies {
         FnPolicies(
             names
                 .iter()
-                .filter(|x| is_ingress(x))
+                .filter(|x| is_ingress(x) && !is_optional(x))
                 .map(|name| (name.to_string(), FnPolicy::Deny))
                 .collect(),
         )
@@ -160,6 +162,13 @@ impl<FlatTyp:TFlatTyp> ProtocolPolicy<FlatTyp> {
             .collect();
         self.insert(name, sigs)
     }
+    fn insert_data(&mut self, name: &str, args: Vec<Vec<Typ<FlatTyp>>>) {
+        let sigs = args
+            .into_iter()
+            .map(|v| Signature::new(v, Typ::data()))
+            .collect();
+        self.insert(name, sigs)
+    }
     fn insert_unit(&mut self, name: &str, args: Vec<Vec<Typ<FlatTyp>>>) {
         let sigs = args
             .into_iter()
@@ -171,17 +180,42 @@ impl<FlatTyp:TFlatTyp> ProtocolPolicy<FlatTyp> {
 
 pub const ALLOW_REST_REQUEST: &str = "allow_rest_request";
 pub const ALLOW_REST_RESPONSE: &str = "allow_rest_response";
+pub const REWRITE_REST_REQUEST: &str = "rewrite_rest_request";
+pub const REWRITE_REST_RESPONSE: &str = "rewrite_rest_response";
+pub const ON_HTTP_COMPLETE: &str = "on_http_complete";
 pub const ALLOW_TCP_CONNECTION: &str = "allow_tcp_connection";
+pub const ALLOW_TCP_PAYLOAD: &str = "allow_tcp_payload";
 pub const ON_TCP_DISCONNECT: &str = "on_tcp_disconnect";
+pub const ALLOW_UDP_FLOW: &str = "allow_udp_flow";
+pub const ALLOW_UDP_DATAGRAM: &str = "allow_udp_datagram";
+pub const ON_POLICY_ERROR: &str = "on_policy_error";
 
 fn is_ingress(function: &String) -> bool {
-    ALLOW_REST_REQUEST == function || ALLOW_TCP_CONNECTION == function
+    ALLOW_REST_REQUEST == function
+        || ALLOW_TCP_CONNECTION == function
+        || ALLOW_TCP_PAYLOAD == function
+        || ALLOW_UDP_FLOW == function
+        || ALLOW_UDP_DATAGRAM == function
 }
 
 fn is_egress(function: &String) -> bool {
     ALLOW_REST_RESPONSE == function 
 }
 
+// Functions added to the protocol tables after their original release. A policy only has an
+// entry for one of these if its program defines it, so that the encoding (and hash) of policies
+// that do not use them is unchanged. Without an entry, they are allowed (i.e. not evaluated).
+const OPTIONAL_FUNCTIONS: [&str; 4] = [
+    REWRITE_REST_REQUEST,
+    REWRITE_REST_RESPONSE,
+    ON_HTTP_COMPLETE,
+    ALLOW_TCP_PAYLOAD,
+];
+
+fn is_optional(function: &str) -> bool {
+    OPTIONAL_FUNCTIONS.contains(&function)
+}
+
 fn http_policy<FlatTyp:TFlatTyp>() -> ProtocolPolicy<FlatTyp> {      
     let mut policy = ProtocolPolicy::default();
     policy.insert_bool(
@@ -202,6 +236,30 @@ fn http_policy<FlatTyp:TFlatTyp>() -> ProtocolPolicy<FlatTyp> {
             Vec::new(),
         ],
     );
+    // new payloads for allowed requests and responses
+    policy.insert_data(
+        REWRITE_REST_REQUEST,
+        vec![vec![Typ::http_request(), Typ::data()]],
+    );
+    policy.insert_data(
+        REWRITE_REST_RESPONSE,
+        vec![vec![Typ::http_response(), Typ::data()]],
+    );
+    // after the response has been delivered (latency in ms, request and response payload bytes)
+    policy.insert_unit(
+        ON_HTTP_COMPLETE,
+        vec![
+            vec![
+                Typ::http_request(),
+                Typ::http_response(),
+                Typ::i64(),
+                Typ::i64(),
+                Typ::i64(),
+            ],
+            vec![Typ::http_request(), Typ::http_response()],
+            Vec::new(),
+        ],
+    );
     policy
 }
 
@@ -215,6 +273,15 @@ fn tcp_policy<FlatTyp:TFlatTyp>() -> ProtocolPolicy<FlatTyp> {
             Vec::new()
         ],
     );
+    policy.insert_bool(
+        ALLOW_TCP_PAYLOAD,
+        vec![
+            vec![Typ::id(), Typ::id(), Typ::connection(), Typ::data()],
+            vec![Typ::connection(), Typ::data()],
+            vec![Typ::data()],
+            Vec::new()
+        ],
+    );
     policy.insert_unit(
         ON_TCP_DISCONNECT,
         vec![
@@ -237,11 +304,43 @@ fn tcp_policy<FlatTyp:TFlatTyp>() -> ProtocolPolicy<FlatTyp> {
     policy
 }
 
+fn udp_policy<FlatTyp:TFlatTyp>() -> ProtocolPolicy<FlatTyp> {
+    let mut policy = ProtocolPolicy::default();
+    policy.insert_bool(
+        ALLOW_UDP_FLOW,
+        vec![
+            vec![Typ::id(), Typ::id(), Typ::connection()],//TODO should only be valid for global policy
+            vec![Typ::connection()],
+            Vec::new()
+        ],
+    );
+    policy.insert_bool(
+        ALLOW_UDP_DATAGRAM,
+        vec![
+            vec![Typ::id(), Typ::id(), Typ::connection(), Typ::data()],//TODO should only be valid for global policy
+            vec![Typ::connection(), Typ::data()],
+            vec![Typ::data()],
+            Vec::new()
+        ],
+    );
+    policy
+}
+
+// fallback for failed evaluations (shared by all protocols)
+fn on_policy_error_sigs<FlatTyp:TFlatTyp>() -> Vec<Signature<FlatTyp>> {
+    vec![
+        Signature::new(vec![Typ::str()], Typ::bool()),
+        Signature::new(Vec::new(), Typ::bool()),
+    ]
+}
+
 lazy_static! {
     static ref CP_HTTP_POLICY: CPProtocolPolicy = http_policy();
     static ref HTTP_POLICY: DPProtocolPolicy = http_policy();
     static ref CP_TCP_POLICY: CPProtocolPolicy = tcp_policy();
     static ref TCP_POLICY: DPProtocolPolicy = tcp_policy();
+    static ref CP_UDP_POLICY: CPProtocolPolicy = udp_policy();
+    static ref UDP_POLICY: DPProtocolPolicy = udp_policy();
 }
 
 
@@ -249,6 +348,7 @@ lazy_static! {
 pub enum Protocol<FlatTyp:TFlatTyp, FlatLiteral:TFlatLiteral<FlatTyp>> {
     HTTP,
     TCP,
+    UDP,
     Phantom(PhantomData<(FlatTyp, FlatLiteral)>)
 }
 
@@ -259,12 +359,9 @@ where
 {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
-            (Self::HTTP, Self::TCP) => Some(Ordering::Less),
-            (Self::TCP, Self::TCP) => Some(Ordering::Equal),
-            (Self::HTTP, Self::HTTP) => Some(Ordering::Equal),
-            (Self::TCP, Self::HTTP) => Some(Ordering::Greater),
             (Self::Phantom(_), _) => Some(Ordering::Less),
             (_, Self::Phantom(_)) => Some(Ordering::Greater),
+            _ => self.rank().partial_cmp(&other.rank()),
         }
     }
 }
@@ -299,6 +396,7 @@ impl From<CPProtocol> for DPProtocol {
         match p {
             CPProtocol::HTTP => Protocol::HTTP,
             CPProtocol::TCP => Protocol::TCP,
+            CPProtocol::UDP => Protocol::UDP,
             CPProtocol::Phantom(_) => Protocol::Phantom(PhantomData)
         }
     } 
@@ -309,6 +407,7 @@ impl TProtocol<FlatTyp, Self> for literals::FlatLiteral {
         match p {
             Protocol::HTTP => &*HTTP_POLICY,
             Protocol::TCP => &*TCP_POLICY,
+            Protocol::UDP => &*UDP_POLICY,
             Protocol::Phantom(_) => unreachable!(), 
         }
     }
@@ -318,6 +417,7 @@ impl TProtocol<types::CPFlatTyp, Self> for literals::CPFlatLiteral {
         match p {
             Protocol::HTTP => &*CP_HTTP_POLICY,
             Protocol::TCP => &*CP_TCP_POLICY,
+            Protocol::UDP => &*CP_UDP_POLICY,
             Protocol::Phantom(_) => unreachable!(), 
         }
     }
@@ -333,6 +433,15 @@ where
     fn policy(&self) -> &ProtocolPolicy<FlatTyp> {
         FlatLiteral::policy(self)
     }
+    // position of the protocol in the `Ord` instance
+    fn rank(&self) -> u8 {
+        match self {
+            Protocol::HTTP => 0,
+            Protocol::TCP => 1,
+            Protocol::UDP => 2,
+            Protocol::Phantom(_) => unreachable!(),
+        }
+    }
 }
 
 impl<FlatTyp, FlatLiteral> fmt::Display for Protocol<FlatTyp, FlatLiteral>
@@ -344,6 +453,7 @@ where
         match self {
             Protocol::HTTP => write!(f, "http"),
             Protocol::TCP => write!(f, "tcp"),
+            Protocol::UDP => write!(f, "udp"),
             Protocol::Phantom(_) => unreachable!()
         }
     }
@@ -359,15 +469,55 @@ where
         match s.to_lowercase().as_str() {
             "tcp" => Ok(Protocol::TCP),
             "http" => Ok(Protocol::HTTP),
+            "udp" => Ok(Protocol::UDP),
             _ => Err(format!("failed to parse protocol: {}", s)),
         }
     }
 }
 
+// what a proxy does when the evaluation of a policy function fails
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum OnError {
+    FailClosed,
+    FailOpen,
+    Fallback(u8), // call `on_policy_error` (with given number of args)
+}
+
+impl Default for OnError {
+    fn default() -> Self {
+        OnError::FailClosed
+    }
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnError::FailClosed => write!(f, "fail-closed"),
+            OnError::FailOpen => write!(f, "fail-open"),
+            OnError::Fallback(_) => write!(f, "{}", ON_POLICY_ERROR),
+        }
+    }
+}
+
+impl FromStr for OnError {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail-closed" | "closed" => Ok(OnError::FailClosed),
+            "fail-open" | "open" => Ok(OnError::FailOpen),
+            _ => Err(format!("failed to parse error strategy: {}", s)),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Policy<FlatTyp:TFlatTyp, FlatLiteral:TFlatLiteral<FlatTyp>> {
     pub program: lang::Program<FlatTyp, FlatLiteral>,
     pub fn_policies: FnPolicies,
+    // not part of the (bincode) encoding, so that hashes and stored policies are unchanged:
+    // the fallback is re-derived from the program and explicit strategies are carried by `Policies`
+    #[serde(skip)]
+    pub on_error: OnError,
 }
 pub type DPPolicy = Policy<types::FlatTyp, literals::DPFlatLiteral>;
 pub type GlobalPolicy = Policy<types::CPFlatTyp, literals::CPFlatLiteral>;
@@ -376,7 +526,8 @@ impl From<GlobalPolicy> for DPPolicy {
     fn from(gps: GlobalPolicy) -> Self {
         DPPolicy {
             program: lang::DPProgram::from(gps.program),
-            fn_policies: gps.fn_policies
+            fn_policies: gps.fn_policies,
+            on_error: gps.on_error,
         }
     }
 }
@@ -391,6 +542,7 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn allow_egress(p: Protocol<FlatTyp, FlatLiteral>) -> Self {
@@ -398,6 +550,7 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn allow_ingress(p: Protocol<FlatTyp, FlatLiteral>) -> Self {
@@ -405,6 +558,7 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn deny_all(p: Protocol<FlatTyp, FlatLiteral>) -> Self {
@@ -412,6 +566,7 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn deny_egress(p: Protocol<FlatTyp, FlatLiteral>) -> Self {
@@ -419,6 +574,7 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn deny_ingress(p: Protocol<FlatTyp, FlatLiteral>) -> Self {
@@ -426,16 +582,25 @@ where
         Policy {
             program: lang::Program::default(),
             fn_policies,
+            on_error: OnError::default(),
         }
     }
     pub fn merge(&self, other: &Self) -> Self{
         Policy{
             program: self.program.merge(&other.program),
-            fn_policies: self.fn_policies.merge(&other.fn_policies)
+            fn_policies: self.fn_policies.merge(&other.fn_policies),
+            on_error: if self.on_error == OnError::default() {
+                other.on_error
+            } else {
+                self.on_error
+            },
         }
     }
     pub fn get(&self, name: &str) -> Option<&FnPolicy> {
-        self.fn_policies.0.get(name)
+        match self.fn_policies.0.get(name) {
+            None if is_optional(name) => Some(&FnPolicy::Allow),
+            fn_policy => fn_policy,
+        }
     }
     pub fn is_allow_all(&self) -> bool {
         self.fn_policies.is_allow_all()
@@ -516,26 +681,62 @@ where
                 } else {
                     log::warn!("failed to get arg count: {}", function);
                 }
-            } else {
+            } else if !is_optional(function) {
                 log::warn!("not present: {}", function);
                 fn_policies.0.insert(function.to_string(), FnPolicy::Allow);
             }
         }
+        if let Some(sig) = program.headers.typ(ON_POLICY_ERROR) {
+            if !on_policy_error_sigs()
+                .iter()
+                .any(|sig_typ| Self::type_check(ON_POLICY_ERROR, &sig, sig_typ))
+            {
+                return Err(expressions::Error::new(format!(
+                    r#"possible types for function "{}" are: (str) -> bool; () -> bool"#,
+                    ON_POLICY_ERROR
+                )));
+            }
+        }
+        let on_error = Self::derived_on_error(&program);
         Ok(Policy {
             program,
             fn_policies,
+            on_error,
         })
     }
+    // error strategy implied by the program: call `on_policy_error` if it is defined
+    fn derived_on_error(program: &lang::Program<FlatTyp, FlatLiteral>) -> OnError {
+        if let Some(sig) = program.headers.typ(ON_POLICY_ERROR) {
+            OnError::Fallback(sig.args().map(|v| v.len() as u8).unwrap_or_default())
+        } else {
+            OnError::default()
+        }
+    }
+    // error strategy that was set explicitly, i.e. that cannot be derived from the program
+    fn explicit_on_error(&self) -> Option<OnError> {
+        match self.on_error {
+            OnError::Fallback(_) => None,
+            on_error if on_error != Self::derived_on_error(&self.program) => Some(on_error),
+            _ => None,
+        }
+    }
+    fn with_derived_on_error(mut self) -> Self {
+        self.on_error = Self::derived_on_error(&self.program);
+        self
+    }
+    pub fn set_on_error(&mut self, on_error: OnError) {
+        self.on_error = on_error
+    }
 }
 
 impl DPPolicy {
     fn from_bincode<R: std::io::Read>(r: R) -> Result<Self, std::io::Error> {
-        armour_utils::bincode_gz_base64_dec(r)
+        armour_utils::bincode_gz_base64_dec(r).map(Self::with_derived_on_error)
     }
 }
 impl GlobalPolicy {
     fn from_bincode<R: std::io::Read>(r: R) -> Result<Self, std::io::Error> {
-        armour_utils::bincode_gz_base64_dec(r)
+        armour_utils::bincode_gz_base64_dec(r).map(Self::with_derived_on_error)
     }
 }
 
@@ -564,6 +765,9 @@ where
             }
 
             writeln!(f, "[{}]", self.blake3())?;
+            if self.on_error != OnError::default() {
+                writeln!(f, "on error: {}", self.on_error)?;
+            }
             write!(f, "{}", self.program)
         }
     }
@@ -625,6 +829,7 @@ where
         let mut policies = Policies::default();
         let tcp: Protocol<FlatTyp, FlatLiteral> = Protocol::TCP;
         let http: Protocol<FlatTyp, FlatLiteral> = Protocol::HTTP;
+        let udp: Protocol<FlatTyp, FlatLiteral> = Protocol::UDP;
         policies
             .0
             .insert(Protocol::TCP, Policy::allow_all(tcp));
@@ -632,11 +837,15 @@ where
             .0
             .insert(Protocol::HTTP, Policy::allow_all(http));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::allow_all(udp));
+        policies
     }
     pub fn allow_egress() -> Self {
         let mut policies = Policies::default();
         let tcp: Protocol<FlatTyp, FlatLiteral> = Protocol::TCP;
         let http: Protocol<FlatTyp, FlatLiteral> = Protocol::HTTP;
+        let udp: Protocol<FlatTyp, FlatLiteral> = Protocol::UDP;
         policies
             .0
             .insert(Protocol::TCP, Policy::allow_egress(tcp));
@@ -644,11 +853,15 @@ where
             .0
             .insert(Protocol::HTTP, Policy::allow_egress(http));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::allow_egress(udp));
+        policies
     }
     pub fn allow_ingress() -> Self {
         let mut policies = Policies::default();
         let tcp: Protocol<FlatTyp, FlatLiteral> = Protocol::TCP;
         let http: Protocol<FlatTyp, FlatLiteral> = Protocol::HTTP;
+        let udp: Protocol<FlatTyp, FlatLiteral> = Protocol::UDP;
         policies
             .0
             .insert(Protocol::TCP, Policy::allow_ingress(tcp));
@@ -656,6 +869,9 @@ where
             .0
             .insert(Protocol::HTTP, Policy::allow_ingress(http));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::allow_ingress(udp));
+        policies
     }
     pub fn deny_all() -> Self {
         let mut policies = Policies::default();
@@ -666,6 +882,9 @@ where
             .0
             .insert(Protocol::HTTP, Policy::deny_all(Protocol::HTTP));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::deny_all(Protocol::UDP));
+        policies
     }
     pub fn deny_egress() -> Self {
         let mut policies = Policies::default();
@@ -676,6 +895,9 @@ where
             .0
             .insert(Protocol::HTTP, Policy::deny_egress(Protocol::HTTP));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::deny_egress(Protocol::UDP));
+        policies
     }
     pub fn deny_ingress() -> Self {
         let mut policies = Policies::default();
@@ -686,6 +908,9 @@ where
             .0
             .insert(Protocol::HTTP, Policy::deny_ingress(Protocol::HTTP));
         policies
+            .0
+            .insert(Protocol::UDP, Policy::deny_ingress(Protocol::UDP));
+        policies
     }
     pub fn is_allow_all(&self) -> bool {
         self.0.values().all(|p| p.is_allow_all())
@@ -717,27 +942,70 @@ where
     pub fn policies_mut(&mut self) -> std::collections::btree_map::IterMut<Protocol<FlatTyp, FlatLiteral>, Policy<FlatTyp, FlatLiteral>> {
         (&mut self.0).iter_mut()
     }
+    // labels needed to evaluate `ID::has_label` calls in the protocol policies
+    pub fn has_label_patterns(&self) -> std::collections::BTreeSet<labels::Label> {
+        self.0
+            .values()
+            .flat_map(|p| p.program.has_label_patterns())
+            .collect()
+    }
+    // hash of the protocol policies (for comparing installed policies)
+    pub fn blake3(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for (protocol, policy) in self.0.iter() {
+            hasher.update(format!("{}:{};", protocol, policy.blake3()).as_bytes());
+            if let Some(on_error) = policy.explicit_on_error() {
+                hasher.update(format!("on_error:{};", on_error).as_bytes());
+            }
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    // program for a protocol (including the error fallback), if the protocol has any functions
+    fn protocol_program(
+        pre_prog: &lang::PreProgram<FlatTyp, FlatLiteral>,
+        p: &Protocol<FlatTyp, FlatLiteral>,
+    ) -> Option<lang::Program<FlatTyp, FlatLiteral>> {
+        let mut functions = p.functions();
+        if pre_prog.program(&functions).is_empty() {
+            None
+        } else {
+            functions.push(ON_POLICY_ERROR.to_string());
+            Some(pre_prog.program(&functions))
+        }
+    }
 
     fn inner_from(pre_prog: lang::PreProgram<FlatTyp, FlatLiteral>) -> Result<Self, expressions::Error> {
         let mut policies = Policies::default();
         let http : Protocol<FlatTyp, FlatLiteral> = Protocol::HTTP;
         let tcp : Protocol<FlatTyp, FlatLiteral> = Protocol::TCP;
-        let http_prog = pre_prog.program(&http.functions());
-        if !http_prog.is_empty() {
+        if let Some(http_prog) = Self::protocol_program(&pre_prog, &http) {
             policies.0.insert(
                 Protocol::HTTP,
                 Policy::from_program(http_prog, http.policy())?,
             );
         }
-        let tcp_prog = pre_prog.program(&tcp.functions());
-        if !tcp_prog.is_empty() {
+        if let Some(tcp_prog) = Self::protocol_program(&pre_prog, &tcp) {
             policies.0.insert(
                 Protocol::TCP,
                 Policy::from_program(tcp_prog, tcp.policy())?,
             );
         }
+        let udp : Protocol<FlatTyp, FlatLiteral> = Protocol::UDP;
+        if let Some(udp_prog) = Self::protocol_program(&pre_prog, &udp) {
+            policies.0.insert(
+                Protocol::UDP,
+                Policy::from_program(udp_prog, udp.policy())?,
+            );
+        }
         Ok(policies)
     }
+    /// Set the error strategy for all protocols (overrides any `on_policy_error` fallback)
+    pub fn set_on_error(&mut self, on_error: OnError) {
+        for policy in self.0.values_mut() {
+            policy.set_on_error(on_error)
+        }
+    }
 
     pub fn from_buf(buf: &str) -> Result<Self, expressions::Error> {
         Self::inner_from(lang::PreProgram::from_buf(buf)?)
@@ -765,6 +1033,12 @@ where
         } else {
             writeln!(f, "-")?
         }
+        write!(f, "UDP: ")?;
+        if let Some(policy) = self.policy(Protocol::UDP) {
+            writeln!(f, "{}", policy)?
+        } else {
+            writeln!(f, "-")?
+        }
         write!(f, "Phantom: ")?;
         if let Some(policy) = self.policy(Protocol::Phantom(PhantomData)) {
             writeln!(f, "{}", policy)?
@@ -786,15 +1060,29 @@ where
     {
         let mut map = serializer.serialize_map(Some(self.0.len()))?;
         for (proto, policy) in self.0.iter() {
-            let bincode_policy = policy
+            let mut bincode_policy = policy
                 .to_bincode()
                 .map_err(|_| serde::ser::Error::custom("failed to convert policy to bincode"))?;
+            // an explicit error strategy follows the encoded policy (`;` is not used by base64)
+            if let Some(on_error) = policy.explicit_on_error() {
+                bincode_policy.push_str(&format!("{}{}", ON_ERROR_SEPARATOR, on_error))
+            }
             map.serialize_entry(proto, &bincode_policy)?;
         }
         map.end()
     }
 }
 
+const ON_ERROR_SEPARATOR: char = ';';
+
+// split an encoded policy into the bincode and the (optional) explicit error strategy
+fn split_on_error(s: &str) -> Result<(&str, Option<OnError>), String> {
+    let mut parts = s.splitn(2, ON_ERROR_SEPARATOR);
+    let bincode_policy = parts.next().unwrap_or_default();
+    let on_error = parts.next().map(|on_error| on_error.parse()).transpose()?;
+    Ok((bincode_policy, on_error))
+}
+
 struct DPPoliciesVisitor {} 
 struct CPPoliciesVisitor {} 
 
@@ -812,8 +1100,13 @@ impl<'de> Visitor<'de> for CPPoliciesVisitor {
         let mut map : GlobalPolicies = Policies::default();
 
         while let Some((proto, bincode_policy)) = access.next_entry::<CPProtocol, String>()? {
-            let policy = GlobalPolicy::from_bincode(bincode_policy.as_bytes())
+            let (bincode_policy, on_error) = split_on_error(&bincode_policy)
+                .map_err(serde::de::Error::custom)?;
+            let mut policy = GlobalPolicy::from_bincode(bincode_policy.as_bytes())
                 .map_err(|_| serde::de::Error::custom("failed to read policy from bincode"))?;
+            if let Some(on_error) = on_error {
+                policy.set_on_error(on_error)
+            }
             map.insert(proto, policy);
         }
 
@@ -834,8 +1127,13 @@ impl<'de> Visitor<'de> for DPPoliciesVisitor {
         let mut map : DPPolicies = Policies::default();
 
         while let Some((proto, bincode_policy)) = access.next_entry::<DPProtocol, String>()? {
-            let policy = DPPolicy::from_bincode(bincode_policy.as_bytes())
+            let (bincode_policy, on_error) = split_on_error(&bincode_policy)
+                .map_err(serde::de::Error::custom)?;
+            let mut policy = DPPolicy::from_bincode(bincode_policy.as_bytes())
                 .map_err(|_| serde::de::Error::custom("failed to read policy from bincode"))?;
+            if let Some(on_error) = on_error {
+                policy.set_on_error(on_error)
+            }
             map.insert(proto, policy);
         }
 
@@ -900,6 +1198,7 @@ impl OnboardingPolicy {
         Ok(OnboardingPolicy {
             fn_policies: FnPolicies::default(),
             program: prog,
+            on_error: OnError::default(),
         })
     }
 
@@ -915,6 +1214,7 @@ impl OnboardingPolicy {
         Ok(OnboardingPolicy {
             fn_policies: FnPolicies::default(),
             program: p,
+            on_error: OnError::default(),
         })
     }
 }
@@ -924,5 +1224,6 @@ lazy_static! {
     static ref ONBOARDING_SERVICES_POLICY: OnboardingPolicy = OnboardingPolicy {
         fn_policies: FnPolicies::default(),
         program: lang::CPProgram::default(),
+        on_error: OnError::default(),
     };
 }
\ No newline at end of file
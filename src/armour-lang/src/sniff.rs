@@ -0,0 +1,215 @@
+//! Protocol sniffers, for inspecting the first bytes sent by a TCP client
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+// read big-endian integers and byte strings from a slice
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            None
+        } else {
+            let (head, tail) = self.0.split_at(n);
+            self.0 = tail;
+            Some(head)
+        }
+    }
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| (b[0] as usize) << 8 | b[1] as usize)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    // a byte string prefixed by its length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n)
+    }
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n)
+    }
+}
+
+const POSTGRES_PROTOCOL_3: u32 = 196_608;
+const POSTGRES_SSL_REQUEST: u32 = 80_877_103;
+const POSTGRES_GSSENC_REQUEST: u32 = 80_877_104;
+
+/// Guess the protocol: `tls`, `http`, `ssh` or `postgres`
+pub fn protocol(data: &[u8]) -> Option<&'static str> {
+    if is_tls(data) {
+        Some("tls")
+    } else if http_request_line(data).is_some() {
+        Some("http")
+    } else if data.starts_with(b"SSH-") {
+        Some("ssh")
+    } else if is_postgres(data) {
+        Some("postgres")
+    } else {
+        None
+    }
+}
+
+// TLS handshake record containing a ClientHello
+fn is_tls(data: &[u8]) -> bool {
+    matches!(data, [0x16, 0x03, _, _, _, 0x01, ..])
+}
+
+fn is_postgres(data: &[u8]) -> bool {
+    let mut cursor = Cursor(data);
+    match (cursor.u32(), cursor.u32()) {
+        (Some(len), Some(code)) => len >= 8 && (code == POSTGRES_PROTOCOL_3 || code == POSTGRES_SSL_REQUEST || code == POSTGRES_GSSENC_REQUEST),
+        _ => false,
+    }
+}
+
+/// Server name indication (SNI) of a TLS ClientHello
+pub fn tls_sni(data: &[u8]) -> Option<String> {
+    if !is_tls(data) {
+        return None;
+    }
+    let mut cursor = Cursor(data);
+    // record header, handshake type and length, client version and random
+    cursor.take(5 + 4 + 2 + 32)?;
+    cursor.vec8()?; // session ID
+    cursor.vec16()?; // cipher suites
+    cursor.vec8()?; // compression methods
+    let mut extensions = Cursor(cursor.vec16()?);
+    while let Some(typ) = extensions.u16() {
+        let extension = extensions.vec16()?;
+        if typ == 0 {
+            let mut names = Cursor(Cursor(extension).vec16()?);
+            while let Some(name_type) = names.u8() {
+                let name = names.vec16()?;
+                if name_type == 0 {
+                    return std::str::from_utf8(name).ok().map(|s| s.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+// first line of the data (without line ending)
+fn first_line(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|b| *b == b'\n')?;
+    std::str::from_utf8(&data[..end]).ok().map(|s| s.trim_end_matches('\r'))
+}
+
+/// Method, path and version of an HTTP/1 request line
+pub fn http_request_line(data: &[u8]) -> Option<(String, String, String)> {
+    match first_line(data)?.split(' ').collect::<Vec<&str>>().as_slice() {
+        [method, path, version] if !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase()) && version.starts_with("HTTP/1.") => {
+            Some((method.to_string(), path.to_string(), version.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// SSH identification string (e.g. `SSH-2.0-OpenSSH_8.2`)
+pub fn ssh_banner(data: &[u8]) -> Option<String> {
+    if data.starts_with(b"SSH-") {
+        first_line(data).map(|s| s.to_string())
+    } else {
+        None
+    }
+}
+
+/// Parameters (e.g. `user` and `database`) of a Postgres startup message
+pub fn postgres_startup(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut cursor = Cursor(data);
+    let len = cursor.u32()? as usize;
+    if cursor.u32()? != POSTGRES_PROTOCOL_3 || len < 8 {
+        return None;
+    }
+    let body = cursor.take(len - 8)?;
+    let mut strings = body.split(|b| *b == 0).map(|s| String::from_utf8_lossy(s).to_string());
+    let mut params = Vec::new();
+    while let (Some(key), Some(value)) = (strings.next(), strings.next()) {
+        if key.is_empty() {
+            break;
+        }
+        params.push((key, value))
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls() {
+        let name = b"example.com";
+        // server name extension
+        let mut ext = vec![0, 0, 0, name.len() as u8 + 5, 0, name.len() as u8 + 3, 0, 0];
+        ext.push(name.len() as u8);
+        ext.extend_from_slice(name);
+        // handshake body: version, random, session id, ciphers, compression, extensions
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 1, 1, 0, 0, ext.len() as u8]);
+        body.extend(ext);
+        let mut hello = vec![0x16, 3, 1, 0, body.len() as u8 + 4, 1, 0, 0, body.len() as u8];
+        hello.extend(body);
+        assert_eq!(protocol(&hello), Some("tls"));
+        assert_eq!(tls_sni(&hello), Some("example.com".to_string()));
+        assert_eq!(tls_sni(&hello[..20]), None)
+    }
+    #[test]
+    fn postgres() {
+        let params = b"user\0alice\0database\0db\0\0";
+        let mut msg = (params.len() as u32 + 8).to_be_bytes().to_vec();
+        msg.extend_from_slice(&POSTGRES_PROTOCOL_3.to_be_bytes());
+        msg.extend_from_slice(params);
+        assert_eq!(protocol(&msg), Some("postgres"));
+        assert_eq!(
+            postgres_startup(&msg),
+            Some(vec![("user".to_string(), "alice".to_string()), ("database".to_string(), "db".to_string())])
+        );
+        assert_eq!(postgres_startup(&msg[..12]), None)
+    }
+    #[test]
+    fn http() {
+        let req = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(protocol(req), Some("http"));
+        assert_eq!(
+            http_request_line(req),
+            Some(("GET".to_string(), "/index.html".to_string(), "HTTP/1.1".to_string()))
+        );
+        assert_eq!(http_request_line(b"get / HTTP/1.1\r\n"), None);
+        assert_eq!(http_request_line(b"GET / HTTP/1.1"), None)
+    }
+    #[test]
+    fn ssh() {
+        let banner = b"SSH-2.0-OpenSSH_8.2\r\n";
+        assert_eq!(protocol(banner), Some("ssh"));
+        assert_eq!(ssh_banner(banner), Some("SSH-2.0-OpenSSH_8.2".to_string()));
+        assert_eq!(protocol(b"hello"), None)
+    }
+}
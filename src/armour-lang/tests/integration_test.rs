@@ -215,6 +215,252 @@ mod tests_dplang {
         assert_eq!( format!("{}", res), "true");
     }
 
+    #[actix_rt::test]
+    async fn test_udp_policy() -> Result<(),  expressions::Error> {
+        let policies = DPPolicies::from_buf("
+        fn allow_udp_flow(c: Connection) -> bool { true }
+        fn allow_udp_datagram(c: Connection, d: data) -> bool { true }
+        ")?;
+        let policy = policies.policy(Protocol::UDP).unwrap();
+        assert_eq!(policy.get(policies::ALLOW_UDP_FLOW), Some(&FnPolicy::Args(1)));
+        assert_eq!(policy.get(policies::ALLOW_UDP_DATAGRAM), Some(&FnPolicy::Args(2)));
+        assert!(policies.policy(Protocol::TCP).is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_rewrite_policy() -> Result<(),  expressions::Error> {
+        let policies = DPPolicies::from_buf(r#"
+        fn allow_rest_response(res: HttpResponse) -> bool { true }
+        fn rewrite_rest_response(res: HttpResponse, payload: data) -> data {
+            data::json_mask(payload, "card")
+        }
+        "#)?;
+        let policy = policies.policy(Protocol::HTTP).unwrap();
+        assert_eq!(policy.get(policies::REWRITE_REST_RESPONSE), Some(&FnPolicy::Args(2)));
+        assert_eq!(policy.get(policies::REWRITE_REST_REQUEST), Some(&FnPolicy::Allow));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_on_http_complete() -> Result<(),  expressions::Error> {
+        let policies = DPPolicies::from_buf(r#"
+        external logger @ "log_sock" {
+          fn log(_) -> ()
+        }
+        fn on_http_complete(req: HttpRequest, res: HttpResponse, ms: i64, sent: i64, received: i64) async {
+            logger::log(ms)
+        }
+        "#)?;
+        let policy = policies.policy(Protocol::HTTP).unwrap();
+        assert_eq!(policy.get(policies::ON_HTTP_COMPLETE), Some(&FnPolicy::Args(5)));
+        assert_eq!(policy.get(policies::ALLOW_REST_REQUEST), Some(&FnPolicy::Allow));
+        // policies that do not define the hook are encoded (and hashed) as before it was added
+        let without = DPPolicies::from_buf(r#"
+        fn allow_rest_request(req: HttpRequest) -> bool { true }
+        "#)?;
+        let without = without.policy(Protocol::HTTP).unwrap();
+        assert!(!without.fn_policies.0.contains_key(policies::ON_HTTP_COMPLETE));
+        assert_eq!(without.get(policies::ON_HTTP_COMPLETE), Some(&FnPolicy::Allow));
+        let mut with = without.clone();
+        with.fn_policies.set_args(policies::ON_HTTP_COMPLETE.to_string(), 2);
+        assert_ne!(with.blake3(), without.blake3());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_on_policy_error() -> Result<(),  expressions::Error> {
+        let mut policies = DPPolicies::from_buf(r#"
+        fn allow_rest_request(req: HttpRequest) -> bool { true }
+        fn on_policy_error(err: str) -> bool { err == "timeout error" }
+        "#)?;
+        assert_eq!(policies.len(), 1);
+        let policy = policies.policy(Protocol::HTTP).unwrap();
+        assert_eq!(policy.on_error, policies::OnError::Fallback(1));
+        policies.set_on_error(policies::OnError::FailOpen);
+        assert_eq!(policies.policy(Protocol::HTTP).unwrap().on_error, policies::OnError::FailOpen);
+        let policies = DPPolicies::from_buf(r#"
+        fn allow_tcp_connection(c: Connection) -> bool { true }
+        "#)?;
+        assert_eq!(policies.policy(Protocol::TCP).unwrap().on_error, policies::OnError::FailClosed);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_on_error_encoding() -> Result<(),  expressions::Error> {
+        // the encoding (and so the hash) of a policy does not depend on its error strategy
+        #[derive(serde::Serialize)]
+        struct PolicyV0<'a> {
+            program: &'a armour_lang::lang::DPProgram,
+            fn_policies: &'a FnPolicies,
+        }
+        let mut policies = DPPolicies::from_buf(r#"
+        fn allow_rest_request(req: HttpRequest) -> bool { true }
+        fn on_policy_error() -> bool { true }
+        "#)?;
+        let policy = policies.policy(Protocol::HTTP).unwrap();
+        let v0 = PolicyV0 { program: &policy.program, fn_policies: &policy.fn_policies };
+        assert_eq!(bincode::serialize(policy).unwrap(), bincode::serialize(&v0).unwrap());
+        let hash = policies.blake3();
+        // the fallback is re-derived from the program
+        let json = serde_json::to_string(&policies).unwrap();
+        assert!(!json.contains(';'));
+        let decoded: DPPolicies = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.policy(Protocol::HTTP).unwrap().on_error, policies::OnError::Fallback(0));
+        assert_eq!(decoded.blake3(), hash);
+        // explicit strategies are preserved
+        policies.set_on_error(policies::OnError::FailOpen);
+        assert_ne!(policies.blake3(), hash);
+        let json = serde_json::to_string(&policies).unwrap();
+        let decoded: DPPolicies = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.policy(Protocol::HTTP).unwrap().on_error, policies::OnError::FailOpen);
+        assert_eq!(decoded.blake3(), policies.blake3());
+        policies.set_on_error(policies::OnError::FailClosed);
+        let json = serde_json::to_string(&policies).unwrap();
+        let decoded: DPPolicies = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.policy(Protocol::HTTP).unwrap().on_error, policies::OnError::FailClosed);
+        // an unknown strategy is an error
+        let json = json.replace("fail-closed", "fail-sometimes");
+        assert!(serde_json::from_str::<DPPolicies>(&json).is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_optional_functions_encoding() -> Result<(),  expressions::Error> {
+        // functions added to the protocol tables do not change the encoding (or hash) of
+        // policies that do not define them
+        #[derive(serde::Serialize)]
+        struct PolicyV0<'a> {
+            program: &'a armour_lang::lang::DPProgram,
+            fn_policies: &'a FnPolicies,
+        }
+        fn fn_policies(entries: &[(&str, FnPolicy)]) -> FnPolicies {
+            FnPolicies(entries.iter().map(|(k, v)| (k.to_string(), v.clone())).collect())
+        }
+        let policies = DPPolicies::from_buf(r#"
+        fn allow_rest_request(req: HttpRequest) -> bool { true }
+        fn allow_tcp_connection(c: Connection) -> bool { true }
+        "#)?;
+        let http = policies.policy(Protocol::HTTP).unwrap();
+        let expected = fn_policies(&[
+            (policies::ALLOW_REST_REQUEST, FnPolicy::Args(1)),
+            (policies::ALLOW_REST_RESPONSE, FnPolicy::Allow),
+        ]);
+        assert_eq!(http.fn_policies, expected);
+        let v0 = PolicyV0 { program: &http.program, fn_policies: &expected };
+        assert_eq!(bincode::serialize(http).unwrap(), bincode::serialize(&v0).unwrap());
+        let tcp = policies.policy(Protocol::TCP).unwrap();
+        assert_eq!(tcp.fn_policies, fn_policies(&[
+            (policies::ALLOW_TCP_CONNECTION, FnPolicy::Args(1)),
+            (policies::ON_TCP_DISCONNECT, FnPolicy::Allow),
+        ]));
+        // undefined optional functions are allowed (i.e. not evaluated)
+        for function in &[policies::REWRITE_REST_REQUEST, policies::ON_HTTP_COMPLETE] {
+            assert_eq!(http.get(function), Some(&FnPolicy::Allow))
+        }
+        assert_eq!(tcp.get(policies::ALLOW_TCP_PAYLOAD), Some(&FnPolicy::Allow));
+        // the same holds for the built-in policies
+        let deny = DPPolicies::deny_all();
+        assert_eq!(deny.policy(Protocol::HTTP).unwrap().fn_policies, fn_policies(&[
+            (policies::ALLOW_REST_REQUEST, FnPolicy::Deny),
+            (policies::ALLOW_REST_RESPONSE, FnPolicy::Deny),
+        ]));
+        assert_eq!(deny.policy(Protocol::TCP).unwrap().fn_policies, fn_policies(&[
+            (policies::ALLOW_TCP_CONNECTION, FnPolicy::Deny),
+            (policies::ON_TCP_DISCONNECT, FnPolicy::Deny),
+        ]));
+        assert!(deny.is_deny_all());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(expressions::Error::new("failed").kind(), expressions::ErrorKind::Other);
+        // type errors are classified by kind, not by their message
+        let err = expressions::Error::from(DPError::Parse("int".to_string()));
+        assert_eq!(err.kind(), expressions::ErrorKind::Type);
+        assert_eq!(err.kind().as_str(), "type");
+        let err = expressions::Error::new("type error in a message");
+        assert_eq!(err.kind(), expressions::ErrorKind::Other);
+        let err = expressions::Error::with_kind(expressions::ErrorKind::Timeout, "slow");
+        assert_eq!(err.to_string(), "slow");
+        assert_eq!(err.kind().as_str(), "timeout");
+    }
+
+    #[actix_rt::test]
+    async fn test_json_mask() -> () {
+        let res = eval_expr(r#"
+        let d = data::json_mask(str::as_bytes("{\"card\": \"4111\", \"items\": [{\"card\": 1}]}"), "card");
+        str::from_utf8(d) == "{\"card\":\"****\",\"items\":[{\"card\":\"****\"}]}"
+        "#).await;
+        assert_eq!( format!("{}", res), "true");
+    }
+
+    #[actix_rt::test]
+    async fn test_tcp_payload_policy() -> Result<(),  expressions::Error> {
+        let policies = DPPolicies::from_buf(r#"
+        fn allow_tcp_payload(c: Connection, d: data) -> bool {
+            data::protocol(d) == "ssh"
+        }
+        "#)?;
+        let policy = policies.policy(Protocol::TCP).unwrap();
+        assert_eq!(policy.get(policies::ALLOW_TCP_PAYLOAD), Some(&FnPolicy::Args(2)));
+        assert_eq!(policy.get(policies::ALLOW_TCP_CONNECTION), Some(&FnPolicy::Allow));
+        // policies that do not check payloads are encoded (and hashed) as before
+        let without = DPPolicies::from_buf(r#"
+        fn allow_tcp_connection(c: Connection) -> bool { true }
+        "#)?;
+        let without = without.policy(Protocol::TCP).unwrap();
+        assert!(!without.fn_policies.0.contains_key(policies::ALLOW_TCP_PAYLOAD));
+        assert_eq!(without.get(policies::ALLOW_TCP_PAYLOAD), Some(&FnPolicy::Allow));
+        let mut with = without.clone();
+        with.fn_policies.set_args(policies::ALLOW_TCP_PAYLOAD.to_string(), 1);
+        assert_ne!(with.blake3(), without.blake3());
+        // ...including the built-in policies
+        let allow = DPPolicies::allow_all();
+        let allow = allow.policy(Protocol::TCP).unwrap();
+        assert!(!allow.fn_policies.0.contains_key(policies::ALLOW_TCP_PAYLOAD));
+        assert_eq!(allow.get(policies::ALLOW_TCP_PAYLOAD), Some(&FnPolicy::Allow));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_sniffers() -> () {
+        let res = eval_expr("
+        let ssh = b\"SSH-2.0-OpenSSH_8.2\r\n\";
+        let http = b\"GET /index.html HTTP/1.1\r\nHost: x\r\n\r\n\";
+        data::protocol(ssh) == \"ssh\" &&
+        data::protocol(http) == \"http\" &&
+        option::is_none(data::tls_sni(http)) &&
+        if let Some(banner) = data::ssh_banner(ssh) {
+            banner == \"SSH-2.0-OpenSSH_8.2\"
+        } else {
+            false
+        } &&
+        if let Some(line) = data::http_request_line(http) {
+            line.0 == \"GET\" && line.1 == \"/index.html\"
+        } else {
+            false
+        }
+        ").await;
+        assert_eq!( format!("{}", res), "true");
+    }
+
+    #[actix_rt::test]
+    async fn test_has_label_patterns() -> Result<(),  expressions::Error> {
+        let policies = DPPolicies::from_buf(r#"
+        fn allow_rest_request(from: ID, to: ID, req: HttpRequest, payload: data) -> bool {
+            to.has_label('Tier::backend') && (from.has_label('Team::<<team>>') || req.method() == "GET")
+        }
+        fn allow_tcp_connection(c: Connection) -> bool {
+            c.from().has_label('Tier::frontend')
+        }
+        "#)?;
+        let patterns: Vec<String> = policies.has_label_patterns().iter().map(|l| l.to_string()).collect();
+        assert_eq!(patterns, vec!["Team::<<team>>", "Tier::backend", "Tier::frontend"]);
+        Ok(())
+    }
+
 }
 
 mod tests_cplang {
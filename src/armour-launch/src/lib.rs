@@ -203,23 +203,33 @@ pub async fn set_ip_addresses(information: &mut OnboardInfo) {
             println!("warn: {}", e)
         } else {
             match docker.inspect_container(&name).await {
-                Ok(container) => info.ipv4_address = get_ip_address(container),
+                Ok(container) => {
+                    let (ipv4_address, ipv6_address) = get_ip_addresses(container);
+                    info.ipv4_address = ipv4_address;
+                    info.ipv6_address = ipv6_address
+                }
                 Err(e) => println!("warn: {}", e),
             }
         }
     }
 }
 
-fn get_ip_address(container: docker_api::rep::ContainerDetails) -> Option<std::net::Ipv4Addr> {
-    container
-        .network_settings
-        .networks
-        .iter()
-        .next()
-        .map(|(_, network)| network.ip_address.parse().ok())
-        .flatten()
+fn get_ip_addresses(
+    container: docker_api::rep::ContainerDetails,
+) -> (Option<std::net::Ipv4Addr>, Option<std::net::Ipv6Addr>) {
+    if let Some((_, network)) = container.network_settings.networks.iter().next() {
+        (
+            network.ip_address.parse().ok(),
+            network.global_ipv6_address.parse().ok(),
+        )
+    } else {
+        (None, None)
+    }
 }
 
+const TPROXY_MARK: &str = "0x1";
+const TPROXY_TABLE: u8 = 100;
+
 fn prerouting_rule(delete: bool, network_name: &str, port: u16) -> String {
     let mut s = format!(
         "iptables -t nat -{} PREROUTING -i {} -p tcp -j DNAT --to-destination 127.0.0.1:{}\n",
@@ -236,7 +246,43 @@ fn prerouting_rule(delete: bool, network_name: &str, port: u16) -> String {
     s
 }
 
-fn etc_hosts_rule(delete: bool, ip: std::net::Ipv4Addr, hostname: &str) -> String {
+// IPv6 has no `route_localnet`, so redirect to the proxy (listening on all interfaces)
+fn prerouting_rule6(delete: bool, network_name: &str, port: u16) -> String {
+    format!(
+        "ip6tables -t nat -{} PREROUTING -i {} -p tcp -j REDIRECT --to-ports {}\n",
+        if delete { "D" } else { "I" },
+        network_name,
+        port
+    )
+}
+
+// UDP flows need their original destination, so divert datagrams with TPROXY (rather than DNAT)
+fn tproxy_rule(delete: bool, iptables: &str, network_name: &str, port: u16) -> String {
+    format!(
+        "{} -t mangle -{} PREROUTING -i {} -p udp -j TPROXY --on-port {} --tproxy-mark {}/{}\n",
+        iptables,
+        if delete { "D" } else { "I" },
+        network_name,
+        port,
+        TPROXY_MARK,
+        TPROXY_MARK
+    )
+}
+
+// deliver TPROXY marked packets locally, so that they reach the (transparent) UDP proxy
+fn tproxy_routing(delete: bool) -> String {
+    let action = if delete { "del" } else { "add" };
+    let mut s = String::new();
+    for (ip, any) in &[("ip", "0.0.0.0/0"), ("ip -6", "::/0")] {
+        s.push_str(&format!(
+            "{0} rule {1} fwmark {2}/{2} lookup {3}\n{0} route {1} local {4} dev lo table {3}\n",
+            ip, action, TPROXY_MARK, TPROXY_TABLE, any
+        ))
+    }
+    s
+}
+
+fn etc_hosts_rule(delete: bool, ip: std::net::IpAddr, hostname: &str) -> String {
     if delete {
         format!("sed -i.bak '/{} {}/d' /etc/hosts\n", ip, hostname)
     } else {
@@ -271,8 +317,9 @@ pub fn rules(
         let proxy_port = proxy.port(port);
         port_map.insert(proxy.label, proxy_port);
     }
-    
-    // PREROUTING DNAT rules for services
+
+    up_file.write_all(tproxy_routing(false).as_bytes())?;
+    // PREROUTING DNAT (TCP) and TPROXY (UDP) rules for services
     for (service_name, service) in compose.services {
         if let armour_compose::network::Networks::Dict(dict) = &service.networks {
             if let Ok(_service_label) = service_name.parse::<labels::Label>() {
@@ -296,18 +343,47 @@ pub fn rules(
                         )?;
                         down_file.write_all(
                             prerouting_rule(true, network_name, *proxy_port).as_bytes(),
-                        )?
+                        )?;
+                        up_file.write_all(
+                            tproxy_rule(false, "iptables", network_name, *proxy_port).as_bytes(),
+                        )?;
+                        down_file.write_all(
+                            tproxy_rule(true, "iptables", network_name, *proxy_port).as_bytes(),
+                        )?;
+                        if network.ipv6_address.is_some() {
+                            up_file.write_all(
+                                prerouting_rule6(false, network_name, *proxy_port).as_bytes(),
+                            )?;
+                            down_file.write_all(
+                                prerouting_rule6(true, network_name, *proxy_port).as_bytes(),
+                            )?;
+                            up_file.write_all(
+                                tproxy_rule(false, "ip6tables", network_name, *proxy_port)
+                                    .as_bytes(),
+                            )?;
+                            down_file.write_all(
+                                tproxy_rule(true, "ip6tables", network_name, *proxy_port)
+                                    .as_bytes(),
+                            )?
+                        }
                     }
-                    if let (Some(ip), Some(hostname)) =
-                        (network.ipv4_address, service.hostname.as_ref())
-                    {
-                        hosts_file.write_all(etc_hosts_rule(false, ip, hostname).as_bytes())?;
-                        down_file.write_all(etc_hosts_rule(true, ip, hostname).as_bytes())?
+                    if let Some(hostname) = service.hostname.as_ref() {
+                        let ips = network
+                            .ipv4_address
+                            .map(std::net::IpAddr::from)
+                            .into_iter()
+                            .chain(network.ipv6_address.map(std::net::IpAddr::from));
+                        for ip in ips {
+                            hosts_file
+                                .write_all(etc_hosts_rule(false, ip, hostname).as_bytes())?;
+                            down_file.write_all(etc_hosts_rule(true, ip, hostname).as_bytes())?
+                        }
                     }
                 }
             }
         }
     }
+    down_file.write_all(tproxy_routing(true).as_bytes())?;
     println!(
         "generated files: {0}_up.sh, {0}_down.sh, {0}_hosts.sh",
         stem.to_string_lossy()
@@ -0,0 +1,217 @@
+//! Audit (dry-run) mode: policies are evaluated, but denials are only recorded
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::policy::PolicyActor;
+use actix::prelude::*;
+use armour_api::host::AuditRecord;
+use armour_lang::expressions;
+
+/// Notification of a decision that would have denied traffic, if not in audit mode
+#[derive(Message)]
+#[rtype("()")]
+pub struct WouldDeny {
+    function: &'static str,
+    from: String,
+    to: String,
+    error: Option<String>,
+}
+
+impl WouldDeny {
+    pub fn new<F: std::fmt::Display, T: std::fmt::Display>(
+        function: &'static str,
+        from: F,
+        to: T,
+        error: Option<String>,
+    ) -> Self {
+        WouldDeny {
+            function,
+            from: from.to_string(),
+            to: to.to_string(),
+            error,
+        }
+    }
+    fn into_record(self, time: u64) -> AuditRecord {
+        AuditRecord {
+            time,
+            function: self.function.to_string(),
+            from: self.from,
+            to: self.to,
+            error: self.error,
+        }
+    }
+}
+
+impl std::fmt::Display for WouldDeny {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} would deny {} -> {}", self.function, self.from, self.to)?;
+        if let Some(err) = &self.error {
+            write!(f, " ({})", err)?
+        }
+        Ok(())
+    }
+}
+
+impl Handler<WouldDeny> for PolicyActor {
+    type Result = ();
+    fn handle(&mut self, msg: WouldDeny, _ctx: &mut Context<Self>) -> Self::Result {
+        log::warn!("audit: {}", msg);
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        self.audit.record(msg.into_record(time))
+    }
+}
+
+/// Records would-be denials for a single decision (only created when in audit mode)
+pub struct Auditor {
+    policy: Recipient<WouldDeny>,
+    function: &'static str,
+    from: String,
+    to: String,
+}
+
+impl Auditor {
+    pub fn record(&self, error: Option<String>) {
+        if let Err(err) = self.policy.do_send(WouldDeny::new(
+            self.function,
+            &self.from,
+            &self.to,
+            error,
+        )) {
+            log::warn!("audit: {}", err)
+        }
+    }
+    /// In audit mode, allow denied (or failed) decisions, recording them as would-be denials
+    pub fn check(
+        auditor: &Option<Auditor>,
+        decision: Result<bool, expressions::Error>,
+    ) -> Result<bool, expressions::Error> {
+        match (auditor, decision) {
+            (Some(auditor), Ok(false)) => {
+                auditor.record(None);
+                Ok(true)
+            }
+            (Some(auditor), Err(err)) => {
+                log::warn!("{}", err);
+                auditor.record(Some(err.to_string()));
+                Ok(true)
+            }
+            (_, decision) => decision,
+        }
+    }
+}
+
+impl PolicyActor {
+    pub fn auditor<F: std::fmt::Display, T: std::fmt::Display>(
+        &self,
+        ctx: &Context<Self>,
+        function: &'static str,
+        from: F,
+        to: T,
+    ) -> Option<Auditor> {
+        if self.audit.enabled {
+            Some(Auditor {
+                policy: ctx.address().recipient(),
+                function,
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use armour_api::host::AuditSummary;
+    use std::sync::{Arc, Mutex};
+
+    // collects would-be denials, in place of the policy actor
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WouldDeny> for Collector {
+        type Result = ();
+        fn handle(&mut self, msg: WouldDeny, _ctx: &mut Context<Self>) -> Self::Result {
+            self.0.lock().unwrap().push(msg.to_string())
+        }
+    }
+
+    #[test]
+    fn format() {
+        let msg = WouldDeny::new("allow_rest_request", "client", "server", None);
+        assert_eq!(msg.to_string(), "allow_rest_request would deny client -> server");
+        let msg = WouldDeny::new("allow_tcp_connection", 1, 2, Some("timeout".to_string()));
+        assert_eq!(msg.to_string(), "allow_tcp_connection would deny 1 -> 2 (timeout)");
+        let record = msg.into_record(42);
+        assert_eq!(record.time, 42);
+        assert_eq!(record.function, "allow_tcp_connection");
+        assert_eq!(record.error, Some("timeout".to_string()));
+
+        let mut summary = AuditSummary::default();
+        summary.record(record);
+        assert_eq!(
+            summary.to_string(),
+            "audit mode: off\nallow_tcp_connection: 1 would-be denials\n[42] allow_tcp_connection: 1 -> 2 (timeout)\n"
+        )
+    }
+
+    #[actix_rt::test]
+    async fn check() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let collector = Collector(records.clone()).start();
+        let auditor = Some(Auditor {
+            policy: collector.recipient(),
+            function: "allow_udp_flow",
+            from: "a".to_string(),
+            to: "b".to_string(),
+        });
+        // without an auditor, decisions are unchanged
+        assert_eq!(Auditor::check(&None, Ok(false)).ok(), Some(false));
+        assert!(Auditor::check(&None, Err(expressions::Error::new("oops"))).is_err());
+        // with an auditor, denials and errors are allowed, but recorded
+        assert_eq!(Auditor::check(&auditor, Ok(true)).ok(), Some(true));
+        assert_eq!(Auditor::check(&auditor, Ok(false)).ok(), Some(true));
+        assert_eq!(
+            Auditor::check(&auditor, Err(expressions::Error::new("oops"))).ok(),
+            Some(true)
+        );
+        actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        let records = records.lock().unwrap().clone();
+        assert_eq!(
+            records,
+            vec![
+                "allow_udp_flow would deny a -> b".to_string(),
+                "allow_udp_flow would deny a -> b (oops)".to_string()
+            ]
+        )
+    }
+}
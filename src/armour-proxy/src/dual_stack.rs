@@ -0,0 +1,107 @@
+//! IPv6 listening sockets, used alongside the IPv4 ones for dual-stack proxies
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, RawFd};
+
+const BACKLOG: usize = 1024;
+
+/// Unspecified address of the same family as `addr` (used for binding outgoing sockets)
+pub fn unspecified(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+// set a boolean socket option
+#[cfg(target_os = "linux")]
+pub fn enable(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } != 0
+    {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// IPv6 socket (IPV6_V6ONLY), bound to all interfaces
+#[cfg(target_os = "linux")]
+fn socket6(typ: nix::sys::socket::SockType, port: u16) -> io::Result<RawFd> {
+    use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag};
+    let to_io = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e);
+    let fd =
+        socket::socket(AddressFamily::Inet6, typ, SockFlag::SOCK_CLOEXEC, None).map_err(to_io)?;
+    let res = enable(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)
+        .and_then(|()| socket::setsockopt(fd, sockopt::ReuseAddr, &true).map_err(to_io))
+        .and_then(|()| {
+            let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+            socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr))).map_err(to_io)
+        });
+    if let Err(err) = res {
+        let _ = nix::unistd::close(fd);
+        Err(err)
+    } else {
+        Ok(fd)
+    }
+}
+
+/// IPv6 only TCP listener, so that it can share a port with an IPv4 listener
+#[cfg(target_os = "linux")]
+pub fn tcp_listener6(port: u16) -> io::Result<TcpListener> {
+    let fd = socket6(nix::sys::socket::SockType::Stream, port)?;
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    nix::sys::socket::listen(fd, BACKLOG).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(listener)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_listener6(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+}
+
+/// IPv6 only UDP socket, so that it can share a port with an IPv4 socket
+#[cfg(target_os = "linux")]
+pub fn udp_socket6(port: u16) -> io::Result<UdpSocket> {
+    let fd = socket6(nix::sys::socket::SockType::Datagram, port)?;
+    Ok(unsafe { UdpSocket::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn udp_socket6(port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+}
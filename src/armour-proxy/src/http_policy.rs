@@ -24,9 +24,14 @@
  * SOFTWARE.
  */
 
-use super::policy::{Policy, PolicyActor, ID};
+use super::meta_keys::OutgoingMeta;
+use super::metrics::Observe;
+use super::on_error::ErrorStrategy;
+use super::policy::{self, Policy, PolicyActor, ID};
+use super::shadow::{Ingress, Shadow};
 use actix::prelude::*;
-use armour_api::host::Status;
+use armour_api::host::{Decision, PolicyVersion, Status};
+use armour_api::proxy::ServiceId;
 use armour_lang::{
     expressions,
     interpret::DPEnv,
@@ -34,7 +39,7 @@ use armour_lang::{
     meta::IngressEgress,
     policies::{self, FnPolicy, Protocol},
 };
-use futures::future::{self, TryFutureExt};
+use futures::future::{self, FutureExt, TryFutureExt};
 use std::boxed::Box;
 use std::sync::Arc;
 
@@ -44,6 +49,12 @@ pub struct PolicyStatus {
     pub timeout: std::time::Duration,
     pub request: FnPolicy,
     pub response: FnPolicy,
+    pub audit: bool,  // forward traffic even when the policy denies it
+    pub shadow: bool, // compare decisions with a shadow policy
+    pub rewrite_request: bool,
+    pub rewrite_response: bool,
+    pub complete: FnPolicy,
+    pub on_error: policies::OnError,
     allow_all: bool,
 }
 
@@ -57,7 +68,23 @@ impl PolicyStatus {
             .get(policies::ALLOW_REST_RESPONSE)
             .cloned()
             .unwrap_or_default();
-        self.allow_all = self.request == FnPolicy::Allow && self.response == FnPolicy::Allow
+        let rewrites = |function| matches!(policy.get(function), Some(FnPolicy::Args(_)));
+        self.rewrite_request = rewrites(policies::REWRITE_REST_REQUEST);
+        self.rewrite_response = rewrites(policies::REWRITE_REST_RESPONSE);
+        self.complete = policy
+            .get(policies::ON_HTTP_COMPLETE)
+            .cloned()
+            .unwrap_or_default();
+        self.on_error = policy.on_error;
+        self.allow_all = self.request == FnPolicy::Allow
+            && self.response == FnPolicy::Allow
+            && !self.rewrite_request
+            && !self.rewrite_response
+            && !self.on_complete()
+    }
+    /// There is an `on_http_complete` function to evaluate
+    pub fn on_complete(&self) -> bool {
+        matches!(self.complete, FnPolicy::Args(_))
     }
 }
 
@@ -65,6 +92,12 @@ impl Default for PolicyStatus {
     fn default() -> Self {
         PolicyStatus {
             timeout: std::time::Duration::from_secs(5),
+            audit: false,
+            shadow: false,
+            rewrite_request: false,
+            rewrite_response: false,
+            complete: FnPolicy::default(),
+            on_error: policies::OnError::default(),
             allow_all: false,
             request: FnPolicy::default(),
             response: FnPolicy::default(),
@@ -75,14 +108,14 @@ impl Default for PolicyStatus {
 struct HttpProxy {
     server: actix_web::dev::Server,
     port: u16,
-    ingress: Option<std::net::SocketAddrV4>,
+    ingress: Option<std::net::SocketAddr>,
 }
 
 impl HttpProxy {
     fn new(
         server: actix_web::dev::Server,
         port: u16,
-        ingress: Option<std::net::SocketAddrV4>,
+        ingress: Option<std::net::SocketAddr>,
     ) -> Self {
         HttpProxy {
             server,
@@ -94,15 +127,16 @@ impl HttpProxy {
 
 pub struct HttpPolicy {
     policy: Arc<policies::DPPolicy>,
+    version: PolicyVersion,
     env: DPEnv,
     proxy: Option<HttpProxy>,
     status: PolicyStatus,
 }
 
-impl Policy<(actix_web::dev::Server, Option<std::net::SocketAddrV4>)> for HttpPolicy {
+impl Policy<(actix_web::dev::Server, Option<std::net::SocketAddr>)> for HttpPolicy {
     fn start(
         &mut self,
-        server_config: (actix_web::dev::Server, Option<std::net::SocketAddrV4>),
+        server_config: (actix_web::dev::Server, Option<std::net::SocketAddr>),
         port: u16,
     ) {
         self.proxy = Some(HttpProxy::new(server_config.0, port, server_config.1))
@@ -115,7 +149,8 @@ impl Policy<(actix_web::dev::Server, Option<std::net::SocketAddrV4>)> for HttpPo
         };
         self.proxy = None
     }
-    fn set_policy(&mut self, p: policies::DPPolicy) {
+    fn set_policy(&mut self, p: policies::DPPolicy, version: u64) {
+        self.version = PolicyVersion::new(version, &p);
         self.status.update_for_policy(&p);
         self.policy = Arc::new(p);
         self.env = DPEnv::new(&self.policy.program)
@@ -126,8 +161,8 @@ impl Policy<(actix_web::dev::Server, Option<std::net::SocketAddrV4>)> for HttpPo
     fn policy(&self) -> Arc<policies::DPPolicy> {
         self.policy.clone()
     }
-    fn hash(&self) -> String {
-        self.policy.blake3()
+    fn version(&self) -> PolicyVersion {
+        self.version.clone()
     }
     fn env(&self) -> &DPEnv {
         &self.env
@@ -136,6 +171,8 @@ impl Policy<(actix_web::dev::Server, Option<std::net::SocketAddrV4>)> for HttpPo
         Box::new(Status {
             port: self.port(),
             policy: (*self.policy()).clone(),
+            on_error: self.policy().on_error,
+            version: self.version.counter,
             ingress: self.proxy.as_ref().map(|p| p.ingress).flatten(),
         })
     }
@@ -145,8 +182,10 @@ impl Default for HttpPolicy {
     fn default() -> Self {
         let policy = Arc::new(policies::DPPolicy::deny_all(Protocol::HTTP));
         let env = DPEnv::new(&policy.program);
+        let version = PolicyVersion::new(0, &policy);
         HttpPolicy {
             policy,
+            version,
             env,
             proxy: None,
             status: PolicyStatus::default(),
@@ -162,18 +201,21 @@ impl HttpPolicy {
         self.status.timeout = std::time::Duration::from_secs(secs.into())
     }
     pub fn ingress(&self) -> Option<std::net::SocketAddr> {
-        match self.proxy.as_ref().map(|p| p.ingress.map(std::net::SocketAddr::from)) {
-            Some(x) => x,
-            None => None
-        }
+        self.proxy.as_ref().and_then(|p| p.ingress)
     }
 }
 
 /// Information about REST policies
+///
+/// Requests are evaluated against the policy (version) that was current when they arrived,
+/// for the service (if any) that they belong to
 #[derive(Clone, MessageResponse)]
 pub struct HttpPolicyResponse {
     pub status: PolicyStatus,
     pub connection: literals::DPConnection,
+    pub version: u64,
+    pub env: DPEnv,
+    pub service: Option<ServiceId>,
 }
 
 /// Request REST policy information
@@ -189,18 +231,28 @@ impl Handler<GetHttpPolicy> for PolicyActor {
 
     fn handle(&mut self, msg: GetHttpPolicy, _ctx: &mut Context<Self>) -> Self::Result {
         log::debug!("Handling HTTP request at proxy: {}", self.label);
-        let status = self.http.get();
-        if status.allow_all {
-            HttpPolicyResponse {
-                status,
-                connection: literals::Connection::default(),
-            }
+        self.metrics.request(&Protocol::HTTP);
+        let (from, to) = msg.0;
+        let service = self.service(&from, &to);
+        let http = self.http_policy(&service);
+        let mut status = http.get();
+        let version = http.version.counter;
+        let env = http.env.clone();
+        // the timeout is a proxy-wide setting
+        status.timeout = self.http.status.timeout;
+        status.audit = self.audit.enabled;
+        status.shadow = self.shadowing(policies::ALLOW_REST_REQUEST);
+        let connection = if status.allow_all && !status.shadow {
+            literals::Connection::default()
         } else {
-            let from_to = msg.0;
-            HttpPolicyResponse {
-                status,
-                connection: self.connection(from_to.0, from_to.1),
-            }
+            self.connection(from, to)
+        };
+        HttpPolicyResponse {
+            status,
+            connection,
+            version,
+            env,
+            service,
         }
     }
 }
@@ -211,16 +263,26 @@ pub enum HttpFn {
     Response,
 }
 
-/// Request evaluation of a (HTTP) policy function
+/// Request evaluation of a (HTTP) policy function, along with any shadow comparison.
+///
+/// Errors are handled with the strategy of the policy that admitted the request (whose
+/// environment is given).
 #[derive(Message)]
-#[rtype(result = "Result<(bool, Option<String>), expressions::Error>")]
-pub struct EvalHttpFn(pub HttpFn, pub Vec<expressions::DPExpr>, pub Option<String>);
+#[rtype(result = "Result<(bool, Option<OutgoingMeta>), expressions::Error>")]
+pub struct EvalHttpFn(
+    pub HttpFn,
+    pub Vec<expressions::DPExpr>,
+    pub Option<String>,
+    pub DPEnv,
+    pub policies::OnError,
+    pub Option<Shadow>,
+);
 
 // handle requests to evaluate the Armour policy
 impl Handler<EvalHttpFn> for PolicyActor {
-    type Result = ResponseFuture<Result<(bool, Option<String>), expressions::Error>>;
+    type Result = ResponseFuture<Result<(bool, Option<OutgoingMeta>), expressions::Error>>;
 
-    fn handle(&mut self, msg: EvalHttpFn, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: EvalHttpFn, ctx: &mut Context<Self>) -> Self::Result {
         let function = match msg.0 {
             HttpFn::Request => policies::ALLOW_REST_REQUEST,
             HttpFn::Response => policies::ALLOW_REST_RESPONSE,
@@ -228,20 +290,101 @@ impl Handler<EvalHttpFn> for PolicyActor {
         // try to decrypt ingress metadata
         let ingress_meta = msg
             .2
-            .map(|xarmour| PolicyActor::decrypt_meta(&self.aead, &xarmour))
+            .map(|xarmour| self.keys.decrypt_meta(&xarmour))
             .flatten();
+        // the shadow policy sees the same (decrypted) metadata
+        let shadow = msg
+            .5
+            .map(|shadow| shadow.with_ingress(Ingress::Decrypted(ingress_meta.clone())));
         let meta = IngressEgress::new(ingress_meta, self.label.clone());
-        let aead = self.aead.clone();
+        let key = self.keys.current();
+        let strategy = ErrorStrategy::for_env(msg.4, &msg.3, function).recorded(ctx.address());
+        Box::pin(
+            Observe::evaluation(
+                ctx.address(),
+                function,
+                policy::evaluate(&msg.3, function, msg.1, meta),
+            )
+            .map(move |res| {
+                if let Some(shadow) = shadow {
+                    let active = res.as_ref().map(|(b, _meta)| *b).map_err(Clone::clone);
+                    shadow.compare(Decision::from(&active))
+                }
+                res
+            })
+            .then(move |res| match res {
+                Err(err) => strategy
+                    .resolve(Err(err))
+                    .map_ok(|allow| (allow, None))
+                    .boxed(),
+                res => future::ready(res).boxed(),
+            })
+            .and_then(move |(b, meta)| future::ok((b, key.outgoing(meta)))),
+        )
+    }
+}
+
+/// Sent by the HTTP proxy once a response has been delivered, for evaluation of `on_http_complete`
+/// in the policy (environment) that admitted the request
+#[derive(Message)]
+#[rtype("()")]
+pub struct HttpComplete {
+    pub request: expressions::DPExpr,
+    pub response: expressions::DPExpr,
+    pub latency: std::time::Duration,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    pub complete: FnPolicy,
+    pub env: DPEnv,
+}
+
+impl Handler<HttpComplete> for PolicyActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: HttpComplete, _ctx: &mut Context<Self>) -> Self::Result {
+        if let FnPolicy::Args(arg_count) = msg.complete {
+            let args = match arg_count {
+                0 => vec![],
+                2 => vec![msg.request, msg.response],
+                5 => vec![
+                    msg.request,
+                    msg.response,
+                    expressions::DPExpr::from(msg.latency.as_millis() as i64),
+                    expressions::DPExpr::from(msg.request_bytes),
+                    expressions::DPExpr::from(msg.response_bytes),
+                ],
+                _ => unreachable!(), // policy is checked beforehand
+            };
+            Box::pin(
+                policy::evaluate(&msg.env, policies::ON_HTTP_COMPLETE, args, IngressEgress::default())
+                    .map(|res: Result<((), _), expressions::Error>| {
+                        if let Err(e) = res {
+                            log::warn!("error: {}", e)
+                        }
+                    }),
+            )
+        } else {
+            Box::pin(future::ready(()))
+        }
+    }
+}
+
+/// Request evaluation of a (HTTP) rewrite function, which returns a new payload
+#[derive(Message)]
+#[rtype(result = "Result<Vec<u8>, expressions::Error>")]
+pub struct RewriteHttpFn(pub HttpFn, pub Vec<expressions::DPExpr>, pub DPEnv);
+
+impl Handler<RewriteHttpFn> for PolicyActor {
+    type Result = ResponseFuture<Result<Vec<u8>, expressions::Error>>;
+
+    fn handle(&mut self, msg: RewriteHttpFn, _ctx: &mut Context<Self>) -> Self::Result {
+        let function = match msg.0 {
+            HttpFn::Request => policies::REWRITE_REST_REQUEST,
+            HttpFn::Response => policies::REWRITE_REST_RESPONSE,
+        };
         Box::pin(
-            self.http
-                .evaluate(function, msg.1, meta)
-                .and_then(move |(b, meta)| {
-                    let encrypted = PolicyActor::encrypt_meta(&aead, meta);
-                    // if let Some(e) = encrypted.as_ref() {
-                    //     log::debug!("meta is: {:?}", PolicyActor::decrypt_meta(&aead, e))
-                    // }
-                    future::ok((b, encrypted))
-                }),
+            policy::evaluate(&msg.2, function, msg.1, IngressEgress::default())
+                .map_ok(|(payload, _meta)| payload),
         )
     }
 }
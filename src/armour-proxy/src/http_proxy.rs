@@ -24,26 +24,42 @@
  * SOFTWARE.
  */
 
-use super::http_policy::{EvalHttpFn, GetHttpPolicy, HttpFn, HttpPolicyResponse, PolicyStatus};
+use super::http_policy::{
+    EvalHttpFn, GetHttpPolicy, HttpComplete, HttpFn, HttpPolicyResponse, PolicyStatus,
+    RewriteHttpFn,
+};
+use super::audit::WouldDeny;
+use super::dual_stack;
+use super::meta_keys::OutgoingMeta;
+use super::metrics::Observe;
 use super::policy::{PolicyActor, ID};
+use super::shadow::{Ingress, Shadow};
+use super::upstream::UpstreamGroup;
 use super::ToArmourExpression;
 use actix_web::{
+    body::{Body, BodySize, MessageBody, ResponseBody},
     client::{
         Client, ClientBuilder, ClientRequest, ClientResponse, Connector, PayloadError,
         SendRequestError,
     },
-    http::header::{ContentEncoding, HeaderMap, HeaderName, HeaderValue},
+    http::header::{self, ContentEncoding, HeaderMap, HeaderName, HeaderValue},
     http::uri,
     middleware, web, App, HttpRequest, HttpResponse, HttpServer, ResponseError,
 };
-use armour_api::proxy::HttpConfig;
-use armour_lang::policies::FnPolicy;
+use armour_api::{host::Decision, proxy::HttpConfig};
+use armour_lang::{
+    expressions::DPExpr,
+    policies::{self, FnPolicy},
+};
 use armour_utils::own_ip;
 use bytes::BytesMut;
 use futures::{stream::Stream, StreamExt};
 use lazy_static::lazy_static;
 use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task;
 
 pub async fn start_proxy(
     policy: actix::Addr<PolicyActor>,
@@ -57,7 +73,11 @@ pub async fn start_proxy(
     let resolver = actix_connect::start_resolver(config, opts)
         .await
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to start resolver"))?;
-    let server = HttpServer::new(move || {
+    // upstream servers (shared by all workers)
+    let upstreams = http_config
+        .upstreams()
+        .map(|upstreams| Arc::new(UpstreamGroup::new(upstreams)));
+    let mut server = HttpServer::new(move || {
         let connector = Connector::new()
             .connector(actix_connect::new_connector(resolver.clone()))
             .finish();
@@ -66,14 +86,19 @@ pub async fn start_proxy(
             .data(policy.clone())
             .data(client)
             .data(http_config.clone())
+            .data(upstreams.clone())
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::new(ContentEncoding::Identity))
             .default_service(web::route().to(request))
     })
-    .bind(socket)?
-    .run();
+    .bind(socket)?;
+    // also listen for IPv6 connections, if IPv6 is available
+    match dual_stack::tcp_listener6(socket.port()) {
+        Ok(listener6) => server = server.listen(listener6)?,
+        Err(err) => log::warn!("HTTP {}: not listening for IPv6: {}", socket.port(), err),
+    }
     log::info!("starting proxy server: http://{}", socket);
-    Ok(server)
+    Ok(server.run())
 }
 
 /// Main HttpRequest proxy
@@ -90,19 +115,35 @@ async fn request(
     if let Some(connection) = Connection::new(&req, &config.into_inner()) {
         if let Ok(p) = policy.send(GetHttpPolicy(connection.from_to())).await {
             // we succeeded in getting a policy
-            match p.status {
+            let now = std::time::Instant::now();
+            let complete = if p.status.on_complete() {
+                Some((
+                    (&req, &p.connection).to_expression(),
+                    p.connection.clone(),
+                    (p.status.complete.clone(), p.env.clone()),
+                ))
+            } else {
+                None
+            };
+            // the payload is not needed when a request is denied (unless auditing, shadowing or completing)
+            let client_payload = if p.status.request != FnPolicy::Deny
+                || p.status.audit
+                || p.status.shadow
+                || complete.is_some()
+            {
+                read_payload(&mut payload).await?
+            } else {
+                BytesMut::new()
+            };
+            let request_bytes = client_payload.len();
+            let response = match p.status {
                 // check request
                 PolicyStatus {
                     request: FnPolicy::Args(count),
-                    timeout,
+                    audit,
                     ..
                 } => {
                     log::debug!("{:?}", req);
-                    let mut client_payload = BytesMut::new();
-                    while let Some(chunk) = payload.next().await {
-                        let chunk = chunk?;
-                        client_payload.extend_from_slice(&chunk)
-                    }
                     let args = match count {
                         0 => vec![],
                         1 => vec![(&req, &p.connection).to_expression()],
@@ -113,26 +154,54 @@ async fn request(
                         _ => unreachable!(),
                     };
                     let ingress = connection.meta().as_ref().cloned();
-                    match policy
-                        .send(EvalHttpFn(HttpFn::Request, args, ingress))
-                        .await
-                    {
+                    let shadow = connection.shadowed(
+                        &policy,
+                        &p,
+                        policies::ALLOW_REST_REQUEST,
+                        ingress.clone(),
+                        || request_args(&req, &p, &client_payload),
+                    );
+                    let res = policy
+                        .send(EvalHttpFn(
+                            HttpFn::Request,
+                            args,
+                            ingress,
+                            p.env.clone(),
+                            p.status.on_error,
+                            shadow,
+                        ))
+                        .await;
+                    match res {
                         // allow request
                         Ok(Ok((true, meta))) => {
-                            // build request
-                            let client_request =
-                                build_request(client, connection.uri(), req, meta, timeout);
-                            // forward the request (with the original client payload)
-                            let res = client_request.send_body(client_payload).await;
-                            // send the response back to the client
-                            response(p, policy, res).await
+                            forward(p, policy.clone(), client, &connection, req, client_payload, meta)
+                                .await
+                        }
+                        // reject (unless auditing)
+                        Ok(Ok((false, _meta))) => {
+                            if audit {
+                                connection.would_deny(&policy, policies::ALLOW_REST_REQUEST, None);
+                                forward(p, policy.clone(), client, &connection, req, client_payload, None)
+                                    .await
+                            } else {
+                                log::info!("request denied by HTTP policy v{}", p.version);
+                                Ok(unauthorized("bad client request"))
+                            }
                         }
-                        // reject
-                        Ok(Ok((false, _meta))) => Ok(unauthorized("bad client request")),
                         // policy error
                         Ok(Err(e)) => {
                             log::warn!("{}", e);
-                            Ok(internal())
+                            if audit {
+                                connection.would_deny(
+                                    &policy,
+                                    policies::ALLOW_REST_REQUEST,
+                                    Some(e.to_string()),
+                                );
+                                forward(p, policy.clone(), client, &connection, req, client_payload, None)
+                                    .await
+                            } else {
+                                Ok(internal())
+                            }
                         }
                         // actor error
                         Err(e) => {
@@ -144,28 +213,69 @@ async fn request(
                 // allow
                 PolicyStatus {
                     request: FnPolicy::Allow,
-                    timeout,
                     ..
                 } => {
                     log::debug!("{:?}", req);
-                    let mut client_payload = BytesMut::new();
-                    while let Some(chunk) = payload.next().await {
-                        let chunk = chunk?;
-                        client_payload.extend_from_slice(&chunk)
-                    }
-                    // build request
-                    let client_request =
-                        build_request(client, connection.uri(), req, None, timeout);
-                    // forward the request (with the original client payload)
-                    let res = client_request.send_body(client_payload).await;
-                    // send the response back to the client
-                    response(p, policy, res).await
+                    policy.do_send(Observe::trivial(policies::ALLOW_REST_REQUEST, true));
+                    connection.shadow(
+                        &policy,
+                        &p,
+                        policies::ALLOW_REST_REQUEST,
+                        connection.meta().clone(),
+                        || request_args(&req, &p, &client_payload),
+                        Decision::Allow,
+                    );
+                    forward(p, policy.clone(), client, &connection, req, client_payload, None).await
                 }
-                // deny
+                // deny (unless auditing)
                 PolicyStatus {
                     request: FnPolicy::Deny,
+                    audit,
                     ..
-                } => Ok(unauthorized("request denied")),
+                } => {
+                    policy.do_send(Observe::trivial(policies::ALLOW_REST_REQUEST, false));
+                    connection.shadow(
+                        &policy,
+                        &p,
+                        policies::ALLOW_REST_REQUEST,
+                        connection.meta().clone(),
+                        || request_args(&req, &p, &client_payload),
+                        Decision::Deny,
+                    );
+                    if audit {
+                        connection.would_deny(&policy, policies::ALLOW_REST_REQUEST, None);
+                        forward(p, policy.clone(), client, &connection, req, client_payload, None)
+                            .await
+                    } else {
+                        Ok(unauthorized("request denied"))
+                    }
+                }
+            };
+            if let Some((request, connection, (complete, env))) = complete {
+                // evaluated off the critical path
+                let message = |res: &HttpResponse| HttpComplete {
+                    request,
+                    response: (res, &connection).to_expression(),
+                    latency: now.elapsed(),
+                    request_bytes,
+                    response_bytes: 0,
+                    complete,
+                    env,
+                };
+                match response {
+                    // once the response body has been sent (or the client has gone away)
+                    Ok(response) => {
+                        let message = message(&response);
+                        Ok(OnComplete::wrap(response, policy.get_ref().clone(), message, now))
+                    }
+                    Err(err) => {
+                        let res = err.as_response_error().error_response();
+                        policy.do_send(message(&res));
+                        Err(err)
+                    }
+                }
+            } else {
+                response
             }
         } else {
             // we failed to get a policy
@@ -178,6 +288,195 @@ async fn request(
     }
 }
 
+/// Response body that reports the completion of a request (for `on_http_complete`) once it has
+/// been sent, has failed or has been dropped
+struct OnComplete {
+    body: ResponseBody<Body>,
+    sent: usize,
+    complete: Option<(actix::Addr<PolicyActor>, HttpComplete, std::time::Instant)>,
+}
+
+impl OnComplete {
+    fn wrap(
+        response: HttpResponse,
+        policy: actix::Addr<PolicyActor>,
+        message: HttpComplete,
+        start: std::time::Instant,
+    ) -> HttpResponse {
+        response.map_body(|_head, body| {
+            ResponseBody::Other(Body::from_message(OnComplete {
+                body,
+                sent: 0,
+                complete: Some((policy, message, start)),
+            }))
+        })
+    }
+    fn complete(&mut self) {
+        if let Some((policy, mut message, start)) = self.complete.take() {
+            message.latency = start.elapsed();
+            message.response_bytes = self.sent;
+            policy.do_send(message)
+        }
+    }
+}
+
+impl MessageBody for OnComplete {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Option<Result<web::Bytes, actix_web::Error>>> {
+        let this = self.get_mut();
+        let next = MessageBody::poll_next(Pin::new(&mut this.body), cx);
+        match &next {
+            task::Poll::Ready(Some(Ok(bytes))) => this.sent += bytes.len(),
+            task::Poll::Ready(_) => this.complete(),
+            task::Poll::Pending => (),
+        }
+        next
+    }
+}
+
+impl Drop for OnComplete {
+    fn drop(&mut self) {
+        self.complete()
+    }
+}
+
+// all of the arguments that a request function can take (for shadow policies)
+fn request_args(req: &HttpRequest, p: &HttpPolicyResponse, payload: &BytesMut) -> Vec<DPExpr> {
+    vec![
+        (req, &p.connection).to_expression(),
+        payload.as_ref().into(),
+    ]
+}
+
+// all of the arguments that a response function can take (for shadow policies)
+fn response_args(res: &HttpResponse, p: &HttpPolicyResponse, payload: &[u8]) -> Vec<DPExpr> {
+    vec![(res, &p.connection).to_expression(), payload.into()]
+}
+
+async fn read_payload(payload: &mut web::Payload) -> Result<BytesMut, actix_web::Error> {
+    let mut client_payload = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        client_payload.extend_from_slice(&chunk)
+    }
+    Ok(client_payload)
+}
+
+/// Forward request to server (with the original client payload)
+async fn forward(
+    p: HttpPolicyResponse,
+    policy: web::Data<actix::Addr<PolicyActor>>,
+    client: web::Data<Client>,
+    connection: &Connection,
+    req: HttpRequest,
+    client_payload: BytesMut,
+    meta: Option<OutgoingMeta>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // apply the policy's payload rewrite (if any)
+    let rewritten = if p.status.rewrite_request {
+        let args = vec![
+            (&req, &p.connection).to_expression(),
+            client_payload.as_ref().into(),
+        ];
+        match rewrite(&policy, &p, HttpFn::Request, args).await {
+            Some(payload) => Some(payload),
+            None => return Ok(internal()),
+        }
+    } else {
+        None
+    };
+    let upstreams = req
+        .app_data::<web::Data<Option<Arc<UpstreamGroup>>>>()
+        .and_then(|upstreams| upstreams.get_ref().clone());
+    // forward the request
+    let send = Forward {
+        uri: connection.uri(),
+        meta,
+        timeout: p.status.timeout,
+        rewrite: rewritten.is_some(),
+    };
+    let payload = rewritten
+        .map(web::Bytes::from)
+        .unwrap_or_else(|| client_payload.freeze());
+    let res = send.send(upstreams.as_deref(), client, req, payload).await;
+    // send the response back to the client
+    response(p, policy, connection, res).await
+}
+
+/// Forwarding of a request to the server (or an upstream group)
+struct Forward<'a> {
+    uri: &'a uri::Uri,
+    meta: Option<OutgoingMeta>, // encrypted for each attempt
+    timeout: std::time::Duration,
+    rewrite: bool, // payload has been rewritten
+}
+
+impl<'a> Forward<'a> {
+    /// Send the request. With an upstream group, a server is selected and idempotent requests are
+    /// retried after 5xx responses and connection failures (within the group's retry budget).
+    async fn send(
+        self,
+        upstreams: Option<&UpstreamGroup>,
+        client: web::Data<Client>,
+        req: HttpRequest,
+        payload: web::Bytes,
+    ) -> Result<
+        ClientResponse<impl Stream<Item = Result<web::Bytes, PayloadError>> + Unpin>,
+        SendRequestError,
+    > {
+        if let Some(upstreams) = upstreams {
+            upstreams.request()
+        }
+        let idempotent = req.method().is_idempotent();
+        let mut tried = Vec::new();
+        let mut attempt = 0;
+        loop {
+            // once every server has been tried, start again
+            let addr = upstreams
+                .and_then(|upstreams| upstreams.select(&tried).or_else(|| upstreams.select(&[])));
+            let uri = addr
+                .map(|addr| Forward::upstream_uri(self.uri, addr))
+                .unwrap_or_else(|| self.uri.clone());
+            let meta = self.meta.as_ref().and_then(OutgoingMeta::encrypt);
+            let mut client_request =
+                build_request(client.clone(), uri, req.clone(), meta, self.timeout);
+            if self.rewrite {
+                // content length is recomputed for the new payload
+                client_request.headers_mut().remove(header::CONTENT_LENGTH);
+            }
+            let (upstreams, addr) = match (upstreams, addr) {
+                (Some(upstreams), Some(addr)) => (upstreams, addr),
+                _ => return client_request.send_body(payload).await,
+            };
+            let in_flight = upstreams.start(addr);
+            let res = client_request.send_body(payload.clone()).await;
+            drop(in_flight);
+            let success = match &res {
+                Ok(res) => !res.status().is_server_error(),
+                Err(_) => false,
+            };
+            upstreams.report(addr, success);
+            tried.push(addr);
+            if success || !idempotent || attempt >= upstreams.max_retries() || !upstreams.retry()
+            {
+                return res;
+            }
+            attempt += 1;
+            log::debug!("retrying request (attempt {})", attempt)
+        }
+    }
+    fn upstream_uri(uri: &uri::Uri, addr: std::net::SocketAddr) -> uri::Uri {
+        let mut parts = uri.clone().into_parts();
+        parts.authority = addr.to_string().parse().ok();
+        uri::Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+}
+
 fn response_builder(
     res: &ClientResponse<impl Stream<Item = Result<web::Bytes, PayloadError>> + Unpin>,
 ) -> actix_web::dev::HttpResponseBuilder {
@@ -195,6 +494,7 @@ fn response_builder(
 async fn response(
     p: HttpPolicyResponse,
     policy: web::Data<actix::Addr<PolicyActor>>,
+    connection: &Connection,
     res: Result<
         ClientResponse<impl Stream<Item = Result<web::Bytes, PayloadError>> + Unpin>,
         SendRequestError,
@@ -206,6 +506,7 @@ async fn response(
                 // check server response
                 PolicyStatus {
                     response: FnPolicy::Args(count),
+                    audit,
                     ..
                 } => {
                     let server_payload = res.body().await?;
@@ -221,28 +522,52 @@ async fn response(
                         _ => unreachable!(),
                     };
                     let ingress = get_x_armour(res.headers());
-                    match policy
-                        .send(EvalHttpFn(HttpFn::Response, args, ingress))
-                        .await
-                    {
+                    let shadow = connection.shadowed(
+                        &policy,
+                        &p,
+                        policies::ALLOW_REST_RESPONSE,
+                        ingress.clone(),
+                        || response_args(&response_builder(&res).finish(), &p, &server_payload),
+                    );
+                    let decided = policy
+                        .send(EvalHttpFn(
+                            HttpFn::Response,
+                            args,
+                            ingress,
+                            p.env.clone(),
+                            p.status.on_error,
+                            shadow,
+                        ))
+                        .await;
+                    match decided {
                         // allow
                         Ok(Ok((true, meta))) => {
-                            let mut builder = response_builder(&res);
-                            // add X-Armour header
-                            if let Some(meta) = meta {
-                                builder.header("x-armour", meta.as_str());
-                            };
-                            log::debug!("{:?}", builder);
-                            Ok(builder.body(server_payload))
+                            let meta = meta.as_ref().and_then(OutgoingMeta::encrypt);
+                            Ok(reply(&policy, &p, &res, meta, server_payload).await)
                         }
-                        // reject
+                        // reject (unless auditing)
                         Ok(Ok((false, _meta))) => {
-                            Ok(unauthorized("request denied (bad server response)"))
+                            if audit {
+                                connection.would_deny(&policy, policies::ALLOW_REST_RESPONSE, None);
+                                Ok(reply(&policy, &p, &res, None, server_payload).await)
+                            } else {
+                                log::info!("response denied by HTTP policy v{}", p.version);
+                                Ok(unauthorized("request denied (bad server response)"))
+                            }
                         }
                         // policy error
                         Ok(Err(e)) => {
                             log::warn!("{}", e);
-                            Ok(internal())
+                            if audit {
+                                connection.would_deny(
+                                    &policy,
+                                    policies::ALLOW_REST_RESPONSE,
+                                    Some(e.to_string()),
+                                );
+                                Ok(reply(&policy, &p, &res, None, server_payload).await)
+                            } else {
+                                Ok(internal())
+                            }
                         }
                         // actor error
                         Err(e) => {
@@ -256,15 +581,42 @@ async fn response(
                     response: FnPolicy::Allow,
                     ..
                 } => {
-                    let mut builder = response_builder(&res);
-                    log::debug!("{:?}", builder);
-                    Ok(builder.body(res.body().await?))
+                    policy.do_send(Observe::trivial(policies::ALLOW_REST_RESPONSE, true));
+                    let server_payload = res.body().await?;
+                    connection.shadow(
+                        &policy,
+                        &p,
+                        policies::ALLOW_REST_RESPONSE,
+                        get_x_armour(res.headers()),
+                        || response_args(&response_builder(&res).finish(), &p, &server_payload),
+                        Decision::Allow,
+                    );
+                    Ok(reply(&policy, &p, &res, None, server_payload).await)
                 }
-                // deny
+                // deny (unless auditing)
                 PolicyStatus {
                     response: FnPolicy::Deny,
+                    audit,
                     ..
-                } => Ok(unauthorized("request denied (bad server response)")),
+                } => {
+                    policy.do_send(Observe::trivial(policies::ALLOW_REST_RESPONSE, false));
+                    if audit || p.status.shadow {
+                        let server_payload = res.body().await?;
+                        connection.shadow(
+                            &policy,
+                            &p,
+                            policies::ALLOW_REST_RESPONSE,
+                            get_x_armour(res.headers()),
+                            || response_args(&response_builder(&res).finish(), &p, &server_payload),
+                            Decision::Deny,
+                        );
+                        if audit {
+                            connection.would_deny(&policy, policies::ALLOW_REST_RESPONSE, None);
+                            return Ok(reply(&policy, &p, &res, None, server_payload).await);
+                        }
+                    }
+                    Ok(unauthorized("request denied (bad server response)"))
+                }
             }
         }
         // error response when connecting to server
@@ -272,6 +624,58 @@ async fn response(
     }
 }
 
+/// Build the client's response from the server response, applying the policy's payload rewrite (if any)
+async fn reply(
+    policy: &actix::Addr<PolicyActor>,
+    p: &HttpPolicyResponse,
+    res: &ClientResponse<impl Stream<Item = Result<web::Bytes, PayloadError>> + Unpin>,
+    meta: Option<String>,
+    server_payload: web::Bytes,
+) -> HttpResponse {
+    let server_payload = if p.status.rewrite_response {
+        let args = vec![
+            (&response_builder(res).finish(), &p.connection).to_expression(),
+            server_payload.as_ref().into(),
+        ];
+        match rewrite(policy, p, HttpFn::Response, args).await {
+            Some(payload) => web::Bytes::from(payload),
+            None => return internal(),
+        }
+    } else {
+        server_payload
+    };
+    // (the content length header is set from the payload)
+    let mut builder = response_builder(res);
+    // add X-Armour header
+    if let Some(meta) = meta {
+        builder.header(X_ARMOUR, meta.as_str());
+    };
+    log::debug!("{:?}", builder);
+    builder.body(server_payload)
+}
+
+/// Evaluate a payload rewrite function
+async fn rewrite(
+    policy: &actix::Addr<PolicyActor>,
+    p: &HttpPolicyResponse,
+    function: HttpFn,
+    args: Vec<DPExpr>,
+) -> Option<Vec<u8>> {
+    match policy.send(RewriteHttpFn(function, args, p.env.clone())).await {
+        Ok(Ok(payload)) => Some(payload),
+        // policy error
+        Ok(Err(e)) => {
+            log::warn!("{}", e);
+            None
+        }
+        // actor error
+        Err(e) => {
+            log::warn!("{}", e);
+            None
+        }
+    }
+}
+
 fn internal() -> HttpResponse {
     HttpResponse::InternalServerError().body("Armour internal error")
 }
@@ -369,6 +773,46 @@ impl Connection {
     fn from_to(&self) -> (ID, ID) {
         (self.from.clone(), self.to.clone())
     }
+    // record a would-be denial (audit mode)
+    fn would_deny(
+        &self,
+        policy: &actix::Addr<PolicyActor>,
+        function: &'static str,
+        error: Option<String>,
+    ) {
+        policy.do_send(WouldDeny::new(function, &self.from, &self.to, error))
+    }
+    // shadow evaluation (when there is a shadow policy), with the same metadata as the active evaluation
+    fn shadowed<A: FnOnce() -> Vec<DPExpr>>(
+        &self,
+        policy: &actix::Addr<PolicyActor>,
+        p: &HttpPolicyResponse,
+        function: &'static str,
+        ingress: Option<String>,
+        args: A,
+    ) -> Option<Shadow> {
+        if p.status.shadow {
+            let request = format!("{} -> {}", self.from, self.uri);
+            let ingress = Ingress::Encrypted(ingress);
+            Some(Shadow::new(policy.clone(), function, args(), request, ingress))
+        } else {
+            None
+        }
+    }
+    // compare a trivial active decision with the shadow policy (when there is one)
+    fn shadow<A: FnOnce() -> Vec<DPExpr>>(
+        &self,
+        policy: &actix::Addr<PolicyActor>,
+        p: &HttpPolicyResponse,
+        function: &'static str,
+        ingress: Option<String>,
+        args: A,
+        active: Decision,
+    ) {
+        if let Some(shadow) = self.shadowed(policy, p, function, ingress, args) {
+            shadow.compare(active)
+        }
+    }
     fn forward_uri(req: &HttpRequest, config: &HttpConfig) -> Result<uri::Uri, actix_web::Error> {
         let info = req.connection_info();
         let host = config
@@ -422,8 +866,10 @@ lazy_static! {
 
 fn is_local_host(host: &str) -> bool {
     use std::str::FromStr;
-    if let Ok(ipv4) = std::net::Ipv4Addr::from_str(host) {
-        own_ip(&ipv4.into())
+    // IPv6 hosts are enclosed in brackets
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = std::net::IpAddr::from_str(ip) {
+        own_ip(&ip)
     } else {
         LOCAL_HOST_NAMES.contains(&host.to_ascii_lowercase())
     }
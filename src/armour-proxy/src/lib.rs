@@ -30,12 +30,22 @@ use armour_lang::{expressions, literals};
 #[rtype("()")]
 pub struct Stop;
 
+pub mod audit;
+pub mod dual_stack;
 pub mod http_policy;
 pub mod http_proxy;
+pub mod meta_keys;
+pub mod metrics;
+pub mod on_error;
 pub mod policy;
+pub mod proxy_protocol;
+pub mod shadow;
 pub mod tcp_codec;
 pub mod tcp_policy;
 pub mod tcp_proxy;
+pub mod udp_policy;
+pub mod udp_proxy;
+pub mod upstream;
 
 /// Trait for converting rust types into Armour expressions
 pub trait ToArmourExpression {
@@ -97,7 +97,7 @@ fn main() -> std::io::Result<()> {
     // install the CLI policy
     let host_socket = matches.value_of("host socket").unwrap().to_string();
     log::info!("connecting to: {}", host_socket);
-    let stream = sys.block_on(tokio::net::UnixStream::connect(host_socket))?;
+    let stream = sys.block_on(tokio::net::UnixStream::connect(host_socket.clone()))?;
     let timeout = matches
         .value_of("timeout")
         .map(|s| s.parse::<u8>().ok())
@@ -105,7 +105,7 @@ fn main() -> std::io::Result<()> {
         .unwrap_or(5);
     match matches.value_of("label").unwrap_or("proxy").parse() {
         Ok(label) => {
-            PolicyActor::create_policy(stream, label, timeout, key);
+            PolicyActor::create_policy(stream, host_socket.into(), label, timeout, key);
             sys.run()
         }
         Err(err) => {
@@ -0,0 +1,385 @@
+//! Encryption of `x-armour` metadata, with key rotation and replay protection
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_api::proxy::KeyRing;
+use armour_lang::meta::Meta;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime};
+
+// type Aead = aes_gcm::Aes256Gcm;
+type Aead = chacha20poly1305::ChaChaPoly1305<chacha20::ChaCha20>;
+
+// nonce is 4 random bytes, followed by a timestamp (nanoseconds since the Unix epoch)
+type Nonce = [u8; 12];
+
+// upper bound on the number of nonces remembered for replay detection
+const MAX_SEEN: usize = 65_536;
+
+fn new_aead(key: &[u8; 32]) -> Aead {
+    use aead::{generic_array::GenericArray, NewAead};
+    // Aes256Gcm::new(&GenericArray::clone_from_slice(key))
+    chacha20poly1305::ChaChaPoly1305::new(&GenericArray::clone_from_slice(key))
+}
+
+fn now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_nanos() as u64)
+        .ok()
+}
+
+/// Key used for encrypting metadata (cloned into policy evaluation futures)
+#[derive(Clone)]
+pub struct MetaKey {
+    id: u32,
+    aead: Aead,
+}
+
+impl MetaKey {
+    fn nonce() -> Option<Nonce> {
+        let mut nonce = [0u8; 12];
+        openssl::rand::rand_bytes(&mut nonce[..4]).ok()?;
+        nonce[4..].copy_from_slice(&now()?.to_be_bytes());
+        Some(nonce)
+    }
+    fn encrypt(&self, message: &str) -> Option<String> {
+        use aead::{generic_array::GenericArray, AeadInPlace};
+        let nonce = MetaKey::nonce()?;
+        let mut block = message.to_string().into_bytes();
+        // the key ID is authenticated (associated data)
+        if self
+            .aead
+            .encrypt_in_place(
+                GenericArray::from_slice(&nonce),
+                &self.id.to_be_bytes(),
+                &mut block,
+            )
+            .is_ok()
+        {
+            Some(format!(
+                "{};{};{}",
+                self.id,
+                base64::encode(&block),
+                base64::encode(&nonce)
+            ))
+        } else {
+            None
+        }
+    }
+    pub fn encrypt_meta(&self, meta: Option<Meta>) -> Option<String> {
+        meta.as_ref()
+            .map(|m| {
+                serde_json::to_string(m)
+                    .map(|s| self.encrypt(&s))
+                    .ok()
+                    .flatten()
+            })
+            .flatten()
+    }
+    pub fn outgoing(&self, meta: Option<Meta>) -> Option<OutgoingMeta> {
+        meta.map(|meta| OutgoingMeta {
+            key: self.clone(),
+            meta,
+        })
+    }
+}
+
+/// Metadata to be sent, which is encrypted (with a fresh nonce) for every message that carries it,
+/// so that retried requests are not rejected as replays
+#[derive(Clone)]
+pub struct OutgoingMeta {
+    key: MetaKey,
+    meta: Meta,
+}
+
+impl OutgoingMeta {
+    pub fn encrypt(&self) -> Option<String> {
+        serde_json::to_string(&self.meta)
+            .ok()
+            .and_then(|s| self.key.encrypt(&s))
+    }
+}
+
+/// Key ring and record of recently received nonces
+pub struct MetaKeys {
+    current: MetaKey,
+    keys: BTreeMap<u32, Aead>,
+    max_age: Duration,
+    // key for (deprecated) metadata without a key ID, if enabled
+    legacy: Option<u32>,
+    // nonces received within `max_age`, ordered by timestamp
+    seen: BTreeSet<(u64, Nonce)>,
+    // timestamp of the latest nonce evicted from `seen` (older nonces are rejected)
+    floor: u64,
+}
+
+impl MetaKeys {
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut keys = MetaKeys {
+            current: MetaKey {
+                id: 0,
+                aead: new_aead(&key),
+            },
+            keys: BTreeMap::new(),
+            max_age: Duration::default(),
+            legacy: None,
+            seen: BTreeSet::new(),
+            floor: 0,
+        };
+        keys.set(KeyRing::new(key));
+        keys
+    }
+    pub fn set(&mut self, ring: KeyRing) {
+        self.keys = ring
+            .keys
+            .iter()
+            .map(|(id, key)| (*id, new_aead(key)))
+            .collect();
+        if let Some(aead) = self.keys.get(&ring.current) {
+            self.current = MetaKey {
+                id: ring.current,
+                aead: aead.clone(),
+            }
+        } else {
+            log::warn!("key ring is missing current key: {}", ring.current)
+        }
+        self.max_age = Duration::from_secs(ring.max_age.into());
+        if ring.legacy.is_some() {
+            log::warn!("accepting metadata without key IDs (deprecated)")
+        }
+        self.legacy = ring.legacy
+    }
+    pub fn ids(&self) -> Vec<u32> {
+        self.keys.keys().cloned().collect()
+    }
+    pub fn current(&self) -> MetaKey {
+        self.current.clone()
+    }
+    // check the nonce timestamp and that the nonce has not been seen before
+    fn fresh(&mut self, nonce: Nonce) -> bool {
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&nonce[4..]);
+        let timestamp = u64::from_be_bytes(timestamp);
+        let max_age = self.max_age.as_nanos() as u64;
+        match now() {
+            Some(now)
+                if timestamp + max_age >= now
+                    && timestamp <= now + max_age
+                    && timestamp > self.floor =>
+            {
+                // forget nonces that are too old to be accepted
+                self.seen = self.seen.split_off(&(now.saturating_sub(max_age), [0; 12]));
+                if !self.seen.insert((timestamp, nonce)) {
+                    return false;
+                }
+                // when full, forget the oldest nonce and reject anything at least as old
+                if self.seen.len() > MAX_SEEN {
+                    if let Some(oldest) = self.seen.iter().next().cloned() {
+                        self.seen.remove(&oldest);
+                        self.floor = oldest.0
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+    fn decrypt(&mut self, message: &str) -> Option<Vec<u8>> {
+        use aead::{generic_array::GenericArray, AeadInPlace};
+        use std::convert::TryFrom;
+        let parts: Vec<&str> = message.split(';').collect();
+        let (id, block, nonce) = match parts.as_slice() {
+            [id, block, nonce] => (Some(id.parse::<u32>().ok()?), block, nonce),
+            // metadata from proxies that do not use key IDs (only if explicitly enabled)
+            [block, nonce] if self.legacy.is_some() => (None, block, nonce),
+            _ => return None,
+        };
+        let nonce = Nonce::try_from(base64::decode(nonce).ok()?.as_slice()).ok()?;
+        let mut block = base64::decode(block).ok()?;
+        let aead = self.keys.get(&id.or(self.legacy)?)?;
+        let associated_data = id.map(u32::to_be_bytes);
+        aead.decrypt_in_place(
+            GenericArray::from_slice(&nonce),
+            associated_data.as_ref().map(|a| &a[..]).unwrap_or_default(),
+            &mut block,
+        )
+        .ok()?;
+        if self.fresh(nonce) {
+            Some(block)
+        } else {
+            log::warn!("rejected stale or replayed metadata");
+            None
+        }
+    }
+    pub fn decrypt_meta(&mut self, message: &str) -> Option<Meta> {
+        let bytes = self.decrypt(message)?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use armour_lang::labels::Label;
+
+    fn meta() -> Meta {
+        Meta::new("Service::sender".parse::<Label>().unwrap())
+    }
+
+    // metadata in the format of proxies that predate key IDs
+    fn encrypt_legacy(key: &[u8; 32], message: &str) -> String {
+        use aead::{generic_array::GenericArray, AeadInPlace};
+        let nonce = MetaKey::nonce().unwrap();
+        let mut block = message.to_string().into_bytes();
+        new_aead(key)
+            .encrypt_in_place(GenericArray::from_slice(&nonce), &[], &mut block)
+            .unwrap();
+        format!("{};{}", base64::encode(&block), base64::encode(nonce))
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let mut keys = MetaKeys::new([1; 32]);
+        let encrypted = keys.current().encrypt_meta(Some(meta())).unwrap();
+        assert!(encrypted.starts_with(&KeyRing::id(&[1; 32]).to_string()));
+        assert_eq!(keys.decrypt_meta(&encrypted), Some(meta()));
+        assert_eq!(keys.current().encrypt_meta(None), None);
+        // other keys
+        let mut others = MetaKeys::new([2; 32]);
+        assert_eq!(others.decrypt_meta(&encrypted), None)
+    }
+
+    #[test]
+    fn replay() {
+        let mut keys = MetaKeys::new([1; 32]);
+        let encrypted = keys.current().encrypt_meta(Some(meta())).unwrap();
+        assert!(keys.decrypt_meta(&encrypted).is_some());
+        assert_eq!(keys.decrypt_meta(&encrypted), None);
+        // tampered key ID (authenticated as associated data)
+        let encrypted = keys.current().encrypt_meta(Some(meta())).unwrap();
+        let mut ring = KeyRing::new([1; 32]);
+        ring.keys.insert(7, [1; 32]);
+        keys.set(ring);
+        let (_id, rest) = encrypted.split_at(encrypted.find(';').unwrap());
+        assert_eq!(keys.decrypt_meta(&format!("7{}", rest)), None)
+    }
+
+    #[test]
+    fn bounded_replay_cache() {
+        let mut keys = MetaKeys::new([1; 32]);
+        let mut ring = KeyRing::new([1; 32]);
+        ring.max_age = 60;
+        keys.set(ring);
+        let start = now().unwrap();
+        let nonce = |i: u64| {
+            let mut nonce = [0u8; 12];
+            nonce[4..].copy_from_slice(&(start + i).to_be_bytes());
+            nonce
+        };
+        for i in 0..=MAX_SEEN as u64 {
+            assert!(keys.fresh(nonce(i)))
+        }
+        assert_eq!(keys.seen.len(), MAX_SEEN);
+        // the evicted nonce (and anything older) cannot be replayed
+        assert!(!keys.fresh(nonce(0)));
+        assert!(!keys.fresh(nonce(1)));
+        assert!(keys.fresh(nonce(MAX_SEEN as u64 + 1)))
+    }
+
+    #[test]
+    fn retries() {
+        let mut receiver = MetaKeys::new([1; 32]);
+        let mut ring = KeyRing::new([1; 32]);
+        ring.max_age = 60;
+        receiver.set(ring);
+        let outgoing = MetaKeys::new([1; 32])
+            .current()
+            .outgoing(Some(meta()))
+            .unwrap();
+        // every attempt is encrypted afresh, and so is accepted
+        let first = outgoing.encrypt().unwrap();
+        let retry = outgoing.encrypt().unwrap();
+        assert_ne!(first, retry);
+        assert_eq!(receiver.decrypt_meta(&first), Some(meta()));
+        assert_eq!(receiver.decrypt_meta(&retry), Some(meta()));
+        // whereas resending the same header is a replay
+        assert_eq!(receiver.decrypt_meta(&first), None);
+        assert!(MetaKeys::new([1; 32]).current().outgoing(None).is_none())
+    }
+
+    #[test]
+    fn rotate_and_remove() {
+        let mut ring = KeyRing::new([1; 32]);
+        let mut keys = MetaKeys::new([1; 32]);
+        let old = keys.current().encrypt_meta(Some(meta())).unwrap();
+        let old_id = ring.current;
+        ring.rotate([2; 32]);
+        keys.set(ring.clone());
+        assert_eq!(keys.ids().len(), 2);
+        // metadata encrypted with the previous key is still accepted
+        assert_eq!(keys.decrypt_meta(&old), Some(meta()));
+        let new = keys.current().encrypt_meta(Some(meta())).unwrap();
+        assert!(new.starts_with(&ring.current.to_string()));
+        // ...until the previous key is removed
+        let old = MetaKeys::new([1; 32])
+            .current()
+            .encrypt_meta(Some(meta()))
+            .unwrap();
+        assert!(ring.remove(old_id));
+        keys.set(ring);
+        assert_eq!(keys.decrypt_meta(&old), None);
+        assert_eq!(keys.decrypt_meta(&new), Some(meta()))
+    }
+
+    #[test]
+    fn legacy() {
+        let message = serde_json::to_string(&meta()).unwrap();
+        let mut ring = KeyRing::new([1; 32]);
+        let mut keys = MetaKeys::new([1; 32]);
+        // rejected by default
+        assert_eq!(keys.decrypt_meta(&encrypt_legacy(&[1; 32], &message)), None);
+        ring.set_legacy(true);
+        keys.set(ring.clone());
+        assert_eq!(
+            keys.decrypt_meta(&encrypt_legacy(&[1; 32], &message)),
+            Some(meta())
+        );
+        assert_eq!(keys.decrypt_meta(&encrypt_legacy(&[2; 32], &message)), None);
+        ring.set_legacy(false);
+        keys.set(ring);
+        assert_eq!(keys.decrypt_meta(&encrypt_legacy(&[1; 32], &message)), None)
+    }
+
+    #[test]
+    fn stale() {
+        let mut keys = MetaKeys::new([1; 32]);
+        let mut ring = KeyRing::new([1; 32]);
+        ring.max_age = 0;
+        keys.set(ring);
+        let encrypted = keys.current().encrypt_meta(Some(meta())).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(keys.decrypt_meta(&encrypted), None)
+    }
+}
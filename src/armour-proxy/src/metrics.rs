@@ -0,0 +1,98 @@
+//! Collection of proxy metrics
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::policy::PolicyActor;
+use actix::prelude::*;
+use armour_lang::{expressions, policies::DPProtocol};
+use futures::future::{BoxFuture, FutureExt};
+use std::time::{Duration, Instant};
+
+/// How often metrics are sent to the host
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Record the outcome of a policy decision (`None` if evaluation failed)
+#[derive(Message)]
+#[rtype("()")]
+pub struct Observe {
+    function: &'static str,
+    result: Option<bool>,
+    elapsed: Option<Duration>,
+}
+
+impl Observe {
+    /// Decision made without evaluating a policy function (function is `allow` or `deny`)
+    pub fn trivial(function: &'static str, allowed: bool) -> Self {
+        Observe {
+            function,
+            result: Some(allowed),
+            elapsed: None,
+        }
+    }
+    /// Time a policy function evaluation and report the decision to the policy actor
+    pub fn evaluation<T: Send + 'static>(
+        policy: Addr<PolicyActor>,
+        function: &'static str,
+        evaluation: BoxFuture<'static, Result<(bool, T), expressions::Error>>,
+    ) -> BoxFuture<'static, Result<(bool, T), expressions::Error>> {
+        let now = Instant::now();
+        evaluation
+            .map(move |res| {
+                policy.do_send(Observe {
+                    function,
+                    result: res.as_ref().ok().map(|r| r.0),
+                    elapsed: Some(now.elapsed()),
+                });
+                res
+            })
+            .boxed()
+    }
+}
+
+impl Handler<Observe> for PolicyActor {
+    type Result = ();
+    fn handle(&mut self, msg: Observe, _ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.decision(msg.function, msg.result, msg.elapsed)
+    }
+}
+
+/// Opening and closing of TCP connections and UDP flows
+#[derive(Message)]
+#[rtype("()")]
+pub enum ConnectionEvent {
+    Opened(DPProtocol),
+    Closed(DPProtocol, usize, usize), // (protocol, bytes sent, bytes received)
+}
+
+impl Handler<ConnectionEvent> for PolicyActor {
+    type Result = ();
+    fn handle(&mut self, msg: ConnectionEvent, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            ConnectionEvent::Opened(protocol) => self.metrics.opened(&protocol),
+            ConnectionEvent::Closed(protocol, sent, received) => {
+                self.metrics.closed(&protocol, sent, received)
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Error strategies for failed policy evaluations (fail-closed, fail-open or fallback)
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::policy::{self, Policy, PolicyActor};
+use actix::prelude::*;
+use armour_lang::{
+    expressions::{DPExpr, Error},
+    interpret::DPEnv,
+    literals::DPLiteral,
+    meta::IngressEgress,
+    policies::{self, OnError},
+};
+use futures::future::{self, BoxFuture, FutureExt};
+
+/// Record the kind of a failed policy evaluation
+#[derive(Message)]
+#[rtype("()")]
+pub struct PolicyError {
+    function: &'static str,
+    kind: &'static str,
+}
+
+impl Handler<PolicyError> for PolicyActor {
+    type Result = ();
+    fn handle(&mut self, msg: PolicyError, _ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.error(msg.function, msg.kind)
+    }
+}
+
+/// Applies a policy's error strategy to the decision of one of its functions
+pub struct ErrorStrategy {
+    policy: Option<Addr<PolicyActor>>, // for recording errors (metrics)
+    function: &'static str,
+    on_error: OnError,
+    env: Option<DPEnv>, // only needed for the fallback
+}
+
+impl ErrorStrategy {
+    /// Strategy that does not record errors in the proxy's metrics
+    pub fn new<P, Q: Policy<P>>(protocol: &Q, function: &'static str) -> Self {
+        ErrorStrategy::for_env(protocol.policy().on_error, protocol.env(), function)
+    }
+    /// Strategy of a policy that may since have been replaced (e.g. the one that admitted a request)
+    pub fn for_env(on_error: OnError, env: &DPEnv, function: &'static str) -> Self {
+        ErrorStrategy {
+            policy: None,
+            function,
+            on_error,
+            env: if let OnError::Fallback(_) = on_error {
+                Some(env.clone())
+            } else {
+                None
+            },
+        }
+    }
+    /// Record errors in the proxy's metrics
+    pub fn recorded(self, policy: Addr<PolicyActor>) -> Self {
+        ErrorStrategy {
+            policy: Some(policy),
+            ..self
+        }
+    }
+    /// Resolve a failed decision (successful decisions are unchanged)
+    pub fn resolve(self, decision: Result<bool, Error>) -> BoxFuture<'static, Result<bool, Error>> {
+        match decision {
+            Err(err) => self.fail(err),
+            decision => future::ready(decision).boxed(),
+        }
+    }
+    fn fail(self, err: Error) -> BoxFuture<'static, Result<bool, Error>> {
+        let kind = err.kind().as_str();
+        log::warn!("{} ({} error): {}", self.function, kind, err);
+        if let Some(policy) = &self.policy {
+            policy.do_send(PolicyError {
+                function: self.function,
+                kind,
+            })
+        }
+        match (self.on_error, self.env) {
+            (OnError::FailOpen, _) => {
+                log::warn!("{}: failing open", self.function);
+                future::ok(true).boxed()
+            }
+            (OnError::Fallback(n), Some(env)) => {
+                let function = self.function;
+                let args = if n == 1 {
+                    vec![DPExpr::LitExpr(DPLiteral::str(err.to_string()))]
+                } else {
+                    Vec::new()
+                };
+                policy::evaluate(
+                    &env,
+                    policies::ON_POLICY_ERROR,
+                    args,
+                    IngressEgress::default(),
+                )
+                .map(move |res| match res {
+                    Ok((allow, _meta)) => {
+                        log::info!(
+                            "{}: {} returned {}",
+                            function,
+                            policies::ON_POLICY_ERROR,
+                            allow
+                        );
+                        Ok(allow)
+                    }
+                    Err(fallback_err) => {
+                        log::warn!("{}: {}", policies::ON_POLICY_ERROR, fallback_err);
+                        Err(err)
+                    }
+                })
+                .boxed()
+            }
+            _ => future::err(err).boxed(),
+        }
+    }
+}
+
+impl PolicyActor {
+    pub fn error_strategy<P, Q: Policy<P>>(
+        ctx: &Context<Self>,
+        protocol: &Q,
+        function: &'static str,
+    ) -> ErrorStrategy {
+        ErrorStrategy::new(protocol, function).recorded(ctx.address())
+    }
+}
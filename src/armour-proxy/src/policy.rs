@@ -23,13 +23,21 @@
  * SOFTWARE.
  */
 
-use super::{http_policy::HttpPolicy, http_proxy, tcp_policy::TcpPolicy, tcp_proxy};
+use super::{
+    http_policy::HttpPolicy, http_proxy, meta_keys::MetaKeys, metrics, shadow::ShadowPolicy,
+    tcp_policy::TcpPolicy, tcp_proxy, udp_policy::UdpPolicy, udp_proxy,
+};
 use actix::prelude::*;
 use actix_web::http::uri;
-use armour_api::host::{PolicyResponse, Status};
-use armour_api::proxy::{LabelOp, PolicyCodec, PolicyRequest};
+use armour_api::host::{
+    self, AuditSummary, PolicyResponse, PolicyVersion, Ports, Resume, ServiceStatus,
+    ServiceVersions, ShadowSummary, Status,
+};
+use armour_api::metrics::Metrics;
+use armour_api::proxy::{LabelOp, LabelSnapshot, PolicyCodec, PolicyRequest, ServiceId};
 use armour_lang::{
     expressions,
+    externals::ExternalStats,
     interpret::{DPEnv, TExprInterpreter},
     labels, literals,
     meta::{IngressEgress, Meta},
@@ -42,14 +50,14 @@ use std::sync::Arc;
 use tokio::io::WriteHalf;
 use tokio_util::codec::FramedRead;
 
-// Trait for managing proxies and their associated policies (implemented for HTTP and TCP protocols)
+// Trait for managing proxies and their associated policies (implemented for HTTP, TCP and UDP protocols)
 pub trait Policy<P> {
     fn start(&mut self, proxy: P, port: u16);
     fn stop(&mut self);
-    fn set_policy(&mut self, p: policies::DPPolicy);
+    fn set_policy(&mut self, p: policies::DPPolicy, version: u64);
     fn port(&self) -> Option<u16>;
     fn policy(&self) -> Arc<policies::DPPolicy>;
-    fn hash(&self) -> String;
+    fn version(&self) -> PolicyVersion;
     fn env(&self) -> &DPEnv;
     fn status(&self) -> Box<Status>;
     fn evaluate<T: std::convert::TryFrom<literals::DPLiteral> + Send + 'static>(
@@ -58,36 +66,46 @@ pub trait Policy<P> {
         args: Vec<expressions::DPExpr>,
         meta: IngressEgress,
     ) -> BoxFuture<'static, Result<(T, Option<Meta>), expressions::Error>> {
-        log::debug!(r#"evaluating "{}""#, function);
-        let now = std::time::Instant::now();
-        let mut env = self.env().clone();
-        env.set_meta(meta);
-        async move {
-            let result = expressions::Expr::evaluate(
-                expressions::Expr::call(function, args),
-                Arc::new(()),
-                env.clone()
-            ) .await?;
-            let meta = env.egress().await;
-            log::debug!("result ({:?}): {}", now.elapsed(), result);
-            if let expressions::Expr::LitExpr(lit) = result {
-                if let Ok(r) = lit.try_into() {
-                    // log::info!("meta is: {:?}", meta);
-                    Ok((r, meta))
-                } else {
-                    Err(expressions::Error::new("literal has wrong type"))
-                }
+        evaluate(self.env(), function, args, meta)
+    }
+}
+
+/// Evaluate a policy function in a given environment
+pub fn evaluate<T: std::convert::TryFrom<literals::DPLiteral> + Send + 'static>(
+    env: &DPEnv,
+    function: &'static str,
+    args: Vec<expressions::DPExpr>,
+    meta: IngressEgress,
+) -> BoxFuture<'static, Result<(T, Option<Meta>), expressions::Error>> {
+    log::debug!(r#"evaluating "{}""#, function);
+    let now = std::time::Instant::now();
+    let mut env = env.clone();
+    env.set_meta(meta);
+    async move {
+        let result = expressions::Expr::evaluate(
+            expressions::Expr::call(function, args),
+            Arc::new(()),
+            env.clone()
+        ) .await?;
+        let meta = env.egress().await;
+        log::debug!("result ({:?}): {}", now.elapsed(), result);
+        if let expressions::Expr::LitExpr(lit) = result {
+            if let Ok(r) = lit.try_into() {
+                // log::info!("meta is: {:?}", meta);
+                Ok((r, meta))
             } else {
-                Err(expressions::Error::new("did not evaluate to a literal"))
+                Err(expressions::Error::with_kind(
+                    expressions::ErrorKind::Type,
+                    "literal has wrong type",
+                ))
             }
+        } else {
+            Err(expressions::Error::new("did not evaluate to a literal"))
         }
-        .boxed()
     }
+    .boxed()
 }
 
-// type Aead = aes_gcm::Aes256Gcm;
-type Aead = chacha20poly1305::ChaChaPoly1305<chacha20::ChaCha20>;
-
 /// Armour policy actor
 pub struct PolicyActor {
     pub label: labels::Label,
@@ -95,10 +113,23 @@ pub struct PolicyActor {
     // proxies
     pub http: HttpPolicy,
     pub tcp: TcpPolicy,
+    pub udp: UdpPolicy,
+    // per-service policies (traffic of other services is subject to the proxy-wide policies)
+    pub services: BTreeMap<ServiceId, Service>,
+    // request, decision and traffic counters
+    pub metrics: Metrics,
+    // audit (dry-run) mode and would-be denials
+    pub audit: AuditSummary,
+    // candidate policy, evaluated alongside the active policy
+    pub shadow: Option<ShadowPolicy>,
     // authenticated encryption with associated data (for metadata)
-    pub aead: Aead,
+    pub keys: MetaKeys,
+    // number of policy installations (the version of the latest policy)
+    policy_version: u64,
     // ID information
     identity: Identity,
+    // host's Unix socket (for re-connecting after the host restarts)
+    socket: std::path::PathBuf,
     // connection to host
     uds_framed:
         actix::io::FramedWrite<PolicyResponse, WriteHalf<tokio::net::UnixStream>, PolicyCodec>,
@@ -107,15 +138,18 @@ pub struct PolicyActor {
 // implement Actor trait for PolicyActor
 impl Actor for PolicyActor {
     type Context = Context<Self>;
-    fn started(&mut self, _ctx: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
         // send a connection message to the data plane host
         self.uds_framed.write(PolicyResponse::Connect(
             std::process::id(),
             None,
             self.label.clone(),
-            self.http.hash(),
-            self.tcp.hash(),
+            self.http.version(),
+            self.tcp.version(),
+            self.udp.version(),
         ));
+        // periodically send metrics to the host
+        ctx.run_interval(metrics::REPORT_INTERVAL, |act, _ctx| act.report_metrics());
         log::info!("started Armour policy actor")
     }
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -127,12 +161,11 @@ impl PolicyActor {
     /// Start a new policy actor that connects to a data plane host on a Unix socket.
     pub fn create_policy(
         stream: tokio::net::UnixStream,
+        socket: std::path::PathBuf,
         label: labels::Label,
         timeout: u8,
         key: [u8; 32],
     ) -> Addr<PolicyActor> {
-        use aead::{generic_array::GenericArray, NewAead};
-        // use aes_gcm::Aes256Gcm;
         let mut http = HttpPolicy::default();
         http.set_timeout(timeout);
         PolicyActor::create(|ctx| {
@@ -143,104 +176,196 @@ impl PolicyActor {
                 connection_number: 0,
                 http,
                 tcp: TcpPolicy::default(),
-                // aead: Aes256Gcm::new(&GenericArray::clone_from_slice(&key)),
-                aead: chacha20poly1305::ChaChaPoly1305::new(&GenericArray::clone_from_slice(&key)),
+                udp: UdpPolicy::default(),
+                services: BTreeMap::new(),
+                metrics: Metrics::default(),
+                audit: AuditSummary::default(),
+                shadow: None,
+                keys: MetaKeys::new(key),
+                policy_version: 0,
                 identity: Identity::default(),
+                socket,
                 uds_framed: actix::io::FramedWrite::new(w, PolicyCodec, ctx),
             }
         })
     }
-    fn nonce() -> Option<Vec<u8>> {
-        use std::time::SystemTime;
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map(|t| [&b"armo"[..4], &t.as_nanos().to_be_bytes()[8..]].concat())
-            .ok()
-    }
-    fn encrypt(aead: &Aead, message: &str) -> Option<String> {
-        use aead::{generic_array::GenericArray, AeadInPlace};
-        let nonce = PolicyActor::nonce()?;
-        let mut block = message.to_string().into_bytes();
-        if aead
-            .encrypt_in_place(GenericArray::from_slice(&nonce), &[], &mut block)
-            .is_ok()
-        {
-            Some(format!(
-                "{};{}",
-                base64::encode(&block),
-                base64::encode(&nonce)
-            ))
-        } else {
-            None
+    fn report_metrics(&mut self) {
+        let mut metrics = self.metrics.clone();
+        metrics.external = ExternalStats::snapshot();
+        self.uds_framed
+            .write(PolicyResponse::Metrics(Box::new(metrics)));
+        if self.audit.enabled {
+            self.uds_framed
+                .write(PolicyResponse::Audit(Box::new(self.audit.clone())))
+        }
+        if let Some(shadow) = self.shadow.as_ref() {
+            self.uds_framed
+                .write(PolicyResponse::Shadow(Box::new(shadow.summary())))
         }
     }
-    fn decrypt(aead: &Aead, message: &str) -> Option<Vec<u8>> {
-        use aead::{generic_array::GenericArray, AeadInPlace};
-        let res: Result<Vec<Vec<u8>>, base64::DecodeError> =
-            message.split(';').map(base64::decode).collect();
-        match res.ok()?.as_slice() {
-            [block, nonce] => {
-                let mut block: Vec<u8> = block.to_vec();
-                if aead
-                    .decrypt_in_place(GenericArray::from_slice(&nonce), &[], &mut block)
-                    .is_ok()
-                {
-                    Some(block)
-                } else {
-                    None
-                }
+}
+// identity/connection management
+impl PolicyActor {
+    fn get_port(u: &http::uri::Uri) -> Option<u16> {
+        u.port_u16().or_else(|| scheme_port(&u))
+    }
+    fn id(&mut self, id: ID) -> literals::DPID {
+        self.identity.id(id)
+    }
+    // performance critical (computes IDs, which could involve DNS lookup)
+    pub fn connection(&mut self, from: ID, to: ID) -> literals::DPConnection {
+        let number = self.connection_number;
+        self.connection_number += 1;
+        // let now = std::time::Instant::now();
+        // log::info!("now: {:?}", now.elapsed());
+        literals::DPConnection::from((&self.id(from), &self.id(to), number))
+    }
+    // connection for re-evaluating an established connection (not counted as a new connection)
+    pub fn established(&mut self, from: ID, to: ID) -> literals::DPConnection {
+        literals::DPConnection::from((&self.id(from), &self.id(to), self.connection_number))
+    }
+    /// Service (if any) that is the destination, or otherwise the source, of traffic
+    pub fn service(&mut self, from: &ID, to: &ID) -> Option<ServiceId> {
+        if self.services.is_empty() {
+            return None;
+        }
+        for id in [to, from].iter() {
+            let id = self.id((*id).clone());
+            if let Some(service) = self.services.keys().find(|service| service.matches(&id)) {
+                return Some(service.clone());
             }
-            _ => None,
         }
+        None
     }
-    pub fn encrypt_meta(aead: &Aead, meta: Option<Meta>) -> Option<String> {
-        meta.as_ref()
-            .map(|m| {
-                serde_json::to_string(m)
-                    .map(|s| PolicyActor::encrypt(aead, &s))
-                    .ok()
-                    .flatten()
-            })
-            .flatten()
+}
+
+/// Policies for a service behind the proxy
+pub struct Service {
+    pub http: HttpPolicy,
+    pub tcp: TcpPolicy,
+    pub udp: UdpPolicy,
+}
+
+impl Service {
+    // a new service starts with copies of the proxy-wide policies
+    fn new(actor: &PolicyActor) -> Self {
+        let mut service = Service {
+            http: HttpPolicy::default(),
+            tcp: TcpPolicy::default(),
+            udp: UdpPolicy::default(),
+        };
+        service
+            .http
+            .set_policy((*actor.http.policy()).clone(), actor.http.version().counter);
+        service
+            .tcp
+            .set_policy((*actor.tcp.policy()).clone(), actor.tcp.version().counter);
+        service
+            .udp
+            .set_policy((*actor.udp.policy()).clone(), actor.udp.version().counter);
+        service
     }
-    pub fn decrypt_meta(aead: &Aead, message: &str) -> Option<Meta> {
-        let bytes = PolicyActor::decrypt(aead, message)?;
-        serde_json::from_slice(&bytes).ok()
+    // proxy status is reported by the proxy-wide policies
+    fn status(&self, actor: &PolicyActor) -> ServiceStatus {
+        ServiceStatus {
+            http: Status {
+                port: actor.http.port(),
+                ingress: actor.http.ingress(),
+                ..*self.http.status()
+            },
+            tcp: Status {
+                port: actor.tcp.port(),
+                ..*self.tcp.status()
+            },
+            udp: Status {
+                port: actor.udp.port(),
+                ..*self.udp.status()
+            },
+        }
     }
 }
-// identity/connection management
+
+// policy selection
 impl PolicyActor {
-    fn get_port(u: &http::uri::Uri) -> Option<u16> {
-        u.port_u16().or_else(|| scheme_port(&u))
+    pub fn http_policy(&self, service: &Option<ServiceId>) -> &HttpPolicy {
+        service
+            .as_ref()
+            .and_then(|id| self.services.get(id))
+            .map(|service| &service.http)
+            .unwrap_or(&self.http)
+    }
+    pub fn tcp_policy(&self, service: &Option<ServiceId>) -> &TcpPolicy {
+        service
+            .as_ref()
+            .and_then(|id| self.services.get(id))
+            .map(|service| &service.tcp)
+            .unwrap_or(&self.tcp)
+    }
+    pub fn udp_policy(&self, service: &Option<ServiceId>) -> &UdpPolicy {
+        service
+            .as_ref()
+            .and_then(|id| self.services.get(id))
+            .map(|service| &service.udp)
+            .unwrap_or(&self.udp)
+    }
+    fn service_mut(&mut self, id: ServiceId) -> &mut Service {
+        if !self.services.contains_key(&id) {
+            let service = Service::new(self);
+            self.services.insert(id.clone(), service);
+        }
+        self.services.get_mut(&id).unwrap()
+    }
+}
+
+#[derive(Default)]
+struct Identity {
+    /// map from IDs to Armour label set expressions
+    host_labels: HashMap<String, labels::Labels>,
+    ip_labels: HashMap<std::net::IpAddr, labels::Labels>,
+    host_cache: HashMap<String, literals::DPID>,
+    ip_cache: HashMap<std::net::IpAddr, literals::DPID>,
+    /// labels of remote identities, pushed by the control plane
+    label_cache: LabelSnapshot,
+}
+
+impl Identity {
+    fn clear_caches(&mut self) {
+        self.host_cache.clear();
+        self.ip_cache.clear()
+    }
+    // labels attached on the data plane and labels from the control plane
+    fn ip_labels<'a>(&'a self, ip: &std::net::IpAddr) -> impl Iterator<Item = &'a labels::Label> {
+        self.ip_labels
+            .get(ip)
+            .into_iter()
+            .chain(self.label_cache.get(ip))
+            .flatten()
     }
+    // IDs (with labels) of hosts and (IPv4 or IPv6) socket addresses
     fn id(&mut self, id: ID) -> literals::DPID {
         match id {
             ID::Anonymous => literals::DPID::default(),
             ID::Uri(u) => {
                 if let Some(host) = u.host() {
-                    if let Some(id) = self.identity.host_cache.get(host) {
+                    if let Some(id) = self.host_cache.get(host) {
                         id.clone()
                     } else {
                         let mut hosts = BTreeSet::new();
                         let mut ips = BTreeSet::new();
                         let mut labels: BTreeSet<labels::Label> = BTreeSet::new();
-                        if let Some(lbls) = self.identity.host_labels.get(host) {
+                        if let Some(lbls) = self.host_labels.get(host) {
                             labels.extend(lbls.iter().cloned())
                         }
                         hosts.insert(host.to_string());
                         if let Ok(host_ips) = dns_lookup::lookup_host(host) {
-                            for ip in host_ips.iter().filter(|ip| ip.is_ipv4()) {
-                                if let Some(lbls) = self.identity.ip_labels.get(&ip) {
-                                    labels.extend(lbls.iter().cloned())
-                                }
+                            for ip in host_ips.iter() {
+                                labels.extend(self.ip_labels(ip).cloned());
                                 ips.insert(*ip);
                             }
                         }
                         log::debug!("creating ID for {} with labels {:?}", u, labels);
                         let id = literals::DPID::new(hosts, ips, PolicyActor::get_port(&u), labels);
-                        self.identity
-                            .host_cache
-                            .insert(host.to_string(), id.clone());
+                        self.host_cache.insert(host.to_string(), id.clone());
                         id
                     }
                 } else {
@@ -256,7 +381,7 @@ impl PolicyActor {
             ID::SocketAddr(s) => {
                 let ip = s.ip();
                 let port = s.port();
-                if let Some(id) = self.identity.ip_cache.get(&ip) {
+                if let Some(id) = self.ip_cache.get(&ip) {
                     id.set_port(port)
                 } else {
                     let mut hosts = BTreeSet::new();
@@ -264,48 +389,24 @@ impl PolicyActor {
                     let mut labels: BTreeSet<labels::Label> = BTreeSet::new();
                     // DNS lookup, with addition of labels
                     if let Ok(host) = dns_lookup::lookup_addr(&ip) {
-                        if let Some(lbls) = self.identity.host_labels.get(&host) {
+                        if let Some(lbls) = self.host_labels.get(&host) {
                             labels.extend(lbls.iter().cloned())
                         }
                         hosts.insert(host);
                     }
-                    if ip.is_ipv4() {
-                        if let Some(lbls) = self.identity.ip_labels.get(&ip) {
-                            labels.extend(lbls.iter().cloned())
-                        }
-                        ips.insert(ip);
-                    }
+                    labels.extend(self.ip_labels(&ip).cloned());
+                    ips.insert(ip);
                     log::debug!("creating ID for {} with labels {:?}", s, labels);
                     let id = literals::DPID::new(hosts, ips, Some(port), labels);
-                    self.identity.ip_cache.insert(ip, id.clone());
+                    self.ip_cache.insert(ip, id.clone());
                     id
                 }
             }
         }
     }
-    // performance critical (computes IDs, which could involve DNS lookup)
-    pub fn connection(&mut self, from: ID, to: ID) -> literals::DPConnection {
-        let number = self.connection_number;
-        self.connection_number += 1;
-        // let now = std::time::Instant::now();
-        // log::info!("now: {:?}", now.elapsed());
-        literals::DPConnection::from((&self.id(from), &self.id(to), number))
-    }
-}
-
-#[derive(Default)]
-struct Identity {
-    /// map from IDs to Armour label set expressions
-    host_labels: HashMap<String, labels::Labels>,
-    ip_labels: HashMap<std::net::IpAddr, labels::Labels>,
-    host_cache: HashMap<String, literals::DPID>,
-    ip_cache: HashMap<std::net::IpAddr, literals::DPID>,
-}
-
-impl Identity {
-    fn clear_caches(&mut self) {
-        self.host_cache.clear();
-        self.ip_cache.clear()
+    fn set_label_cache(&mut self, snapshot: LabelSnapshot) {
+        self.label_cache = snapshot;
+        self.clear_caches()
     }
     fn clear_labels(&mut self) {
         self.host_labels.clear();
@@ -383,6 +484,16 @@ impl ID {
     }
 }
 
+impl std::fmt::Display for ID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ID::Uri(u) => write!(f, "{}", u),
+            ID::SocketAddr(s) => write!(f, "{}", s),
+            ID::Anonymous => write!(f, "anonymous"),
+        }
+    }
+}
+
 impl From<uri::Uri> for ID {
     fn from(uri: uri::Uri) -> Self {
         ID::Uri(ID::authority(uri))
@@ -413,7 +524,10 @@ fn scheme_port(u: &uri::Uri) -> Option<u16> {
     }
 }
 
-impl actix::io::WriteHandler<std::io::Error> for PolicyActor {}
+// losing the host is handled by re-connecting (see `StreamHandler::finished`)
+impl actix::io::WriteHandler<std::io::Error> for PolicyActor {
+    fn finished(&mut self, _ctx: &mut Context<Self>) {}
+}
 
 impl StreamHandler<Result<PolicyRequest, std::io::Error>> for PolicyActor {
     fn handle(&mut self, msg: Result<PolicyRequest, std::io::Error>, ctx: &mut Context<Self>) {
@@ -422,18 +536,93 @@ impl StreamHandler<Result<PolicyRequest, std::io::Error>> for PolicyActor {
             ctx.notify(request)
         }
     }
-    fn finished(&mut self, _ctx: &mut Context<Self>) {
-        log::info!("lost connection to host");
-        System::current().stop()
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        log::info!("lost connection to host, waiting for it to restart");
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_secs(host::RESUME_TIMEOUT);
+        self.reconnect(ctx, std::time::Duration::from_millis(250), deadline)
+    }
+}
+
+// re-attachment to a restarted host
+impl PolicyActor {
+    fn reconnect(
+        &mut self,
+        ctx: &mut Context<Self>,
+        delay: std::time::Duration,
+        deadline: std::time::Instant,
+    ) {
+        ctx.run_later(delay, move |act, ctx| {
+            tokio::net::UnixStream::connect(act.socket.clone())
+                .into_actor(act)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(stream) => act.resume(stream, ctx),
+                        Err(_) if std::time::Instant::now() < deadline => {
+                            let delay = std::cmp::min(2 * delay, MAX_RECONNECT_DELAY);
+                            act.reconnect(ctx, delay, deadline)
+                        }
+                        Err(err) => {
+                            log::warn!("failed to re-connect to host: {}", err);
+                            System::current().stop()
+                        }
+                    };
+                    async {}.into_actor(act)
+                })
+                .wait(ctx)
+        });
+    }
+    fn resume(&mut self, stream: tokio::net::UnixStream, ctx: &mut Context<Self>) {
+        log::info!("re-connected to host");
+        let (r, w) = tokio::io::split(stream);
+        ctx.add_stream(FramedRead::new(r, PolicyCodec));
+        self.uds_framed = actix::io::FramedWrite::new(w, PolicyCodec, ctx);
+        let resume = Resume {
+            pid: std::process::id(),
+            label: self.label.clone(),
+            http: self.http.version(),
+            tcp: self.tcp.version(),
+            udp: self.udp.version(),
+            services: self
+                .services
+                .iter()
+                .map(|(id, service)| {
+                    let versions = ServiceVersions {
+                        http: service.http.version(),
+                        tcp: service.tcp.version(),
+                        udp: service.udp.version(),
+                    };
+                    (id.clone(), versions)
+                })
+                .collect(),
+            ports: Ports {
+                http: self.http.port(),
+                tcp: self.tcp.port(),
+                udp: self.udp.port(),
+            },
+        };
+        self.uds_framed.write(PolicyResponse::Resume(Box::new(resume)))
     }
 }
 
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
 // handle messages from the data plane host
 impl Handler<PolicyRequest> for PolicyActor {
     type Result = ();
 
     fn handle(&mut self, msg: PolicyRequest, ctx: &mut Context<Self>) -> Self::Result {
         match msg {
+            PolicyRequest::Audit(enabled) => {
+                if enabled && !self.audit.enabled {
+                    // start a fresh summary
+                    self.audit = AuditSummary::default()
+                }
+                self.audit.enabled = enabled;
+                log::info!("audit mode: {}", enabled);
+                self.uds_framed
+                    .write(PolicyResponse::Audit(Box::new(self.audit.clone())))
+            }
             PolicyRequest::CPOnboard(ip_labels) => {
                 log::info!("Starting CP onboarding");
                 self.uds_framed.write(PolicyResponse::CPOnboardingProxy(ip_labels.clone()));
@@ -441,16 +630,46 @@ impl Handler<PolicyRequest> for PolicyActor {
             PolicyRequest::Label(op) =>{
                 self.handle_label_op(op);
             },
+            PolicyRequest::LabelCache(snapshot) => {
+                log::info!(
+                    "label cache: version {} ({} labels)",
+                    snapshot.version,
+                    snapshot.len()
+                );
+                self.identity.set_label_cache(snapshot)
+            }
+            PolicyRequest::ProxyProtocol(config) => {
+                log::info!("PROXY protocol: {}", config);
+                self.tcp.set_proxy_protocol(config)
+            }
+            PolicyRequest::SetKeys(ring) => {
+                self.keys.set(ring);
+                log::info!("metadata keys: {:?}", self.keys.ids())
+            }
+            PolicyRequest::TcpUpdate(update) => {
+                self.tcp.set_on_update(update);
+                log::info!("TCP policy update: {:?}", update)
+            }
             PolicyRequest::Timeout(secs) => {
                 self.http.set_timeout(secs);
                 log::info!("timeout: {:?}", secs)
             }
+            PolicyRequest::UdpTimeout(secs) => {
+                self.udp.set_idle_timeout(secs);
+                log::info!("UDP idle timeout: {:?}", secs)
+            }
             PolicyRequest::Status => {
                 self.uds_framed.write(PolicyResponse::Status {
                     label: self.label.clone(),
                     labels: self.labels(),
                     http: self.http.status(),
                     tcp: self.tcp.status(),
+                    udp: self.udp.status(),
+                    services: self
+                        .services
+                        .iter()
+                        .map(|(id, service)| (id.clone(), service.status(self)))
+                        .collect(),
                 });
             }
             PolicyRequest::Stop(Protocol::HTTP) => {
@@ -458,7 +677,7 @@ impl Handler<PolicyRequest> for PolicyActor {
                     self.uds_framed.write(PolicyResponse::RequestFailed)
                 } else {
                     self.http.stop();
-                    self.uds_framed.write(PolicyResponse::Stopped)
+                    self.uds_framed.write(PolicyResponse::Stopped(Protocol::HTTP))
                 }
             }
             PolicyRequest::Stop(Protocol::TCP) => {
@@ -466,7 +685,15 @@ impl Handler<PolicyRequest> for PolicyActor {
                     self.uds_framed.write(PolicyResponse::RequestFailed)
                 } else {
                     self.tcp.stop();
-                    self.uds_framed.write(PolicyResponse::Stopped)
+                    self.uds_framed.write(PolicyResponse::Stopped(Protocol::TCP))
+                }
+            }
+            PolicyRequest::Stop(Protocol::UDP) => {
+                if self.udp.port().is_none() {
+                    self.uds_framed.write(PolicyResponse::RequestFailed)
+                } else {
+                    self.udp.stop();
+                    self.uds_framed.write(PolicyResponse::Stopped(Protocol::UDP))
                 }
             }
             PolicyRequest::Stop(Protocol::Phantom(_)) => { unreachable!() }
@@ -486,7 +713,7 @@ impl Handler<PolicyRequest> for PolicyActor {
                         match server {
                             Ok(server) => {
                                 act.http.start((server, config.ingress()), port);
-                                act.uds_framed.write(PolicyResponse::Started)
+                                act.uds_framed.write(PolicyResponse::Started(Protocol::HTTP, port))
                             },
                             Err(err) => {
                                 log::warn!("failed to start HTTP proxy: {}", err)
@@ -505,13 +732,13 @@ impl Handler<PolicyRequest> for PolicyActor {
                     }
                 }
                 self.tcp.stop();
-                tcp_proxy::start_proxy(port, ctx.address())
+                tcp_proxy::start_proxy(port, ctx.address(), self.tcp.proxy_protocol())
                     .into_actor(self)
                     .then(move |server, act, _ctx| {
                         match server {
                             Ok(server) => {
                                 act.tcp.start(server, port);
-                                act.uds_framed.write(PolicyResponse::Started)
+                                act.uds_framed.write(PolicyResponse::Started(Protocol::TCP, port))
                             }
                             Err(err) => log::warn!(
                                 "failed to start TCP proxy, port {}\n\t{}", 
@@ -523,14 +750,56 @@ impl Handler<PolicyRequest> for PolicyActor {
                     })
                     .wait(ctx)
             }
-            PolicyRequest::SetPolicy(policy) => {
+            PolicyRequest::StartUdp(port) => {
+                if let Some(current_port) = self.udp.port() {
+                    log::info!("UDP proxy already started");
+                    if port == current_port {
+                        self.uds_framed.write(PolicyResponse::RequestFailed);
+                        return;
+                    }
+                }
+                self.udp.stop();
+                udp_proxy::start_proxy(port, ctx.address(), self.udp.idle_timeout())
+                    .into_actor(self)
+                    .then(move |server, act, _ctx| {
+                        match server {
+                            Ok(server) => {
+                                act.udp.start(server, port);
+                                act.uds_framed.write(PolicyResponse::Started(Protocol::UDP, port))
+                            }
+                            Err(err) => log::warn!(
+                                "failed to start UDP proxy, port {}\n\t{}",
+                                port,
+                                err
+                            )
+                        };
+                        async {}.into_actor(act)
+                    })
+                    .wait(ctx)
+            }
+            PolicyRequest::SetPolicy(service, policy) => {
                 if let Some(tcp_policy) = policy.policy(Protocol::TCP) {
-                    self.install_tcp(tcp_policy.clone())
+                    self.install_tcp(service.clone(), tcp_policy.clone())
+                }
+                if let Some(udp_policy) = policy.policy(Protocol::UDP) {
+                    self.install_udp(service.clone(), udp_policy.clone())
                 }
                 if let Some(http_policy) = policy.policy(Protocol::HTTP) {
-                    self.install_http(http_policy.clone())
+                    self.install_http(service, http_policy.clone())
                 }
             }
+            PolicyRequest::SetShadowPolicy(policy) => {
+                self.shadow = policy.as_ref().map(ShadowPolicy::new);
+                let summary = if let Some(shadow) = self.shadow.as_ref() {
+                    log::info!("installed shadow policy");
+                    shadow.summary()
+                } else {
+                    log::info!("removed shadow policy");
+                    ShadowSummary::default()
+                };
+                self.uds_framed
+                    .write(PolicyResponse::Shadow(Box::new(summary)))
+            }
             PolicyRequest::Shutdown => {
                 log::info!("shutting down");
                 self.uds_framed.write(PolicyResponse::ShuttingDown);
@@ -574,7 +843,6 @@ impl PolicyActor {
             }
             LabelOp::AddIp(ip_labels) => {
                 for (ip, labels) in ip_labels {
-                    let ip = ip.into();
                     for label in labels {
                         log::info!("adding label for: {}", ip);
                         self.identity.add_ip(ip, label)
@@ -592,7 +860,6 @@ impl PolicyActor {
                 }
             },
             LabelOp::RemoveIp(ip, label) => {
-                let ip = ip.into();
                 log::info!("removing label for: {}", ip);
                 self.identity.remove_ip(&ip, label)
             }
@@ -606,19 +873,148 @@ impl PolicyActor {
 
 // install policies
 impl PolicyActor {
-    fn install_http(&mut self, policy: policies::DPPolicy) {
-        let hash = policy.blake3();
-        self.http.set_policy(policy);
+    fn next_version(&mut self) -> u64 {
+        self.policy_version += 1;
+        self.policy_version
+    }
+    fn install_http(&mut self, service: Option<ServiceId>, policy: policies::DPPolicy) {
+        let version = self.next_version();
+        let http = match service.clone() {
+            Some(id) => &mut self.service_mut(id).http,
+            None => &mut self.http,
+        };
+        http.set_policy(policy, version);
+        let version = http.version();
+        log::info!("installed HTTP policy {}{}", version, for_service(&service));
         self.uds_framed
-            .write(PolicyResponse::UpdatedPolicy(Protocol::HTTP, hash));
-        log::info!("installed HTTP policy")
+            .write(PolicyResponse::UpdatedPolicy(service, Protocol::HTTP, version))
     }
-    fn install_tcp(&mut self, policy: policies::DPPolicy) {
-        let hash = policy.blake3();
-        self.tcp.set_policy(policy);
+    fn install_tcp(&mut self, service: Option<ServiceId>, policy: policies::DPPolicy) {
+        let version = self.next_version();
+        let tcp = match service.clone() {
+            Some(id) => &mut self.service_mut(id).tcp,
+            None => &mut self.tcp,
+        };
+        tcp.set_policy(policy, version);
+        let version = tcp.version();
+        if service.is_some() {
+            // established connections are managed by the proxy-wide TCP policy
+            self.tcp.updated(version.counter)
+        }
+        log::info!("installed TCP policy {}{}", version, for_service(&service));
+        self.uds_framed
+            .write(PolicyResponse::UpdatedPolicy(service, Protocol::TCP, version))
+    }
+    fn install_udp(&mut self, service: Option<ServiceId>, policy: policies::DPPolicy) {
+        let version = self.next_version();
+        let udp = match service.clone() {
+            Some(id) => &mut self.service_mut(id).udp,
+            None => &mut self.udp,
+        };
+        udp.set_policy(policy, version);
+        let version = udp.version();
+        log::info!("installed UDP policy {}{}", version, for_service(&service));
         self.uds_framed
-            .write(PolicyResponse::UpdatedPolicy(Protocol::TCP, hash));
-        log::info!("installed TCP policy")
+            .write(PolicyResponse::UpdatedPolicy(service, Protocol::UDP, version))
     }
 }
 
+fn for_service(service: &Option<ServiceId>) -> String {
+    service
+        .as_ref()
+        .map(|id| format!(" for service {}", id))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    // service (if any) selected for traffic, and the version of its TCP policy
+    #[derive(Message)]
+    #[rtype("(Option<ServiceId>, u64)")]
+    struct Select(SocketAddr, SocketAddr);
+
+    impl Handler<Select> for PolicyActor {
+        type Result = MessageResult<Select>;
+        fn handle(&mut self, msg: Select, _ctx: &mut Context<Self>) -> Self::Result {
+            let service = self.service(&ID::SocketAddr(msg.0), &ID::SocketAddr(msg.1));
+            let version = self.tcp_policy(&service).version().counter;
+            MessageResult((service, version))
+        }
+    }
+
+    #[test]
+    fn ipv6_labels() {
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let label: labels::Label = "Service::v6".parse().unwrap();
+        let remote: labels::Label = "Host::remote".parse().unwrap();
+        let mut identity = Identity::default();
+        identity.add_ip(ip, label.clone());
+        let id = identity.id(ID::SocketAddr(SocketAddr::new(ip, 443)));
+        assert!(id.ips.contains(&ip));
+        assert!(id.has_label(&label));
+        assert_eq!(id.port, Some(443));
+        // labels from the control plane
+        let mut ips = BTreeMap::new();
+        ips.insert(ip, vec![remote.clone()].into_iter().collect());
+        identity.set_label_cache(LabelSnapshot::new(1, ips));
+        let id = identity.id(ID::SocketAddr(SocketAddr::new(ip, 80)));
+        assert!(id.has_label(&label) && id.has_label(&remote));
+        // cached IDs take the new port
+        assert_eq!(identity.id(ID::SocketAddr(SocketAddr::new(ip, 8080))).port, Some(8080));
+        identity.remove_ip(&ip, Some(label.clone()));
+        let id = identity.id(ID::SocketAddr(SocketAddr::new(ip, 80)));
+        assert!(!id.has_label(&label) && id.has_label(&remote))
+    }
+
+    #[actix_rt::test]
+    async fn services() {
+        let (stream, _host) = tokio::net::UnixStream::pair().unwrap();
+        let label = "Proxy::test".parse().unwrap();
+        let policy = PolicyActor::create_policy(stream, Default::default(), label, 5, [0; 32]);
+        let ip = |n| IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+        let socket = |n, port| SocketAddr::new(ip(n), port);
+        let frontend = ServiceId::Ip(ip(1));
+        let backend: ServiceId = "Service::backend".parse().unwrap();
+        let tls = ServiceId::Socket(socket(3, 443));
+        let ops = vec![
+            PolicyRequest::SetPolicy(None, policies::DPPolicies::allow_all()),
+            PolicyRequest::SetPolicy(Some(frontend.clone()), policies::DPPolicies::deny_all()),
+            PolicyRequest::SetPolicy(Some(backend.clone()), policies::DPPolicies::deny_all()),
+            PolicyRequest::SetPolicy(Some(tls.clone()), policies::DPPolicies::deny_all()),
+            PolicyRequest::Label(LabelOp::AddIp(vec![(
+                ip(2),
+                vec!["Service::backend".parse().unwrap()].into_iter().collect(),
+            )])),
+        ];
+        for op in ops {
+            policy.send(op).await.unwrap()
+        }
+        let select = |from, to| policy.send(Select(from, to));
+        let proxy = select(socket(9, 5000), socket(9, 80)).await.unwrap();
+        let (service, version) = select(socket(9, 5000), socket(1, 80)).await.unwrap();
+        assert_eq!(proxy.0, None);
+        assert_eq!(service, Some(frontend.clone()));
+        assert!(version > proxy.1);
+        // the destination is preferred over the source
+        assert_eq!(
+            select(socket(1, 5000), socket(2, 80)).await.unwrap().0,
+            Some(backend.clone())
+        );
+        assert_eq!(
+            select(socket(1, 5000), socket(9, 80)).await.unwrap().0,
+            Some(frontend)
+        );
+        // socket addresses need a matching port
+        assert_eq!(
+            select(socket(9, 5000), socket(3, 443)).await.unwrap().0,
+            Some(tls)
+        );
+        assert_eq!(select(socket(9, 5000), socket(3, 80)).await.unwrap().0, None);
+        // each service has its own policy
+        let (_, backend_version) = select(socket(9, 5000), socket(2, 80)).await.unwrap();
+        assert!(backend_version > version)
+    }
+}
@@ -0,0 +1,472 @@
+//! PROXY protocol (version 2) headers, carrying Armour metadata in a TLV
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+// version 2, PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+// address family and transport protocol
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+// TLV type for (encrypted) Armour metadata, from the range reserved for custom use
+const TYPE_ARMOUR: u8 = 0xEA;
+const HEADER_BYTES: usize = 16;
+// version 1 (text) headers, e.g. from load balancers
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_BYTES: usize = 107;
+
+// time to wait for a header from an upstream Armour proxy
+const HEADER_TIMEOUT: Duration = Duration::from_millis(200);
+// time to wait for the rest of a header, once its start has been received
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(2);
+// interval between peeks at an incomplete header
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+// what the first bytes of a connection say about a PROXY protocol header
+#[derive(Debug, PartialEq)]
+enum Start {
+    None,            // not a PROXY protocol header
+    Incomplete,      // could be the start of a header
+    Invalid,         // malformed header
+    Complete(usize), // header of the given length
+}
+
+/// Streams whose data can be inspected before it is read
+pub trait Peek: AsyncRead + Unpin {
+    fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>>;
+}
+
+impl Peek for tokio::net::TcpStream {
+    fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        tokio::net::TcpStream::poll_peek(self, cx, buf)
+    }
+}
+
+async fn peek<S: Peek>(stream: &mut S, buf: &mut [u8]) -> std::io::Result<usize> {
+    futures::future::poll_fn(|cx| stream.poll_peek(cx, buf)).await
+}
+
+/// PROXY protocol header
+#[derive(Debug, Default, PartialEq)]
+pub struct Header {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    /// encrypted metadata (see `meta_keys`)
+    pub armour: Option<String>,
+}
+
+impl Header {
+    pub fn new(source: SocketAddr, destination: SocketAddr, armour: String) -> Self {
+        Header {
+            source: Some(source),
+            destination: Some(destination),
+            armour: Some(armour),
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = SIGNATURE.to_vec();
+        buf.push(VERSION_COMMAND);
+        let mut body = Vec::new();
+        match (self.source, self.destination) {
+            (Some(SocketAddr::V4(src)), Some(SocketAddr::V4(dst))) => {
+                buf.push(TCP4);
+                body.extend_from_slice(&src.ip().octets());
+                body.extend_from_slice(&dst.ip().octets());
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+            }
+            (Some(src), Some(dst)) => {
+                buf.push(TCP6);
+                body.extend_from_slice(&ipv6_octets(src.ip()));
+                body.extend_from_slice(&ipv6_octets(dst.ip()));
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+            }
+            // UNSPEC
+            _ => buf.push(0x00),
+        }
+        if let Some(armour) = &self.armour {
+            body.push(TYPE_ARMOUR);
+            body.extend_from_slice(&(armour.len() as u16).to_be_bytes());
+            body.extend_from_slice(armour.as_bytes())
+        }
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend(body);
+        buf
+    }
+    // length of the header, if the bytes start with a PROXY protocol (v2) header
+    fn length(buf: &[u8]) -> Option<usize> {
+        if buf.len() >= HEADER_BYTES && buf.starts_with(&SIGNATURE) && buf[12] == VERSION_COMMAND {
+            Some(HEADER_BYTES + u16::from_be_bytes([buf[14], buf[15]]) as usize)
+        } else {
+            None
+        }
+    }
+    fn start(buf: &[u8]) -> Start {
+        let n = buf.len();
+        if n == 0 {
+            Start::Incomplete
+        } else if SIGNATURE.starts_with(&buf[..n.min(SIGNATURE.len())]) {
+            match Header::length(buf) {
+                Some(len) => Start::Complete(len),
+                None if n < HEADER_BYTES => Start::Incomplete,
+                None => Start::Invalid,
+            }
+        } else if V1_PREFIX.starts_with(&buf[..n.min(V1_PREFIX.len())]) {
+            match buf.windows(2).position(|w| w == b"\r\n") {
+                Some(i) if i + 2 <= V1_MAX_BYTES => Start::Complete(i + 2),
+                None if n < V1_MAX_BYTES => Start::Incomplete,
+                _ => Start::Invalid,
+            }
+        } else {
+            Start::None
+        }
+    }
+    // version 1: `PROXY TCP4 <source IP> <destination IP> <source port> <destination port>\r\n`
+    fn decode_v1(buf: &[u8]) -> Option<Header> {
+        let line = std::str::from_utf8(buf.strip_suffix(b"\r\n")?).ok()?;
+        match line.split(' ').collect::<Vec<&str>>().as_slice() {
+            ["PROXY", "UNKNOWN", ..] => Some(Header::default()),
+            ["PROXY", "TCP4", src, dst, sport, dport]
+            | ["PROXY", "TCP6", src, dst, sport, dport] => Some(Header {
+                source: Some(SocketAddr::new(src.parse().ok()?, sport.parse().ok()?)),
+                destination: Some(SocketAddr::new(dst.parse().ok()?, dport.parse().ok()?)),
+                armour: None,
+            }),
+            _ => None,
+        }
+    }
+    pub fn decode(buf: &[u8]) -> Option<Header> {
+        if buf.starts_with(V1_PREFIX) {
+            return Header::decode_v1(buf);
+        }
+        let len = Header::length(buf)?;
+        let body = buf.get(HEADER_BYTES..len)?;
+        let (mut header, tlvs) = match buf[13] {
+            TCP4 if body.len() >= 12 => (
+                Header {
+                    source: Some(socket(ipv4(&body[0..4]), &body[8..10])),
+                    destination: Some(socket(ipv4(&body[4..8]), &body[10..12])),
+                    armour: None,
+                },
+                &body[12..],
+            ),
+            TCP6 if body.len() >= 36 => (
+                Header {
+                    source: Some(socket(ipv6(&body[0..16]), &body[32..34])),
+                    destination: Some(socket(ipv6(&body[16..32]), &body[34..36])),
+                    armour: None,
+                },
+                &body[36..],
+            ),
+            0x00 => (Header::default(), &body[body.len()..]),
+            _ => return None,
+        };
+        let mut tlvs = tlvs;
+        while tlvs.len() >= 3 {
+            let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+            let value = tlvs.get(3..3 + len)?;
+            if tlvs[0] == TYPE_ARMOUR {
+                header.armour = Some(String::from_utf8(value.to_vec()).ok()?)
+            }
+            tlvs = &tlvs[3 + len..]
+        }
+        Some(header)
+    }
+    /// Read (and consume) a PROXY protocol header, if the client sends one.
+    ///
+    /// Fails if the client starts to send a header but does not complete it (in time),
+    /// or if the header is malformed, in which case the connection should be closed.
+    /// Clients that wait for the server to speak first are held up by `HEADER_TIMEOUT`, so this
+    /// is only used when the proxy is configured to expect upstream Armour proxies.
+    pub async fn read<S: Peek>(stream: &mut S) -> std::io::Result<Option<Header>> {
+        let mut buf = [0u8; V1_MAX_BYTES];
+        match tokio::time::timeout(HEADER_TIMEOUT, peek(stream, &mut buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(None), // the client has not sent anything
+            Ok(res) => res?,
+        };
+        tokio::time::timeout(HEADER_READ_TIMEOUT, Header::read_header(stream, &mut buf))
+            .await
+            .map_err(|_| invalid("timeout"))?
+    }
+    async fn read_header<S: Peek>(
+        stream: &mut S,
+        buf: &mut [u8],
+    ) -> std::io::Result<Option<Header>> {
+        loop {
+            let n = peek(stream, buf).await?;
+            match Header::start(&buf[..n]) {
+                Start::None => return Ok(None),
+                Start::Invalid => return Err(invalid("malformed")),
+                Start::Incomplete if n == 0 => return Err(invalid("truncated")),
+                Start::Incomplete => tokio::time::delay_for(PEEK_INTERVAL).await,
+                Start::Complete(len) => {
+                    let mut header = vec![0u8; len];
+                    stream.read_exact(&mut header).await?;
+                    return Header::decode(&header)
+                        .map(Some)
+                        .ok_or_else(|| invalid("malformed"));
+                }
+            }
+        }
+    }
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("PROXY protocol header: {}", reason),
+    )
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn ipv4(b: &[u8]) -> IpAddr {
+    IpAddr::from([b[0], b[1], b[2], b[3]])
+}
+
+fn ipv6(b: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(b);
+    IpAddr::from(octets)
+}
+
+fn socket(ip: IpAddr, port: &[u8]) -> SocketAddr {
+    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sockets(v6: bool) -> (SocketAddr, SocketAddr) {
+        if v6 {
+            (
+                "[2001:db8::1]:1234".parse().unwrap(),
+                "[2001:db8::2]:80".parse().unwrap(),
+            )
+        } else {
+            (
+                "10.0.0.1:1234".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        for v6 in [false, true].iter() {
+            let (src, dst) = sockets(*v6);
+            let header = Header::new(src, dst, "1;abc;def".to_string());
+            let buf = header.encode();
+            assert_eq!(buf[13], if *v6 { TCP6 } else { TCP4 });
+            assert_eq!(Header::start(&buf), Start::Complete(buf.len()));
+            assert_eq!(Header::decode(&buf), Some(header));
+            let header = Header {
+                source: Some(src),
+                destination: Some(dst),
+                armour: None,
+            };
+            assert_eq!(Header::decode(&header.encode()), Some(header))
+        }
+        let header = Header::default();
+        assert_eq!(Header::decode(&header.encode()), Some(header))
+    }
+
+    #[test]
+    fn v1_decode() {
+        let (src, dst) = sockets(false);
+        let buf = b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 80\r\n";
+        assert_eq!(Header::start(buf), Start::Complete(buf.len()));
+        assert_eq!(
+            Header::decode(buf),
+            Some(Header {
+                source: Some(src),
+                destination: Some(dst),
+                armour: None
+            })
+        );
+        let (src, dst) = sockets(true);
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 1234 80\r\n";
+        assert_eq!(Header::decode(buf).unwrap().source, Some(src));
+        assert_eq!(Header::decode(buf).unwrap().destination, Some(dst));
+        assert_eq!(
+            Header::decode(b"PROXY UNKNOWN\r\n"),
+            Some(Header::default())
+        );
+        assert_eq!(
+            Header::decode(b"PROXY TCP4 10.0.0.1 10.0.0.2 1234\r\n"),
+            None
+        );
+        assert_eq!(
+            Header::decode(b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 http\r\n"),
+            None
+        )
+    }
+
+    #[test]
+    fn truncated() {
+        let (src, dst) = sockets(true);
+        let buf = Header::new(src, dst, "1;abc;def".to_string()).encode();
+        for n in 0..HEADER_BYTES {
+            assert_eq!(Header::start(&buf[..n]), Start::Incomplete)
+        }
+        for n in 0..buf.len() {
+            assert_eq!(Header::decode(&buf[..n]), None)
+        }
+        let v1 = b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 80\r\n";
+        assert_eq!(Header::start(&v1[..20]), Start::Incomplete);
+        assert_eq!(Header::decode(&v1[..20]), None);
+        // not headers
+        assert_eq!(Header::start(b"GET / HTTP/1.1\r\n"), Start::None);
+        assert_eq!(Header::start(b"\x0D\x0Ax"), Start::None);
+        // malformed
+        let mut bad = buf.clone();
+        bad[12] = 0x20;
+        assert_eq!(Header::start(&bad), Start::Invalid);
+        assert_eq!(Header::start(&[b'P'; V1_MAX_BYTES][..]), Start::None);
+        let mut long = V1_PREFIX.to_vec();
+        long.resize(V1_MAX_BYTES, b'x');
+        assert_eq!(Header::start(&long), Start::Invalid)
+    }
+
+    // in-memory stream, with data sent through a channel
+    struct Stream {
+        rx: futures::channel::mpsc::UnboundedReceiver<Vec<u8>>,
+        buf: Vec<u8>,
+        closed: bool,
+    }
+
+    type Sender = futures::channel::mpsc::UnboundedSender<Vec<u8>>;
+
+    fn connect() -> (Sender, Stream) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let stream = Stream {
+            rx,
+            buf: Vec::new(),
+            closed: false,
+        };
+        (tx, stream)
+    }
+
+    impl Stream {
+        fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            use futures::StreamExt;
+            while !self.closed {
+                match self.rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(data)) => self.buf.extend(data),
+                    Poll::Ready(None) => self.closed = true,
+                    Poll::Pending if self.buf.is_empty() => return Poll::Pending,
+                    Poll::Pending => break,
+                }
+            }
+            Poll::Ready(())
+        }
+    }
+
+    impl Peek for Stream {
+        fn poll_peek(
+            &mut self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            futures::ready!(self.poll_data(cx));
+            let n = buf.len().min(self.buf.len());
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncRead for Stream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            futures::ready!(self.poll_data(cx));
+            let n = buf.len().min(self.buf.len());
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn read() {
+        let (src, dst) = sockets(false);
+        let header = Header::new(src, dst, "1;abc;def".to_string());
+        // header followed by data, sent in two parts
+        let (client, mut server) = connect();
+        let buf = header.encode();
+        client.unbounded_send(buf[..10].to_vec()).unwrap();
+        let sender = client.clone();
+        actix_rt::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            sender.unbounded_send(buf[10..].to_vec()).unwrap();
+            sender.unbounded_send(b"hello".to_vec()).unwrap();
+        });
+        assert_eq!(Header::read(&mut server).await.unwrap(), Some(header));
+        let mut data = [0u8; 5];
+        server.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        // no header
+        let (client, mut server) = connect();
+        client.unbounded_send(b"hello".to_vec()).unwrap();
+        assert_eq!(Header::read(&mut server).await.unwrap(), None);
+        server.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        // the client does not send anything
+        let (_client, mut server) = connect();
+        assert_eq!(Header::read(&mut server).await.unwrap(), None)
+    }
+
+    #[actix_rt::test]
+    async fn read_truncated() {
+        let (src, dst) = sockets(false);
+        let buf = Header::new(src, dst, "1;abc;def".to_string()).encode();
+        // the client closes the connection part way through the header
+        let (client, mut server) = connect();
+        client.unbounded_send(buf[..10].to_vec()).unwrap();
+        drop(client);
+        assert!(Header::read(&mut server).await.is_err());
+        // the client stalls
+        let (client, mut server) = connect();
+        client
+            .unbounded_send(buf[..buf.len() - 1].to_vec())
+            .unwrap();
+        assert!(Header::read(&mut server).await.is_err())
+    }
+}
@@ -0,0 +1,332 @@
+//! Shadow policies: candidate policies evaluated alongside (but never enforced by) the active policy
+
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::policy::{self, PolicyActor};
+use actix::prelude::*;
+use armour_api::host::{Decision, ShadowRecord, ShadowSummary};
+use armour_lang::{
+    expressions::DPExpr,
+    interpret::DPEnv,
+    meta::{IngressEgress, Meta},
+    policies::{self, DPPolicies, DPPolicy, DPProtocol, FnPolicy, Protocol},
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// most shadow evaluations queued (or running) at once; any more are skipped, so that shadowing
+// cannot build an unbounded backlog under load
+const MAX_QUEUED: usize = 256;
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Shadow policy, along with a summary of its disagreements with the active policy
+pub struct ShadowPolicy {
+    policies: BTreeMap<DPProtocol, (DPPolicy, DPEnv)>,
+    pub summary: ShadowSummary,
+}
+
+impl ShadowPolicy {
+    pub fn new(policies: &DPPolicies) -> Self {
+        let mut summary = ShadowSummary::default();
+        let policies = policies
+            .policies()
+            .map(|(protocol, policy)| {
+                summary
+                    .hashes
+                    .insert(protocol.to_string(), policy.blake3());
+                let env = DPEnv::new(&policy.program);
+                (protocol.clone(), (policy.clone(), env))
+            })
+            .collect();
+        SKIPPED.store(0, Ordering::Relaxed);
+        ShadowPolicy { policies, summary }
+    }
+    /// Summary of disagreements, including the number of evaluations skipped under load
+    pub fn summary(&self) -> ShadowSummary {
+        let mut summary = self.summary.clone();
+        summary.skipped = SKIPPED.load(Ordering::Relaxed);
+        summary
+    }
+    // shadow policy for a function (if the shadow policy covers the function's protocol)
+    fn get(&self, function: &str) -> Option<(FnPolicy, &DPEnv)> {
+        let protocol = match function {
+            policies::ALLOW_REST_REQUEST | policies::ALLOW_REST_RESPONSE => Protocol::HTTP,
+            policies::ALLOW_TCP_CONNECTION => Protocol::TCP,
+            policies::ALLOW_UDP_FLOW | policies::ALLOW_UDP_DATAGRAM => Protocol::UDP,
+            _ => return None,
+        };
+        self.policies.get(&protocol).map(|(policy, env)| {
+            (policy.get(function).cloned().unwrap_or_default(), env)
+        })
+    }
+    pub fn covers(&self, function: &str) -> bool {
+        self.get(function).is_some()
+    }
+    /// Evaluate a policy function, given all of the arguments that it could take.
+    /// The arguments are then selected according to the shadow policy's arity.
+    fn evaluate(
+        &self,
+        function: &'static str,
+        args: Vec<DPExpr>,
+        meta: IngressEgress,
+    ) -> Option<BoxFuture<'static, Decision>> {
+        let (fn_policy, env) = self.get(function)?;
+        Some(match fn_policy {
+            FnPolicy::Allow => future::ready(Decision::Allow).boxed(),
+            FnPolicy::Deny => future::ready(Decision::Deny).boxed(),
+            FnPolicy::Args(n) => {
+                // the single argument of a datagram function is the payload, not the connection
+                let skip = if function == policies::ALLOW_UDP_DATAGRAM && n == 1 {
+                    1
+                } else {
+                    0
+                };
+                let args = args.into_iter().skip(skip).take(n as usize).collect();
+                policy::evaluate(env, function, args, meta)
+                    .map(|res| Decision::from(&res.map(|(b, _meta)| b)))
+                    .boxed()
+            }
+        })
+    }
+    fn compare(&mut self, function: &str, request: String, active: Decision, shadow: Decision) {
+        self.summary.evaluations += 1;
+        if active != shadow {
+            log::info!(
+                "shadow: {} disagrees on {} (active: {}, shadow: {})",
+                function,
+                request,
+                active,
+                shadow
+            );
+            let time = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default();
+            self.summary.record(ShadowRecord {
+                time,
+                function: function.to_string(),
+                request,
+                active,
+                shadow,
+            })
+        }
+    }
+}
+
+/// Ingress metadata for a shadow evaluation (the same metadata as the active evaluation)
+pub enum Ingress {
+    /// metadata decrypted for the active evaluation (nonces cannot be decrypted twice)
+    Decrypted(Option<Meta>),
+    /// metadata that the active decision did not need
+    Encrypted(Option<String>),
+}
+
+impl Default for Ingress {
+    fn default() -> Self {
+        Ingress::Decrypted(None)
+    }
+}
+
+/// Pending shadow evaluation, waiting on the active policy's decision
+pub struct Shadow {
+    policy: Addr<PolicyActor>,
+    function: &'static str,
+    args: Vec<DPExpr>,
+    request: String,
+    ingress: Ingress,
+}
+
+impl Shadow {
+    pub fn new<R: std::fmt::Display>(
+        policy: Addr<PolicyActor>,
+        function: &'static str,
+        args: Vec<DPExpr>,
+        request: R,
+        ingress: Ingress,
+    ) -> Self {
+        Shadow {
+            policy,
+            function,
+            args,
+            request: request.to_string(),
+            ingress,
+        }
+    }
+    pub fn with_ingress(self, ingress: Ingress) -> Self {
+        Shadow { ingress, ..self }
+    }
+    /// Evaluate the shadow policy (off the request path) and compare with the active decision.
+    /// The comparison is skipped if too many evaluations are already queued.
+    pub fn compare(self, active: Decision) {
+        if QUEUED.fetch_add(1, Ordering::Relaxed) >= MAX_QUEUED {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+            log::debug!("shadow: skipping {} (queue full)", self.function);
+            return;
+        }
+        self.policy.do_send(ShadowEval {
+            function: self.function,
+            args: self.args,
+            request: self.request,
+            ingress: self.ingress,
+            active,
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+struct ShadowEval {
+    function: &'static str,
+    args: Vec<DPExpr>,
+    request: String,
+    ingress: Ingress,
+    active: Decision,
+}
+
+impl Handler<ShadowEval> for PolicyActor {
+    type Result = ();
+    fn handle(&mut self, msg: ShadowEval, ctx: &mut Context<Self>) -> Self::Result {
+        let ShadowEval {
+            function,
+            args,
+            request,
+            ingress,
+            active,
+        } = msg;
+        let ingress = match ingress {
+            Ingress::Decrypted(meta) => meta,
+            Ingress::Encrypted(meta) => meta.and_then(|meta| self.keys.decrypt_meta(&meta)),
+        };
+        let meta = IngressEgress::new(ingress, self.label.clone());
+        if let Some(decision) = self
+            .shadow
+            .as_ref()
+            .and_then(|shadow| shadow.evaluate(function, args, meta))
+        {
+            ctx.spawn(decision.into_actor(self).map(move |decision, act, _ctx| {
+                QUEUED.fetch_sub(1, Ordering::Relaxed);
+                if let Some(shadow) = act.shadow.as_mut() {
+                    shadow.compare(function, request, active, decision)
+                }
+            }));
+        } else {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl PolicyActor {
+    /// A pending shadow evaluation, if there is a shadow policy for the function.
+    /// The arguments are only computed when needed.
+    pub fn shadow<R, A>(
+        &mut self,
+        ctx: &Context<Self>,
+        function: &'static str,
+        request: R,
+        ingress: Ingress,
+        args: A,
+    ) -> Option<Shadow>
+    where
+        R: std::fmt::Display,
+        A: FnOnce(&mut Self) -> Vec<DPExpr>,
+    {
+        if self.shadowing(function) {
+            Some(Shadow::new(
+                ctx.address(),
+                function,
+                args(self),
+                request,
+                ingress,
+            ))
+        } else {
+            None
+        }
+    }
+    pub fn shadowing(&self, function: &str) -> bool {
+        self.shadow
+            .as_ref()
+            .map(|shadow| shadow.covers(function))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_only(body: &str) -> DPPolicies {
+        DPPolicies::from_buf(&format!("fn allow_tcp_connection() -> bool {{ {} }}", body)).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn covers() {
+        let shadow = ShadowPolicy::new(&DPPolicies::deny_all());
+        assert!(shadow.summary.is_active());
+        assert!(shadow.covers(policies::ALLOW_REST_REQUEST));
+        assert!(shadow.covers(policies::ALLOW_TCP_CONNECTION));
+        // only protocols in the shadow policy are shadowed
+        let shadow = ShadowPolicy::new(&tcp_only("true"));
+        assert_eq!(shadow.summary.hashes.keys().collect::<Vec<_>>(), vec!["tcp"]);
+        assert!(shadow.covers(policies::ALLOW_TCP_CONNECTION));
+        assert!(!shadow.covers(policies::ALLOW_REST_REQUEST));
+        assert!(!shadow.covers(policies::ON_TCP_DISCONNECT))
+    }
+
+    #[actix_rt::test]
+    async fn evaluate() {
+        let shadow = ShadowPolicy::new(&DPPolicies::deny_all());
+        let meta = IngressEgress::default;
+        let decision = shadow.evaluate(policies::ALLOW_REST_REQUEST, Vec::new(), meta()).unwrap();
+        assert_eq!(decision.await, Decision::Deny);
+        let shadow = ShadowPolicy::new(&tcp_only("false"));
+        let decision = shadow.evaluate(policies::ALLOW_TCP_CONNECTION, Vec::new(), meta()).unwrap();
+        assert_eq!(decision.await, Decision::Deny);
+        assert!(shadow.evaluate(policies::ALLOW_UDP_FLOW, Vec::new(), meta()).is_none())
+    }
+
+    #[actix_rt::test]
+    async fn divergence() {
+        let mut shadow = ShadowPolicy::new(&DPPolicies::deny_all());
+        // agreement is counted, but not recorded
+        shadow.compare(policies::ALLOW_TCP_CONNECTION, "a -> b".to_string(), Decision::Deny, Decision::Deny);
+        shadow.compare(policies::ALLOW_TCP_CONNECTION, "a -> c".to_string(), Decision::Allow, Decision::Deny);
+        shadow.compare(
+            policies::ALLOW_REST_REQUEST,
+            "GET /".to_string(),
+            Decision::Allow,
+            Decision::Error("timeout".to_string()),
+        );
+        let summary = &shadow.summary;
+        assert_eq!(summary.evaluations, 3);
+        assert_eq!(summary.disagreements.get(policies::ALLOW_TCP_CONNECTION), Some(&1));
+        assert_eq!(summary.disagreements.get(policies::ALLOW_REST_REQUEST), Some(&1));
+        assert_eq!(summary.recent.len(), 2);
+        assert_eq!(summary.recent[0].request, "a -> c");
+        assert_eq!(summary.recent[1].shadow, Decision::Error("timeout".to_string()))
+    }
+}
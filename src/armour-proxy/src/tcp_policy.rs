@@ -23,26 +23,104 @@
  * SOFTWARE.
  */
 
+use super::audit::Auditor;
+use super::metrics::Observe;
+use super::on_error::ErrorStrategy;
 use super::policy::{Policy, PolicyActor, ID};
+use super::shadow::{Ingress, Shadow};
 use super::tcp_proxy;
 use super::Stop;
 use actix::prelude::*;
-use armour_api::host::Status;
+use armour_api::host::{Decision, PolicyVersion, Status};
+use armour_api::proxy::{ProxyProtocol, ServiceId, TcpUpdate};
 use armour_lang::{
-    expressions::{Error, DPExpr},
+    expressions::{DPExpr, Error},
     interpret::DPEnv,
-    meta::IngressEgress,
+    meta::{IngressEgress, Meta},
     policies::{self, FnPolicy, Protocol},
 };
-use futures::future::{self, TryFutureExt};
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
 use std::sync::Arc;
 
 pub struct TcpPolicy {
     connect: FnPolicy,
+    payload: FnPolicy,
     disconnect: FnPolicy,
     policy: Arc<policies::DPPolicy>,
+    version: PolicyVersion,
     env: DPEnv,
     proxy: Option<(Addr<tcp_proxy::TcpDataServer>, u16)>,
+    // exchange metadata with other Armour proxies using PROXY protocol headers
+    proxy_protocol: ProxyProtocol,
+    // handling of established connections when a new policy is installed
+    on_update: TcpUpdate,
+}
+
+impl TcpPolicy {
+    pub fn proxy_protocol(&self) -> ProxyProtocol {
+        self.proxy_protocol.clone()
+    }
+    pub fn set_proxy_protocol(&mut self, config: ProxyProtocol) {
+        self.proxy_protocol = config.clone();
+        if let Some((server, _port)) = &self.proxy {
+            server.do_send(tcp_proxy::SetProxyProtocol(config))
+        }
+    }
+    pub fn set_on_update(&mut self, update: TcpUpdate) {
+        self.on_update = update
+    }
+    /// Decide if an established connection is allowed, without any side effects (metrics, shadow
+    /// evaluation or audit records). Errors are handled with the policy's error strategy.
+    pub fn reevaluate(
+        &self,
+        connection: DPExpr,
+        meta: IngressEgress,
+        payload: Option<Vec<u8>>,
+    ) -> BoxFuture<'static, Result<bool, Error>> {
+        let connect = match self.connect {
+            FnPolicy::Allow => future::ok(true).boxed(),
+            FnPolicy::Deny => future::ok(false).boxed(),
+            FnPolicy::Args(n) => {
+                let args = if n == 0 {
+                    Vec::new()
+                } else {
+                    vec![connection.clone()]
+                };
+                let strategy = ErrorStrategy::new(self, policies::ALLOW_TCP_CONNECTION);
+                self.evaluate(policies::ALLOW_TCP_CONNECTION, args, meta)
+                    .map(|res| res.map(|(allow, _meta)| allow))
+                    .then(move |res| strategy.resolve(res))
+                    .boxed()
+            }
+        };
+        // connections admitted without inspection are inspected as if the client sent nothing
+        let inspect = match self.payload {
+            FnPolicy::Allow => future::ok(true).boxed(),
+            FnPolicy::Deny => future::ok(false).boxed(),
+            FnPolicy::Args(n) => {
+                let data = DPExpr::from(payload.unwrap_or_default());
+                let args = match n {
+                    0 => Vec::new(),
+                    1 => vec![data],
+                    _ => vec![connection, data],
+                };
+                let strategy = ErrorStrategy::new(self, policies::ALLOW_TCP_PAYLOAD);
+                self.evaluate(policies::ALLOW_TCP_PAYLOAD, args, IngressEgress::default())
+                    .map(|res| res.map(|(allow, _meta)| allow))
+                    .then(move |res| strategy.resolve(res))
+                    .boxed()
+            }
+        };
+        async move { Ok(connect.await? && inspect.await?) }.boxed()
+    }
+    /// Notify the proxy that a TCP policy (with the given version) has been installed
+    pub fn updated(&self, version: u64) {
+        if let Some((server, _port)) = &self.proxy {
+            if self.on_update != TcpUpdate::Keep {
+                server.do_send(tcp_proxy::PolicyUpdated(self.on_update, version))
+            }
+        }
+    }
 }
 
 impl Policy<Addr<tcp_proxy::TcpDataServer>> for TcpPolicy {
@@ -56,17 +134,23 @@ impl Policy<Addr<tcp_proxy::TcpDataServer>> for TcpPolicy {
         }
         self.proxy = None
     }
-    fn set_policy(&mut self, p: policies::DPPolicy) {
+    fn set_policy(&mut self, p: policies::DPPolicy, version: u64) {
+        self.version = PolicyVersion::new(version, &p);
         self.connect = p
             .get(policies::ALLOW_TCP_CONNECTION)
             .cloned()
             .unwrap_or_default();
+        self.payload = p
+            .get(policies::ALLOW_TCP_PAYLOAD)
+            .cloned()
+            .unwrap_or_default();
         self.disconnect = p
             .get(policies::ON_TCP_DISCONNECT)
             .cloned()
             .unwrap_or_default();
         self.policy = Arc::new(p);
-        self.env = DPEnv::new(&self.policy.program)
+        self.env = DPEnv::new(&self.policy.program);
+        self.updated(version)
     }
     fn port(&self) -> Option<u16> {
         self.proxy.as_ref().map(|p| p.1)
@@ -74,8 +158,8 @@ impl Policy<Addr<tcp_proxy::TcpDataServer>> for TcpPolicy {
     fn policy(&self) -> Arc<policies::DPPolicy> {
         self.policy.clone()
     }
-    fn hash(&self) -> String {
-        self.policy.blake3()
+    fn version(&self) -> PolicyVersion {
+        self.version.clone()
     }
     fn env(&self) -> &DPEnv {
         &self.env
@@ -84,6 +168,8 @@ impl Policy<Addr<tcp_proxy::TcpDataServer>> for TcpPolicy {
         Box::new(Status {
             port: self.port(),
             policy: (*self.policy()).clone(),
+            on_error: self.policy().on_error,
+            version: self.version.counter,
             ingress: None,
         })
     }
@@ -93,64 +179,309 @@ impl Default for TcpPolicy {
     fn default() -> Self {
         let policy = Arc::new(policies::DPPolicy::deny_all(Protocol::TCP));
         let env = DPEnv::new(&policy.program);
+        let version = PolicyVersion::new(0, &policy);
         TcpPolicy {
             connect: FnPolicy::default(),
+            payload: FnPolicy::default(),
             disconnect: FnPolicy::default(),
             policy,
+            version,
             env,
             proxy: None,
+            proxy_protocol: ProxyProtocol::default(),
+            on_update: TcpUpdate::default(),
         }
     }
 }
 
-// TCP connection policies
+// TCP connection policies (with encrypted metadata from a PROXY protocol header)
 #[derive(Message)]
 #[rtype("Result<TcpPolicyStatus, Error>")]
-pub struct GetTcpPolicy(pub std::net::SocketAddr, pub std::net::SocketAddr);
+pub struct GetTcpPolicy(
+    pub std::net::SocketAddr,
+    pub std::net::SocketAddr,
+    pub Option<String>,
+);
 
 pub enum TcpPolicyStatus {
-    Allow(Box<Option<ConnectionStats>>),
+    Allow(Box<Admission>),
+    // allowed, subject to the `allow_tcp_payload` policy
+    Inspect(Box<Admission>),
     Block,
 }
 
+/// Allowed connection, tagged with the version of the policy that allowed it
+pub struct Admission {
+    pub connection: Option<ConnectionStats>,
+    pub meta: Option<String>, // encrypted egress metadata
+    pub version: u64,
+    pub ingress: Option<Meta>, // decrypted ingress metadata (for re-evaluation)
+}
+
+impl Admission {
+    fn new(connection: Option<ConnectionStats>, meta: Option<String>, version: u64) -> Box<Self> {
+        Box::new(Admission {
+            connection,
+            meta,
+            version,
+            ingress: None,
+        })
+    }
+    fn with_ingress(mut self: Box<Self>, ingress: Option<Meta>) -> Box<Self> {
+        self.ingress = ingress;
+        self
+    }
+}
+
+/// Re-evaluate an established connection, after a new policy has been installed.
+///
+/// Unlike `GetTcpPolicy`, there are no side effects, and the connection is evaluated with the
+/// ingress metadata (and payload) that it was admitted with. Returns the policy version if the
+/// connection is still allowed.
+#[derive(Message)]
+#[rtype("Result<Option<u64>, Error>")]
+pub struct Reevaluate {
+    pub from: std::net::SocketAddr,
+    pub to: std::net::SocketAddr,
+    pub ingress: Option<Meta>,
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Handler<Reevaluate> for PolicyActor {
+    type Result = ResponseFuture<Result<Option<u64>, Error>>;
+
+    fn handle(&mut self, msg: Reevaluate, _ctx: &mut Context<Self>) -> Self::Result {
+        let (from, to) = (ID::SocketAddr(msg.from), ID::SocketAddr(msg.to));
+        let service = self.service(&from, &to);
+        let version = self.tcp_policy(&service).version.counter;
+        // in audit (dry-run) mode connections are never closed
+        if self.audit.enabled {
+            return Box::pin(future::ok(Some(version)));
+        }
+        let connection = self.established(from, to).into();
+        let meta = IngressEgress::new(msg.ingress, self.label.clone());
+        Box::pin(
+            self.tcp_policy(&service)
+                .reevaluate(connection, meta, msg.payload)
+                .map_ok(move |allow| if allow { Some(version) } else { None }),
+        )
+    }
+}
+
 impl Handler<GetTcpPolicy> for PolicyActor {
     type Result = ResponseFuture<Result<TcpPolicyStatus, Error>>;
 
-    fn handle(&mut self, msg: GetTcpPolicy, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: GetTcpPolicy, ctx: &mut Context<Self>) -> Self::Result {
+        let service = self.service(&ID::SocketAddr(msg.0), &ID::SocketAddr(msg.1));
+        let status = self.tcp_connection(msg, service.clone(), ctx);
+        if self.tcp_policy(&service).payload == FnPolicy::Allow {
+            status
+        } else {
+            Box::pin(status.map_ok(|status| match status {
+                TcpPolicyStatus::Allow(admission) => TcpPolicyStatus::Inspect(admission),
+                status => status,
+            }))
+        }
+    }
+}
+
+// TCP payload policies, evaluated on the first bytes sent by the client
+#[derive(Message)]
+#[rtype("Result<bool, Error>")]
+pub struct GetTcpPayloadPolicy(
+    pub std::net::SocketAddr,
+    pub std::net::SocketAddr,
+    pub Vec<u8>,
+);
+
+impl Handler<GetTcpPayloadPolicy> for PolicyActor {
+    type Result = ResponseFuture<Result<bool, Error>>;
+
+    fn handle(&mut self, msg: GetTcpPayloadPolicy, ctx: &mut Context<Self>) -> Self::Result {
+        log::debug!("Handling TCP payload at proxy: {}", self.label);
+        let service = self.service(&ID::SocketAddr(msg.0), &ID::SocketAddr(msg.1));
+        match self.tcp_policy(&service).payload {
+            FnPolicy::Allow => {
+                self.metrics
+                    .decision(policies::ALLOW_TCP_PAYLOAD, Some(true), None);
+                Box::pin(future::ok(true))
+            }
+            FnPolicy::Deny => {
+                self.metrics
+                    .decision(policies::ALLOW_TCP_PAYLOAD, Some(false), None);
+                if let Some(auditor) = self.auditor(ctx, policies::ALLOW_TCP_PAYLOAD, msg.0, msg.1)
+                {
+                    auditor.record(None);
+                    Box::pin(future::ok(true))
+                } else {
+                    Box::pin(future::ok(false))
+                }
+            }
+            FnPolicy::Args(n) => {
+                let data = DPExpr::from(msg.2);
+                let args = match n {
+                    0 => Vec::new(),
+                    1 => vec![data],
+                    2 => vec![
+                        self.connection(ID::SocketAddr(msg.0), ID::SocketAddr(msg.1))
+                            .into(),
+                        data,
+                    ],
+                    _ => unreachable!(), // policy is checked beforehand
+                };
+                let auditor = self.auditor(ctx, policies::ALLOW_TCP_PAYLOAD, msg.0, msg.1);
+                let strategy = PolicyActor::error_strategy(
+                    ctx,
+                    self.tcp_policy(&service),
+                    policies::ALLOW_TCP_PAYLOAD,
+                );
+                Box::pin(
+                    Observe::evaluation(
+                        ctx.address(),
+                        policies::ALLOW_TCP_PAYLOAD,
+                        self.tcp_policy(&service).evaluate(
+                            policies::ALLOW_TCP_PAYLOAD,
+                            args,
+                            IngressEgress::default(),
+                        ),
+                    )
+                    .map(|res| res.map(|(res, _meta)| res))
+                    .then(move |res| ErrorStrategy::resolve(strategy, res))
+                    .map(move |res| Auditor::check(&auditor, res)),
+                )
+            }
+        }
+    }
+}
+
+impl PolicyActor {
+    fn tcp_connection(
+        &mut self,
+        msg: GetTcpPolicy,
+        service: Option<ServiceId>,
+        ctx: &mut Context<Self>,
+    ) -> ResponseFuture<Result<TcpPolicyStatus, Error>> {
         log::debug!("Handling TCP request at proxy: {}", self.label);
-        match self.tcp.connect {
+        self.metrics.request(&Protocol::TCP);
+        let request = format!("{} -> {}", msg.0, msg.1);
+        let version = self.tcp_policy(&service).version.counter;
+        // try to decrypt ingress metadata (kept for any re-evaluation of the connection)
+        let ingress = msg.2.as_ref().and_then(|meta| self.keys.decrypt_meta(meta));
+        match self.tcp_policy(&service).connect {
             FnPolicy::Allow => {
-                self.connection_number += 1;
-                Box::pin(future::ok(TcpPolicyStatus::Allow(Box::new(None))))
+                self.metrics
+                    .decision(policies::ALLOW_TCP_CONNECTION, Some(true), None);
+                if let Some(shadow) = self.shadow_connection(ctx, request, &msg, &ingress) {
+                    shadow.compare(Decision::Allow)
+                }
+                Box::pin(future::ok(TcpPolicyStatus::Allow(
+                    Admission::new(None, None, version).with_ingress(ingress),
+                )))
             }
             FnPolicy::Deny => {
-                log::info!("deny");
-                Box::pin(future::ok(TcpPolicyStatus::Block))
+                self.metrics
+                    .decision(policies::ALLOW_TCP_CONNECTION, Some(false), None);
+                if let Some(shadow) = self.shadow_connection(ctx, request, &msg, &ingress) {
+                    shadow.compare(Decision::Deny)
+                }
+                if let Some(auditor) =
+                    self.auditor(ctx, policies::ALLOW_TCP_CONNECTION, msg.0, msg.1)
+                {
+                    auditor.record(None);
+                    Box::pin(future::ok(TcpPolicyStatus::Allow(
+                        Admission::new(None, None, version).with_ingress(ingress),
+                    )))
+                } else {
+                    log::info!("deny");
+                    Box::pin(future::ok(TcpPolicyStatus::Block))
+                }
             }
             FnPolicy::Args(n) if n == 1 => {
                 let connection = self
                     .connection(ID::SocketAddr(msg.0), ID::SocketAddr(msg.1))
                     .into();
-                let stats = ConnectionStats::new(&connection);
+                let stats = ConnectionStats::new(&connection, service.clone());
+                let shadow = self.shadow(
+                    ctx,
+                    policies::ALLOW_TCP_CONNECTION,
+                    request,
+                    Ingress::Decrypted(ingress.clone()),
+                    |_| vec![connection.clone()],
+                );
+                let auditor = self.auditor(ctx, policies::ALLOW_TCP_CONNECTION, msg.0, msg.1);
+                let strategy = PolicyActor::error_strategy(
+                    ctx,
+                    self.tcp_policy(&service),
+                    policies::ALLOW_TCP_CONNECTION,
+                );
+                let meta = IngressEgress::new(ingress.clone(), self.label.clone());
+                let key = self.keys.current();
                 Box::pin(
-                    self.tcp
-                        .evaluate(
+                    Observe::evaluation(
+                        ctx.address(),
+                        policies::ALLOW_TCP_CONNECTION,
+                        self.tcp_policy(&service).evaluate(
                             policies::ALLOW_TCP_CONNECTION,
                             vec![connection],
-                            IngressEgress::default(), // TODO
-                        )
-                        .and_then(move |(res, _meta)| {
-                            future::ok(if res {
-                                TcpPolicyStatus::Allow(Box::new(Some(stats)))
-                            } else {
-                                TcpPolicyStatus::Block
-                            })
-                        }),
+                            meta,
+                        ),
+                    )
+                    .map(move |res| {
+                        let (res, meta) = match res {
+                            Ok((res, meta)) => (Ok(res), key.encrypt_meta(meta)),
+                            Err(err) => (Err(err), None),
+                        };
+                        if let Some(shadow) = shadow {
+                            shadow.compare(Decision::from(&res))
+                        }
+                        res.map(|res| (res, meta))
+                    })
+                    .then(move |res| match res {
+                        Err(err) => strategy
+                            .resolve(Err(err))
+                            .map_ok(|allow| (allow, None))
+                            .boxed(),
+                        res => future::ready(res).boxed(),
+                    })
+                    .map(move |res| {
+                        let meta = res.as_ref().ok().and_then(|(_, meta)| meta.clone());
+                        Auditor::check(&auditor, res.map(|(res, _meta)| res)).map(|res| (res, meta))
+                    })
+                    .and_then(move |(res, meta)| {
+                        future::ok(if res {
+                            TcpPolicyStatus::Allow(
+                                Admission::new(Some(stats), meta, version).with_ingress(ingress),
+                            )
+                        } else {
+                            TcpPolicyStatus::Block
+                        })
+                    }),
                 )
             }
             _ => unreachable!(), // policy is checked beforehand
         }
     }
+
+    // shadow evaluation for a connection, when the active decision is trivial
+    // (every connection is numbered, whether or not it is shadowed)
+    fn shadow_connection(
+        &mut self,
+        ctx: &Context<Self>,
+        request: String,
+        msg: &GetTcpPolicy,
+        ingress: &Option<Meta>,
+    ) -> Option<Shadow> {
+        let (from, to) = (msg.0, msg.1);
+        let ingress = Ingress::Decrypted(ingress.clone());
+        let function = policies::ALLOW_TCP_CONNECTION;
+        let shadow = self.shadow(ctx, function, request, ingress, |act| {
+            vec![act
+                .established(ID::SocketAddr(from), ID::SocketAddr(to))
+                .into()]
+        });
+        self.connection_number += 1;
+        shadow
+    }
 }
 
 #[derive(Message)]
@@ -160,14 +491,16 @@ pub struct ConnectionStats {
     pub sent: usize,
     pub received: usize,
     pub connection: DPExpr,
+    pub service: Option<ServiceId>,
 }
 
 impl ConnectionStats {
-    pub fn new(connection: &DPExpr) -> ConnectionStats {
+    pub fn new(connection: &DPExpr, service: Option<ServiceId>) -> ConnectionStats {
         ConnectionStats {
             sent: 0,
             received: 0,
             connection: connection.clone(),
+            service,
         }
     }
 }
@@ -177,7 +510,8 @@ impl Handler<ConnectionStats> for PolicyActor {
     type Result = ResponseFuture<Result<(), ()>>;
 
     fn handle(&mut self, msg: ConnectionStats, _ctx: &mut Context<Self>) -> Self::Result {
-        if let FnPolicy::Args(arg_count) = self.tcp.disconnect {
+        let tcp = self.tcp_policy(&msg.service);
+        if let FnPolicy::Args(arg_count) = tcp.disconnect {
             let args = match arg_count {
                 3 => vec![
                     msg.connection,
@@ -187,8 +521,7 @@ impl Handler<ConnectionStats> for PolicyActor {
                 _ => unreachable!(), // policy is checked beforehand
             };
             Box::pin(
-                self.tcp
-                    .evaluate(policies::ON_TCP_DISCONNECT, args, IngressEgress::default())
+                tcp.evaluate(policies::ON_TCP_DISCONNECT, args, IngressEgress::default())
                     .and_then(|((), _meta)| future::ok(()))
                     .map_err(|e| log::warn!("error: {}", e)),
             )
@@ -197,3 +530,72 @@ impl Handler<ConnectionStats> for PolicyActor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use armour_lang::{labels::Label, literals::DPConnection};
+
+    fn tcp_policy(buf: &str) -> TcpPolicy {
+        let policies = policies::DPPolicies::from_buf(buf).unwrap();
+        let mut tcp = TcpPolicy::default();
+        tcp.set_policy(policies.policy(Protocol::TCP).unwrap().clone(), 1);
+        tcp
+    }
+
+    async fn reevaluate(tcp: &TcpPolicy, ingress: Option<Meta>, payload: Option<&[u8]>) -> bool {
+        let meta = IngressEgress::new(ingress, "Armour::test".parse().unwrap());
+        tcp.reevaluate(
+            DPConnection::default().into(),
+            meta,
+            payload.map(|p| p.to_vec()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn reevaluate_ingress() {
+        let tcp = tcp_policy(
+            r#"
+            fn allow_tcp_connection(c: Connection) -> bool {
+                if let Some(id) = Ingress::id() { id == 'Service::frontend' } else { false }
+            }
+            "#,
+        );
+        let frontend: Label = "Service::frontend".parse().unwrap();
+        let backend: Label = "Service::backend".parse().unwrap();
+        assert!(reevaluate(&tcp, Some(Meta::new(frontend)), None).await);
+        assert!(!reevaluate(&tcp, Some(Meta::new(backend)), None).await);
+        assert!(!reevaluate(&tcp, None, None).await)
+    }
+
+    #[actix_rt::test]
+    async fn reevaluate_payload() {
+        let tcp = tcp_policy(
+            r#"
+            fn allow_tcp_payload(c: Connection, d: data) -> bool {
+                data::protocol(d) == "ssh"
+            }
+            "#,
+        );
+        assert!(reevaluate(&tcp, None, Some(b"SSH-2.0-OpenSSH_8.2\r\n")).await);
+        assert!(!reevaluate(&tcp, None, Some(b"GET / HTTP/1.1\r\n\r\n")).await);
+        assert!(!reevaluate(&tcp, None, None).await)
+    }
+
+    #[actix_rt::test]
+    async fn reevaluate_allow_deny() {
+        let tcp = tcp_policy("fn allow_tcp_connection(c: Connection) -> bool { true }");
+        assert!(reevaluate(&tcp, None, None).await);
+        let tcp = tcp_policy("fn allow_tcp_connection(c: Connection) -> bool { false }");
+        assert!(!reevaluate(&tcp, None, None).await);
+        let tcp = tcp_policy(
+            r#"
+            fn allow_tcp_connection() -> bool { true }
+            fn allow_tcp_payload(d: data) -> bool { false }
+            "#,
+        );
+        assert!(!reevaluate(&tcp, None, Some(b"data")).await)
+    }
+}
@@ -23,16 +23,22 @@
  */
 
 use super::{
+    dual_stack,
+    metrics::ConnectionEvent,
     policy,
+    proxy_protocol::Header,
     tcp_codec::{client, server},
     tcp_policy, Stop,
 };
 use actix::prelude::*;
+use armour_api::proxy::{ProxyProtocol, TcpUpdate};
+use armour_lang::{meta::Meta, policies::Protocol};
 use futures::StreamExt;
 use policy::PolicyActor;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use tcp_policy::TcpPolicyStatus;
-use tokio::io::WriteHalf;
+use tokio::io::{AsyncWriteExt, WriteHalf};
 use tokio_util::codec::FramedRead;
 
 #[cfg(target_os = "linux")]
@@ -41,10 +47,20 @@ use std::os::unix::io::AsRawFd;
 pub async fn start_proxy(
     proxy_port: u16,
     policy: Addr<PolicyActor>,
+    proxy_protocol: ProxyProtocol,
 ) -> std::io::Result<Addr<TcpDataServer>> {
     let socket_in = SocketAddr::from(([0, 0, 0, 0], proxy_port));
     log::info!("starting TCP repeater on port {}", proxy_port);
     let listener = Box::new(tokio::net::TcpListener::bind(&socket_in).await?);
+    // also listen for IPv6 connections, if IPv6 is available
+    let listener6 =
+        match dual_stack::tcp_listener6(proxy_port).and_then(tokio::net::TcpListener::from_std) {
+            Ok(listener6) => Some(Box::new(listener6)),
+            Err(err) => {
+                log::warn!("TCP {}: not listening for IPv6: {}", proxy_port, err);
+                None
+            }
+        };
     // start server, listening for connections on a TCP socket
     let server = TcpDataServer::create(move |ctx| {
         ctx.add_stream(
@@ -52,9 +68,18 @@ pub async fn start_proxy(
                 .incoming()
                 .map(|st| TcpConnect(st.unwrap())),
         );
+        if let Some(listener6) = listener6 {
+            ctx.add_stream(
+                Box::leak(listener6)
+                    .incoming()
+                    .map(|st| TcpConnect(st.unwrap())),
+            );
+        }
         TcpDataServer {
             policy,
             port: socket_in.port(),
+            proxy_protocol,
+            connections: HashMap::new(),
         }
     });
     Ok(server)
@@ -66,6 +91,19 @@ pub async fn start_proxy(
 pub struct TcpDataServer {
     policy: Addr<PolicyActor>,
     pub port: u16,
+    // read and write PROXY protocol headers (carrying Armour metadata)
+    proxy_protocol: ProxyProtocol,
+    // established connections
+    connections: HashMap<Addr<TcpData>, Admitted>,
+}
+
+// established connection, with what is needed to re-evaluate it
+struct Admitted {
+    from: SocketAddr,
+    to: SocketAddr,
+    version: u64,             // version of the policy that admitted the connection
+    ingress: Option<Meta>,    // metadata from an upstream Armour proxy
+    payload: Option<Vec<u8>>, // first bytes sent by the client, if they were inspected
 }
 
 impl Actor for TcpDataServer {
@@ -82,11 +120,100 @@ impl Handler<Stop> for TcpDataServer {
     }
 }
 
-// obtain the original socket destination (SO_ORIGINAL_DST)
-// we assume Linux's `iptables` have been used to redirect connections to the proxy
+/// Configure PROXY protocol headers
+#[derive(Message)]
+#[rtype("()")]
+pub struct SetProxyProtocol(pub ProxyProtocol);
+
+impl Handler<SetProxyProtocol> for TcpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: SetProxyProtocol, _ctx: &mut Context<Self>) -> Self::Result {
+        self.proxy_protocol = msg.0
+    }
+}
+
+/// Notification that a new TCP policy version has been installed
+#[derive(Message)]
+#[rtype("()")]
+pub struct PolicyUpdated(pub TcpUpdate, pub u64);
+
+impl Handler<PolicyUpdated> for TcpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: PolicyUpdated, ctx: &mut Context<Self>) -> Self::Result {
+        let PolicyUpdated(update, version) = msg;
+        for (data, admitted) in self.connections.iter() {
+            if admitted.version >= version {
+                continue;
+            }
+            let (from, to) = (admitted.from, admitted.to);
+            match update {
+                TcpUpdate::Keep => (),
+                TcpUpdate::Drain => {
+                    log::info!("draining {} -> {} (policy v{})", from, to, admitted.version);
+                    data.do_send(Stop)
+                }
+                TcpUpdate::Reevaluate => {
+                    let data = data.clone();
+                    self.policy
+                        .send(tcp_policy::Reevaluate {
+                            from,
+                            to,
+                            ingress: admitted.ingress.clone(),
+                            payload: admitted.payload.clone(),
+                        })
+                        .into_actor(self)
+                        .map(move |allow, act, _ctx| match allow {
+                            Ok(Ok(Some(version))) => {
+                                if let Some(connection) = act.connections.get_mut(&data) {
+                                    connection.version = version
+                                }
+                            }
+                            _ => {
+                                log::info!(
+                                    "closing {} -> {} (denied by policy v{})",
+                                    from,
+                                    to,
+                                    version
+                                );
+                                data.do_send(Stop)
+                            }
+                        })
+                        .spawn(ctx);
+                }
+            }
+        }
+    }
+}
+
+/// Registration of established connections
+#[derive(Message)]
+#[rtype("()")]
+enum Established {
+    Opened(Addr<TcpData>, Admitted),
+    Closed(Addr<TcpData>),
+}
+
+impl Handler<Established> for TcpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: Established, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            Established::Opened(data, admitted) => {
+                self.connections.insert(data, admitted);
+            }
+            Established::Closed(data) => {
+                self.connections.remove(&data);
+            }
+        }
+    }
+}
+
+// obtain the original socket destination (SO_ORIGINAL_DST or IP6T_SO_ORIGINAL_DST)
+// we assume Linux's `iptables` (or `ip6tables`) have been used to redirect connections to the proxy
 #[cfg(target_os = "linux")]
 fn original_dst(sock: &tokio::net::TcpStream) -> Option<std::net::SocketAddr> {
-    if let Ok(sock_in) =
+    if sock.local_addr().ok()?.is_ipv6() {
+        original_dst6(sock)
+    } else if let Ok(sock_in) =
         nix::sys::socket::getsockopt(sock.as_raw_fd(), nix::sys::socket::sockopt::OriginalDst)
     {
         // swap byte order
@@ -107,6 +234,31 @@ fn original_dst(sock: &tokio::net::TcpStream) -> Option<std::net::SocketAddr> {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn original_dst6(sock: &tokio::net::TcpStream) -> Option<std::net::SocketAddr> {
+    let mut sock_in6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IP6T_SO_ORIGINAL_DST,
+            &mut sock_in6 as *mut libc::sockaddr_in6 as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0
+    {
+        Some(std::net::SocketAddr::from(std::net::SocketAddrV6::new(
+            std::net::Ipv6Addr::from(sock_in6.sin6_addr.s6_addr),
+            u16::from_be(sock_in6.sin6_port),
+            sock_in6.sin6_flowinfo,
+            sock_in6.sin6_scope_id,
+        )))
+    } else {
+        None
+    }
+}
+
 #[cfg(not(target_os = "linux"))]
 fn original_dst(_sock: &tokio::net::TcpStream) -> Option<std::net::SocketAddr> {
     None
@@ -120,6 +272,26 @@ fn shutdown_both(stream: tokio::net::TcpStream) {
 
 // const LINGER_TIME: u64 = 60;
 
+// number of client bytes inspected by the `allow_tcp_payload` policy
+const PAYLOAD_BYTES: usize = 1024;
+// time to wait for the client to send its first bytes
+const PAYLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// peek at the first bytes sent by the client, without consuming them
+async fn peek_payload(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+    let mut buf = vec![0u8; PAYLOAD_BYTES];
+    match tokio::time::timeout(PAYLOAD_TIMEOUT, stream.peek(&mut buf)).await {
+        Ok(Ok(n)) => buf.truncate(n),
+        Ok(Err(e)) => {
+            log::warn!("failed to read TCP payload: {}", e);
+            buf.clear()
+        }
+        // the client has not sent anything (e.g. the server speaks first)
+        Err(_) => buf.clear(),
+    }
+    buf
+}
+
 /// Notification of new TCP socket connection
 #[derive(Message)]
 #[rtype("()")]
@@ -149,61 +321,125 @@ impl StreamHandler<TcpConnect> for TcpDataServer {
                     };
                 } else {
                     let policy = self.policy.clone();
-                    self.policy
-                        .send(tcp_policy::GetTcpPolicy(peer_addr, socket))
-                        .into_actor(self)
-                        .then(move |allow, act, _ctx| {
-                            async move {
-                                match allow {
-                                    Ok(Ok(TcpPolicyStatus::Allow(connection))) => {
-                                        // For each incoming connection we create a `TcpData` actor
-                                        if let Ok(sock) =
-                                            tokio::net::TcpStream::connect(&socket).await
-                                        {
-                                            TcpData::create(move |ctx| {
-                                                let (r, wc) = tokio::io::split(msg.0);
-                                                ctx.add_stream(FramedRead::new(
-                                                    r,
-                                                    client::ClientCodec,
-                                                ));
-                                                let (r, ws) = tokio::io::split(sock);
-                                                ctx.add_stream(FramedRead::new(
-                                                    r,
-                                                    server::ServerCodec,
-                                                ));
-                                                TcpData {
-                                                    policy,
-                                                    counter: 0,
-                                                    client_writer: actix::io::Writer::new(wc, ctx),
-                                                    server_writer: actix::io::Writer::new(ws, ctx),
-                                                    connection: *connection,
-                                                }
-                                            });
-                                        } else {
-                                            log::warn!("failed to connect to socket: {}", socket);
-                                            shutdown_both(msg.0)
-                                        }
-                                    }
-                                    // reject
-                                    Ok(Ok(TcpPolicyStatus::Block)) => {
-                                        log::info!("connection denied");
-                                        shutdown_both(msg.0)
-                                    }
-                                    // policy error
-                                    Ok(Err(e)) => {
-                                        log::warn!("{}", e);
-                                        shutdown_both(msg.0)
+                    let listen = self.proxy_protocol.listen;
+                    // only destinations proxied by Armour expect a header
+                    let send_header = self.proxy_protocol.destinations.contains(&socket.ip());
+                    let server = ctx.address();
+                    // the connection is set up in its own task, so that slow clients (and
+                    // payload inspection) do not hold up the server
+                    actix::spawn(async move {
+                        let mut stream = msg.0;
+                        // metadata from an upstream Armour proxy
+                        let header = if listen {
+                            match Header::read(&mut stream).await {
+                                Ok(header) => header,
+                                Err(e) => {
+                                    log::warn!("{}", e);
+                                    shutdown_both(stream);
+                                    return;
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        if let Some(header) = &header {
+                            log::debug!(
+                                "PROXY header: {:?} -> {:?}",
+                                header.source,
+                                header.destination
+                            )
+                        }
+                        let allow = policy
+                            .send(tcp_policy::GetTcpPolicy(
+                                peer_addr,
+                                socket,
+                                header.and_then(|header| header.armour),
+                            ))
+                            .await;
+                        let mut inspected = None;
+                        let allow = match allow {
+                            Ok(Ok(TcpPolicyStatus::Inspect(admission))) => {
+                                let payload = peek_payload(&mut stream).await;
+                                inspected = Some(payload.clone());
+                                match policy
+                                    .send(tcp_policy::GetTcpPayloadPolicy(
+                                        peer_addr, socket, payload,
+                                    ))
+                                    .await
+                                {
+                                    Ok(Ok(true)) => Ok(Ok(TcpPolicyStatus::Allow(admission))),
+                                    Ok(Ok(false)) => {
+                                        log::info!("payload denied");
+                                        Ok(Ok(TcpPolicyStatus::Block))
                                     }
-                                    // actor error
-                                    Err(e) => {
-                                        log::warn!("{}", e);
-                                        shutdown_both(msg.0)
+                                    Ok(Err(e)) => Ok(Err(e)),
+                                    Err(e) => Err(e),
+                                }
+                            }
+                            allow => allow,
+                        };
+                        match allow {
+                            Ok(Ok(TcpPolicyStatus::Allow(admission))) => {
+                                let tcp_policy::Admission {
+                                    connection,
+                                    meta,
+                                    version,
+                                    ingress,
+                                } = *admission;
+                                // For each incoming connection we create a `TcpData` actor
+                                if let Ok(mut sock) = tokio::net::TcpStream::connect(&socket).await
+                                {
+                                    // pass on egress metadata to a downstream Armour proxy
+                                    if let Some(meta) = meta.filter(|_| send_header) {
+                                        let header = Header::new(peer_addr, socket, meta);
+                                        if let Err(e) = sock.write_all(&header.encode()).await {
+                                            log::warn!("failed to send PROXY header: {}", e)
+                                        }
                                     }
+                                    TcpData::create(move |ctx| {
+                                        let (r, wc) = tokio::io::split(stream);
+                                        ctx.add_stream(FramedRead::new(r, client::ClientCodec));
+                                        let (r, ws) = tokio::io::split(sock);
+                                        ctx.add_stream(FramedRead::new(r, server::ServerCodec));
+                                        TcpData {
+                                            policy,
+                                            server,
+                                            from: peer_addr,
+                                            to: socket,
+                                            version,
+                                            ingress,
+                                            payload: inspected,
+                                            counter: 0,
+                                            sent: 0,
+                                            received: 0,
+                                            client_writer: actix::io::Writer::new(wc, ctx),
+                                            server_writer: actix::io::Writer::new(ws, ctx),
+                                            connection,
+                                        }
+                                    });
+                                } else {
+                                    log::warn!("failed to connect to socket: {}", socket);
+                                    shutdown_both(stream)
                                 }
                             }
-                            .into_actor(act)
-                        })
-                        .wait(ctx)
+                            Ok(Ok(TcpPolicyStatus::Inspect(..))) => unreachable!(), // inspected above
+                            // reject
+                            Ok(Ok(TcpPolicyStatus::Block)) => {
+                                log::info!("connection denied");
+                                shutdown_both(stream)
+                            }
+                            // policy error
+                            Ok(Err(e)) => {
+                                log::warn!("{}", e);
+                                shutdown_both(stream)
+                            }
+                            // actor error
+                            Err(e) => {
+                                log::warn!("{}", e);
+                                shutdown_both(stream)
+                            }
+                        }
+                    })
                 }
             } else {
                 log::warn!("TCP {}: could not obtain source IP address", self.port)
@@ -219,7 +455,15 @@ impl StreamHandler<TcpConnect> for TcpDataServer {
 /// There will be one actor per TCP socket connection
 pub struct TcpData {
     policy: Addr<PolicyActor>,
+    server: Addr<TcpDataServer>,
+    from: SocketAddr,
+    to: SocketAddr,
+    version: u64, // version of the policy that admitted the connection
+    ingress: Option<Meta>,
+    payload: Option<Vec<u8>>,
     counter: usize,
+    sent: usize,
+    received: usize,
     client_writer: actix::io::Writer<WriteHalf<tokio::net::TcpStream>, std::io::Error>,
     server_writer: actix::io::Writer<WriteHalf<tokio::net::TcpStream>, std::io::Error>,
     connection: Option<tcp_policy::ConnectionStats>,
@@ -229,13 +473,37 @@ impl Actor for TcpData {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.policy.do_send(ConnectionEvent::Opened(Protocol::TCP));
+        self.server.do_send(Established::Opened(
+            ctx.address(),
+            Admitted {
+                from: self.from,
+                to: self.to,
+                version: self.version,
+                ingress: self.ingress.take(),
+                payload: self.payload.take(),
+            },
+        ));
         self.hb(ctx);
     }
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.server.do_send(Established::Closed(ctx.address()));
         if let Some(connection) = &self.connection {
             self.policy.do_send(connection.clone());
         }
-        log::info!("end of connection")
+        self.policy.do_send(ConnectionEvent::Closed(
+            Protocol::TCP,
+            self.sent,
+            self.received,
+        ));
+        log::info!("end of connection (policy v{})", self.version)
+    }
+}
+
+impl Handler<Stop> for TcpData {
+    type Result = ();
+    fn handle(&mut self, _msg: Stop, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop()
     }
 }
 
@@ -266,6 +534,7 @@ impl StreamHandler<Result<client::ClientBytes, std::io::Error>> for TcpData {
             ctx.stop();
         } else if let Ok(client::ClientBytes(bytes)) = msg {
             self.counter += 1;
+            self.sent += bytes.len();
             if let Some(connection) = self.connection.as_mut() {
                 connection.sent += bytes.len();
             }
@@ -289,6 +558,7 @@ impl StreamHandler<Result<server::ServerBytes, std::io::Error>> for TcpData {
             ctx.stop()
         } else if let Ok(server::ServerBytes(bytes)) = msg {
             self.counter += 1;
+            self.received += bytes.len();
             if let Some(connection) = self.connection.as_mut() {
                 connection.received += bytes.len();
             }
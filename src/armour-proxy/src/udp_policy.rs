@@ -0,0 +1,390 @@
+//! UDP flow and datagram policies
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::audit::Auditor;
+use super::metrics::Observe;
+use super::on_error::ErrorStrategy;
+use super::policy::{Policy, PolicyActor, ID};
+use super::shadow::Ingress;
+use super::udp_proxy;
+use super::Stop;
+use actix::prelude::*;
+use armour_api::host::{Decision, PolicyVersion, Status};
+use armour_lang::{
+    expressions::{DPExpr, Error},
+    interpret::DPEnv,
+    literals::DPConnection,
+    meta::IngressEgress,
+    policies::{self, FnPolicy, Protocol},
+};
+use futures::future::{self, FutureExt, TryFutureExt};
+use std::sync::Arc;
+
+/// Default number of seconds after which an inactive UDP flow is forgotten
+pub const DEFAULT_IDLE_TIMEOUT: u16 = 60;
+
+pub struct UdpPolicy {
+    flow: FnPolicy,
+    datagram: FnPolicy,
+    idle_timeout: u16,
+    policy: Arc<policies::DPPolicy>,
+    version: PolicyVersion,
+    env: DPEnv,
+    proxy: Option<(Addr<udp_proxy::UdpDataServer>, u16)>,
+}
+
+impl Policy<Addr<udp_proxy::UdpDataServer>> for UdpPolicy {
+    fn start(&mut self, server: Addr<udp_proxy::UdpDataServer>, port: u16) {
+        self.proxy = Some((server, port))
+    }
+    fn stop(&mut self) {
+        if let Some((server, port)) = &self.proxy {
+            log::info!("stopping UDP proxy on port {}", port);
+            server.do_send(Stop);
+        }
+        self.proxy = None
+    }
+    fn set_policy(&mut self, p: policies::DPPolicy, version: u64) {
+        self.version = PolicyVersion::new(version, &p);
+        self.flow = p
+            .get(policies::ALLOW_UDP_FLOW)
+            .cloned()
+            .unwrap_or_default();
+        self.datagram = p
+            .get(policies::ALLOW_UDP_DATAGRAM)
+            .cloned()
+            .unwrap_or(FnPolicy::Allow);
+        self.policy = Arc::new(p);
+        self.env = DPEnv::new(&self.policy.program)
+    }
+    fn port(&self) -> Option<u16> {
+        self.proxy.as_ref().map(|p| p.1)
+    }
+    fn policy(&self) -> Arc<policies::DPPolicy> {
+        self.policy.clone()
+    }
+    fn version(&self) -> PolicyVersion {
+        self.version.clone()
+    }
+    fn env(&self) -> &DPEnv {
+        &self.env
+    }
+    fn status(&self) -> Box<Status> {
+        Box::new(Status {
+            port: self.port(),
+            policy: (*self.policy()).clone(),
+            on_error: self.policy().on_error,
+            version: self.version.counter,
+            ingress: None,
+        })
+    }
+}
+
+impl UdpPolicy {
+    pub fn idle_timeout(&self) -> u16 {
+        self.idle_timeout
+    }
+    pub fn set_idle_timeout(&mut self, secs: u16) {
+        self.idle_timeout = secs;
+        if let Some((server, _port)) = &self.proxy {
+            server.do_send(udp_proxy::IdleTimeout(secs))
+        }
+    }
+}
+
+impl Default for UdpPolicy {
+    fn default() -> Self {
+        let policy = Arc::new(policies::DPPolicy::deny_all(Protocol::UDP));
+        let env = DPEnv::new(&policy.program);
+        let version = PolicyVersion::new(0, &policy);
+        UdpPolicy {
+            flow: FnPolicy::default(),
+            datagram: FnPolicy::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            policy,
+            version,
+            env,
+            proxy: None,
+        }
+    }
+}
+
+// UDP flow policies (evaluated on the first datagram of a flow)
+#[derive(Message)]
+#[rtype("Result<UdpPolicyStatus, Error>")]
+pub struct GetUdpPolicy(pub std::net::SocketAddr, pub std::net::SocketAddr);
+
+pub enum UdpPolicyStatus {
+    /// Flow is allowed. The connection is returned when datagrams must also be checked.
+    Allow(Option<DPConnection>),
+    Block,
+}
+
+// arguments of the UDP policy functions, for each of their signatures (see `policies::udp_policy`)
+fn flow_args(n: u8, connection: Option<DPConnection>) -> Result<Vec<DPExpr>, Error> {
+    match (n, connection) {
+        (0, _) => Ok(Vec::new()),
+        (1, Some(connection)) => Ok(vec![connection.into()]),
+        (3, Some(connection)) => Ok(vec![
+            connection.from_lit().into(),
+            connection.to_lit().into(),
+            connection.into(),
+        ]),
+        _ => Err(Error::from(format!(
+            "{}: unsupported number of arguments: {}",
+            policies::ALLOW_UDP_FLOW,
+            n
+        ))),
+    }
+}
+
+fn datagram_args(n: u8, connection: DPConnection, data: Vec<u8>) -> Result<Vec<DPExpr>, Error> {
+    match n {
+        0 => Ok(Vec::new()),
+        1 => Ok(vec![DPExpr::from(data)]),
+        2 => Ok(vec![connection.into(), DPExpr::from(data)]),
+        4 => Ok(vec![
+            connection.from_lit().into(),
+            connection.to_lit().into(),
+            connection.into(),
+            DPExpr::from(data),
+        ]),
+        _ => Err(Error::from(format!(
+            "{}: unsupported number of arguments: {}",
+            policies::ALLOW_UDP_DATAGRAM,
+            n
+        ))),
+    }
+}
+
+impl Handler<GetUdpPolicy> for PolicyActor {
+    type Result = ResponseFuture<Result<UdpPolicyStatus, Error>>;
+
+    fn handle(&mut self, msg: GetUdpPolicy, ctx: &mut Context<Self>) -> Self::Result {
+        log::debug!("Handling UDP flow at proxy: {}", self.label);
+        self.metrics.request(&Protocol::UDP);
+        let service = self.service(&ID::SocketAddr(msg.0), &ID::SocketAddr(msg.1));
+        let udp = self.udp_policy(&service);
+        let (flow, datagram) = (udp.flow.clone(), udp.datagram.clone());
+        // connection literal is needed if either policy function may use it
+        let connection: Option<DPConnection> = match (&flow, &datagram) {
+            (FnPolicy::Args(_), _) | (_, FnPolicy::Args(_)) => {
+                Some(self.connection(ID::SocketAddr(msg.0), ID::SocketAddr(msg.1)))
+            }
+            _ => None,
+        };
+        // datagrams only need inspecting when the datagram policy is not trivial
+        let inspect = if let FnPolicy::Args(_) = datagram {
+            connection.clone()
+        } else {
+            None
+        };
+        let (from, to) = (msg.0, msg.1);
+        // UDP flows do not carry Armour metadata
+        let shadow = self.shadow(
+            ctx,
+            policies::ALLOW_UDP_FLOW,
+            format!("{} -> {}", from, to),
+            Ingress::default(),
+            |act| {
+                vec![connection
+                    .clone()
+                    .unwrap_or_else(|| act.connection(ID::SocketAddr(from), ID::SocketAddr(to)))
+                    .into()]
+            },
+        );
+        match flow {
+            FnPolicy::Allow => {
+                if let Some(shadow) = shadow {
+                    shadow.compare(Decision::Allow)
+                }
+                if datagram == FnPolicy::Deny {
+                    self.metrics
+                        .decision(policies::ALLOW_UDP_DATAGRAM, Some(false), None);
+                    if let Some(auditor) =
+                        self.auditor(ctx, policies::ALLOW_UDP_DATAGRAM, msg.0, msg.1)
+                    {
+                        auditor.record(None);
+                        Box::pin(future::ok(UdpPolicyStatus::Allow(None)))
+                    } else {
+                        log::info!("deny (all datagrams)");
+                        Box::pin(future::ok(UdpPolicyStatus::Block))
+                    }
+                } else {
+                    self.metrics
+                        .decision(policies::ALLOW_UDP_FLOW, Some(true), None);
+                    self.connection_number += 1;
+                    Box::pin(future::ok(UdpPolicyStatus::Allow(inspect)))
+                }
+            }
+            FnPolicy::Deny => {
+                if let Some(shadow) = shadow {
+                    shadow.compare(Decision::Deny)
+                }
+                self.metrics
+                    .decision(policies::ALLOW_UDP_FLOW, Some(false), None);
+                if let Some(auditor) = self.auditor(ctx, policies::ALLOW_UDP_FLOW, msg.0, msg.1) {
+                    auditor.record(None);
+                    Box::pin(future::ok(UdpPolicyStatus::Allow(inspect)))
+                } else {
+                    log::info!("deny");
+                    Box::pin(future::ok(UdpPolicyStatus::Block))
+                }
+            }
+            FnPolicy::Args(n) => {
+                let auditor = self.auditor(ctx, policies::ALLOW_UDP_FLOW, msg.0, msg.1);
+                let strategy =
+                    PolicyActor::error_strategy(ctx, self.udp_policy(&service), policies::ALLOW_UDP_FLOW);
+                let evaluation = match flow_args(n, connection) {
+                    // UDP flows do not carry Armour metadata
+                    Ok(args) => self.udp_policy(&service).evaluate(
+                        policies::ALLOW_UDP_FLOW,
+                        args,
+                        IngressEgress::default(),
+                    ),
+                    Err(err) => future::err(err).boxed(),
+                };
+                Box::pin(
+                    Observe::evaluation(ctx.address(), policies::ALLOW_UDP_FLOW, evaluation)
+                    .map(move |res| {
+                        let res = res.map(|(res, _meta)| res);
+                        if let Some(shadow) = shadow {
+                            shadow.compare(Decision::from(&res))
+                        }
+                        res
+                    })
+                    .then(move |res| ErrorStrategy::resolve(strategy, res))
+                    .map(move |res| Auditor::check(&auditor, res))
+                    .and_then(move |res| {
+                        future::ok(if res {
+                            UdpPolicyStatus::Allow(inspect)
+                        } else {
+                            UdpPolicyStatus::Block
+                        })
+                    }),
+                )
+            }
+        }
+    }
+}
+
+// UDP datagram policies (evaluated on every client datagram of an inspected flow)
+#[derive(Message)]
+#[rtype("Result<bool, Error>")]
+pub struct GetUdpDatagramPolicy(
+    pub DPConnection,
+    pub Vec<u8>,
+    pub (std::net::SocketAddr, std::net::SocketAddr),
+);
+
+impl Handler<GetUdpDatagramPolicy> for PolicyActor {
+    type Result = ResponseFuture<Result<bool, Error>>;
+
+    fn handle(&mut self, msg: GetUdpDatagramPolicy, ctx: &mut Context<Self>) -> Self::Result {
+        // only inspected flows reach here, so the shadow policy is never consulted for
+        // datagrams that the active policy does not check
+        let (from, to) = msg.2;
+        let service = self.service(&ID::SocketAddr(from), &ID::SocketAddr(to));
+        let shadow = self.shadow(
+            ctx,
+            policies::ALLOW_UDP_DATAGRAM,
+            format!("{} -> {} ({} bytes)", from, to, msg.1.len()),
+            Ingress::default(),
+            |_| vec![msg.0.clone().into(), DPExpr::from(msg.1.clone())],
+        );
+        match self.udp_policy(&service).datagram {
+            FnPolicy::Allow => {
+                if let Some(shadow) = shadow {
+                    shadow.compare(Decision::Allow)
+                }
+                Box::pin(future::ok(true))
+            }
+            FnPolicy::Deny => {
+                if let Some(shadow) = shadow {
+                    shadow.compare(Decision::Deny)
+                }
+                if let Some(auditor) = self.auditor(ctx, policies::ALLOW_UDP_DATAGRAM, from, to) {
+                    auditor.record(None);
+                    Box::pin(future::ok(true))
+                } else {
+                    Box::pin(future::ok(false))
+                }
+            }
+            FnPolicy::Args(n) => {
+                let auditor = self.auditor(ctx, policies::ALLOW_UDP_DATAGRAM, from, to);
+                let strategy = PolicyActor::error_strategy(
+                    ctx,
+                    self.udp_policy(&service),
+                    policies::ALLOW_UDP_DATAGRAM,
+                );
+                let evaluation = match datagram_args(n, msg.0, msg.1) {
+                    Ok(args) => self.udp_policy(&service).evaluate(
+                        policies::ALLOW_UDP_DATAGRAM,
+                        args,
+                        IngressEgress::default(),
+                    ),
+                    Err(err) => future::err(err).boxed(),
+                };
+                Box::pin(
+                    Observe::evaluation(ctx.address(), policies::ALLOW_UDP_DATAGRAM, evaluation)
+                    .map(move |res| {
+                        let res = res.map(|(res, _meta)| res);
+                        if let Some(shadow) = shadow {
+                            shadow.compare(Decision::from(&res))
+                        }
+                        res
+                    })
+                    .then(move |res| ErrorStrategy::resolve(strategy, res))
+                    .map(move |res| Auditor::check(&auditor, res)),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use armour_lang::literals::DPID;
+
+    fn connection() -> DPConnection {
+        DPConnection::from((&DPID::default(), &DPID::default(), 1))
+    }
+
+    #[test]
+    fn arguments() {
+        // every signature of the UDP policy functions is supported
+        for n in &[0, 1, 3] {
+            assert_eq!(flow_args(*n, Some(connection())).unwrap().len(), *n as usize)
+        }
+        for n in &[0, 1, 2, 4] {
+            assert_eq!(datagram_args(*n, connection(), vec![1, 2]).unwrap().len(), *n as usize)
+        }
+        // other arities are errors, not panics
+        assert!(flow_args(1, None).is_err());
+        assert!(flow_args(2, Some(connection())).is_err());
+        assert!(datagram_args(3, connection(), Vec::new()).is_err())
+    }
+}
@@ -0,0 +1,627 @@
+//! Transparent UDP proxy
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::{dual_stack, metrics::ConnectionEvent, policy, udp_policy, Stop};
+use actix::prelude::*;
+use armour_lang::policies::Protocol;
+use bytes::{Bytes, BytesMut};
+use policy::PolicyActor;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::{codec::BytesCodec, udp::UdpFramed};
+use udp_policy::UdpPolicyStatus;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+// largest UDP payload
+const MAX_DATAGRAM: usize = 65_507;
+// datagrams held back while a flow policy is being evaluated
+const MAX_PENDING: usize = 16;
+// how often the receive thread checks whether the proxy has been stopped
+const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+// most flows (allowed, pending or blocked) tracked at once, e.g. under a spoofed-source flood
+const MAX_FLOWS: usize = 65_536;
+// how often expired blocked flows are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn start_proxy(
+    proxy_port: u16,
+    policy: Addr<PolicyActor>,
+    idle_timeout: u16,
+) -> std::io::Result<Addr<UdpDataServer>> {
+    let socket_in = SocketAddr::from(([0, 0, 0, 0], proxy_port));
+    log::info!("starting UDP repeater on port {}", proxy_port);
+    let listener = listener(&socket_in)?;
+    let receiver = listener.try_clone()?;
+    // also receive IPv6 datagrams, if IPv6 is available
+    let listener6 = match listener6(proxy_port) {
+        Ok(listener6) => Some(listener6),
+        Err(err) => {
+            log::warn!("UDP {}: not listening for IPv6: {}", proxy_port, err);
+            None
+        }
+    };
+    let receiver6 = listener6.as_ref().map(UdpSocket::try_clone).transpose()?;
+    let running = Arc::new(AtomicBool::new(true));
+    let server = UdpDataServer::create(|ctx| {
+        // `recvmsg` is needed for the original destination, so receive on a dedicated thread
+        let addr = ctx.address();
+        let receiving = running.clone();
+        if let Some(receiver6) = receiver6 {
+            let (addr, receiving) = (addr.clone(), receiving.clone());
+            std::thread::spawn(move || receive(receiver6, addr, receiving));
+        }
+        std::thread::spawn(move || receive(receiver, addr, receiving));
+        UdpDataServer {
+            policy,
+            port: socket_in.port(),
+            listener,
+            listener6,
+            running,
+            idle_timeout,
+            flows: HashMap::new(),
+        }
+    });
+    Ok(server)
+}
+
+// bind the listening socket, asking for the original destination of each datagram
+// we assume Linux's `iptables` TPROXY target has been used to divert datagrams to the proxy
+#[cfg(target_os = "linux")]
+fn listener(socket_in: &SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(socket_in)?;
+    let fd = socket.as_raw_fd();
+    nix::sys::socket::setsockopt(fd, nix::sys::socket::sockopt::IpTransparent, &true)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    dual_stack::enable(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    Ok(socket)
+}
+
+// IPv6 version of `listener` (ip6tables TPROXY, IPV6_ORIGDSTADDR)
+#[cfg(target_os = "linux")]
+fn listener6(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = dual_stack::udp_socket6(port)?;
+    let fd = socket.as_raw_fd();
+    dual_stack::enable(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)?;
+    dual_stack::enable(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listener(socket_in: &SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(socket_in)?;
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listener6(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = dual_stack::udp_socket6(port)?;
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr_in(sock_in: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::from(std::net::SocketAddrV4::new(
+        std::net::Ipv4Addr::from(u32::from_be(sock_in.sin_addr.s_addr)),
+        u16::from_be(sock_in.sin_port),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr_in6(sock_in6: &libc::sockaddr_in6) -> SocketAddr {
+    SocketAddr::from(std::net::SocketAddrV6::new(
+        std::net::Ipv6Addr::from(sock_in6.sin6_addr.s6_addr),
+        u16::from_be(sock_in6.sin6_port),
+        sock_in6.sin6_flowinfo,
+        sock_in6.sin6_scope_id,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn from_sockaddr_storage(storage: &libc::sockaddr_storage) -> SocketAddr {
+    if storage.ss_family == libc::AF_INET6 as libc::sa_family_t {
+        from_sockaddr_in6(&*(storage as *const _ as *const libc::sockaddr_in6))
+    } else {
+        from_sockaddr_in(&*(storage as *const _ as *const libc::sockaddr_in))
+    }
+}
+
+// receive a datagram, together with its source and original destination (IP_ORIGDSTADDR or IPV6_ORIGDSTADDR)
+#[cfg(target_os = "linux")]
+fn recv_original_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    unsafe {
+        let mut src: libc::sockaddr_storage = std::mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u8; 64];
+        let mut hdr: libc::msghdr = std::mem::zeroed();
+        hdr.msg_name = &mut src as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = control.len() as _;
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut hdr, 0);
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut dst = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_IP && (*cmsg).cmsg_type == libc::IP_ORIGDSTADDR {
+                let sock_in = std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in
+                );
+                dst = Some(from_sockaddr_in(&sock_in))
+            } else if (*cmsg).cmsg_level == libc::SOL_IPV6
+                && (*cmsg).cmsg_type == libc::IPV6_ORIGDSTADDR
+            {
+                let sock_in6 = std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in6
+                );
+                dst = Some(from_sockaddr_in6(&sock_in6))
+            }
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+        Ok((n as usize, from_sockaddr_storage(&src), dst))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_original_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let (n, src) = socket.recv_from(buf)?;
+    Ok((n, src, None))
+}
+
+// receive loop, runs until the proxy is stopped
+fn receive(socket: UdpSocket, server: Addr<UdpDataServer>, running: Arc<AtomicBool>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    while running.load(Ordering::Relaxed) {
+        match recv_original_dst(&socket, &mut buf) {
+            Ok((n, src, Some(dst))) => server.do_send(Datagram {
+                src,
+                dst,
+                data: Bytes::copy_from_slice(&buf[..n]),
+            }),
+            Ok((_n, src, None)) => {
+                log::warn!("UDP: could not obtain original destination for {}", src)
+            }
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut
+                    || err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                log::warn!("UDP: receive failed: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+// a socket for replying to the client, that appears to come from the original destination
+#[cfg(target_os = "linux")]
+fn reply_socket(dst: &SocketAddr) -> std::io::Result<UdpSocket> {
+    use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+    use std::os::unix::io::FromRawFd;
+    let to_io = |e: nix::Error| std::io::Error::new(std::io::ErrorKind::Other, e);
+    let family = if dst.is_ipv6() {
+        AddressFamily::Inet6
+    } else {
+        AddressFamily::Inet
+    };
+    let fd = socket::socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(to_io)?;
+    // take ownership of the descriptor, so that it is closed on error
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    if dst.is_ipv6() {
+        dual_stack::enable(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)?
+    } else {
+        socket::setsockopt(fd, sockopt::IpTransparent, &true).map_err(to_io)?
+    }
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true).map_err(to_io)?;
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(dst))).map_err(to_io)?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reply_socket(_dst: &SocketAddr) -> std::io::Result<UdpSocket> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "transparent sockets not supported",
+    ))
+}
+
+/// Datagram received by the proxy
+#[derive(Message)]
+#[rtype("()")]
+struct Datagram {
+    src: SocketAddr,
+    dst: SocketAddr,
+    data: Bytes,
+}
+
+/// Change the idle timeout (in seconds) for UDP flows
+#[derive(Message)]
+#[rtype("()")]
+pub struct IdleTimeout(pub u16);
+
+/// Notification that a UDP flow has ended (sent by `UdpFlow` when stopping)
+#[derive(Message)]
+#[rtype("()")]
+struct FlowClosed(SocketAddr, SocketAddr);
+
+enum Flow {
+    /// policy is being evaluated
+    Pending(Vec<Bytes>),
+    Allowed(Addr<UdpFlow>),
+    /// flow was denied (remembered until the idle timeout expires)
+    Blocked(Instant),
+}
+
+/// Actor that receives datagrams and manages UDP flows.
+///
+/// A flow is identified by its source and original destination.
+pub struct UdpDataServer {
+    policy: Addr<PolicyActor>,
+    pub port: u16,
+    listener: UdpSocket,
+    listener6: Option<UdpSocket>,
+    running: Arc<AtomicBool>,
+    idle_timeout: u16,
+    flows: HashMap<(SocketAddr, SocketAddr), Flow>,
+}
+
+impl Actor for UdpDataServer {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _ctx| {
+            let removed = sweep(&mut act.flows, Duration::from_secs(act.idle_timeout.into()));
+            if removed > 0 {
+                log::debug!("UDP {}: forgot {} blocked flows", act.port, removed)
+            }
+        });
+    }
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.running.store(false, Ordering::Relaxed);
+        log::info!("stopped socket: {}", self.port);
+    }
+}
+
+impl Handler<Stop> for UdpDataServer {
+    type Result = ();
+    fn handle(&mut self, _msg: Stop, ctx: &mut Context<Self>) -> Self::Result {
+        for flow in self.flows.values() {
+            if let Flow::Allowed(addr) = flow {
+                addr.do_send(Stop)
+            }
+        }
+        ctx.stop()
+    }
+}
+
+impl Handler<IdleTimeout> for UdpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: IdleTimeout, _ctx: &mut Context<Self>) -> Self::Result {
+        self.idle_timeout = msg.0;
+        for flow in self.flows.values() {
+            if let Flow::Allowed(addr) = flow {
+                addr.do_send(IdleTimeout(msg.0))
+            }
+        }
+    }
+}
+
+impl Handler<FlowClosed> for UdpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: FlowClosed, _ctx: &mut Context<Self>) -> Self::Result {
+        log::debug!("UDP {}: flow {} -> {} closed", self.port, msg.0, msg.1);
+        self.flows.remove(&(msg.0, msg.1));
+    }
+}
+
+impl Handler<Datagram> for UdpDataServer {
+    type Result = ();
+    fn handle(&mut self, msg: Datagram, ctx: &mut Context<Self>) -> Self::Result {
+        let key = (msg.src, msg.dst);
+        match self.flows.get_mut(&key) {
+            Some(Flow::Allowed(addr)) => {
+                addr.do_send(msg);
+                return;
+            }
+            Some(Flow::Pending(queue)) => {
+                if queue.len() < MAX_PENDING {
+                    queue.push(msg.data)
+                }
+                return;
+            }
+            Some(Flow::Blocked(since))
+                if since.elapsed() < Duration::from_secs(self.idle_timeout.into()) =>
+            {
+                return
+            }
+            _ => (),
+        }
+        if msg.dst.port() == self.port && armour_utils::INTERFACE_IPS.contains(&msg.dst.ip()) {
+            log::warn!("UDP {}: trying to forward to self", self.port);
+            return;
+        }
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&key) {
+            log::debug!("UDP {}: too many flows, dropping datagram from {}", self.port, msg.src);
+            return;
+        }
+        log::info!(
+            "UDP {}: new flow from {}, forwarding to {}",
+            self.port,
+            msg.src,
+            msg.dst
+        );
+        self.flows.insert(key, Flow::Pending(vec![msg.data]));
+        let policy = self.policy.clone();
+        self.policy
+            .send(udp_policy::GetUdpPolicy(msg.src, msg.dst))
+            .into_actor(self)
+            .then(move |allow, act, ctx| {
+                let queue = match act.flows.remove(&key) {
+                    Some(Flow::Pending(queue)) => queue,
+                    _ => Vec::new(),
+                };
+                match allow {
+                    Ok(Ok(UdpPolicyStatus::Allow(connection))) => {
+                        match UdpFlow::start(act, ctx.address(), policy, key, connection) {
+                            Ok(flow) => {
+                                for data in queue {
+                                    flow.do_send(Datagram {
+                                        src: key.0,
+                                        dst: key.1,
+                                        data,
+                                    })
+                                }
+                                act.flows.insert(key, Flow::Allowed(flow));
+                            }
+                            Err(err) => {
+                                log::warn!("failed to connect to socket: {}\n\t{}", key.1, err)
+                            }
+                        }
+                    }
+                    // reject
+                    Ok(Ok(UdpPolicyStatus::Block)) => {
+                        log::info!("flow denied");
+                        act.flows.insert(key, Flow::Blocked(Instant::now()));
+                    }
+                    // policy error
+                    Ok(Err(e)) => log::warn!("{}", e),
+                    // actor error
+                    Err(e) => log::warn!("{}", e),
+                }
+                async {}.into_actor(act)
+            })
+            .spawn(ctx);
+    }
+}
+
+// forget blocked flows whose idle timeout has expired, returning how many were removed
+fn sweep(flows: &mut HashMap<(SocketAddr, SocketAddr), Flow>, idle_timeout: Duration) -> usize {
+    let before = flows.len();
+    flows.retain(|_, flow| match flow {
+        Flow::Blocked(since) => since.elapsed() < idle_timeout,
+        _ => true,
+    });
+    before - flows.len()
+}
+
+/// Actor that forwards the datagrams of a single UDP flow
+///
+/// There will be one actor per (source, original destination) pair
+pub struct UdpFlow {
+    server: Addr<UdpDataServer>,
+    policy: Addr<PolicyActor>,
+    client: SocketAddr,
+    server_addr: SocketAddr,
+    // connected socket to the original destination
+    upstream: UdpSocket,
+    // socket used to send replies to the client
+    reply: UdpSocket,
+    // connection literal, present when datagrams must be checked against the policy
+    connection: Option<armour_lang::literals::DPConnection>,
+    idle_timeout: u16,
+    last_seen: Instant,
+    sent: usize,
+    received: usize,
+}
+
+impl UdpFlow {
+    fn start(
+        server: &UdpDataServer,
+        server_addr: Addr<UdpDataServer>,
+        policy: Addr<PolicyActor>,
+        (client, dst): (SocketAddr, SocketAddr),
+        connection: Option<armour_lang::literals::DPConnection>,
+    ) -> std::io::Result<Addr<UdpFlow>> {
+        let upstream = UdpSocket::bind(dual_stack::unspecified(&dst))?;
+        upstream.connect(dst)?;
+        let receiver = tokio::net::UdpSocket::from_std(upstream.try_clone()?)?;
+        let reply = reply_socket(&dst).or_else(|err| {
+            log::debug!("UDP: replying from proxy socket ({})", err);
+            match (dst.is_ipv6(), &server.listener6) {
+                (true, Some(listener6)) => listener6.try_clone(),
+                _ => server.listener.try_clone(),
+            }
+        })?;
+        Ok(UdpFlow::create(move |ctx| {
+            ctx.add_stream(UdpFramed::new(receiver, BytesCodec::new()));
+            UdpFlow {
+                server: server_addr,
+                policy,
+                client,
+                server_addr: dst,
+                upstream,
+                reply,
+                connection,
+                idle_timeout: server.idle_timeout,
+                last_seen: Instant::now(),
+                sent: 0,
+                received: 0,
+            }
+        }))
+    }
+    fn send_upstream(&mut self, data: &[u8]) {
+        self.last_seen = Instant::now();
+        match self.upstream.send(data) {
+            Ok(n) => self.sent += n,
+            Err(err) => log::warn!("UDP: failed to send to {}: {}", self.server_addr, err),
+        }
+    }
+}
+
+impl Actor for UdpFlow {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.policy.do_send(ConnectionEvent::Opened(Protocol::UDP));
+        ctx.run_interval(Duration::from_secs(1), |act, ctx| {
+            if act.last_seen.elapsed() >= Duration::from_secs(act.idle_timeout.into()) {
+                log::debug!("UDP: flow {} -> {} idle", act.client, act.server_addr);
+                ctx.stop()
+            }
+        });
+    }
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.server
+            .do_send(FlowClosed(self.client, self.server_addr));
+        self.policy.do_send(ConnectionEvent::Closed(
+            Protocol::UDP,
+            self.sent,
+            self.received,
+        ));
+        log::info!("end of flow")
+    }
+}
+
+impl Handler<Stop> for UdpFlow {
+    type Result = ();
+    fn handle(&mut self, _msg: Stop, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop()
+    }
+}
+
+impl Handler<IdleTimeout> for UdpFlow {
+    type Result = ();
+    fn handle(&mut self, msg: IdleTimeout, _ctx: &mut Context<Self>) -> Self::Result {
+        self.idle_timeout = msg.0
+    }
+}
+
+// datagram from client becomes datagram to server
+impl Handler<Datagram> for UdpFlow {
+    type Result = ();
+    fn handle(&mut self, msg: Datagram, ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(connection) = self.connection.clone() {
+            // wait, so that datagrams are forwarded in order
+            self.policy
+                .send(udp_policy::GetUdpDatagramPolicy(
+                    connection,
+                    msg.data.to_vec(),
+                    (self.client, self.server_addr),
+                ))
+                .into_actor(self)
+                .then(move |allow, act, _ctx| {
+                    match allow {
+                        Ok(Ok(true)) => act.send_upstream(&msg.data),
+                        Ok(Ok(false)) => log::info!("datagram denied"),
+                        Ok(Err(e)) => log::warn!("{}", e),
+                        Err(e) => log::warn!("{}", e),
+                    }
+                    async {}.into_actor(act)
+                })
+                .wait(ctx)
+        } else {
+            self.send_upstream(&msg.data)
+        }
+    }
+}
+
+// datagram from server becomes datagram to client
+impl StreamHandler<Result<(BytesMut, SocketAddr), std::io::Error>> for UdpFlow {
+    fn handle(
+        &mut self,
+        msg: Result<(BytesMut, SocketAddr), std::io::Error>,
+        _ctx: &mut Self::Context,
+    ) {
+        if let Ok((bytes, _addr)) = msg {
+            self.last_seen = Instant::now();
+            match self.reply.send_to(&bytes, self.client) {
+                Ok(n) => self.received += n,
+                Err(err) => log::warn!("UDP: failed to reply to {}: {}", self.client, err),
+            }
+        }
+    }
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        ctx.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(port: u16) -> (SocketAddr, SocketAddr) {
+        (
+            SocketAddr::from(([10, 0, 0, 1], port)),
+            SocketAddr::from(([10, 0, 0, 2], 53)),
+        )
+    }
+
+    #[test]
+    fn sweep_blocked_flows() {
+        let mut flows = HashMap::new();
+        let old = Instant::now() - Duration::from_secs(120);
+        flows.insert(key(1), Flow::Blocked(old));
+        flows.insert(key(2), Flow::Blocked(Instant::now()));
+        flows.insert(key(3), Flow::Pending(Vec::new()));
+        assert_eq!(sweep(&mut flows, Duration::from_secs(60)), 1);
+        assert!(!flows.contains_key(&key(1)));
+        assert!(flows.contains_key(&key(2)));
+        // pending flows are resolved by their policy evaluation
+        assert!(flows.contains_key(&key(3)));
+        assert_eq!(sweep(&mut flows, Duration::from_secs(0)), 1);
+        assert_eq!(flows.len(), 1)
+    }
+}
@@ -0,0 +1,313 @@
+//! Upstream groups for ingress proxies: load balancing, retry budgets and passive outlier ejection
+/*
+ * Copyright (c) 2021 Arm Limited.
+ *
+ * SPDX-License-Identifier: MIT
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use armour_api::proxy::{Balance, LoadBalancing, Upstreams};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// retries that are always permitted (per window), regardless of the retry budget
+const MIN_RETRIES: u64 = 3;
+// the retry budget only counts requests and retries within a sliding window
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+const BUDGET_BUCKET: Duration = Duration::from_secs(1);
+
+struct Server {
+    addr: SocketAddr,
+    active: AtomicUsize,
+    failures: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Server {
+    fn new(addr: SocketAddr) -> Self {
+        Server {
+            addr,
+            active: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .map(|until| until.map(|t| now < t).unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+struct Bucket {
+    start: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+/// Requests and retries over the last `BUDGET_WINDOW`, in buckets of `BUDGET_BUCKET`
+#[derive(Default)]
+struct RetryBudget(VecDeque<Bucket>);
+
+impl RetryBudget {
+    fn bucket(&mut self, now: Instant) -> &mut Bucket {
+        while self
+            .0
+            .front()
+            .map(|b| now.saturating_duration_since(b.start) >= BUDGET_WINDOW)
+            .unwrap_or(false)
+        {
+            self.0.pop_front();
+        }
+        if self
+            .0
+            .back()
+            .map(|b| now.saturating_duration_since(b.start) >= BUDGET_BUCKET)
+            .unwrap_or(true)
+        {
+            self.0.push_back(Bucket {
+                start: now,
+                requests: 0,
+                retries: 0,
+            })
+        }
+        self.0.back_mut().expect("bucket")
+    }
+    fn request(&mut self, now: Instant) {
+        self.bucket(now).requests += 1
+    }
+    fn retry(&mut self, now: Instant, percent: u8) -> bool {
+        self.bucket(now);
+        let requests: u64 = self.0.iter().map(|b| b.requests).sum();
+        let retries: u64 = self.0.iter().map(|b| b.retries).sum();
+        if retries < requests * u64::from(percent) / 100 + MIN_RETRIES {
+            self.bucket(now).retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Group of upstream servers, shared by all of the HTTP proxy's workers
+pub struct UpstreamGroup {
+    servers: Vec<Server>,
+    config: LoadBalancing,
+    next: AtomicUsize,
+    budget: Mutex<RetryBudget>,
+}
+
+/// Marks a request as in flight (for least-request balancing) until dropped
+pub struct InFlight<'a>(&'a Server);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl UpstreamGroup {
+    pub fn new(upstreams: &Upstreams) -> Self {
+        UpstreamGroup {
+            servers: upstreams.servers.iter().cloned().map(Server::new).collect(),
+            config: upstreams.load_balancing.clone(),
+            next: AtomicUsize::new(0),
+            budget: Mutex::new(RetryBudget::default()),
+        }
+    }
+    /// Choose a server, avoiding ejected servers and those that have already been `tried`.
+    ///
+    /// If every remaining server is ejected then ejection is ignored.
+    pub fn select(&self, tried: &[SocketAddr]) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let untried: Vec<&Server> = self
+            .servers
+            .iter()
+            .filter(|s| !tried.contains(&s.addr))
+            .collect();
+        let healthy: Vec<&Server> = untried
+            .iter()
+            .filter(|s| !s.is_ejected(now))
+            .cloned()
+            .collect();
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        let server = match self.config.balance {
+            Balance::RoundRobin => candidates[start],
+            Balance::LeastRequest => candidates
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(candidates.len())
+                .min_by_key(|s| s.active.load(Ordering::Relaxed))
+                .cloned()?,
+        };
+        Some(server.addr)
+    }
+    pub fn start(&self, addr: SocketAddr) -> Option<InFlight<'_>> {
+        let server = self.server(addr)?;
+        server.active.fetch_add(1, Ordering::Relaxed);
+        Some(InFlight(server))
+    }
+    /// Count a new (client) request, for the retry budget
+    pub fn request(&self) {
+        if let Ok(mut budget) = self.budget.lock() {
+            budget.request(Instant::now())
+        }
+    }
+    pub fn max_retries(&self) -> u8 {
+        self.config.retries
+    }
+    /// Take a retry from the budget, if possible
+    pub fn retry(&self) -> bool {
+        let retry = self
+            .budget
+            .lock()
+            .map(|mut budget| budget.retry(Instant::now(), self.config.retry_budget))
+            .unwrap_or(false);
+        if !retry {
+            log::debug!("retry budget exhausted")
+        }
+        retry
+    }
+    /// Record the outcome of a request, ejecting servers after consecutive failures
+    pub fn report(&self, addr: SocketAddr, success: bool) {
+        if let Some(server) = self.server(addr) {
+            if success {
+                server.failures.store(0, Ordering::Relaxed)
+            } else {
+                let failures = server.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= usize::from(self.config.eject_after.max(1)) {
+                    server.failures.store(0, Ordering::Relaxed);
+                    if let Ok(mut until) = server.ejected_until.lock() {
+                        let secs = self.config.eject_secs;
+                        log::warn!("ejecting upstream {} for {}s", addr, secs);
+                        *until = Some(Instant::now() + Duration::from_secs(secs.into()))
+                    }
+                }
+            }
+        }
+    }
+    fn server(&self, addr: SocketAddr) -> Option<&Server> {
+        self.servers.iter().find(|s| s.addr == addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(n: u16, config: LoadBalancing) -> UpstreamGroup {
+        UpstreamGroup::new(&Upstreams {
+            servers: (0..n)
+                .map(|i| SocketAddr::from(([127, 0, 0, 1], 8000 + i)))
+                .collect(),
+            load_balancing: config,
+        })
+    }
+
+    #[test]
+    fn retry_budget() {
+        let now = Instant::now();
+        let mut budget = RetryBudget::default();
+        // minimum retries
+        assert!((0..MIN_RETRIES).all(|_| budget.retry(now, 20)));
+        assert!(!budget.retry(now, 20));
+        // 20% of requests
+        for _ in 0..10 {
+            budget.request(now)
+        }
+        assert!(budget.retry(now, 20));
+        assert!(budget.retry(now, 20));
+        assert!(!budget.retry(now, 20));
+    }
+
+    #[test]
+    fn retry_budget_window() {
+        let now = Instant::now();
+        let mut budget = RetryBudget::default();
+        for _ in 0..100 {
+            budget.request(now)
+        }
+        // past requests do not fund retries after the window
+        let later = now + BUDGET_WINDOW;
+        assert!((0..MIN_RETRIES).all(|_| budget.retry(later, 20)));
+        assert!(!budget.retry(later, 20));
+        // past retries do not exhaust the budget after the window
+        let later = later + BUDGET_WINDOW;
+        assert!(budget.retry(later, 20));
+        // old buckets are dropped
+        assert!(budget.0.len() <= (BUDGET_WINDOW.as_secs() / BUDGET_BUCKET.as_secs()) as usize);
+    }
+
+    #[test]
+    fn retry_budget_buckets() {
+        let now = Instant::now();
+        let mut budget = RetryBudget::default();
+        for i in 0..20u32 {
+            budget.request(now + BUDGET_BUCKET * i)
+        }
+        // only the requests in the last window count: 10 requests => 2 retries + minimum
+        let now = now + BUDGET_BUCKET * 19;
+        assert!((0..MIN_RETRIES + 2).all(|_| budget.retry(now, 20)));
+        assert!(!budget.retry(now, 20));
+    }
+
+    #[test]
+    fn select_and_eject() {
+        let upstreams = group(
+            2,
+            LoadBalancing {
+                eject_after: 2,
+                ..LoadBalancing::default()
+            },
+        );
+        let a = upstreams.select(&[]).unwrap();
+        let b = upstreams.select(&[a]).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(upstreams.select(&[a, b]), None);
+        upstreams.report(a, false);
+        upstreams.report(a, false);
+        assert!((0..4).all(|_| upstreams.select(&[]) == Some(b)));
+        // ejection is ignored if there is no other server
+        assert_eq!(upstreams.select(&[b]), Some(a));
+    }
+
+    #[test]
+    fn least_request() {
+        let upstreams = group(
+            2,
+            LoadBalancing {
+                balance: Balance::LeastRequest,
+                ..LoadBalancing::default()
+            },
+        );
+        let a = upstreams.select(&[]).unwrap();
+        let _in_flight = upstreams.start(a).unwrap();
+        assert!((0..4).all(|_| upstreams.select(&[]) != Some(a)));
+    }
+}
@@ -95,7 +95,7 @@ pub fn bincode_gz_base64_dec<R: std::io::Read, D: serde::de::DeserializeOwned>(
 
 pub fn parse_https_url(s: &str, default_port: u16) -> Result<url::Url, String> {
     let err = || format!("failed to parse HTTPS URL: {}", s);
-    if let Ok(socket) = s.parse::<std::net::SocketAddrV4>() {
+    if let Ok(socket) = s.parse::<std::net::SocketAddr>() {
         format!("https://{}", socket)
             .parse::<url::Url>()
             .map_err(|_| err())
@@ -180,3 +180,37 @@ pub fn client<P: AsRef<std::path::Path>>(
         )
         .finish())
 }
+
+/// Prefix of credentials that are bound to an mTLS client certificate.
+pub const CERTIFICATE_CREDENTIALS: &str = "cert";
+
+/// Credentials that prove possession of a pkcs12 certificate.
+///
+/// The result has the form `cert:<certificate>:<time>:<nonce>:<signature>`, where the
+/// signature (made with the certificate's private key) covers `<claimant>:<time>:<nonce>`.
+/// The certificate should also be the client's mTLS certificate.
+pub fn certificate_credentials<P: AsRef<std::path::Path>>(
+    certificate_password: &str,
+    certificate: P,
+    claimant: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = std::fs::read(certificate.as_ref())?;
+    let p12 = openssl::pkcs12::Pkcs12::from_der(&bytes)?.parse(certificate_password)?;
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut nonce = [0u8; 16];
+    openssl::rand::rand_bytes(&mut nonce)?;
+    let nonce: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+    let mut signer =
+        openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &p12.pkey)?;
+    signer.update(format!("{}:{}:{}", claimant, time, nonce).as_bytes())?;
+    Ok(format!(
+        "{}:{}:{}:{}:{}",
+        CERTIFICATE_CREDENTIALS,
+        openssl::base64::encode_block(&p12.cert.to_der()?),
+        time,
+        nonce,
+        openssl::base64::encode_block(&signer.sign_to_vec()?)
+    ))
+}